@@ -0,0 +1,149 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::key_value_store::DurableKeyValueStore;
+
+/// Put queued by [`AsyncWriter::put_async`], with where its outcome goes.
+struct QueuedPut {
+    key: Vec<u8>,
+    val: Vec<u8>,
+    done: mpsc::Sender<io::Result<()>>,
+}
+
+/// Applies puts to a [`DurableKeyValueStore`] from a writer thread, so producers don't wait for the WAL write. The
+/// queue between them is bounded: once `capacity` puts are waiting, [`AsyncWriter::put_async`] blocks until the
+/// writer takes one, so a producer outpacing the disk is slowed down instead of growing the queue without limit.
+///
+/// Dropping the writer applies the puts already queued, then stops the thread.
+pub struct AsyncWriter {
+    sender: Option<SyncSender<QueuedPut>>,
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Outcome of a queued put, see [`AsyncWriter::put_async`].
+#[derive(Debug)]
+pub struct PendingPut {
+    done: Receiver<io::Result<()>>,
+}
+
+impl PendingPut {
+    /// Waits until the put is applied, with the error of its WAL write if it failed.
+    pub fn wait(self) -> io::Result<()> {
+        self.done.recv().unwrap_or_else(|_| Err(writer_stopped()))
+    }
+}
+
+impl AsyncWriter {
+    /// Starts the writer thread putting to `store`, with room for `capacity` queued puts (at least 1).
+    pub fn start<W: Write + Send + Sync + 'static>(store: Arc<DurableKeyValueStore<W>>, capacity: usize) -> io::Result<Self> {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<QueuedPut>(capacity);
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        let writer_depth = depth.clone();
+        let writer = thread::Builder::new()
+            .name("pigment-async-writer".to_string())
+            .spawn(move || {
+                for queued in receiver {
+                    writer_depth.fetch_sub(1, Ordering::AcqRel);
+                    // the producer may have dropped its `PendingPut` without waiting
+                    let _ = queued.done.send(store.put(queued.key, queued.val));
+                }
+            })?;
+
+        Ok(AsyncWriter { sender: Some(sender), depth, capacity, writer: Some(writer) })
+    }
+
+    /// Queues the put of `val` at `key`, blocking while the queue is full. The returned [`PendingPut`] tells when it
+    /// was applied; puts are applied in the order they were queued.
+    pub fn put_async(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<PendingPut> {
+        let (done, pending) = mpsc::channel();
+        self.depth.fetch_add(1, Ordering::AcqRel);
+        let sent = self.sender.as_ref().expect("sender lives as long as the writer").send(QueuedPut { key, val, done });
+        if sent.is_err() {
+            self.depth.fetch_sub(1, Ordering::AcqRel);
+            return Err(writer_stopped());
+        }
+        Ok(PendingPut { done: pending })
+    }
+
+    /// Puts handed to [`AsyncWriter::put_async`] and not taken by the writer yet, including the ones still waiting
+    /// for room in the queue. Only producers blocked by back-pressure take it over the capacity.
+    pub fn queue_depth(&self) -> usize {
+        self.depth.load(Ordering::Acquire)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "async writer thread stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    #[test]
+    fn test_back_pressure() {
+        let store = Arc::new(DurableKeyValueStore::new_vec_based());
+        let writer = AsyncWriter::start(store.clone(), 2).unwrap();
+
+        // holding the entry of `slow` stalls the writer on its first put, as a slow disk would
+        let (entered, entered_receiver) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel::<()>();
+        let blocker_store = store.clone();
+        let blocker = thread::spawn(move || {
+            blocker_store.with_entry(b"slow".to_vec(), |_| {
+                entered.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            }).unwrap();
+        });
+        entered_receiver.recv().unwrap();
+
+        let mut pending = vec![writer.put_async(b"slow".to_vec(), b"0".to_vec()).unwrap()];
+        while writer.queue_depth() > 0 {
+            thread::yield_now();
+        }
+        for i in 1..=2u8 {
+            pending.push(writer.put_async(vec![i], vec![i]).unwrap());
+        }
+        assert_eq!(writer.queue_depth(), 2);
+
+        let (queued, queued_receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| queued.send(writer.put_async(vec![3], vec![3]).unwrap()).unwrap());
+            assert_eq!(queued_receiver.recv_timeout(Duration::from_millis(100)).unwrap_err(), RecvTimeoutError::Timeout);
+            assert_eq!(writer.queue_depth(), 3);
+
+            release.send(()).unwrap();
+            pending.push(queued_receiver.recv().unwrap());
+        });
+        blocker.join().unwrap();
+
+        for put in pending {
+            put.wait().unwrap();
+        }
+        assert_eq!(writer.queue_depth(), 0);
+        assert_eq!(store.get(b"slow"), Some(b"0".to_vec()));
+        assert_eq!(store.get(&[3]), Some(vec![3]));
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for expiry, injected so time-dependent behaviour can be tested and benchmarked
+/// without sleeping, see [`crate::key_value_store::DurableKeyValueStore::with_clock`].
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// Wall clock time, the default of every store.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+    }
+}
+
+/// Clock moving only when told to. Shared as an `Arc` between the store and the test driving it.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    millis: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(start_millis: u64) -> Self {
+        ManualClock { millis: AtomicU64::new(start_millis) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.millis.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
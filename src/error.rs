@@ -0,0 +1,117 @@
+use std::fmt;
+use std::io;
+
+use crate::wal::WalReadError;
+
+/// Error of the crate's fallible reads and updates, for callers which need to tell what went wrong. APIs returning
+/// `io::Result` wrap it in an `io::Error`, get it back with `error.get_ref()` and `downcast_ref::<PigmentError>()`.
+/// Offsets are relative to the end of the WAL header.
+#[derive(Debug)]
+pub enum PigmentError {
+    Io(io::Error),
+    /// The block ending at `offset` doesn't point back at a valid block start.
+    Corruption { offset: usize },
+    /// Data of the block at `offset` passed CRC verification but isn't a valid encoding of its action type.
+    Decode { offset: usize },
+    /// Data of the block at `offset` doesn't match its CRC.
+    CrcMismatch { offset: usize },
+    /// The stored value isn't an 8 bytes number.
+    NotANumber,
+    /// The number would go past `u64::MAX`.
+    Overflow,
+    /// The key is longer than the store's [`crate::model::SizeLimits::max_key_bytes`].
+    KeyTooLarge { len: usize, max: usize },
+    /// The value is longer than the store's [`crate::model::SizeLimits::max_value_bytes`].
+    ValueTooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for PigmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PigmentError::Io(error) => write!(f, "I/O error: {}", error),
+            PigmentError::Corruption { offset } => write!(f, "corrupted WAL block ending at offset {}", offset),
+            PigmentError::Decode { offset } => write!(f, "can't decode WAL block at offset {}", offset),
+            PigmentError::CrcMismatch { offset } => write!(f, "CRC mismatch of WAL block at offset {}", offset),
+            PigmentError::NotANumber => write!(f, "stored value is not an 8 bytes number"),
+            PigmentError::Overflow => write!(f, "number overflow"),
+            PigmentError::KeyTooLarge { len, max } => write!(f, "key of {} bytes is over the limit of {} bytes", len, max),
+            PigmentError::ValueTooLarge { len, max } => write!(f, "value of {} bytes is over the limit of {} bytes", len, max),
+        }
+    }
+}
+
+impl std::error::Error for PigmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PigmentError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// I/O errors are equal when of the same kind.
+impl PartialEq for PigmentError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PigmentError::Io(error), PigmentError::Io(other)) => error.kind() == other.kind(),
+            (PigmentError::Corruption { offset }, PigmentError::Corruption { offset: other })
+            | (PigmentError::Decode { offset }, PigmentError::Decode { offset: other })
+            | (PigmentError::CrcMismatch { offset }, PigmentError::CrcMismatch { offset: other }) => offset == other,
+            (PigmentError::NotANumber, PigmentError::NotANumber) | (PigmentError::Overflow, PigmentError::Overflow) => true,
+            (PigmentError::KeyTooLarge { len, max }, PigmentError::KeyTooLarge { len: other_len, max: other_max })
+            | (PigmentError::ValueTooLarge { len, max }, PigmentError::ValueTooLarge { len: other_len, max: other_max }) => {
+                len == other_len && max == other_max
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for PigmentError {
+    fn from(error: io::Error) -> Self {
+        PigmentError::Io(error)
+    }
+}
+
+impl From<WalReadError> for PigmentError {
+    fn from(error: WalReadError) -> Self {
+        match error {
+            WalReadError::DecodeFailed { offset } => PigmentError::Decode { offset },
+            WalReadError::CrcMismatch { offset } => PigmentError::CrcMismatch { offset },
+            WalReadError::CutShort { end, .. } => PigmentError::Corruption { offset: end },
+        }
+    }
+}
+
+impl From<PigmentError> for io::Error {
+    fn from(error: PigmentError) -> Self {
+        match error {
+            PigmentError::Io(error) => error,
+            PigmentError::Overflow | PigmentError::KeyTooLarge { .. } | PigmentError::ValueTooLarge { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, error)
+            }
+            _ => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let error = PigmentError::from(WalReadError::DecodeFailed { offset: 7 });
+        assert_eq!(error, PigmentError::Decode { offset: 7 });
+
+        let io_error = io::Error::from(PigmentError::NotANumber);
+        assert_eq!(io_error.kind(), io::ErrorKind::InvalidData);
+        let inner = io_error.get_ref().and_then(|inner| inner.downcast_ref::<PigmentError>());
+        assert!(matches!(inner, Some(PigmentError::NotANumber)));
+        assert_eq!(io::Error::from(PigmentError::Overflow).kind(), io::ErrorKind::InvalidInput);
+
+        let error = PigmentError::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert!(std::error::Error::source(&error).is_some());
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::key_value_store::DurableKeyValueStore;
+
+/// Count per node id, the value stored under each counter key.
+pub type Slots = BTreeMap<Vec<u8>, u64>;
+
+/// Grow-only counters (G-Counter CRDT) on top of [`DurableKeyValueStore`]: each node increments only its own slot
+/// and the value of a counter is the sum of all slots. Replicas exchange [`GCounterStore::slots`] and merge them by
+/// taking the max per slot, which is commutative and idempotent, so no increment is lost or counted twice whatever
+/// the order or number of merges.
+pub struct GCounterStore<W: Write> {
+    store: DurableKeyValueStore<W>,
+}
+
+impl GCounterStore<File> {
+    pub fn init_new(store_dir: &str) -> Self {
+        GCounterStore::new(DurableKeyValueStore::init_new(store_dir))
+    }
+}
+
+impl GCounterStore<Vec<u8>> {
+    pub fn new_vec_based() -> Self {
+        GCounterStore::new(DurableKeyValueStore::new_vec_based())
+    }
+}
+
+impl<W: Write> GCounterStore<W> {
+    /// Every value of `store` is expected to be counter slots, other values fail with `ErrorKind::InvalidData`.
+    pub fn new(store: DurableKeyValueStore<W>) -> Self {
+        GCounterStore { store }
+    }
+
+    /// Adds `by` to the slot of `node_id`, returns the new count of the slot.
+    pub fn increment(&self, key: Vec<u8>, node_id: &[u8], by: u64) -> io::Result<u64> {
+        let slots = self.update_slots(key, |slots| {
+            let count = slots.entry(node_id.to_vec()).or_insert(0);
+            *count = count.saturating_add(by);
+            true
+        })?;
+        Ok(slots[node_id])
+    }
+
+    /// Sum of all slots, 0 for a counter never incremented.
+    pub fn value(&self, key: &[u8]) -> io::Result<u64> {
+        Ok(self.slots(key)?.values().fold(0, |sum, count| sum.saturating_add(*count)))
+    }
+
+    /// Slots of the counter to be sent to other replicas, empty for a counter never incremented.
+    pub fn slots(&self, key: &[u8]) -> io::Result<Slots> {
+        match self.store.get(key) {
+            Some(bytes) => decode(&bytes),
+            None => Ok(Slots::new()),
+        }
+    }
+
+    /// Merges slots of the same counter received from another replica, keeping the greater count per slot.
+    /// Nothing is written if no slot grows.
+    pub fn merge(&self, key: Vec<u8>, other_slots: &Slots) -> io::Result<()> {
+        self.update_slots(key, |slots| {
+            let mut changed = false;
+            for (node_id, other_count) in other_slots {
+                let count = slots.entry(node_id.clone()).or_insert(0);
+                if *other_count > *count {
+                    *count = *other_count;
+                    changed = true;
+                }
+            }
+            changed
+        })?;
+        Ok(())
+    }
+
+    /// Runs `func` over the slots under the entry lock of `key` and writes them back if it returns `true`.
+    fn update_slots(&self, key: Vec<u8>, func: impl FnOnce(&mut Slots) -> bool) -> io::Result<Slots> {
+        self.store.with_entry(key, |entry| {
+            let mut slots = match entry.get() {
+                Some(bytes) => decode(bytes)?,
+                None => Slots::new(),
+            };
+            if func(&mut slots) {
+                entry.put(bincode::serialize(&slots).expect("slots should be serialized with bincode"));
+            }
+            Ok(slots)
+        })?
+    }
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Slots> {
+    bincode::deserialize(bytes)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("stored value is not counter slots: {}", error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_divergent_replicas() {
+        let replica_a = GCounterStore::new_vec_based();
+        let replica_b = GCounterStore::new_vec_based();
+
+        replica_a.increment(b"hits".to_vec(), b"a", 3).unwrap();
+        replica_b.increment(b"hits".to_vec(), b"b", 5).unwrap();
+        replica_a.merge(b"hits".to_vec(), &replica_b.slots(b"hits").unwrap()).unwrap();
+
+        replica_a.increment(b"hits".to_vec(), b"a", 2).unwrap();
+        replica_b.increment(b"hits".to_vec(), b"b", 1).unwrap();
+        assert_eq!(replica_a.value(b"hits").unwrap(), 10);
+        assert_eq!(replica_b.value(b"hits").unwrap(), 6);
+
+        replica_a.merge(b"hits".to_vec(), &replica_b.slots(b"hits").unwrap()).unwrap();
+        replica_b.merge(b"hits".to_vec(), &replica_a.slots(b"hits").unwrap()).unwrap();
+        replica_b.merge(b"hits".to_vec(), &replica_a.slots(b"hits").unwrap()).unwrap();
+        assert_eq!(replica_a.value(b"hits").unwrap(), 11);
+        assert_eq!(replica_b.value(b"hits").unwrap(), 11);
+        assert_eq!(replica_a.slots(b"hits").unwrap(), replica_b.slots(b"hits").unwrap());
+
+        assert_eq!(replica_a.value(b"misses").unwrap(), 0);
+        replica_a.store.put(b"raw".to_vec(), vec![1]).unwrap();
+        assert_eq!(replica_a.value(b"raw").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
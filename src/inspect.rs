@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::wal::model::StoreType;
+use crate::wal::{CorruptionPolicy, CorruptionReport, ReplaySource, WalContent, WalStats};
+
+/// WAL file of each store type, as named by the stores in their directory.
+const STORE_WAL_FILES: [(StoreType, &str); 4] = [
+    (StoreType::KeyValue, crate::key_value_store::KV_WAL_FILE_NAME),
+    (StoreType::KeySet, crate::key_set_store::SET_WAL_FILE_NAME),
+    (StoreType::KeyMap, crate::key_map_store::MAP_WAL_FILE_NAME),
+    (StoreType::LinkedMap, crate::linked_map_store::LINKED_MAP_WAL_FILE_NAME),
+];
+
+/// Summary of the stores found in a directory by [`inspect_dir`], in the order of [`StoreType`].
+#[derive(Debug, Clone, Default)]
+pub struct DirReport {
+    pub stores: Vec<StoreReport>,
+}
+
+impl DirReport {
+    pub fn store(&self, store_type: StoreType) -> Option<&StoreReport> {
+        self.stores.iter().find(|store| store.store_type == store_type)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreReport {
+    pub store_type: StoreType,
+    pub wal_path: PathBuf,
+    /// Keys the store would hold once restored.
+    pub entries: usize,
+    /// Block counts per action type and total bytes, see [`WalStats`].
+    pub wal_stats: WalStats,
+    /// Blocks left out of `entries` for failing their CRC, from the first bad one to the end.
+    pub corruption: CorruptionReport,
+}
+
+/// Finds the WAL of each store type in `dir` and summarizes it without changing anything: the WALs are only mapped
+/// (or streamed, when they can't be) for reading, unlike opening the stores, which rewrites them. A WAL cut short or failing a CRC is reported up to
+/// its first bad block, as [`CorruptionPolicy::TruncateAt`] would restore it. Fails with a
+/// [`crate::wal::StoreTypeMismatch`] for a WAL written by another store type than its file name says.
+pub fn inspect_dir(dir: &Path) -> io::Result<DirReport> {
+    let mut stores = Vec::new();
+    for (store_type, file_name) in STORE_WAL_FILES {
+        let wal_path = dir.join(file_name);
+        if wal_path.exists() {
+            stores.push(inspect_wal(store_type, wal_path)?);
+        }
+    }
+    Ok(DirReport { stores })
+}
+
+fn inspect_wal(store_type: StoreType, wal_path: PathBuf) -> io::Result<StoreReport> {
+    crate::wal::check_store_type(&wal_path, store_type)?;
+    let file = File::open(&wal_path)?;
+    let total_bytes = file.metadata()?.len() as usize;
+    if total_bytes == 0 {
+        let wal_stats = crate::wal::wal_stats(&[]);
+        return Ok(StoreReport { store_type, wal_path, entries: 0, wal_stats, corruption: CorruptionReport::default() });
+    }
+
+    let (entries, corruption, wal_stats) = match crate::wal::map_or_stream(file) {
+        WalContent::Mapped(mmap) => {
+            let (entries, corruption) = count_entries(store_type, mmap.as_ref())?;
+            (entries, corruption, crate::wal::wal_stats(mmap.as_ref()))
+        }
+        content => {
+            let (entries, corruption) = count_entries(store_type, content)?;
+            // the replay consumed the file, stats read it again from the start
+            (entries, corruption, crate::wal::wal_stats_from_reader(File::open(&wal_path)?, total_bytes)?)
+        }
+    };
+
+    Ok(StoreReport { store_type, wal_path, entries, wal_stats, corruption })
+}
+
+/// Keys the store of `store_type` would hold once restored from `source`, up to its first bad block.
+fn count_entries<S: ReplaySource>(store_type: StoreType, source: S) -> Result<(usize, CorruptionReport), S::Error> {
+    let policy = CorruptionPolicy::TruncateAt;
+    Ok(match store_type {
+        StoreType::KeyValue => {
+            // only keys are counted, so merges can be replayed with any operator
+            let keep_operand = |_: Option<&[u8]>, operand: &[u8]| operand.to_vec();
+            let (map, _expirations, report) = crate::wal::replay_forward_transformed(source, Some(&keep_operand), None, policy, None)?;
+            (map.len(), report)
+        }
+        StoreType::KeySet => {
+            let (sets, report) = crate::wal::replay_for_set(source, policy)?;
+            (sets.len(), report)
+        }
+        StoreType::KeyMap => {
+            let (maps, report) = crate::wal::replay_for_map(source, policy)?;
+            (maps.len(), report)
+        }
+        StoreType::LinkedMap => {
+            let (maps, report) = crate::wal::replay_for_linked_map(source, policy)?;
+            (maps.len(), report)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_map_store::DurableKeyMapStore;
+    use crate::key_set_store::DurableKeySetStore;
+    use crate::key_value_store::DurableKeyValueStore;
+    use crate::model::SearchKey;
+    use crate::wal::model::{MAP_PUT_ACT, PUT_ACT, SET_APPEND_ACT};
+
+    #[test]
+    fn test_inspect_dir() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_inspect_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+
+        {
+            let kv = DurableKeyValueStore::init_new(store_dir);
+            kv.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+            kv.put(b"k1".to_vec(), b"v2".to_vec()).unwrap();
+            kv.put(b"k2".to_vec(), b"v".to_vec()).unwrap();
+
+            let set = DurableKeySetStore::init_new(store_dir);
+            set.append(b"s".to_vec(), b"a".to_vec()).unwrap();
+            set.append(b"s".to_vec(), b"b".to_vec()).unwrap();
+
+            let map = DurableKeyMapStore::init_new(store_dir);
+            map.put(b"m1".to_vec(), SearchKey::from("x"), b"1".to_vec()).unwrap();
+            map.put(b"m2".to_vec(), SearchKey::from("y"), b"2".to_vec()).unwrap();
+            map.put(b"m3".to_vec(), SearchKey::from("z"), b"3".to_vec()).unwrap();
+        }
+        let kv_wal = std::fs::read(dir.join("kv.wal.dat")).unwrap();
+
+        let report = inspect_dir(&dir).unwrap();
+        assert_eq!(report.stores.len(), 3);
+        assert!(report.store(StoreType::LinkedMap).is_none());
+
+        let kv = report.store(StoreType::KeyValue).unwrap();
+        assert_eq!(kv.entries, 2);
+        assert_eq!(kv.wal_stats.action_count(PUT_ACT), 3);
+        assert_eq!(kv.wal_stats.total_bytes, kv_wal.len());
+        assert!(kv.corruption.is_clean());
+
+        let set = report.store(StoreType::KeySet).unwrap();
+        assert_eq!((set.entries, set.wal_stats.action_count(SET_APPEND_ACT)), (1, 2));
+
+        let map = report.store(StoreType::KeyMap).unwrap();
+        assert_eq!((map.entries, map.wal_stats.action_count(MAP_PUT_ACT)), (3, 3));
+        assert_eq!(map.wal_path, dir.join("map.wal.dat"));
+
+        let streamed = crate::wal::streamed::with_mmap_failing(|| inspect_dir(&dir)).unwrap();
+        assert_eq!(streamed.stores.len(), 3);
+        for (mapped, streamed) in report.stores.iter().zip(&streamed.stores) {
+            assert_eq!((streamed.entries, &streamed.wal_stats, &streamed.corruption), (mapped.entries, &mapped.wal_stats, &mapped.corruption));
+        }
+
+        assert_eq!(std::fs::read(dir.join("kv.wal.dat")).unwrap(), kv_wal);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
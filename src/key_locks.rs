@@ -0,0 +1,72 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::{Condvar, Mutex};
+
+const KEY_LOCK_SHARDS: usize = 64;
+
+/// Advisory locks on keys for critical sections spanning several store calls (e.g. read, call another service,
+/// then write). Only callers taking the lock are serialized: the store itself never checks it, so a plain `put`
+/// of a locked key goes through. Locked keys are kept in sharded sets, a key takes no memory once unlocked.
+pub struct KeyLocks {
+    shards: Vec<KeyLockShard>,
+}
+
+struct KeyLockShard {
+    locked: Mutex<HashSet<Vec<u8>>>,
+    unlocked: Condvar,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        let shards = (0..KEY_LOCK_SHARDS)
+            .map(|_| KeyLockShard { locked: Mutex::new(HashSet::new()), unlocked: Condvar::new() })
+            .collect();
+        KeyLocks { shards }
+    }
+
+    /// Blocks until no other guard of `key` is alive, the lock is released when the returned guard is dropped.
+    /// Not reentrant: locking a key again from the thread holding it deadlocks.
+    pub fn lock(&self, key: &[u8]) -> KeyLockGuard<'_> {
+        let shard = &self.shards[shard_idx(key)];
+        let mut locked = shard.locked.lock().unwrap();
+        while locked.contains(key) {
+            locked = shard.unlocked.wait(locked).unwrap();
+        }
+        locked.insert(key.to_vec());
+
+        KeyLockGuard { shard, key: key.to_vec() }
+    }
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        KeyLocks::new()
+    }
+}
+
+/// Holds the lock of a key taken by [`KeyLocks::lock`] until dropped.
+pub struct KeyLockGuard<'a> {
+    shard: &'a KeyLockShard,
+    key: Vec<u8>,
+}
+
+impl KeyLockGuard<'_> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl Drop for KeyLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.shard.locked.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        locked.remove(&self.key);
+        self.shard.unlocked.notify_all();
+    }
+}
+
+fn shard_idx(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % KEY_LOCK_SHARDS
+}
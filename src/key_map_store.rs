@@ -1,79 +1,122 @@
 use dashmap::DashMap;
 use log::info;
 
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
 
-use memmap::MmapOptions;
 use std::fs::File;
 
-use crate::model::{Key, SearchKey};
-use crate::wal::WalStorage;
+use crate::error::PigmentError;
+use crate::model::{self, Key, SearchKey, ShardHasher, SizeLimits};
+use crate::reentrancy;
+use crate::wal::model::StoreType;
+use crate::wal::{CorruptionPolicy, CorruptionReport, ReadableWal, SegmentedWal, SortedMaps, SyncWal, WalStats, WalStorage};
 use dashmap::mapref::entry::Entry;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+use std::sync::Arc;
 
-const MAP_WAL_FILE_NAME: &str = "map.wal.dat";
+pub(crate) const MAP_WAL_FILE_NAME: &str = "map.wal.dat";
 const TMP_MAP_WAL_FILE_NAME: &str = ".map.wal.dat";
+const MAP_WAL_NAME: &str = "map.wal";
+/// Size of the count and length prefixes of [`DurableKeyMapStore::range_entries_encoded`].
+const RANGE_LEN_BYTES: usize = 4;
 
+/// Sorted map of each key, sharded by key.
+type ShardedMaps = DashMap<Vec<u8>, Arc<BTreeMap<SearchKey, Vec<u8>>>, ShardHasher>;
+
+/// Changes are written to the WAL and then applied to the sorted maps under the entry lock of their key, so the WAL
+/// is always at least as new as the maps, see [`crate::key_value_store::DurableKeyValueStore`].
+///
+/// Inner sorted maps are shared through `Arc`, so [`DurableKeyMapStore::snapshot_sorted_map`] is a reference
+/// count increment. Mutations go through `Arc::make_mut`: free while no snapshot of that key is alive, otherwise
+/// the first mutation after a snapshot clones the whole inner map (O(n) in its size) under the entry lock.
 pub struct DurableKeyMapStore<W: Write> {
-    store: DashMap<Vec<u8>, BTreeMap<SearchKey, Vec<u8>>>,
+    store: ShardedMaps,
     wal: WalStorage<W>,
+    limits: SizeLimits,
 }
 
 #[allow(unused)]
 impl DurableKeyMapStore<File> {
     pub fn init_new(store_dir: &str) -> Self {
+        Self::init(store_dir, CorruptionPolicy::Abort, false).0
+    }
+
+    /// Same as [`DurableKeyMapStore::init_new`], failing with a [`crate::wal::StoreTypeMismatch`] if the WAL was
+    /// written by another store type, see [`crate::key_value_store::DurableKeyValueStore::try_init_new`].
+    pub fn try_init_new(store_dir: &str) -> io::Result<Self> {
+        Ok(Self::try_init(store_dir, CorruptionPolicy::Abort, false)?.0)
+    }
+
+    /// Same as [`DurableKeyMapStore::init_new`], additionally renumbering the elements appended with
+    /// [`DurableKeyMapStore::append_ordered_element`] densely from 0 in their order, so a list left with 0, 5, 9
+    /// after removals becomes 0, 1, 2. Other search keys are kept as is. Opt-in, as references to the old
+    /// numbers held outside of the store no longer match.
+    pub fn init_new_renumbering_ordered(store_dir: &str) -> Self {
+        Self::init(store_dir, CorruptionPolicy::Abort, true).0
+    }
+
+    /// Opens the store in `store_dir` by replaying its WAL in place, see
+    /// [`crate::key_value_store::DurableKeyValueStore::open_in_place`].
+    pub fn open_in_place(store_dir: &str) -> io::Result<Self> {
+        let wal_file_path = Path::new(store_dir).join(MAP_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeyMap)?;
+        let wal = WalStorage::open_file_based(&wal_file_path)?;
+
+        let file = File::open(&wal_file_path)?;
+        let (map, _) = crate::wal::into_io(crate::wal::replay_for_map(crate::wal::map_or_stream(file), CorruptionPolicy::Abort))?;
+        let mut store: ShardedMaps = DashMap::with_capacity_and_hasher(map.len(), ShardHasher::default());
+        store.extend(map.into_iter().map(|(key, map)| (key, Arc::new(map))));
+        info!("opened {} entries in place from {}", store.len(), wal_file_path.to_str().unwrap());
+
+        Ok(DurableKeyMapStore { store, wal, limits: SizeLimits::default() })
+    }
+
+    /// Same as [`DurableKeyMapStore::init_new`], with corrupted blocks of the previous WAL handled according to `policy`,
+    /// see [`crate::key_value_store::DurableKeyValueStore::init_new_with_corruption_policy`].
+    pub fn init_new_with_corruption_policy(store_dir: &str, policy: CorruptionPolicy) -> io::Result<(Self, CorruptionReport)> {
+        Self::try_init(store_dir, policy, false)
+    }
+
+    fn init(store_dir: &str, policy: CorruptionPolicy, renumber_ordered: bool) -> (Self, CorruptionReport) {
+        match Self::try_init(store_dir, policy, renumber_ordered) {
+            Ok(initialized) => initialized,
+            Err(e) => panic!("can't restore {}: {}", Path::new(store_dir).join(MAP_WAL_FILE_NAME).to_str().unwrap(), e),
+        }
+    }
+
+    fn try_init(store_dir: &str, policy: CorruptionPolicy, renumber_ordered: bool) -> io::Result<(Self, CorruptionReport)> {
         let store_dir_path = Path::new(store_dir);
         let wal_file_path = store_dir_path.join(MAP_WAL_FILE_NAME);
         let tmp_wal_file_path = store_dir_path.join(TMP_MAP_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeyMap)?;
 
-        let store: DashMap<Vec<u8>, BTreeMap<SearchKey, Vec<u8>>> = DashMap::new();
+        let mut store: ShardedMaps = DashMap::default();
         let mut found_set_wal = wal_file_path.exists();
 
         if found_set_wal {
-            if std::fs::metadata(&wal_file_path).unwrap().len() == 0 {
+            if std::fs::metadata(&wal_file_path)?.len() == 0 {
                 let _ = std::fs::remove_file(&wal_file_path);
                 found_set_wal = false;
             } else {
-                let _ = std::fs::rename(&wal_file_path, &tmp_wal_file_path).unwrap();
+                std::fs::rename(&wal_file_path, &tmp_wal_file_path)?;
             }
         }
 
-        let wal = WalStorage::new_file_based(wal_file_path.as_path());
+        let wal = WalStorage::new_file_based_for_store(wal_file_path.as_path(), StoreType::KeyMap);
+        let mut report = CorruptionReport::default();
 
         if found_set_wal {
-            let file = File::open(&tmp_wal_file_path).unwrap();
+            let file = File::open(&tmp_wal_file_path)?;
             info!(
                 "found KeySet WAL file: {}, trying to restore...",
                 &wal_file_path.to_str().unwrap()
             );
 
-            let content_as_slice = unsafe { MmapOptions::new().map(&file).unwrap() };
-
-            let map = crate::wal::read_for_map(content_as_slice.as_ref());
-            info!(
-                "restored map with size: {}, adding new new WAL file",
-                map.len()
-            );
-
-            for (each_key, entry_map) in map {
-                for (search_key, element) in entry_map {
-                    let (key, search_key, element) =
-                        wal.store_put_to_map_event(each_key.clone(), search_key, element);
-                    match store.entry(each_key.clone()) {
-                        Entry::Occupied(mut entry) => {
-                            let found_map: &mut BTreeMap<SearchKey, Vec<u8>> = entry.get_mut();
-                            found_map.insert(search_key, element);
-                        }
-                        Entry::Vacant(vacant) => {
-                            let mut new_map = BTreeMap::new();
-                            new_map.insert(search_key, element);
-                            vacant.insert(new_map);
-                        }
-                    }
-                }
-            }
-            info!("{} entries added to store", store.len());
+            let (map, replay_report) = crate::wal::into_io(crate::wal::replay_for_map(crate::wal::map_or_stream(file), policy))?;
+            restore(&mut store, &wal, map, renumber_ordered)?;
+            report = replay_report;
 
             let _ = std::fs::remove_file(tmp_wal_file_path.as_path());
             info!(
@@ -87,29 +130,119 @@ impl DurableKeyMapStore<File> {
             );
         }
 
-        DurableKeyMapStore { store, wal }
+        Ok((DurableKeyMapStore { store, wal, limits: SizeLimits::default() }, report))
+    }
+}
+
+impl DurableKeyMapStore<SegmentedWal> {
+    /// Same as [`DurableKeyMapStore::init_new`], with the WAL split into `map.wal.NNNNN.dat` segments of about
+    /// `max_segment_bytes`. Segments of the previous run are replayed in order and removed afterwards.
+    pub fn init_new_segmented(store_dir: &str, max_segment_bytes: u64) -> io::Result<Self> {
+        let store_dir_path = Path::new(store_dir);
+        let previous = crate::wal::take_previous_segments(store_dir_path, MAP_WAL_NAME)?;
+
+        let mut store = DashMap::default();
+        let wal = WalStorage::new_segmented(store_dir_path, MAP_WAL_NAME, max_segment_bytes)?;
+
+        if let Some((bytes, previous_paths)) = previous {
+            info!("found {} KeyMap WAL segments, trying to restore...", previous_paths.len());
+            restore(&mut store, &wal, crate::wal::read_for_map(&bytes), false)?;
+            for path in previous_paths {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(DurableKeyMapStore { store, wal, limits: SizeLimits::default() })
+    }
+}
+
+/// Loads the maps replayed from the WAL of a previous run into `store`, writing each element to the new `wal`.
+/// Stops at the first element which can't be written.
+fn restore<W: Write>(store: &mut ShardedMaps, wal: &WalStorage<W>, mut map: SortedMaps, renumber_ordered: bool) -> io::Result<()> {
+    if renumber_ordered {
+        map.values_mut().for_each(renumber_ordered_elements);
+    }
+    info!(
+        "restored map with size: {}, adding new new WAL file",
+        map.len()
+    );
+    if store.is_empty() {
+        *store = DashMap::with_capacity_and_hasher(map.len(), store.hasher().clone());
+    }
+
+    for (each_key, entry_map) in map {
+        for (search_key, element) in entry_map {
+            let (_key, search_key, element) =
+                wal.store_put_to_map_event(each_key.clone(), search_key, element)?;
+            match store.entry(each_key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let found_map: &mut BTreeMap<SearchKey, Vec<u8>> = Arc::make_mut(entry.get_mut());
+                    found_map.insert(search_key, element);
+                }
+                Entry::Vacant(vacant) => {
+                    let mut new_map = BTreeMap::new();
+                    new_map.insert(search_key, element);
+                    vacant.insert(Arc::new(new_map));
+                }
+            }
+        }
     }
+    info!("{} entries added to store", store.len());
+    Ok(())
 }
 
 impl DurableKeyMapStore<Vec<u8>> {
     #[allow(unused)]
     pub fn new_vec_based() -> Self {
         DurableKeyMapStore {
-            store: DashMap::new(),
+            store: DashMap::default(),
             wal: WalStorage::new_vec_based(),
+            limits: SizeLimits::default(),
         }
     }
 }
 
+impl<W: SyncWal> DurableKeyMapStore<W> {
+    /// Flushes and syncs the WAL and releases it, consuming the store, see [`WalStorage::close`].
+    pub fn shutdown(self) -> io::Result<()> {
+        self.wal.close()
+    }
+}
+
 #[allow(unused)]
+impl<W: ReadableWal> DurableKeyMapStore<W> {
+    /// Diagnostics over the WAL backing this store: block counts per action type and live/total bytes.
+    pub fn wal_stats(&self) -> io::Result<WalStats> {
+        self.wal.wal_stats()
+    }
+}
+
 impl<W: Write> DurableKeyMapStore<W> {
+    /// Places keys in shards with `shard_hasher`, see [`crate::key_value_store::DurableKeyValueStore::with_shard_hasher`].
+    pub fn with_shard_hasher(self, shard_hasher: ShardHasher) -> Self {
+        let mut store = DashMap::with_hasher(shard_hasher);
+        store.extend(self.store);
+        DurableKeyMapStore { store, wal: self.wal, limits: self.limits }
+    }
+
+    /// Rejects keys and elements over `limits` in `put`, see
+    /// [`crate::key_value_store::DurableKeyValueStore::with_size_limits`].
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Index of the shard `key` is placed in, stable for a store built with [`ShardHasher::seeded`].
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        self.store.determine_map(key)
+    }
+
     pub fn get_sorted_map(&self, key: &[u8]) -> Option<BTreeMap<SearchKey, Vec<u8>>> {
         match self.store.get(key) {
             None => None,
             Some(inner_val) => {
                 let found = inner_val.value();
                 let mut map = BTreeMap::new();
-                for (k, v) in found {
+                for (k, v) in found.iter() {
                     map.insert(k.clone(), v.clone());
                 }
                 Some(map)
@@ -117,6 +250,12 @@ impl<W: Write> DurableKeyMapStore<W> {
         }
     }
 
+    /// Point-in-time view of the sorted map of `key` without copying it, later mutations of `key` don't affect
+    /// the returned map. Holding it makes the next mutation of `key` clone the map, see [`DurableKeyMapStore`].
+    pub fn snapshot_sorted_map(&self, key: &[u8]) -> Option<Arc<BTreeMap<SearchKey, Vec<u8>>>> {
+        self.store.get(key).map(|inner_val| Arc::clone(inner_val.value()))
+    }
+
     pub fn get_element(&self, key: &[u8], search_key: &SearchKey) -> Option<Vec<u8>> {
         match self.store.get(key) {
             None => None,
@@ -124,6 +263,11 @@ impl<W: Write> DurableKeyMapStore<W> {
         }
     }
 
+    /// Length of the element at `search_key` of `key`, read under the shard guard without copying the element.
+    pub fn element_len(&self, key: &[u8], search_key: &SearchKey) -> Option<usize> {
+        self.store.get(key).and_then(|inner_val| inner_val.value().get(search_key).map(Vec::len))
+    }
+
     pub fn contains_in_map(&self, key: &[u8], search_key: &SearchKey) -> bool {
         match self.store.get(key) {
             None => false,
@@ -131,25 +275,124 @@ impl<W: Write> DurableKeyMapStore<W> {
         }
     }
 
-    pub fn put(&self, key: Vec<u8>, search_key: SearchKey, val: Vec<u8>) {
-        let (key, search_key, val) = self.wal.store_put_to_map_event(key, search_key, val);
+    /// Fails with `ErrorKind::InvalidInput` for an empty `search_key`, which orders before every other one and
+    /// has no first `Key` to number ordered elements by.
+    pub fn put(&self, key: Vec<u8>, search_key: SearchKey, val: Vec<u8>) -> io::Result<()> {
+        if search_key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "search key must have at least one key"));
+        }
+        self.limits.check(&key, &val)?;
+        reentrancy::check_not_in_compute();
+
+        match self.store.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (_key, search_key, val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, val)?;
+                Arc::make_mut(entry.get_mut()).insert(search_key, val);
+            }
+            Entry::Vacant(entry) => {
+                let (_key, search_key, val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, val)?;
+                entry.insert(Arc::new(BTreeMap::from([(search_key, val)])));
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts `f(current, operand)` at `search_key`, `current` being the element already there if any. The new element
+    /// is computed under the lock of `key` and written to the WAL as a single put, so concurrent merges of the same
+    /// slot don't lose updates, unlike `get_element` followed by `put`.
+    pub fn merge_element(&self, key: Vec<u8>, search_key: SearchKey, operand: &[u8],
+                         f: impl FnOnce(Option<&[u8]>, &[u8]) -> Vec<u8>) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        if search_key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "search key must have at least one key"));
+        }
 
-        match self.store.get_mut(&key) {
-            None => {
-                let mut new_sorted_map = BTreeMap::new();
-                new_sorted_map.insert(search_key, val);
-                self.store.insert(key, new_sorted_map);
+        match self.store.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let new_val = f(entry.get().get(&search_key).map(Vec::as_slice), operand);
+                let (_key, search_key, new_val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, new_val)?;
+                Arc::make_mut(entry.get_mut()).insert(search_key, new_val);
+            }
+            Entry::Vacant(entry) => {
+                let new_val = f(None, operand);
+                let (_key, search_key, new_val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, new_val)?;
+                entry.insert(Arc::new(BTreeMap::from([(search_key, new_val)])));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `by` to the 8 bytes little-endian number at `search_key`, starting from 0 if there is none, and returns
+    /// the sum. Like [`DurableKeyMapStore::merge_element`], the sum is computed and written as a single put under
+    /// the lock of `key`. Fails with `ErrorKind::InvalidData` if the element there isn't 8 bytes long.
+    pub fn increment_element(&self, key: Vec<u8>, search_key: SearchKey, by: u64) -> io::Result<u64> {
+        reentrancy::check_not_in_compute();
+        if search_key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "search key must have at least one key"));
+        }
+
+        match self.store.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let new_num = match entry.get().get(&search_key) {
+                    Some(current) => read_le_number(current).ok_or_else(not_a_number)?
+                        .checked_add(by).ok_or(PigmentError::Overflow)?,
+                    None => by,
+                };
+                let (_key, search_key, new_val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, new_num.to_le_bytes().to_vec())?;
+                Arc::make_mut(entry.get_mut()).insert(search_key, new_val);
+                Ok(new_num)
+            }
+            Entry::Vacant(entry) => {
+                let (_key, search_key, new_val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, by.to_le_bytes().to_vec())?;
+                entry.insert(Arc::new(BTreeMap::from([(search_key, new_val)])));
+                Ok(by)
             }
-            Some(ref mut sorted_map) => {
+        }
+    }
+
+    /// Number written by [`DurableKeyMapStore::increment_element`] at `search_key`, `Err` if the element there isn't
+    /// 8 bytes long.
+    pub fn read_element_number(&self, key: &[u8], search_key: &SearchKey) -> Option<Result<u64, PigmentError>> {
+        self.store.get(key)
+            .and_then(|inner_val| inner_val.value().get(search_key).map(|element| read_le_number(element).ok_or(PigmentError::NotANumber)))
+    }
+
+    /// Replaces the element at `from` by `val` at `search_key`, under the lock of `key` and with a single WAL write,
+    /// so readers never see both or neither of them. Same as [`DurableKeyMapStore::put`] if there's nothing at `from`.
+    pub fn move_element(&self, key: Vec<u8>, from: &SearchKey, search_key: SearchKey, val: Vec<u8>) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        if search_key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "search key must have at least one key"));
+        }
+
+        match self.store.entry(key) {
+            Entry::Occupied(mut entry) if entry.get().contains_key(from) && *from != search_key => {
+                let (_key, search_key, val) = self.wal.store_move_in_sorted_map_event(entry.key().clone(), from.clone(), search_key, val)?;
+                let sorted_map = Arc::make_mut(entry.get_mut());
+                sorted_map.remove(from);
                 sorted_map.insert(search_key, val);
             }
+            Entry::Occupied(mut entry) => {
+                let (_key, search_key, val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, val)?;
+                Arc::make_mut(entry.get_mut()).insert(search_key, val);
+            }
+            Entry::Vacant(entry) => {
+                let (_key, search_key, val) = self.wal.store_put_to_map_event(entry.key().clone(), search_key, val)?;
+                entry.insert(Arc::new(BTreeMap::from([(search_key, val)])));
+            }
         }
+        Ok(())
     }
 
     pub fn contains_key(&self, key: &[u8]) -> bool {
         self.store.contains_key(key)
     }
 
+    /// [`DurableKeyMapStore::contains_key`] of each of `keys`, in input order, locking each involved shard once.
+    pub fn contains_keys(&self, keys: &[&[u8]]) -> Vec<bool> {
+        model::contains_keys(&self.store, keys, |_| true)
+    }
+
     pub fn contains_search_key(&self, key: &[u8], search_key: &SearchKey) -> bool {
         if let Some(entry) = self.store.get(key) {
             if entry.value().contains_key(search_key) {
@@ -159,54 +402,85 @@ impl<W: Write> DurableKeyMapStore<W> {
         false
     }
 
-    pub fn remove_from_sorted_map(&self, key: Vec<u8>, search_key: SearchKey) -> Option<Vec<u8>> {
-        let (key, search_key) = self.wal.store_remove_from_sorted_map_event(key, search_key);
+    pub fn remove_from_sorted_map(&self, key: Vec<u8>, search_key: SearchKey) -> io::Result<Option<Vec<u8>>> {
+        self.remove_from_sorted_map_with(key, search_key, false, |_| {})
+    }
 
-        match self.store.entry(key) {
-            Entry::Occupied(mut entry) => {
-                let old_value = entry.get_mut().remove(&search_key);
-                if entry.get().is_empty() {
-                    self.wal.store_delete_event(entry.key());
-                    entry.remove();
-                }
-                old_value
-            }
-            Entry::Vacant(_) => None,
-        }
+    /// Same as [`DurableKeyMapStore::remove_from_sorted_map`], keeping `key` with an empty sorted map instead of
+    /// deleting it when the last element is removed, so `contains_key` stays true. The WAL has no record of an
+    /// empty map though, so the key is gone after a restart unless elements were put again.
+    pub fn remove_from_sorted_map_keep_empty(&self, key: Vec<u8>, search_key: SearchKey) -> io::Result<Option<Vec<u8>>> {
+        self.remove_from_sorted_map_with(key, search_key, true, |_| {})
     }
 
+    /// Same as [`DurableKeyMapStore::remove_from_sorted_map`], returns the removed value.
+    ///
+    /// `key_removed_callback` fires only when this removal left the sorted map of `key` empty, so the whole key
+    /// was deleted as well. It gets the removed `search_key` and is called under the entry lock, after both WAL
+    /// events were written. It doesn't fire when `key` still has elements or when nothing was removed.
     pub fn remove_from_sorted_map_callback(
         &self,
         key: Vec<u8>,
         search_key: SearchKey,
         key_removed_callback: impl FnOnce(&SearchKey),
-    ) {
-        let (key, search_key) = self.wal.store_remove_from_sorted_map_event(key, search_key);
+    ) -> io::Result<Option<Vec<u8>>> {
+        self.remove_from_sorted_map_with(key, search_key, false, key_removed_callback)
+    }
 
-        match self.store.entry(key) {
+    fn remove_from_sorted_map_with(&self, key: Vec<u8>, search_key: SearchKey, keep_empty: bool,
+                                   key_removed_callback: impl FnOnce(&SearchKey)) -> io::Result<Option<Vec<u8>>> {
+        reentrancy::check_not_in_compute();
+        let entry = self.store.entry(key);
+        let (_key, search_key) = self.wal.store_remove_from_sorted_map_event(entry.key().clone(), search_key)?;
+
+        match entry {
             Entry::Occupied(mut entry) => {
-                entry.get_mut().remove(&search_key);
-                if entry.get().is_empty() {
-                    self.wal.store_delete_event(entry.key());
+                let old_value = Arc::make_mut(entry.get_mut()).remove(&search_key);
+                if entry.get().is_empty() && !keep_empty {
+                    self.wal.store_delete_event(entry.key())?;
                     entry.remove();
 
                     key_removed_callback(&search_key);
                 }
+                Ok(old_value)
             }
-            Entry::Vacant(_) => {}
+            Entry::Vacant(_) => Ok(None),
         }
     }
 
-    pub fn remove_key(&self, key: &[u8]) {
-        self.wal.store_delete_event(key);
+    pub fn remove_key(&self, key: &[u8]) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        let entry = self.store.entry(key.to_vec());
+        self.wal.store_delete_event(key)?;
 
-        self.store.remove(key);
+        if let Entry::Occupied(entry) = entry {
+            entry.remove();
+        }
+        Ok(())
     }
 
     pub fn size(&self) -> usize {
         self.store.len()
     }
 
+    /// Writes the maps sorted by key for inspection, see [`crate::key_value_store::DurableKeyValueStore::debug_dump`]:
+    /// a line per key with its entry count, followed by its entries in search key order, indented.
+    pub fn debug_dump(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut maps: Vec<_> = self.store.iter()
+            .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
+            .collect();
+        maps.sort_by(|(key_a, _), (key_b, _)| key_a.cmp(key_b));
+
+        writeln!(writer, "{} keys", maps.len())?;
+        for (key, sorted_map) in maps {
+            writeln!(writer, "{} ({} bytes): {} entries", faster_hex::hex_string(&key), key.len(), sorted_map.len())?;
+            for (search_key, value) in sorted_map.iter() {
+                writeln!(writer, "  {:?} => {} ({} bytes)", search_key, faster_hex::hex_string(value), value.len())?;
+            }
+        }
+        writer.flush()
+    }
+
     pub fn sorted_map_size(&self, key: &[u8]) -> Option<usize> {
         self.store.get(key).map(|v| v.value().len())
     }
@@ -222,7 +496,7 @@ impl<W: Write> DurableKeyMapStore<W> {
         self.store.get(key).map(|v| {
             v.value()
                 .range((bound_start, bound_end))
-                .map(|(k, v)| k.clone())
+                .map(|(k, _)| k.clone())
                 .filter(predicate)
                 .collect()
         })
@@ -234,14 +508,24 @@ impl<W: Write> DurableKeyMapStore<W> {
         bound_start: std::ops::Bound<SearchKey>,
         bound_end: std::ops::Bound<SearchKey>,
     ) -> Option<Vec<SearchKey>> {
+        self.range_keys(key, (bound_start, bound_end))
+    }
+
+    /// Search keys of `key` within `bounds` (e.g. `start..end` or a pair of `Bound`s), values are not cloned.
+    pub fn range_keys(&self, key: &[u8], bounds: impl RangeBounds<SearchKey>) -> Option<Vec<SearchKey>> {
         self.store.get(key).map(|v| {
             v.value()
-                .range((bound_start, bound_end))
-                .map(|(k, v)| k.clone())
+                .range(bounds)
+                .map(|(k, _)| k.clone())
                 .collect()
         })
     }
 
+    /// Number of search keys of `key` within `bounds`, nothing is cloned.
+    pub fn count_range(&self, key: &[u8], bounds: impl RangeBounds<SearchKey>) -> Option<usize> {
+        self.store.get(key).map(|v| v.value().range(bounds).count())
+    }
+
     pub fn range_entries(
         &self,
         key: &[u8],
@@ -256,6 +540,72 @@ impl<W: Write> DurableKeyMapStore<W> {
         })
     }
 
+    /// Entries of [`DurableKeyMapStore::range_entries`] encoded straight from the map into one blob ready to be sent,
+    /// read back by [`decode_range_response`]: the entry count, then per entry the length of the search key in
+    /// [`SearchKey::encode_order_preserving`] form, that encoding, the length of the value and the value. Counts and
+    /// lengths are big-endian u32.
+    pub fn range_entries_encoded(&self, key: &[u8], bounds: impl RangeBounds<SearchKey>) -> Option<Vec<u8>> {
+        self.store.get(key).map(|v| {
+            let mut count: u32 = 0;
+            let mut encoded = count.to_be_bytes().to_vec();
+            for (search_key, value) in v.value().range(bounds) {
+                put_length_prefixed(&mut encoded, &search_key.encode_order_preserving());
+                put_length_prefixed(&mut encoded, value);
+                count += 1;
+            }
+            encoded[..RANGE_LEN_BYTES].copy_from_slice(&count.to_be_bytes());
+            encoded
+        })
+    }
+
+    /// Entries of `key` within `bounds` in descending order of search keys, e.g. most recent first for time keys.
+    pub fn range_entries_rev(&self, key: &[u8], bounds: impl RangeBounds<SearchKey>) -> Option<Vec<(SearchKey, Vec<u8>)>> {
+        self.store.get(key).map(|v| {
+            v.value()
+                .range(bounds)
+                .rev()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        })
+    }
+
+    /// First `limit` entries of [`DurableKeyMapStore::range_entries_rev`], only those are cloned.
+    pub fn range_rev_limited(&self, key: &[u8], bounds: impl RangeBounds<SearchKey>, limit: usize) -> Option<Vec<(SearchKey, Vec<u8>)>> {
+        self.store.get(key).map(|v| {
+            v.value()
+                .range(bounds)
+                .rev()
+                .take(limit)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        })
+    }
+
+    /// Entries within `bounds` of each of `keys`, as [`DurableKeyMapStore::range_entries`] would return them, with
+    /// missing keys left out. Keys are grouped by shard, so each shard is read locked once rather than once per key.
+    pub fn multi_range(&self, keys: &[&[u8]], bounds: impl RangeBounds<SearchKey>) -> HashMap<Vec<u8>, Vec<(SearchKey, Vec<u8>)>> {
+        let mut keys_by_shard: BTreeMap<usize, Vec<&[u8]>> = BTreeMap::new();
+        for key in keys {
+            keys_by_shard.entry(self.store.determine_map(*key)).or_default().push(key);
+        }
+
+        let shards = self.store.shards();
+        let mut result = HashMap::with_capacity(keys.len());
+        for (shard, shard_keys) in keys_by_shard {
+            let shard_guard = shards[shard].read();
+            for key in shard_keys {
+                if let Some(map) = shard_guard.get(key) {
+                    let entries = map.get()
+                        .range((bounds.start_bound(), bounds.end_bound()))
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect();
+                    result.insert(key.to_vec(), entries);
+                }
+            }
+        }
+        result
+    }
+
     pub fn range_entries_filtered<P>(
         &self,
         key: &[u8],
@@ -299,88 +649,109 @@ impl<W: Write> DurableKeyMapStore<W> {
         }
     }
 
-    pub fn pop_first(&self, key: Vec<u8>) -> Option<(SearchKey, Vec<u8>)> {
+    /// Up to `n` entries of `key` with the smallest search keys, in ascending order; empty if `key` is missing.
+    /// Only the returned entries are cloned.
+    pub fn first_n(&self, key: &[u8], n: usize) -> Vec<(SearchKey, Vec<u8>)> {
+        self.store.get(key).map_or_else(Vec::new, |v| {
+            v.value().iter().take(n).map(|(k, v)| (k.clone(), v.clone())).collect()
+        })
+    }
+
+    /// Up to `n` entries of `key` with the greatest search keys, in descending order like
+    /// [`DurableKeyMapStore::range_rev_limited`]; empty if `key` is missing.
+    pub fn last_n(&self, key: &[u8], n: usize) -> Vec<(SearchKey, Vec<u8>)> {
+        self.store.get(key).map_or_else(Vec::new, |v| {
+            v.value().iter().rev().take(n).map(|(k, v)| (k.clone(), v.clone())).collect()
+        })
+    }
+
+    pub fn pop_first(&self, key: Vec<u8>) -> io::Result<Option<(SearchKey, Vec<u8>)>> {
+        reentrancy::check_not_in_compute();
         match self.store.entry(key.clone()) {
             Entry::Occupied(mut entry) => {
-                let result = if let Some((search_key, _element)) = entry.get_mut().pop_first() {
-                    let (element, search_key) =
-                        self.wal.store_remove_from_sorted_map_event(key, search_key);
+                let result = if let Some((search_key, _element)) = entry.get().first_key_value() {
+                    let (_key, search_key) =
+                        self.wal.store_remove_from_sorted_map_event(key, search_key.clone())?;
+                    let element = Arc::make_mut(entry.get_mut()).remove(&search_key).unwrap();
                     Some((search_key, element))
                 } else {
                     None
                 };
                 if entry.get().is_empty() {
-                    self.wal.store_delete_event(entry.key());
+                    self.wal.store_delete_event(entry.key())?;
                     entry.remove();
                 }
-                result
+                Ok(result)
             }
-            Entry::Vacant(_) => None,
+            Entry::Vacant(_) => Ok(None),
         }
     }
 
-    pub fn pop_last(&self, key: Vec<u8>) -> Option<(SearchKey, Vec<u8>)> {
+    pub fn pop_last(&self, key: Vec<u8>) -> io::Result<Option<(SearchKey, Vec<u8>)>> {
+        reentrancy::check_not_in_compute();
         match self.store.entry(key.clone()) {
             Entry::Occupied(mut entry) => {
-                let result = if let Some((search_key, _element)) = entry.get_mut().pop_last() {
-                    let (element, search_key) =
-                        self.wal.store_remove_from_sorted_map_event(key, search_key);
+                let result = if let Some((search_key, _element)) = entry.get().last_key_value() {
+                    let (_key, search_key) =
+                        self.wal.store_remove_from_sorted_map_event(key, search_key.clone())?;
+                    let element = Arc::make_mut(entry.get_mut()).remove(&search_key).unwrap();
                     Some((search_key, element))
                 } else {
                     None
                 };
                 if entry.get().is_empty() {
-                    self.wal.store_delete_event(entry.key());
+                    self.wal.store_delete_event(entry.key())?;
                     entry.remove();
                 }
-                result
+                Ok(result)
             }
-            Entry::Vacant(_) => None,
+            Entry::Vacant(_) => Ok(None),
         }
     }
 
-    pub fn append_ordered_element(&self, key: Vec<u8>, element: Vec<u8>) {
+    /// Puts `element` under the search key following the greatest `USIZE` one of `key`. Search keys of other
+    /// types put manually into the same map are ignored, so they never reset the counter.
+    pub fn append_ordered_element(&self, key: Vec<u8>, element: Vec<u8>) -> io::Result<()> {
+        self.append_ordered_indexed(key, element).map(|_| ())
+    }
+
+    /// Same as [`DurableKeyMapStore::append_ordered_element`], returning the number the element was put under,
+    /// i.e. its search key is `SearchKey::from(number)`.
+    pub fn append_ordered_indexed(&self, key: Vec<u8>, element: Vec<u8>) -> io::Result<usize> {
+        reentrancy::check_not_in_compute();
         match self.store.entry(key.clone()) {
             Entry::Occupied(mut entry) => {
-                let map = entry.get_mut();
-                let cur_num = {
-                    if let Some(last_entry) = map.last_entry() {
-                        let last_search_key = last_entry.key().first().unwrap();
-                        if let Key::USIZE(count) = last_search_key {
-                            count + 1
-                        } else {
-                            0
-                        }
-                    } else {
-                        0
-                    }
-                };
+                let map = Arc::make_mut(entry.get_mut());
+                let cur_num = next_ordered_number(map);
                 let (_key, search_key, element) =
                     self.wal
-                        .store_put_to_map_event(key, cur_num.into(), element);
+                        .store_put_to_map_event(key, cur_num.into(), element)?;
                 map.insert(search_key, element);
+                Ok(cur_num)
             }
             Entry::Vacant(entry) => {
                 let mut map: BTreeMap<SearchKey, Vec<u8>> = BTreeMap::new();
                 let (_key, search_key, element) =
-                    self.wal.store_put_to_map_event(key, 0.into(), element);
+                    self.wal.store_put_to_map_event(key, 0.into(), element)?;
                 map.insert(search_key, element);
-                entry.insert(map);
+                entry.insert(Arc::new(map));
+                Ok(0)
             }
         }
     }
 
     pub fn compute(&self, key: Vec<u8>, func: impl FnOnce(&mut BTreeMap<SearchKey, Vec<u8>>)) {
+        reentrancy::check_not_in_compute();
         let entry = self.store.entry(key);
         match entry {
             Entry::Occupied(mut occupied_entry) => {
-                let map = occupied_entry.get_mut();
-                func(map);
+                let map = Arc::make_mut(occupied_entry.get_mut());
+                reentrancy::run_compute(|| func(map));
             }
             Entry::Vacant(vacant_entry) => {
                 let mut map = BTreeMap::new();
-                func(&mut map);
-                vacant_entry.insert(map);
+                reentrancy::run_compute(|| func(&mut map));
+                vacant_entry.insert(Arc::new(map));
             }
         };
     }
@@ -390,11 +761,12 @@ impl<W: Write> DurableKeyMapStore<W> {
         key: Vec<u8>,
         func: impl FnOnce(&mut BTreeMap<SearchKey, Vec<u8>>),
     ) {
+        reentrancy::check_not_in_compute();
         let entry = self.store.entry(key);
         match entry {
             Entry::Occupied(mut occupied_entry) => {
-                let map = occupied_entry.get_mut();
-                func(map);
+                let map = Arc::make_mut(occupied_entry.get_mut());
+                reentrancy::run_compute(|| func(map));
             }
             Entry::Vacant(_) => {}
         };
@@ -405,20 +777,95 @@ impl<W: Write> DurableKeyMapStore<W> {
         key: Vec<u8>,
         func: impl FnOnce(&mut BTreeMap<SearchKey, Vec<u8>>),
     ) {
+        reentrancy::check_not_in_compute();
         let entry = self.store.entry(key);
         match entry {
             Entry::Occupied(_) => {}
             Entry::Vacant(vacant_entry) => {
                 let mut map = BTreeMap::new();
-                func(&mut map);
-                vacant_entry.insert(map);
+                reentrancy::run_compute(|| func(&mut map));
+                vacant_entry.insert(Arc::new(map));
             }
         };
     }
 }
 
+/// Renumbers search keys made of a single `Key::USIZE`, as given by [`DurableKeyMapStore::append_ordered_element`],
+/// to 0, 1, 2... in their current order.
+/// Entries encoded by [`DurableKeyMapStore::range_entries_encoded`], `None` if `bytes` are not a valid encoding.
+pub fn decode_range_response(mut bytes: &[u8]) -> Option<Vec<(SearchKey, Vec<u8>)>> {
+    let count = take_length(&mut bytes)?;
+    let mut entries = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        let search_key = SearchKey::decode_order_preserving(take_length_prefixed(&mut bytes)?)?;
+        let value = take_length_prefixed(&mut bytes)?.to_vec();
+        entries.push((search_key, value));
+    }
+    if !bytes.is_empty() {
+        return None;
+    }
+    Some(entries)
+}
+
+fn read_le_number(element: &[u8]) -> Option<u64> {
+    element.try_into().ok().map(u64::from_le_bytes)
+}
+
+fn not_a_number() -> io::Error {
+    PigmentError::NotANumber.into()
+}
+
+fn put_length_prefixed(encoded: &mut Vec<u8>, bytes: &[u8]) {
+    encoded.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(bytes);
+}
+
+fn take_length(bytes: &mut &[u8]) -> Option<usize> {
+    if bytes.len() < RANGE_LEN_BYTES {
+        return None;
+    }
+    let (length, rest) = bytes.split_at(RANGE_LEN_BYTES);
+    *bytes = rest;
+    Some(u32::from_be_bytes(length.try_into().ok()?) as usize)
+}
+
+fn take_length_prefixed<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let length = take_length(bytes)?;
+    if bytes.len() < length {
+        return None;
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Some(taken)
+}
+
+fn renumber_ordered_elements(map: &mut BTreeMap<SearchKey, Vec<u8>>) {
+    let first_after_usize = SearchKey::from(vec![Key::I128(0)]);
+    let ordered_keys: Vec<SearchKey> = map.range(..first_after_usize)
+        .map(|(search_key, _)| search_key)
+        .filter(|search_key| matches!(search_key.first(), Some(Key::USIZE(_))) && search_key.get(1).is_none())
+        .cloned()
+        .collect();
+
+    let elements: Vec<Vec<u8>> = ordered_keys.iter().map(|search_key| map.remove(search_key).unwrap()).collect();
+    for (number, element) in elements.into_iter().enumerate() {
+        map.insert(SearchKey::from(number), element);
+    }
+}
+
+/// Number following the greatest search key starting with `Key::USIZE`, 0 if there is none.
+/// The derived `Ord` of `Key` compares variants first, so such keys sort right before the first `Key::I128` one.
+fn next_ordered_number(map: &BTreeMap<SearchKey, Vec<u8>>) -> usize {
+    let first_after_usize = SearchKey::from(vec![Key::I128(0)]);
+    match map.range(..first_after_usize).next_back().and_then(|(search_key, _)| search_key.first()) {
+        Some(Key::USIZE(count)) => count + 1,
+        _ => 0,
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::error::PigmentError;
     use crate::model::SearchKey;
     use std::collections::BTreeMap;
 
@@ -431,15 +878,15 @@ mod tests {
         let store = DurableKeyMapStore::new_vec_based();
 
         let key_1 = "key_1".as_bytes().to_vec();
-        store.put(key_1.clone(), 3.into(), "c".as_bytes().to_vec());
-        store.put(key_1.clone(), 1.into(), "a".as_bytes().to_vec());
-        store.put(key_1.clone(), 2.into(), "b".as_bytes().to_vec());
-        store.put(key_1.clone(), 3.into(), "c_".as_bytes().to_vec());
+        store.put(key_1.clone(), 3.into(), "c".as_bytes().to_vec()).unwrap();
+        store.put(key_1.clone(), 1.into(), "a".as_bytes().to_vec()).unwrap();
+        store.put(key_1.clone(), 2.into(), "b".as_bytes().to_vec()).unwrap();
+        store.put(key_1.clone(), 3.into(), "c_".as_bytes().to_vec()).unwrap();
 
         let key_2 = "key_2".as_bytes().to_vec();
-        store.put(key_2.clone(), 3.into(), "C".as_bytes().to_vec());
-        store.put(key_2.clone(), 1.into(), "A".as_bytes().to_vec());
-        store.put(key_2.clone(), 2.into(), "B".as_bytes().to_vec());
+        store.put(key_2.clone(), 3.into(), "C".as_bytes().to_vec()).unwrap();
+        store.put(key_2.clone(), 1.into(), "A".as_bytes().to_vec()).unwrap();
+        store.put(key_2.clone(), 2.into(), "B".as_bytes().to_vec()).unwrap();
 
         assert_eq!(
             store.get_element(&key_1, &2.into()),
@@ -467,7 +914,7 @@ mod tests {
             Some("A".as_bytes().to_vec())
         );
 
-        store.remove_from_sorted_map(key_1.clone(), 1.into());
+        store.remove_from_sorted_map(key_1.clone(), 1.into()).unwrap();
         assert_eq!(store.get_element(&key_1, &1.into()), None);
     }
 
@@ -519,13 +966,455 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_range_keys_and_count() {
+        use std::ops::Bound;
+
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"pages".to_vec();
+        let large_value = vec![7u8; 1024 * 1024];
+        for i in 0..32usize {
+            store.put(key.clone(), i.into(), large_value.clone()).unwrap();
+        }
+
+        let (start, end): (SearchKey, SearchKey) = (4.into(), 20.into());
+        let entries = store.range_entries(&key, Bound::Included(start.clone()), Bound::Excluded(end.clone())).unwrap();
+        let keys = store.range_keys(&key, start.clone()..end.clone()).unwrap();
+
+        assert_eq!(store.count_range(&key, start.clone()..end.clone()), Some(entries.len()));
+        assert_eq!(keys, entries.into_iter().map(|(search_key, _)| search_key).collect::<Vec<_>>());
+        assert_eq!(store.range_search_keys(&key, Bound::Included(start), Bound::Excluded(end)), Some(keys));
+        assert_eq!(store.count_range(&key, ..), Some(32));
+        assert_eq!(store.count_range(&key, SearchKey::from(40)..), Some(0));
+        assert_eq!(store.count_range(b"missing", ..), None);
+        assert_eq!(store.range_keys(b"missing", ..), None);
+    }
+
+    #[test]
+    fn test_append_ordered_indexed() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"handles".to_vec();
+
+        for i in 0..5usize {
+            let number = store.append_ordered_indexed(key.clone(), format!("element_{}", i).into_bytes()).unwrap();
+            assert_eq!(number, i);
+            assert_eq!(store.get_element(&key, &SearchKey::from(number)), Some(format!("element_{}", i).into_bytes()));
+        }
+        store.remove_from_sorted_map(key.clone(), 2.into()).unwrap();
+        store.append_ordered_element(key.clone(), b"plain".to_vec()).unwrap();
+        assert_eq!(store.append_ordered_indexed(key.clone(), b"last".to_vec()).unwrap(), 6);
+        assert_eq!(store.get_element(&key, &SearchKey::from(5)), Some(b"plain".to_vec()));
+    }
+
+    #[test]
+    fn test_empty_search_key() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"list".to_vec();
+        let empty = SearchKey::from(Vec::<crate::model::Key>::new());
+
+        let error = store.put(key.clone(), empty.clone(), b"empty".to_vec()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(!store.contains_key(&key));
+
+        // e.g. restored from a WAL written before empty search keys were rejected
+        store.compute(key.clone(), |map| { map.insert(empty.clone(), b"empty".to_vec()); });
+        assert_eq!(store.append_ordered_indexed(key.clone(), b"first".to_vec()).unwrap(), 0);
+        assert_eq!(store.append_ordered_indexed(key.clone(), b"second".to_vec()).unwrap(), 1);
+        assert_eq!(store.get_element(&key, &empty), Some(b"empty".to_vec()));
+    }
+
+    #[test]
+    fn test_merge_element() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let sum = |current: Option<&[u8]>, operand: &[u8]| {
+            let current = current.map_or(0, |bytes| u64::from_be_bytes(bytes.try_into().unwrap()));
+            (current + u64::from_be_bytes(operand.try_into().unwrap())).to_be_bytes().to_vec()
+        };
+
+        for count in [3u64, 4, 5] {
+            store.merge_element(b"clicks".to_vec(), SearchKey::from("2024-01-01"), &count.to_be_bytes(), sum).unwrap();
+        }
+        store.merge_element(b"clicks".to_vec(), SearchKey::from("2024-01-02"), &1u64.to_be_bytes(), sum).unwrap();
+
+        let slot = store.get_element(b"clicks", &SearchKey::from("2024-01-01")).unwrap();
+        assert_eq!(slot, 12u64.to_be_bytes().to_vec());
+        assert_eq!(store.sorted_map_size(b"clicks"), Some(2));
+        assert_eq!(store.wal_stats().unwrap().blocks, 4);
+    }
+
+    #[test]
+    fn test_remove_keep_empty() {
+        let store = DurableKeyMapStore::new_vec_based();
+        store.put(b"k".to_vec(), SearchKey::from("a"), b"1".to_vec()).unwrap();
+
+        let removed = store.remove_from_sorted_map_keep_empty(b"k".to_vec(), SearchKey::from("a")).unwrap();
+        assert_eq!(removed, Some(b"1".to_vec()));
+        assert!(store.contains_key(b"k"));
+        assert_eq!(store.size(), 1);
+        assert_eq!(store.sorted_map_size(b"k"), Some(0));
+
+        store.put(b"k".to_vec(), SearchKey::from("b"), b"2".to_vec()).unwrap();
+        store.remove_from_sorted_map(b"k".to_vec(), SearchKey::from("b")).unwrap();
+        assert!(!store.contains_key(b"k"));
+    }
+
+    #[test]
+    fn test_first_n_last_n() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"scores".to_vec();
+        for i in [7usize, 2, 9, 0, 5, 1, 8, 3, 6, 4] {
+            store.put(key.clone(), i.into(), vec![i as u8]).unwrap();
+        }
+
+        let first: Vec<SearchKey> = store.first_n(&key, 3).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(first, vec![SearchKey::from(0usize), SearchKey::from(1usize), SearchKey::from(2usize)]);
+        let last: Vec<Vec<u8>> = store.last_n(&key, 2).into_iter().map(|(_, v)| v).collect();
+        assert_eq!(last, vec![vec![9], vec![8]]);
+
+        assert_eq!(store.first_n(&key, 20).len(), 10);
+        assert!(store.last_n(b"missing", 3).is_empty());
+    }
+
+    #[test]
+    fn test_element_len() {
+        let store = DurableKeyMapStore::new_vec_based();
+        store.put(b"k".to_vec(), SearchKey::from("a"), vec![1; 300]).unwrap();
+
+        assert_eq!(store.element_len(b"k", &SearchKey::from("a")), Some(300));
+        assert_eq!(store.element_len(b"k", &SearchKey::from("b")), None);
+        assert_eq!(store.element_len(b"missing", &SearchKey::from("a")), None);
+    }
+
+    #[test]
+    fn test_increment_element() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_map_increment_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+        let metric = b"requests".to_vec();
+        let (bucket_a, bucket_b) = (SearchKey::from(1_000usize), SearchKey::from(2_000usize));
+
+        {
+            let store = DurableKeyMapStore::init_new(store_dir);
+            for _ in 0..10 {
+                store.increment_element(metric.clone(), bucket_a.clone(), 1).unwrap();
+            }
+            assert_eq!(store.increment_element(metric.clone(), bucket_b.clone(), 5).unwrap(), 5);
+            assert_eq!(store.increment_element(metric.clone(), bucket_a.clone(), 2).unwrap(), 12);
+
+            store.put(metric.clone(), SearchKey::from("text"), b"abc".to_vec()).unwrap();
+            let error = store.increment_element(metric.clone(), SearchKey::from("text"), 1).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(store.read_element_number(&metric, &SearchKey::from("text")), Some(Err(PigmentError::NotANumber)));
+        }
+
+        let store = DurableKeyMapStore::init_new(store_dir);
+        assert_eq!(store.read_element_number(&metric, &bucket_a), Some(Ok(12)));
+        assert_eq!(store.read_element_number(&metric, &bucket_b), Some(Ok(5)));
+        assert_eq!(store.read_element_number(&metric, &SearchKey::from(3_000usize)), None);
+        assert_eq!(store.get_element(&metric, &bucket_b), Some(5u64.to_le_bytes().to_vec()));
+        let error = store.increment_element(metric.clone(), bucket_b.clone(), u64::MAX).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(store.read_element_number(&metric, &bucket_b), Some(Ok(5)));
+        drop(store);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_range_entries_encoded() {
+        use super::*;
+
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"samples".to_vec();
+        for i in 0..10usize {
+            store.put(key.clone(), i.into(), vec![i as u8; i]).unwrap();
+        }
+        store.put(key.clone(), SearchKey::from("text"), b"with\0zero".to_vec()).unwrap();
+
+        let bounds = SearchKey::from(3usize)..;
+        let encoded = store.range_entries_encoded(&key, bounds.clone()).unwrap();
+        let decoded = decode_range_response(&encoded).unwrap();
+        assert_eq!(decoded.len(), 8);
+        assert_eq!(decoded, store.range_entries(&key, bounds.start_bound().cloned(), bounds.end_bound().cloned()).unwrap());
+
+        let empty = store.range_entries_encoded(&key, SearchKey::from(20usize)..SearchKey::from(30usize)).unwrap();
+        assert_eq!(decode_range_response(&empty), Some(Vec::new()));
+        assert_eq!(store.range_entries_encoded(b"missing", ..), None);
+
+        assert_eq!(decode_range_response(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(decode_range_response(&[0, 0]), None);
+    }
+
+    #[test]
+    fn test_range_rev() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"samples".to_vec();
+        for i in 0..100usize {
+            store.put(key.clone(), i.into(), vec![i as u8]).unwrap();
+        }
+
+        let latest = store.range_rev_limited(&key, .., 5).unwrap();
+        let expected: Vec<(SearchKey, Vec<u8>)> = (95..100usize).rev().map(|i| (i.into(), vec![i as u8])).collect();
+        assert_eq!(latest, expected);
+
+        let window = store.range_entries_rev(&key, SearchKey::from(10)..SearchKey::from(20)).unwrap();
+        assert_eq!(window.len(), 10);
+        assert!(window.windows(2).all(|pair| pair[0].0 > pair[1].0));
+        assert_eq!(window.first().unwrap().0, SearchKey::from(19));
+        assert_eq!(store.range_rev_limited(&key, ..SearchKey::from(3), 5).unwrap().len(), 3);
+        assert_eq!(store.range_entries_rev(b"missing", ..), None);
+    }
+
+    #[test]
+    fn test_multi_range() {
+        use std::ops::Bound;
+
+        let store = DurableKeyMapStore::new_vec_based();
+        let sensors: Vec<Vec<u8>> = (0..5u8).map(|i| format!("sensor_{}", i).into_bytes()).collect();
+        for (i, sensor) in sensors.iter().enumerate() {
+            for minute in (i * 3)..(i * 3 + 20) {
+                store.put(sensor.clone(), minute.into(), vec![i as u8, minute as u8]).unwrap();
+            }
+        }
+
+        let mut keys: Vec<&[u8]> = sensors.iter().map(|sensor| sensor.as_slice()).collect();
+        keys.push(b"missing");
+        let (start, end): (SearchKey, SearchKey) = (10.into(), 25.into());
+        let result = store.multi_range(&keys, start.clone()..end.clone());
+
+        assert_eq!(result.len(), sensors.len());
+        for sensor in &sensors {
+            let expected = store.range_entries(sensor, Bound::Included(start.clone()), Bound::Excluded(end.clone())).unwrap();
+            assert!(!expected.is_empty());
+            assert_eq!(result.get(sensor), Some(&expected));
+        }
+        assert!(store.multi_range(&keys, SearchKey::from(100)..).values().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn test_remove_callback() {
+        use std::cell::Cell;
+
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"key".to_vec();
+        store.put(key.clone(), 1.into(), b"a".to_vec()).unwrap();
+        store.put(key.clone(), 2.into(), b"b".to_vec()).unwrap();
+
+        let fired = Cell::new(false);
+        let removed = store.remove_from_sorted_map_callback(key.clone(), 1.into(), |_| fired.set(true)).unwrap();
+        assert_eq!(removed, Some(b"a".to_vec()));
+        assert!(!fired.get());
+        assert!(store.contains_key(&key));
+
+        let removed = store.remove_from_sorted_map_callback(key.clone(), 3.into(), |_| fired.set(true)).unwrap();
+        assert_eq!(removed, None);
+        assert!(!fired.get());
+
+        let removed = store.remove_from_sorted_map_callback(key.clone(), 2.into(), |search_key| {
+            assert_eq!(search_key, &SearchKey::from(2));
+            fired.set(true);
+        }).unwrap();
+        assert_eq!(removed, Some(b"b".to_vec()));
+        assert!(fired.get());
+        assert!(!store.contains_key(&key));
+    }
+
+    #[test]
+    fn test_snapshot_sorted_map() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let key = b"key".to_vec();
+        store.put(key.clone(), 1.into(), b"a".to_vec()).unwrap();
+        store.put(key.clone(), 2.into(), b"b".to_vec()).unwrap();
+
+        let snapshot = store.snapshot_sorted_map(&key).unwrap();
+
+        store.put(key.clone(), 3.into(), b"c".to_vec()).unwrap();
+        store.put(key.clone(), 1.into(), b"a_".to_vec()).unwrap();
+        store.remove_from_sorted_map(key.clone(), 2.into()).unwrap();
+        store.pop_last(key.clone()).unwrap();
+
+        let expected: BTreeMap<SearchKey, Vec<u8>> = vec![(1.into(), b"a".to_vec()), (2.into(), b"b".to_vec())].into_iter().collect();
+        assert_eq!(*snapshot, expected);
+        assert_eq!(store.get_element(&key, &1.into()), Some(b"a_".to_vec()));
+        assert_eq!(store.sorted_map_size(&key), Some(1));
+
+        store.remove_key(&key).unwrap();
+        assert_eq!(*snapshot, expected);
+        assert!(store.snapshot_sorted_map(&key).is_none());
+    }
+
+    #[test]
+    fn test_ordered_after_mixed_put_and_restart() {
+        use crate::model::Key;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_ordered_map_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key: Vec<u8> = b"log".to_vec();
+
+        let store = DurableKeyMapStore::init_new(dir.to_str().unwrap());
+        store.append_ordered_element(key.clone(), b"first".to_vec()).unwrap();
+        store.append_ordered_element(key.clone(), b"second".to_vec()).unwrap();
+        store.put(key.clone(), SearchKey::from("manual"), b"manual".to_vec()).unwrap();
+        store.put(key.clone(), SearchKey::from(vec![Key::I128(7)]), b"wide".to_vec()).unwrap();
+        drop(store);
+
+        let store = DurableKeyMapStore::init_new(dir.to_str().unwrap());
+        store.append_ordered_element(key.clone(), b"third".to_vec()).unwrap();
+
+        let map = store.get_sorted_map(&key).unwrap();
+        assert_eq!(map.len(), 5);
+        assert_eq!(map.get(&SearchKey::from(0)), Some(&b"first".to_vec()));
+        assert_eq!(map.get(&SearchKey::from(1)), Some(&b"second".to_vec()));
+        assert_eq!(map.get(&SearchKey::from(2)), Some(&b"third".to_vec()));
+        assert_eq!(map.get(&SearchKey::from("manual")), Some(&b"manual".to_vec()));
+
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_in_place() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_map_in_place_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let key: Vec<u8> = b"log".to_vec();
+
+        let store = DurableKeyMapStore::init_new(dir_str);
+        for i in 0..10usize {
+            store.put(key.clone(), i.into(), vec![i as u8]).unwrap();
+        }
+        store.remove_from_sorted_map(key.clone(), 4.into()).unwrap();
+        let expected = store.get_sorted_map(&key);
+        store.shutdown().unwrap();
+
+        let store = DurableKeyMapStore::open_in_place(dir_str).unwrap();
+        assert_eq!(store.get_sorted_map(&key), expected);
+        store.append_ordered_element(key.clone(), b"next".to_vec()).unwrap();
+        store.shutdown().unwrap();
+
+        let renamed = DurableKeyMapStore::init_new(dir_str);
+        assert_eq!(renamed.sorted_map_size(&key), Some(10));
+        assert_eq!(renamed.get_element(&key, &SearchKey::from(10)), Some(b"next".to_vec()));
+
+        drop(renamed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_streams_wal_when_mmap_fails() {
+        use crate::wal::streamed::with_mmap_failing;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_map_streamed_recovery_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyMapStore::init_new(dir_str);
+        for i in 0..100usize {
+            store.put(vec![(i % 10) as u8], i.into(), vec![i as u8]).unwrap();
+        }
+        store.remove_from_sorted_map(vec![3], 13.into()).unwrap();
+        store.remove_key(&[4]).unwrap();
+        let expected = store.get_sorted_map(&[3]);
+        store.shutdown().unwrap();
+
+        let (store, report) = with_mmap_failing(|| DurableKeyMapStore::init_new_with_corruption_policy(dir_str, crate::wal::CorruptionPolicy::Abort)).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(store.size(), 9);
+        assert_eq!(store.get_sorted_map(&[3]), expected);
+        assert_eq!(store.get_sorted_map(&[4]), None);
+        store.shutdown().unwrap();
+
+        let store = with_mmap_failing(|| DurableKeyMapStore::open_in_place(dir_str)).unwrap();
+        assert_eq!(store.size(), 9);
+        assert_eq!(store.sorted_map_size(&[9]), Some(10));
+        store.shutdown().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_renumbering_ordered() {
+        use std::ops::Bound;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_renumbered_map_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let key: Vec<u8> = b"list".to_vec();
+
+        let store = DurableKeyMapStore::init_new(dir_str);
+        for i in 0..10u8 {
+            store.append_ordered_element(key.clone(), vec![i]).unwrap();
+        }
+        for i in [1usize, 2, 3, 4, 6, 7, 8] {
+            store.remove_from_sorted_map(key.clone(), i.into()).unwrap();
+        }
+        store.put(key.clone(), SearchKey::from("manual"), b"manual".to_vec()).unwrap();
+        drop(store);
+
+        let store = DurableKeyMapStore::init_new(dir_str);
+        assert_eq!(store.range_keys(&key, ..SearchKey::from(usize::MAX)), Some(vec![0.into(), 5.into(), 9.into()]));
+        drop(store);
+
+        let store = DurableKeyMapStore::init_new_renumbering_ordered(dir_str);
+        let ordered = store.range_entries(&key, Bound::Unbounded, Bound::Excluded(SearchKey::from(usize::MAX))).unwrap();
+        assert_eq!(ordered, vec![(0.into(), vec![0]), (1.into(), vec![5]), (2.into(), vec![9])]);
+        assert_eq!(store.get_element(&key, &SearchKey::from("manual")), Some(b"manual".to_vec()));
+        store.append_ordered_element(key.clone(), vec![10]).unwrap();
+        assert_eq!(store.get_element(&key, &SearchKey::from(3)), Some(vec![10]));
+        drop(store);
+
+        let store = DurableKeyMapStore::init_new(dir_str);
+        assert_eq!(store.sorted_map_size(&key), Some(5));
+        assert_eq!(store.get_element(&key, &SearchKey::from(2)), Some(vec![9]));
+
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_appends_match_wal() {
+        let store = DurableKeyMapStore::new_vec_based();
+        let threads = 4u8;
+
+        std::thread::scope(|scope| {
+            for thread in 0..threads {
+                let store = &store;
+                scope.spawn(move || {
+                    for i in 0..500u32 {
+                        let key = i.to_be_bytes().to_vec();
+                        if thread % 2 == 0 {
+                            store.append_ordered_element(key.clone(), vec![thread]).unwrap();
+                        } else {
+                            store.put(key.clone(), SearchKey::from(vec![thread]), vec![thread]).unwrap();
+                        }
+                        if i % 7 == 0 {
+                            store.remove_from_sorted_map(key, SearchKey::from(0)).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let replayed = store.wal.read_bytes(crate::wal::read_for_map);
+        assert_eq!(replayed.len(), store.size());
+        for (key, sorted_map) in replayed {
+            assert_eq!(store.get_sorted_map(&key), Some(sorted_map));
+        }
+        for i in (0..500u32).filter(|i| i % 7 != 0) {
+            assert_eq!(store.sorted_map_size(&i.to_be_bytes()), Some(threads as usize));
+        }
+    }
+
     #[test]
     fn test_ordered() {
         let store = DurableKeyMapStore::new_vec_based();
         let key: Vec<u8> = vec![0];
 
         (0..10).for_each(|i| {
-            store.append_ordered_element(key.clone(), format!("{}", i).into_bytes());
+            store.append_ordered_element(key.clone(), format!("{}", i).into_bytes()).unwrap();
         });
 
         let map = store.get_sorted_map(&key).unwrap();
@@ -1,68 +1,173 @@
-use dashmap::DashMap;
+use dashmap::{DashMap, SharedValue};
 use log::info;
 
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
 
-use memmap::MmapOptions;
 use std::fs::File;
 
-use crate::wal::WalStorage;
+use crate::model::{self, SizeLimits};
+use crate::reentrancy;
+use crate::wal::model::StoreType;
+use crate::wal::{CorruptionPolicy, CorruptionReport, ReadableWal, SegmentedWal, Sets, SyncWal, WalStats, WalStorage};
 use dashmap::mapref::entry::Entry;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Bound;
 
-const SET_WAL_FILE_NAME: &str = "set.wal.dat";
+pub(crate) const SET_WAL_FILE_NAME: &str = "set.wal.dat";
 const TMP_SET_WAL_FILE_NAME: &str = ".set.wal.dat";
+const SET_WAL_NAME: &str = "set.wal";
+
+/// In-memory container of a key's elements: `HashSet` by default, `BTreeSet` for [`DurableKeySetStore::new_ordered`].
+/// Only the container differs, both write the same WAL actions.
+pub trait ElementSet: Default + Extend<Vec<u8>> + FromIterator<Vec<u8>> {
+    fn insert(&mut self, element: Vec<u8>) -> bool;
+    fn remove(&mut self, element: &[u8]) -> bool;
+    fn contains(&self, element: &[u8]) -> bool;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    fn elements(&self) -> Box<dyn Iterator<Item = &Vec<u8>> + '_>;
+}
+
+impl ElementSet for HashSet<Vec<u8>> {
+    fn insert(&mut self, element: Vec<u8>) -> bool {
+        HashSet::insert(self, element)
+    }
+
+    fn remove(&mut self, element: &[u8]) -> bool {
+        HashSet::remove(self, element)
+    }
+
+    fn contains(&self, element: &[u8]) -> bool {
+        HashSet::contains(self, element)
+    }
+
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        HashSet::is_empty(self)
+    }
+
+    fn elements(&self) -> Box<dyn Iterator<Item = &Vec<u8>> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+impl ElementSet for BTreeSet<Vec<u8>> {
+    fn insert(&mut self, element: Vec<u8>) -> bool {
+        BTreeSet::insert(self, element)
+    }
 
-pub struct DurableKeySetStore<W: Write> {
-    store: DashMap<Vec<u8>, HashSet<Vec<u8>>>,
+    fn remove(&mut self, element: &[u8]) -> bool {
+        BTreeSet::remove(self, element)
+    }
+
+    fn contains(&self, element: &[u8]) -> bool {
+        BTreeSet::contains(self, element)
+    }
+
+    fn len(&self) -> usize {
+        BTreeSet::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        BTreeSet::is_empty(self)
+    }
+
+    fn elements(&self) -> Box<dyn Iterator<Item = &Vec<u8>> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+/// Changes are written to the WAL and then applied to the sets under the entry lock of their key, so the WAL is
+/// always at least as new as the sets, see [`crate::key_value_store::DurableKeyValueStore`].
+pub struct DurableKeySetStore<W: Write, S: ElementSet = HashSet<Vec<u8>>> {
+    store: DashMap<Vec<u8>, S>,
     wal: WalStorage<W>,
+    limits: SizeLimits,
 }
 
 impl DurableKeySetStore<File> {
     pub fn init_new(store_dir: &str) -> Self {
+        Self::init(store_dir, CorruptionPolicy::Abort).0
+    }
+
+    /// Same as [`DurableKeySetStore::init_new`], failing with a [`crate::wal::StoreTypeMismatch`] if the WAL was
+    /// written by another store type, see [`crate::key_value_store::DurableKeyValueStore::try_init_new`].
+    pub fn try_init_new(store_dir: &str) -> io::Result<Self> {
+        Ok(Self::try_init(store_dir, CorruptionPolicy::Abort)?.0)
+    }
+
+    /// Opens the store in `store_dir` by replaying its WAL in place, see
+    /// [`crate::key_value_store::DurableKeyValueStore::open_in_place`].
+    pub fn open_in_place(store_dir: &str) -> io::Result<Self> {
+        let wal_file_path = Path::new(store_dir).join(SET_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeySet)?;
+        let wal = WalStorage::open_file_based(&wal_file_path)?;
+
+        let file = File::open(&wal_file_path)?;
+        let (map, _) = crate::wal::into_io(crate::wal::replay_for_set(crate::wal::map_or_stream(file), CorruptionPolicy::Abort))?;
+        let store: DashMap<Vec<u8>, HashSet<Vec<u8>>> = map.into_iter().collect();
+        info!("opened {} entries in place from {}", store.len(), wal_file_path.to_str().unwrap());
+
+        Ok(DurableKeySetStore { store, wal, limits: SizeLimits::default() })
+    }
+
+    /// Same as [`DurableKeySetStore::init_new`], with corrupted blocks of the previous WAL handled according to `policy`,
+    /// see [`crate::key_value_store::DurableKeyValueStore::init_new_with_corruption_policy`].
+    pub fn init_new_with_corruption_policy(store_dir: &str, policy: CorruptionPolicy) -> io::Result<(Self, CorruptionReport)> {
+        Self::try_init(store_dir, policy)
+    }
+}
+
+impl DurableKeySetStore<File, BTreeSet<Vec<u8>>> {
+    /// Same as [`DurableKeySetStore::init_new`], with the elements of each key kept sorted, see [`DurableKeySetStore::new_ordered`].
+    pub fn init_new_ordered(store_dir: &str) -> Self {
+        Self::init(store_dir, CorruptionPolicy::Abort).0
+    }
+}
+
+impl<S: ElementSet> DurableKeySetStore<File, S> {
+    fn init(store_dir: &str, policy: CorruptionPolicy) -> (Self, CorruptionReport) {
+        match Self::try_init(store_dir, policy) {
+            Ok(initialized) => initialized,
+            Err(e) => panic!("can't restore {}: {}", Path::new(store_dir).join(SET_WAL_FILE_NAME).to_str().unwrap(), e),
+        }
+    }
+
+    fn try_init(store_dir: &str, policy: CorruptionPolicy) -> io::Result<(Self, CorruptionReport)> {
         let store_dir_path = Path::new(store_dir);
         let wal_file_path = store_dir_path.join(SET_WAL_FILE_NAME);
         let tmp_wal_file_path = store_dir_path.join(TMP_SET_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeySet)?;
 
-        let store = DashMap::new();
+        let mut store = DashMap::new();
         let mut found_set_wal = wal_file_path.exists();
 
         if found_set_wal {
-            if std::fs::metadata(&wal_file_path).unwrap().len() == 0 {
+            if std::fs::metadata(&wal_file_path)?.len() == 0 {
                 let _ = std::fs::remove_file(&wal_file_path);
                 found_set_wal = false;
             } else {
-                let _ = std::fs::rename(&wal_file_path, &tmp_wal_file_path).unwrap();
+                std::fs::rename(&wal_file_path, &tmp_wal_file_path)?;
             }
         }
 
-        let wal = WalStorage::new_file_based(wal_file_path.as_path());
+        let wal = WalStorage::new_file_based_for_store(wal_file_path.as_path(), StoreType::KeySet);
+        let mut report = CorruptionReport::default();
 
         if found_set_wal {
-            let file = File::open(&tmp_wal_file_path).unwrap();
+            let file = File::open(&tmp_wal_file_path)?;
             info!(
                 "found KeySet WAL file: {}, trying to restore...",
                 &wal_file_path.to_str().unwrap()
             );
 
-            let content_as_slice = unsafe { MmapOptions::new().map(&file).unwrap() };
-
-            let map = crate::wal::read_for_set(content_as_slice.as_ref());
-            info!(
-                "restored map with size: {}, adding new new WAL file",
-                map.len()
-            );
-
-            for (each_key, set) in map {
-                let mut key = each_key;
-                for set_val in &set {
-                    let (k, _) = wal.store_append_to_set_event(key, set_val.to_owned());
-                    key = k;
-                }
-                store.insert(key, set);
-            }
-            info!("{} entries added to store", store.len());
+            let (map, replay_report) = crate::wal::into_io(crate::wal::replay_for_set(crate::wal::map_or_stream(file), policy))?;
+            restore(&mut store, &wal, map)?;
+            report = replay_report;
 
             let _ = std::fs::remove_file(tmp_wal_file_path.as_path());
             info!(
@@ -76,8 +181,48 @@ impl DurableKeySetStore<File> {
             );
         }
 
-        DurableKeySetStore { store, wal }
+        Ok((DurableKeySetStore { store, wal, limits: SizeLimits::default() }, report))
+    }
+}
+
+impl DurableKeySetStore<SegmentedWal> {
+    /// Same as [`DurableKeySetStore::init_new`], with the WAL split into `set.wal.NNNNN.dat` segments of about
+    /// `max_segment_bytes`. Segments of the previous run are replayed in order and removed afterwards.
+    pub fn init_new_segmented(store_dir: &str, max_segment_bytes: u64) -> io::Result<Self> {
+        let store_dir_path = Path::new(store_dir);
+        let previous = crate::wal::take_previous_segments(store_dir_path, SET_WAL_NAME)?;
+
+        let mut store = DashMap::new();
+        let wal = WalStorage::new_segmented(store_dir_path, SET_WAL_NAME, max_segment_bytes)?;
+
+        if let Some((bytes, previous_paths)) = previous {
+            info!("found {} KeySet WAL segments, trying to restore...", previous_paths.len());
+            restore(&mut store, &wal, crate::wal::read_for_set(&bytes))?;
+            for path in previous_paths {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(DurableKeySetStore { store, wal, limits: SizeLimits::default() })
+    }
+}
+
+/// Loads the sets replayed from the WAL of a previous run into `store`, writing each one as one block to the new `wal`.
+/// Stops at the first set which can't be written.
+fn restore<W: Write, S: ElementSet>(store: &mut DashMap<Vec<u8>, S>, wal: &WalStorage<W>, map: Sets) -> io::Result<()> {
+    info!(
+        "restored map with size: {}, adding new new WAL file",
+        map.len()
+    );
+    if store.is_empty() {
+        *store = DashMap::with_capacity(map.len());
+    }
+
+    for (key, set) in map {
+        let (key, elements) = wal.store_append_many_to_set_event(key, set.into_iter().collect())?;
+        store.insert(key, elements.into_iter().collect());
     }
+    info!("{} entries added to store", store.len());
+    Ok(())
 }
 
 impl DurableKeySetStore<Vec<u8>> {
@@ -86,18 +231,46 @@ impl DurableKeySetStore<Vec<u8>> {
         DurableKeySetStore {
             store: DashMap::new(),
             wal: WalStorage::new_vec_based(),
+            limits: SizeLimits::default(),
+        }
+    }
+}
+
+impl DurableKeySetStore<Vec<u8>, BTreeSet<Vec<u8>>> {
+    /// Backs each key with a `BTreeSet`, so [`DurableKeySetStore::sorted_elements`] and
+    /// [`DurableKeySetStore::element_range`] return elements in byte order. The WAL is the same as of a `HashSet` store.
+    #[allow(unused)]
+    pub fn new_ordered() -> Self {
+        DurableKeySetStore {
+            store: DashMap::new(),
+            wal: WalStorage::new_vec_based(),
+            limits: SizeLimits::default(),
         }
     }
 }
 
-impl<W: Write> DurableKeySetStore<W> {
+impl<W: SyncWal, S: ElementSet> DurableKeySetStore<W, S> {
+    /// Flushes and syncs the WAL and releases it, consuming the store, see [`WalStorage::close`].
+    pub fn shutdown(self) -> io::Result<()> {
+        self.wal.close()
+    }
+}
+
+impl<W: ReadableWal, S: ElementSet> DurableKeySetStore<W, S> {
+    /// Diagnostics over the WAL backing this store: block counts per action type and live/total bytes.
+    pub fn wal_stats(&self) -> io::Result<WalStats> {
+        self.wal.wal_stats()
+    }
+}
+
+impl<W: Write, S: ElementSet> DurableKeySetStore<W, S> {
     pub fn get_hashset(&self, key: &[u8]) -> Option<HashSet<Vec<u8>>> {
         match self.store.get(key) {
             None => None,
             Some(inner_val) => {
                 let found_set = inner_val.value();
                 let mut result = HashSet::with_capacity(found_set.len());
-                for vec in found_set {
+                for vec in found_set.elements() {
                     result.insert(vec.clone());
                 }
                 Some(result)
@@ -112,73 +285,171 @@ impl<W: Write> DurableKeySetStore<W> {
         }
     }
 
-    pub fn append(&self, key: Vec<u8>, val: Vec<u8>) {
-        let (key, val) = self.wal.store_append_to_set_event(key, val);
+    /// Number of elements of `key`, without copying them like `get_hashset` does.
+    pub fn set_len(&self, key: &[u8]) -> Option<usize> {
+        self.store.get(key).map(|inner_val| inner_val.len())
+    }
 
-        match self.store.get_mut(&key) {
-            None => {
-                let mut new_hashset = HashSet::new();
-                new_hashset.insert(val);
-                self.store.insert(key, new_hashset);
-            }
-            Some(ref mut hashset) => {
-                hashset.insert(val);
-            }
+    /// Those of `candidates` which are elements of `key`, in the order given. All are checked under a single read
+    /// lock of the set, which isn't copied.
+    pub fn set_intersect_with(&self, key: &[u8], candidates: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        match self.store.get(key) {
+            None => Vec::new(),
+            Some(inner_val) => candidates.iter()
+                .filter(|candidate| inner_val.contains(candidate))
+                .cloned()
+                .collect(),
         }
     }
 
-    pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.store.contains_key(key)
+    /// Rejects keys and elements over `limits` in `append` and `append_many`, see
+    /// [`crate::key_value_store::DurableKeyValueStore::with_size_limits`].
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
     }
 
-    pub fn remove_from_set(&self, key: Vec<u8>, set_entry: Vec<u8>) {
-        let (key, set_entry) = self.wal.store_remove_from_set_event(key, set_entry);
+    pub fn append(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<()> {
+        self.limits.check(&key, &val)?;
+        reentrancy::check_not_in_compute();
 
         match self.store.entry(key) {
             Entry::Occupied(mut entry) => {
-                entry.get_mut().remove(&set_entry);
-                if entry.get().is_empty() {
-                    self.wal.store_delete_event(entry.key());
-                    entry.remove();
+                let (_key, val) = self.wal.store_append_to_set_event(entry.key().clone(), val)?;
+                entry.get_mut().insert(val);
+            }
+            Entry::Vacant(entry) => {
+                let (_key, val) = self.wal.store_append_to_set_event(entry.key().clone(), val)?;
+                let mut new_hashset = S::default();
+                new_hashset.insert(val);
+                entry.insert(new_hashset);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends several elements to the set of `key`, writing the key to the WAL only once.
+    pub fn append_many(&self, key: Vec<u8>, elements: Vec<Vec<u8>>) -> io::Result<()> {
+        for element in &elements {
+            self.limits.check(&key, element)?;
+        }
+        reentrancy::check_not_in_compute();
+
+        match self.store.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (_key, elements) = self.wal.store_append_many_to_set_event(entry.key().clone(), elements)?;
+                entry.get_mut().extend(elements);
+            }
+            Entry::Vacant(entry) => {
+                let (_key, elements) = self.wal.store_append_many_to_set_event(entry.key().clone(), elements)?;
+                entry.insert(elements.into_iter().collect());
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `element` to the set of each of `keys`, returning to how many sets it was new. Appends to the sets
+    /// not holding it yet are written with a single WAL write, and each involved shard is locked once (lower shard
+    /// index first) for the whole call, so readers see `element` in either none or all of the sets of a shard.
+    pub fn append_to_many(&self, keys: Vec<Vec<u8>>, element: Vec<u8>) -> io::Result<usize> {
+        for key in &keys {
+            self.limits.check(key, &element)?;
+        }
+        reentrancy::check_not_in_compute();
+        let mut shard_indexes: Vec<usize> = keys.iter().map(|key| self.store.determine_map(key)).collect();
+        shard_indexes.sort_unstable();
+        shard_indexes.dedup();
+
+        let shards = self.store.shards();
+        let mut guards: Vec<_> = shard_indexes.iter().map(|&shard| shards[shard].write()).collect();
+        let guard_index = |key: &[u8]| shard_indexes.binary_search(&self.store.determine_map(key)).unwrap();
+
+        let mut keys = keys;
+        keys.sort_unstable();
+        keys.dedup();
+        keys.retain(|key| !guards[guard_index(key)].get(key.as_slice()).is_some_and(|set| set.get().contains(&element)));
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.wal.store_append_to_sets_event(&keys, &element)?;
+
+        let appended = keys.len();
+        for key in keys {
+            let guard = &mut guards[guard_index(&key)];
+            match guard.get_mut(key.as_slice()) {
+                Some(set) => {
+                    set.get_mut().insert(element.clone());
+                }
+                None => {
+                    let mut set = S::default();
+                    set.insert(element.clone());
+                    guard.insert(key, SharedValue::new(set));
                 }
             }
-            Entry::Vacant(_) => {}
         }
+        Ok(appended)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.store.contains_key(key)
     }
 
-    pub fn compute(&self, key: Vec<u8>, func: impl FnOnce(&mut HashSet<Vec<u8>>)) {
+    /// Some key picked at random, see [`crate::key_value_store::DurableKeyValueStore::random_key`].
+    pub fn random_key(&self) -> Option<Vec<u8>> {
+        model::random_key(&self.store, |_| true)
+    }
+
+    /// [`DurableKeySetStore::contains_key`] of each of `keys`, in input order, locking each involved shard once.
+    pub fn contains_keys(&self, keys: &[&[u8]]) -> Vec<bool> {
+        model::contains_keys(&self.store, keys, |_| true)
+    }
+
+    pub fn remove_from_set(&self, key: Vec<u8>, set_entry: Vec<u8>) -> io::Result<()> {
+        self.remove_from_set_with(key, set_entry, false, |_| {})
+    }
+
+    /// Same as [`DurableKeySetStore::remove_from_set`], keeping `key` with an empty set instead of deleting it when
+    /// the last element is removed, so `contains_key` stays true. The empty set is restored after a restart too.
+    pub fn remove_from_set_keep_empty(&self, key: Vec<u8>, set_entry: Vec<u8>) -> io::Result<()> {
+        self.remove_from_set_with(key, set_entry, true, |_| {})
+    }
+
+    pub fn compute(&self, key: Vec<u8>, func: impl FnOnce(&mut S)) {
+        reentrancy::check_not_in_compute();
         let entry = self.store.entry(key);
         match entry {
             Entry::Occupied(mut occupied_entry) => {
                 let set = occupied_entry.get_mut();
-                func(set);
+                reentrancy::run_compute(|| func(set));
             }
             Entry::Vacant(vacant_entry) => {
-                let mut set = HashSet::new();
-                func(&mut set);
+                let mut set = S::default();
+                reentrancy::run_compute(|| func(&mut set));
                 vacant_entry.insert(set);
             }
         };
     }
 
-    pub fn compute_if_present(&self, key: Vec<u8>, func: impl FnOnce(&mut HashSet<Vec<u8>>)) {
+    pub fn compute_if_present(&self, key: Vec<u8>, func: impl FnOnce(&mut S)) {
+        reentrancy::check_not_in_compute();
         let entry = self.store.entry(key);
         match entry {
             Entry::Occupied(mut occupied_entry) => {
                 let set = occupied_entry.get_mut();
-                func(set);
+                reentrancy::run_compute(|| func(set));
             }
             Entry::Vacant(_) => {}
         };
     }
 
-    pub fn compute_if_absent(&self, key: Vec<u8>, func: impl FnOnce(&mut HashSet<Vec<u8>>)) {
+    pub fn compute_if_absent(&self, key: Vec<u8>, func: impl FnOnce(&mut S)) {
+        reentrancy::check_not_in_compute();
         let entry = self.store.entry(key);
         match entry {
             Entry::Occupied(_) => {}
             Entry::Vacant(vacant_entry) => {
-                let mut set = HashSet::new();
-                func(&mut set);
+                let mut set = S::default();
+                reentrancy::run_compute(|| func(&mut set));
                 vacant_entry.insert(set);
             }
         };
@@ -189,14 +460,21 @@ impl<W: Write> DurableKeySetStore<W> {
         key: Vec<u8>,
         set_entry: Vec<u8>,
         key_removed_callback: impl FnOnce(&[u8]),
-    ) {
-        let (key, set_entry) = self.wal.store_remove_from_set_event(key, set_entry);
+    ) -> io::Result<()> {
+        self.remove_from_set_with(key, set_entry, false, key_removed_callback)
+    }
 
-        match self.store.entry(key) {
+    fn remove_from_set_with(&self, key: Vec<u8>, set_entry: Vec<u8>, keep_empty: bool,
+                            key_removed_callback: impl FnOnce(&[u8])) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        let entry = self.store.entry(key);
+        let (_key, set_entry) = self.wal.store_remove_from_set_event(entry.key().clone(), set_entry)?;
+
+        match entry {
             Entry::Occupied(mut entry) => {
                 entry.get_mut().remove(&set_entry);
-                if entry.get().is_empty() {
-                    self.wal.store_delete_event(entry.key());
+                if entry.get().is_empty() && !keep_empty {
+                    self.wal.store_delete_event(entry.key())?;
                     entry.remove();
 
                     key_removed_callback(&set_entry);
@@ -204,17 +482,95 @@ impl<W: Write> DurableKeySetStore<W> {
             }
             Entry::Vacant(_) => {}
         }
+        Ok(())
+    }
+
+    pub fn remove_key(&self, key: &[u8]) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        let entry = self.store.entry(key.to_vec());
+        self.wal.store_delete_event(key)?;
+
+        if let Entry::Occupied(entry) = entry {
+            entry.remove();
+        }
+        Ok(())
     }
 
-    pub fn remove_key(&self, key: &[u8]) {
-        self.wal.store_delete_event(key);
+    /// Moves `member` from the set of `from_key` to the set of `to_key`, deleting `from_key` if it is left empty.
+    /// Returns `false` without writing anything if `from_key` doesn't hold `member`. Like
+    /// [`crate::key_value_store::DurableKeyValueStore::swap`], both shard locks are held while a single WAL block is
+    /// written, so neither readers nor a recovery see the member in both sets or in neither.
+    pub fn move_member(&self, from_key: &[u8], to_key: &[u8], member: Vec<u8>) -> io::Result<bool> {
+        reentrancy::check_not_in_compute();
+        if from_key == to_key {
+            return Ok(self.contains_in_set(from_key, &member));
+        }
+        let shard_from = self.store.determine_map(from_key);
+        let shard_to = self.store.determine_map(to_key);
+        let (low, high) = (shard_from.min(shard_to), shard_from.max(shard_to));
+
+        let shards = self.store.shards();
+        let mut low_guard = shards[low].write();
+        let mut high_guard = if high != low { Some(shards[high].write()) } else { None };
+
+        let from_map = if shard_from == low { &*low_guard } else { high_guard.as_deref().unwrap() };
+        if !from_map.get(from_key).is_some_and(|set| set.get().contains(&member)) {
+            return Ok(false);
+        }
+
+        let (from_key, to_key, member) = self.wal.store_set_move_event(from_key.to_vec(), to_key.to_vec(), member)?;
+
+        let from_map = if shard_from == low { &mut *low_guard } else { high_guard.as_deref_mut().unwrap() };
+        let from_set = from_map.get_mut(from_key.as_slice()).unwrap().get_mut();
+        from_set.remove(&member);
+        if from_set.is_empty() {
+            from_map.remove(from_key.as_slice());
+        }
 
-        self.store.remove(key);
+        let to_map = if shard_to == low { &mut *low_guard } else { high_guard.as_deref_mut().unwrap() };
+        to_map.entry(to_key).or_insert_with(|| SharedValue::new(S::default())).get_mut().insert(member);
+        Ok(true)
     }
 
     pub fn size(&self) -> usize {
         self.store.len()
     }
+
+    /// Writes the sets sorted by key for inspection, see [`crate::key_value_store::DurableKeyValueStore::debug_dump`]:
+    /// a line per key with its element count, followed by its elements in byte order, indented.
+    pub fn debug_dump(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut sets: Vec<(Vec<u8>, Vec<Vec<u8>>)> = self.store.iter()
+            .map(|entry| (entry.key().clone(), entry.value().elements().cloned().collect()))
+            .collect();
+        sets.sort();
+
+        writeln!(writer, "{} keys", sets.len())?;
+        for (key, mut elements) in sets {
+            elements.sort();
+            writeln!(writer, "{} ({} bytes): {} elements", faster_hex::hex_string(&key), key.len(), elements.len())?;
+            for element in elements {
+                writeln!(writer, "  {} ({} bytes)", faster_hex::hex_string(&element), element.len())?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+impl<W: Write> DurableKeySetStore<W, BTreeSet<Vec<u8>>> {
+    /// Elements of `key` in ascending byte order.
+    pub fn sorted_elements(&self, key: &[u8]) -> Option<Vec<Vec<u8>>> {
+        self.store.get(key).map(|set| set.iter().cloned().collect())
+    }
+
+    /// Elements of `key` within `start..end` in ascending byte order, empty if `start` is not below `end`.
+    pub fn element_range(&self, key: &[u8], start: &[u8], end: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let set = self.store.get(key)?;
+        if start >= end {
+            return Some(Vec::new());
+        }
+        let range = set.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)));
+        Some(range.cloned().collect())
+    }
 }
 
 mod tests {
@@ -225,14 +581,14 @@ mod tests {
 
         let store = DurableKeySetStore::new_vec_based();
 
-        store.append(b"a".to_vec(), b"apple".to_vec());
-        store.append(b"a".to_vec(), b"article".to_vec());
-        store.append(b"a".to_vec(), b"atmosphere".to_vec());
+        store.append(b"a".to_vec(), b"apple".to_vec()).unwrap();
+        store.append(b"a".to_vec(), b"article".to_vec()).unwrap();
+        store.append(b"a".to_vec(), b"atmosphere".to_vec()).unwrap();
 
-        store.append(b"b".to_vec(), b"banana".to_vec());
+        store.append(b"b".to_vec(), b"banana".to_vec()).unwrap();
 
-        store.append(b"c".to_vec(), b"cinema".to_vec());
-        store.append(b"c".to_vec(), b"cinamon".to_vec());
+        store.append(b"c".to_vec(), b"cinema".to_vec()).unwrap();
+        store.append(b"c".to_vec(), b"cinamon".to_vec()).unwrap();
 
         assert_eq!(store.size(), 3);
 
@@ -243,7 +599,7 @@ mod tests {
         assert_eq!(res_a.contains(&b"atmosphere".to_vec()[..]), true);
         assert_eq!(res_a.contains(&b"banana".to_vec()[..]), false);
 
-        store.remove_from_set(b"a".to_vec(), b"article".to_vec());
+        store.remove_from_set(b"a".to_vec(), b"article".to_vec()).unwrap();
         let res_a = store.get_hashset(b"a").unwrap();
         assert_eq!(res_a.contains(&b"article".to_vec()[..]), false);
 
@@ -258,7 +614,7 @@ mod tests {
         assert_eq!(res_c.contains(&b"cinamon".to_vec()[..]), true);
         assert_eq!(res_c.contains(&b"apple".to_vec()[..]), false);
 
-        store.remove_key(b"b");
+        store.remove_key(b"b").unwrap();
         assert_eq!(store.size(), 2);
     }
 
@@ -289,7 +645,7 @@ mod tests {
         let res_set = store.get_hashset(&[0]);
         assert_eq!(res_set, None);
 
-        store.append(vec![0], vec![1]);
+        store.append(vec![0], vec![1]).unwrap();
 
         store.compute_if_present(vec![0], |set| {
             set.insert(vec![2]);
@@ -304,7 +660,7 @@ mod tests {
     #[test]
     fn test_compute_if_absent() {
         let store = crate::key_set_store::DurableKeySetStore::new_vec_based();
-        store.append(vec![0], vec![1]);
+        store.append(vec![0], vec![1]).unwrap();
 
         store.compute_if_absent(vec![0], |set| {
             set.insert(vec![1]);
@@ -322,26 +678,373 @@ mod tests {
         assert_eq!(store.get_hashset(&[2]), None);
     }
 
+    #[test]
+    fn test_append_many() {
+        use super::*;
+
+        let store = DurableKeySetStore::new_vec_based();
+
+        store.append(b"a".to_vec(), b"apple".to_vec()).unwrap();
+        store.append_many(b"a".to_vec(), vec![b"apple".to_vec(), b"avocado".to_vec()]).unwrap();
+        store.append_many(b"b".to_vec(), vec![b"banana".to_vec()]).unwrap();
+
+        assert_eq!(store.size(), 2);
+        assert_eq!(store.get_hashset(b"a").unwrap().len(), 2);
+        assert!(store.contains_in_set(b"a", b"avocado"));
+        assert!(store.contains_in_set(b"b", b"banana"));
+    }
+
+    #[test]
+    fn test_append_to_many() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_set_append_to_many_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| format!("item_{}", i).into_bytes()).collect();
+
+        let store = DurableKeySetStore::init_new(dir_str);
+        store.append(keys[0].clone(), b"featured".to_vec()).unwrap();
+        store.append(keys[1].clone(), b"other".to_vec()).unwrap();
+        let blocks = store.wal_stats().unwrap().blocks;
+        let mut tagged = keys.clone();
+        tagged.push(keys[2].clone());
+        assert_eq!(store.append_to_many(tagged, b"featured".to_vec()).unwrap(), 99);
+        assert_eq!(store.wal_stats().unwrap().blocks, blocks + 99);
+        assert_eq!(store.append_to_many(keys.clone(), b"featured".to_vec()).unwrap(), 0);
+        assert_eq!(store.wal_stats().unwrap().blocks, blocks + 99);
+        store.shutdown().unwrap();
+
+        let store = DurableKeySetStore::init_new(dir_str);
+        assert_eq!(store.size(), 100);
+        for key in &keys {
+            assert!(store.contains_in_set(key, b"featured"));
+        }
+        assert!(store.contains_in_set(&keys[1], b"other"));
+        store.shutdown().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_fails_on_wal_write_error() {
+        use super::*;
+
+        /// Takes the header of the WAL only.
+        struct StorageFullWriter {
+            header_written: bool,
+        }
+
+        impl Write for StorageFullWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.header_written {
+                    return Err(io::Error::from(io::ErrorKind::StorageFull));
+                }
+                self.header_written = true;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let sets: Sets = [(b"set".to_vec(), [b"a".to_vec()].into_iter().collect())].into_iter().collect();
+        let wal = WalStorage::new_writer_based(StorageFullWriter { header_written: false });
+        let mut store: DashMap<Vec<u8>, HashSet<Vec<u8>>> = DashMap::new();
+        let error = restore(&mut store, &wal, sets).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_open_in_place() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_set_in_place_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeySetStore::init_new(dir_str);
+        store.append(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.append_many(b"a".to_vec(), vec![b"2".to_vec(), b"3".to_vec()]).unwrap();
+        store.append(b"b".to_vec(), b"1".to_vec()).unwrap();
+        store.remove_from_set(b"a".to_vec(), b"2".to_vec()).unwrap();
+        store.shutdown().unwrap();
+        let written_len = std::fs::metadata(dir.join(SET_WAL_FILE_NAME)).unwrap().len();
+
+        let store = DurableKeySetStore::open_in_place(dir_str).unwrap();
+        assert_eq!(std::fs::metadata(dir.join(SET_WAL_FILE_NAME)).unwrap().len(), written_len);
+        assert_eq!(store.get_hashset(b"a"), Some([b"1".to_vec(), b"3".to_vec()].into_iter().collect()));
+        store.append(b"b".to_vec(), b"2".to_vec()).unwrap();
+        store.shutdown().unwrap();
+
+        let renamed = DurableKeySetStore::init_new(dir_str);
+        assert_eq!(renamed.size(), 2);
+        assert_eq!(renamed.get_hashset(b"a"), Some([b"1".to_vec(), b"3".to_vec()].into_iter().collect()));
+        assert_eq!(renamed.get_hashset(b"b").unwrap().len(), 2);
+
+        drop(renamed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_streams_wal_when_mmap_fails() {
+        use super::*;
+        use crate::wal::streamed::with_mmap_failing;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_set_streamed_recovery_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeySetStore::init_new(dir_str);
+        for i in 0..100u8 {
+            store.append(vec![i % 10], vec![i]).unwrap();
+        }
+        store.remove_from_set(vec![3], vec![13]).unwrap();
+        store.remove_key(&[4]).unwrap();
+        store.shutdown().unwrap();
+
+        let (store, report) = with_mmap_failing(|| DurableKeySetStore::init_new_with_corruption_policy(dir_str, CorruptionPolicy::Abort)).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(store.size(), 9);
+        assert_eq!(store.get_hashset(&[3]).unwrap().len(), 9);
+        assert_eq!(store.get_hashset(&[4]), None);
+        store.shutdown().unwrap();
+
+        let store = with_mmap_failing(|| DurableKeySetStore::open_in_place(dir_str)).unwrap();
+        assert_eq!(store.size(), 9);
+        assert_eq!(store.get_hashset(&[9]).unwrap().len(), 10);
+        store.shutdown().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ordered() {
+        use super::*;
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+
+        let store = DurableKeySetStore::new_ordered();
+        let random = RandomState::new();
+        let mut expected = BTreeSet::new();
+        for i in 0..1_000u64 {
+            let mut hasher = random.build_hasher();
+            hasher.write_u64(i);
+            let element = (hasher.finish() % 500).to_be_bytes().to_vec();
+            expected.insert(element.clone());
+            store.append(b"tags".to_vec(), element).unwrap();
+        }
+        store.remove_from_set(b"tags".to_vec(), expected.pop_first().unwrap()).unwrap();
+
+        let sorted = store.sorted_elements(b"tags").unwrap();
+        assert_eq!(sorted, expected.iter().cloned().collect::<Vec<_>>());
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(store.sorted_elements(b"missing"), None);
+
+        let start = 100u64.to_be_bytes();
+        let end = 200u64.to_be_bytes();
+        let range = store.element_range(b"tags", &start, &end).unwrap();
+        let expected_range: Vec<Vec<u8>> = expected.range(start.to_vec()..end.to_vec()).cloned().collect();
+        assert_eq!(range, expected_range);
+        assert_eq!(store.element_range(b"tags", &end, &start), Some(Vec::new()));
+
+        let replayed = store.wal.read_bytes(crate::wal::read_for_set);
+        assert_eq!(replayed.get(b"tags".as_slice()).unwrap().len(), expected.len());
+    }
+
     #[test]
     fn test_remove_if_empty() {
         use super::*;
 
         let store = DurableKeySetStore::new_vec_based();
 
-        store.append(b"a".to_vec(), b"apple".to_vec());
-        store.append(b"a".to_vec(), b"apricote".to_vec());
+        store.append(b"a".to_vec(), b"apple".to_vec()).unwrap();
+        store.append(b"a".to_vec(), b"apricote".to_vec()).unwrap();
 
-        store.append(b"b".to_vec(), b"banana".to_vec());
+        store.append(b"b".to_vec(), b"banana".to_vec()).unwrap();
 
         assert_eq!(store.size(), 2);
 
-        store.remove_from_set(b"a".to_vec(), b"apple".to_vec());
+        store.remove_from_set(b"a".to_vec(), b"apple".to_vec()).unwrap();
         assert_eq!(store.size(), 2);
 
-        store.remove_from_set(b"a".to_vec(), b"apricote".to_vec());
+        store.remove_from_set(b"a".to_vec(), b"apricote".to_vec()).unwrap();
         assert_eq!(store.size(), 1);
 
-        store.remove_from_set(b"b".to_vec(), b"banana".to_vec());
+        store.remove_from_set(b"b".to_vec(), b"banana".to_vec()).unwrap();
         assert_eq!(store.size(), 0);
     }
+
+    #[test]
+    fn test_remove_keep_empty() {
+        use super::*;
+
+        let store = DurableKeySetStore::new_vec_based();
+        store.append(b"k".to_vec(), b"a".to_vec()).unwrap();
+
+        store.remove_from_set_keep_empty(b"k".to_vec(), b"a".to_vec()).unwrap();
+        assert!(store.contains_key(b"k"));
+        assert_eq!(store.size(), 1);
+        assert!(!store.contains_in_set(b"k", b"a"));
+
+        store.append(b"k".to_vec(), b"b".to_vec()).unwrap();
+        store.remove_from_set(b"k".to_vec(), b"b".to_vec()).unwrap();
+        assert!(!store.contains_key(b"k"));
+    }
+
+    #[test]
+    fn test_store_type_mismatch() {
+        use super::*;
+        use crate::key_value_store::DurableKeyValueStore;
+        use crate::wal::StoreTypeMismatch;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_store_type_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+
+        {
+            let kv = DurableKeyValueStore::init_new(store_dir);
+            kv.put(b"k".to_vec(), b"v".to_vec()).unwrap();
+        }
+        std::fs::rename(dir.join("kv.wal.dat"), dir.join(SET_WAL_FILE_NAME)).unwrap();
+        let kv_wal = std::fs::read(dir.join(SET_WAL_FILE_NAME)).unwrap();
+
+        let error = DurableKeySetStore::try_init_new(store_dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mismatch = error.get_ref().unwrap().downcast_ref::<StoreTypeMismatch>().unwrap();
+        assert_eq!((mismatch.expected, mismatch.found), (StoreType::KeySet, StoreType::KeyValue));
+        assert_eq!(std::fs::read(dir.join(SET_WAL_FILE_NAME)).unwrap(), kv_wal);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_member() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_set_move_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+
+        {
+            let store = DurableKeySetStore::init_new(store_dir);
+            store.append(b"todo".to_vec(), b"task1".to_vec()).unwrap();
+            store.append(b"todo".to_vec(), b"task2".to_vec()).unwrap();
+
+            assert!(store.move_member(b"todo", b"done", b"task1".to_vec()).unwrap());
+            assert!(!store.move_member(b"todo", b"done", b"missing".to_vec()).unwrap());
+            assert!(!store.contains_in_set(b"todo", b"task1"));
+            assert!(store.contains_in_set(b"done", b"task1"));
+        }
+
+        let store = DurableKeySetStore::init_new(store_dir);
+        assert!(!store.contains_in_set(b"todo", b"task1"));
+        assert!(store.contains_in_set(b"done", b"task1"));
+        assert!(!store.contains_in_set(b"done", b"missing"));
+
+        assert!(store.move_member(b"todo", b"done", b"task2".to_vec()).unwrap());
+        assert!(!store.contains_key(b"todo"));
+        drop(store);
+
+        // crash while the second move is written: the block is cut short and dropped on recovery
+        let store = DurableKeySetStore::init_new(store_dir);
+        assert!(!store.contains_key(b"todo"));
+        store.move_member(b"done", b"todo", b"task2".to_vec()).unwrap();
+        drop(store);
+        let wal_path = dir.join(SET_WAL_FILE_NAME);
+        let wal_len = std::fs::metadata(&wal_path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(wal_len - 3).unwrap();
+
+        let (store, report) = DurableKeySetStore::init_new_with_corruption_policy(store_dir, CorruptionPolicy::TruncateAt).unwrap();
+        assert!(!report.is_clean());
+        assert!(store.contains_in_set(b"done", b"task2"));
+        assert!(!store.contains_key(b"todo"));
+        drop(store);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_intersect_with() {
+        use super::*;
+
+        /// Fails the test if the elements are iterated, e.g. to copy the set.
+        #[derive(Default)]
+        struct NoCopySet(HashSet<Vec<u8>>);
+
+        impl Extend<Vec<u8>> for NoCopySet {
+            fn extend<I: IntoIterator<Item = Vec<u8>>>(&mut self, iter: I) {
+                self.0.extend(iter)
+            }
+        }
+
+        impl FromIterator<Vec<u8>> for NoCopySet {
+            fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+                NoCopySet(iter.into_iter().collect())
+            }
+        }
+
+        impl ElementSet for NoCopySet {
+            fn insert(&mut self, element: Vec<u8>) -> bool { self.0.insert(element) }
+            fn remove(&mut self, element: &[u8]) -> bool { self.0.remove(element) }
+            fn contains(&self, element: &[u8]) -> bool { self.0.contains(element) }
+            fn len(&self) -> usize { self.0.len() }
+            fn is_empty(&self) -> bool { self.0.is_empty() }
+            fn elements(&self) -> Box<dyn Iterator<Item = &Vec<u8>> + '_> { panic!("set elements iterated") }
+        }
+
+        let store: DurableKeySetStore<Vec<u8>, NoCopySet> = DurableKeySetStore { store: DashMap::new(), wal: WalStorage::new_vec_based(), limits: SizeLimits::default() };
+        store.append_many(b"tags".to_vec(), (0..100_000u32).map(|i| format!("tag{}", i * 2).into_bytes()).collect()).unwrap();
+
+        let candidates: Vec<Vec<u8>> = ["tag10", "tag11", "missing", "tag199998", "tag200000"].iter()
+            .map(|tag| tag.as_bytes().to_vec())
+            .collect();
+        assert_eq!(store.set_intersect_with(b"tags", &candidates), vec![b"tag10".to_vec(), b"tag199998".to_vec()]);
+        assert_eq!(store.set_intersect_with(b"tags", &[]), Vec::<Vec<u8>>::new());
+        assert_eq!(store.set_intersect_with(b"absent", &candidates), Vec::<Vec<u8>>::new());
+        assert_eq!(store.set_len(b"tags"), Some(100_000));
+        assert_eq!(store.set_len(b"absent"), None);
+    }
+
+    #[test]
+    fn test_contains_keys() {
+        use super::*;
+
+        let store = DurableKeySetStore::new_vec_based();
+        for i in (0..1000u32).step_by(3) {
+            store.append_many(i.to_be_bytes().to_vec(), vec![b"element".to_vec()]).unwrap();
+        }
+        let keys: Vec<[u8; 4]> = (0..1000u32).rev().map(|i| i.to_be_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+
+        let expected: Vec<bool> = (0..1000u32).rev().map(|i| i % 3 == 0).collect();
+        assert_eq!(store.contains_keys(&keys), expected);
+        assert_eq!(store.contains_keys(&[b"absent", &0u32.to_be_bytes(), &0u32.to_be_bytes()]), vec![false, true, true]);
+        assert_eq!(store.contains_keys(&[]), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn test_random_key() {
+        use super::*;
+
+        let store = DurableKeySetStore::new_vec_based();
+        assert_eq!(store.random_key(), None);
+        for i in 0..100u32 {
+            store.append(i.to_be_bytes().to_vec(), b"element".to_vec()).unwrap();
+        }
+
+        let mut seen = HashSet::new();
+        for _ in 0..100_000 {
+            seen.insert(store.random_key().unwrap());
+            if seen.len() == 100 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 100);
+    }
 }
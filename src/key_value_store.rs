@@ -1,56 +1,370 @@
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use dashmap::DashMap;
-use log::info;
-use memmap::MmapOptions;
+use dashmap::{DashMap, SharedValue};
+use log::{error, info};
 
 use dashmap::mapref::entry::Entry;
-use crate::wal::WalStorage;
+use crate::clock::{Clock, SystemClock};
+use crate::error::PigmentError;
+use crate::key_locks::{KeyLockGuard, KeyLocks};
+use crate::lru::{LruConfig, LruTracker};
+use crate::model::{self, MergeOperator, ShardHasher, SizeLimits, ValueTransformer};
+use crate::namespace::Namespace;
+use crate::reentrancy;
+#[cfg(not(feature = "bytes"))]
+use crate::small_value::SmallValue;
+use crate::wal::model::StoreType;
+use crate::wal::{CompactableWal, CorruptionPolicy, CorruptionReport, MemoryBufferedFile, ReadableWal, ScrubConfig, Scrubber, SegmentedWal, SyncWal, Expirations, VersionedMap, WalError, WalLock, WalContent, WalMetrics, WalStats, WalStorage, Operation, timed};
 
-const KV_WAL_FILE_NAME: &str = "kv.wal.dat";
+pub(crate) const KV_WAL_FILE_NAME: &str = "kv.wal.dat";
 const TMP_KV_WAL_FILE_NAME: &str = ".kv.wal.dat";
+const KV_WAL_LOCK_FILE_NAME: &str = "kv.wal.lock";
+const KV_WAL_NAME: &str = "kv.wal";
+/// First byte of a value written by [`DurableKeyValueStore::add_f64`], followed by the little-endian f64. The extra
+/// byte keeps it from being read as an 8 bytes integer and the other way round.
+const F64_VALUE_TAG: u8 = 0xF6;
+/// Prefix of the keys of the checkpoints recorded by [`DurableKeyValueStore::set_checkpoint`].
+const CHECKPOINT_KEY_PREFIX: &[u8] = b"\0pigment.checkpoint/";
 
+fn is_checkpoint(key: &[u8]) -> bool {
+    key.starts_with(CHECKPOINT_KEY_PREFIX)
+}
+
+/// Value as held in the map: `Bytes` with the `bytes` feature, so [`DurableKeyValueStore::get_bytes`] hands out
+/// reference-counted slices instead of copies, otherwise a [`SmallValue`] holding short values inline.
+#[cfg(feature = "bytes")]
+type StoredValue = bytes::Bytes;
+#[cfg(not(feature = "bytes"))]
+type StoredValue = SmallValue;
+
+#[cfg(feature = "bytes")]
+fn stored(value: Vec<u8>) -> StoredValue {
+    bytes::Bytes::from(value)
+}
+
+#[cfg(not(feature = "bytes"))]
+fn stored(value: Vec<u8>) -> StoredValue {
+    SmallValue::from(value)
+}
+
+/// Same as [`stored`] for a value built on the stack, e.g. a counter, which then takes no `Vec` of its own.
+#[cfg(feature = "bytes")]
+fn stored_slice(value: &[u8]) -> StoredValue {
+    bytes::Bytes::copy_from_slice(value)
+}
+
+#[cfg(not(feature = "bytes"))]
+fn stored_slice(value: &[u8]) -> StoredValue {
+    SmallValue::from_slice(value)
+}
+
+#[cfg(feature = "bytes")]
+fn into_vec(value: StoredValue) -> Vec<u8> {
+    Vec::from(value)
+}
+
+#[cfg(not(feature = "bytes"))]
+fn into_vec(value: StoredValue) -> Vec<u8> {
+    value.into_vec()
+}
+
+/// Every change is written to the WAL and then applied to the map, both under the entry lock of its key (the shard
+/// locks of its keys for changes of several keys). So the WAL is always at least as new as the map: a failed or
+/// interrupted WAL write leaves the map unchanged, and concurrent changes of a key reach the map in the order they
+/// reached the WAL, which a recovery replays.
 pub struct DurableKeyValueStore<W: Write> {
-    store: DashMap<Vec<u8>, Vec<u8>>,
+    store: DashMap<Vec<u8>, StoredValue, ShardHasher>,
+    /// Incremented by every change of a key's value, always updated under the entry lock of `store`.
+    versions: DashMap<Vec<u8>, u64, ShardHasher>,
     wal: WalStorage<W>,
+    merge_operator: Option<Arc<MergeOperator>>,
+    key_locks: KeyLocks,
+    lru: Option<LruTracker>,
+    /// Expiry of keys put by [`DurableKeyValueStore::put_with_ttl`], dropped by any other change of the value.
+    /// Updated under the entry lock of `store`, like `versions`.
+    expirations: DashMap<Vec<u8>, u64, ShardHasher>,
+    clock: Arc<dyn Clock>,
+    coalescing: Option<CoalescingConfig>,
+    /// Counters changed by [`DurableKeyValueStore::increment_or_init`] since their last WAL write, when coalescing.
+    /// Updated under the entry lock of `store`.
+    pending_increments: DashMap<Vec<u8>, PendingIncrement, ShardHasher>,
+    /// Held by stores opened from a directory, so a second opener can't replace the WAL under this one.
+    wal_lock: Option<WalLock>,
+    limits: SizeLimits,
+}
+
+/// When coalesced increments are written, see [`DurableKeyValueStore::with_increment_coalescing`]. A counter is
+/// written once either limit is reached, counting from its first increment not written yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalescingConfig {
+    pub max_increments: u32,
+    pub max_delay: Duration,
+}
+
+struct PendingIncrement {
+    increments: u32,
+    /// Time of the first increment not written yet, see [`Clock::now`].
+    since: u64,
+}
+
+/// Decides what happens to a recovered entry, see [`DurableKeyValueStore::init_new_with_recovery_filter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryAction {
+    Keep,
+    /// The entry is neither restored nor written to the new WAL, so it's compacted out for good.
+    Skip,
+    /// Restores the given key and value instead.
+    Replace(Vec<u8>, Vec<u8>),
+}
+
+/// Outcome of the closure given to [`DurableKeyValueStore::compute`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComputeResult {
+    /// Leaves the entry as it is, nothing is written to the WAL.
+    Keep,
+    Set(Vec<u8>),
+    /// Removes the entry, nothing is written if it doesn't exist.
+    Delete,
+}
+
+type RecoveryFilter<'a> = &'a mut dyn FnMut(&[u8], &[u8]) -> RecoveryAction;
+
+/// What a store initialized from a directory found in the WAL of the previous run, see
+/// [`DurableKeyValueStore::init_new_with_stats`]. All zero when there was no previous WAL.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryStats {
+    /// Blocks of the previous WAL, dropped ones included.
+    pub blocks_read: usize,
+    /// Size of the previous WAL, header included.
+    pub bytes_read: usize,
+    /// Entries in the store once restored, after the recovery filter if any.
+    pub entries_restored: usize,
+    /// Corrupted blocks dropped under [`CorruptionPolicy::SkipBlock`].
+    pub blocks_skipped: usize,
+    /// Time spent replaying the previous WAL and writing the restored entries to the new one.
+    pub duration: Duration,
+    pub corruption: CorruptionReport,
+}
+
+/// Returned by [`DurableKeyValueStore::put_if_version`] when the key was changed since `expected` was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub expected: u64,
+    /// Current version, 0 if the key is absent.
+    pub actual: u64,
 }
 
 impl DurableKeyValueStore<File> {
     pub fn init_new(store_dir: &str) -> Self {
+        Self::init(store_dir, None, None, CorruptionPolicy::Abort, None, None).0
+    }
+
+    /// Same as [`DurableKeyValueStore::init_new`], failing with a [`crate::wal::StoreTypeMismatch`] instead of
+    /// panicking if the WAL in `store_dir` was written by another store type, or with a
+    /// [`crate::wal::AlreadyLocked`] if another store has `store_dir` open. Nothing is changed in either case.
+    pub fn try_init_new(store_dir: &str) -> io::Result<Self> {
+        Ok(Self::try_init(store_dir, None, None, CorruptionPolicy::Abort, None, None)?.0)
+    }
+
+    /// Same as [`DurableKeyValueStore::init_new`], with corrupted blocks of the previous WAL handled according to
+    /// `policy` instead of aborting. The report tells what was dropped, the dropped blocks are gone for good once the
+    /// restored entries are written to the new WAL. Fails like [`DurableKeyValueStore::try_init_new`], or if the
+    /// restored entries can't be written to the new WAL.
+    pub fn init_new_with_corruption_policy(store_dir: &str, policy: CorruptionPolicy) -> io::Result<(Self, CorruptionReport)> {
+        let (store, stats) = Self::try_init(store_dir, None, None, policy, None, None)?;
+        Ok((store, stats.corruption))
+    }
+
+    /// Same as [`DurableKeyValueStore::init_new`], also returning what the recovery went through, e.g. to log or
+    /// monitor how large and slow the replay of the previous WAL was.
+    pub fn init_new_with_stats(store_dir: &str) -> (Self, RecoveryStats) {
+        Self::init(store_dir, None, None, CorruptionPolicy::Abort, None, None)
+    }
+
+    /// Applies `recovery_filter` to each entry of the previous WAL as it's replayed into the new one, so entries
+    /// can be dropped or rewritten (e.g. stripping a deprecated key prefix). Versions of kept and replaced
+    /// entries are carried over; a replacement key which collides with another entry overwrites it.
+    pub fn init_new_with_recovery_filter(
+        store_dir: &str,
+        mut recovery_filter: impl FnMut(&[u8], &[u8]) -> RecoveryAction,
+    ) -> Self {
+        Self::init(store_dir, None, Some(&mut recovery_filter), CorruptionPolicy::Abort, None, None).0
+    }
+
+    /// Store used as a cache: once an entry is tracked beyond `config`, least recently used keys are removed with a
+    /// delete written to the WAL, so they stay gone after a restart. Keys are tracked by `get` and by every write,
+    /// removed keys are forgotten. Entries restored from the previous WAL start in no particular order and are
+    /// evicted right away if over the limits.
+    pub fn new_lru(store_dir: &str, config: LruConfig) -> Self {
+        let mut store = Self::init(store_dir, None, None, CorruptionPolicy::Abort, None, None).0;
+        let lru = LruTracker::new(config);
+        let restored: Vec<(Vec<u8>, usize)> = store.store.iter()
+            .filter(|entry| !is_checkpoint(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.key().len() + entry.value().len()))
+            .collect();
+        let evicted: Vec<Vec<u8>> = restored.into_iter().flat_map(|(key, bytes)| lru.record(&key, bytes)).collect();
+        store.lru = Some(lru);
+        store.evict(evicted);
+        store
+    }
+
+    /// Registers `merge_operator` for [`DurableKeyValueStore::merge`], merge actions of the previous WAL are folded during restore.
+    pub fn init_new_with_merge_operator(
+        store_dir: &str,
+        merge_operator: impl Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        Self::init(store_dir, Some(Arc::new(merge_operator)), None, CorruptionPolicy::Abort, None, None).0
+    }
+
+    /// Writes values encoded by `transformer` to the WAL, e.g. encrypted, while the store holds and returns them
+    /// plain, see [`WalStorage::with_value_transformer`]. The previous WAL is read back with the same transformer, so
+    /// it must be the one it was written with.
+    pub fn init_new_with_value_transformer(store_dir: &str, transformer: Arc<dyn ValueTransformer>) -> Self {
+        Self::init(store_dir, None, None, CorruptionPolicy::Abort, Some(transformer), None).0
+    }
+
+    /// Reports latencies to `metrics`, see [`WalMetrics`]: of each block replayed from the previous WAL, then of the
+    /// WAL writes and of `put`, `put_with_ttl`, `put_batch` and `remove`.
+    pub fn init_new_with_metrics(store_dir: &str, metrics: Arc<dyn WalMetrics>) -> Self {
+        Self::init(store_dir, None, None, CorruptionPolicy::Abort, None, Some(metrics)).0
+    }
+
+    /// Opens the store in `store_dir` by replaying its WAL in place: entries go straight into the map and new blocks
+    /// are appended after the existing ones, so recovery writes nothing. Superseded blocks are kept, the WAL is only
+    /// compacted by [`DurableKeyValueStore::init_new`] or [`DurableKeyValueStore::shutdown_compacted`].
+    pub fn open_in_place(store_dir: &str) -> io::Result<Self> {
+        Self::try_open(store_dir, None, None)
+    }
+
+    /// Same as [`DurableKeyValueStore::open_in_place`], registering `merge_operator` for [`DurableKeyValueStore::merge`]
+    /// and folding the merge actions of the WAL with it.
+    pub fn open_in_place_with_merge_operator(
+        store_dir: &str,
+        merge_operator: impl Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        Self::try_open(store_dir, Some(Arc::new(merge_operator)), None)
+    }
+
+    /// Same as [`DurableKeyValueStore::open_in_place`] for a WAL written with `transformer`, see
+    /// [`DurableKeyValueStore::init_new_with_value_transformer`].
+    pub fn open_in_place_with_value_transformer(store_dir: &str, transformer: Arc<dyn ValueTransformer>) -> io::Result<Self> {
+        Self::try_open(store_dir, None, Some(transformer))
+    }
+
+    fn try_open(store_dir: &str, merge_operator: Option<Arc<MergeOperator>>, value_transformer: Option<Arc<dyn ValueTransformer>>)
+                -> io::Result<Self> {
+        let wal_lock = WalLock::acquire(&Path::new(store_dir).join(KV_WAL_LOCK_FILE_NAME))?;
+        let wal_file_path = Path::new(store_dir).join(KV_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeyValue)?;
+        let wal = WalStorage::open_file_based(&wal_file_path)?;
+        let wal = match value_transformer {
+            Some(transformer) => wal.with_value_transformer(transformer),
+            None => wal,
+        };
+        let mut store = DurableKeyValueStore::with_wal(wal, merge_operator);
+        store.wal_lock = Some(wal_lock);
+
+        let file = File::open(&wal_file_path)?;
+        let (map, expirations, _) = crate::wal::map_or_stream(file)
+            .read_forward_transformed(store.merge_operator.as_deref(), store.wal.value_transformer().as_deref(), CorruptionPolicy::Abort, None)?;
+        store.presize(map.len());
+        for (k, (v, version)) in map {
+            store.versions.insert(k.clone(), version);
+            store.store.insert(k, stored(v));
+        }
+        for (k, expires_at) in expirations {
+            store.expirations.insert(k, expires_at);
+        }
+        info!("opened {} entries in place from {}", store.size(), wal_file_path.to_str().unwrap());
+
+        Ok(store)
+    }
+
+    /// Re-verifies the CRCs of the WAL in the background, calling `on_corruption` with the offset of each corrupted
+    /// block, see [`Scrubber`]. Runs until the returned scrubber is dropped.
+    pub fn start_scrubber(&self, config: ScrubConfig, on_corruption: impl Fn(usize) + Send + 'static) -> io::Result<Scrubber> {
+        self.wal.start_scrubber(config, on_corruption)
+    }
+
+    /// Same as [`DurableKeyValueStore::shutdown`], then compacts the WAL down to a block per live entry by the same
+    /// rewrite a restart does. `store_dir` must be the directory the store was initialized with.
+    pub fn shutdown_compacted(self, store_dir: &str) -> io::Result<()> {
+        self.flush_increments()?;
+        let DurableKeyValueStore { wal, merge_operator, wal_lock, .. } = self;
+        let value_transformer = wal.value_transformer();
+        wal.close()?;
+        drop(wal_lock);
+
+        let (store, _stats) = Self::try_init(store_dir, merge_operator, None, CorruptionPolicy::Abort, value_transformer, None)?;
+        store.shutdown()
+    }
+
+    fn init(store_dir: &str, merge_operator: Option<Arc<MergeOperator>>, recovery_filter: Option<RecoveryFilter>,
+            policy: CorruptionPolicy, value_transformer: Option<Arc<dyn ValueTransformer>>, metrics: Option<Arc<dyn WalMetrics>>)
+            -> (Self, RecoveryStats) {
+        match Self::try_init(store_dir, merge_operator, recovery_filter, policy, value_transformer, metrics) {
+            Ok(initialized) => initialized,
+            Err(e) => panic!("can't restore {}: {}", Path::new(store_dir).join(KV_WAL_FILE_NAME).to_str().unwrap(), e),
+        }
+    }
+
+    /// Takes the lock of `store_dir` before anything else, so a concurrent opener fails here instead of racing
+    /// for the rename of the WAL.
+    fn try_init(store_dir: &str, merge_operator: Option<Arc<MergeOperator>>, recovery_filter: Option<RecoveryFilter>,
+                policy: CorruptionPolicy, value_transformer: Option<Arc<dyn ValueTransformer>>, metrics: Option<Arc<dyn WalMetrics>>)
+                -> io::Result<(Self, RecoveryStats)> {
         let store_dir_path = Path::new(store_dir);
+        let wal_lock = WalLock::acquire(&store_dir_path.join(KV_WAL_LOCK_FILE_NAME))?;
         let wal_file_path = store_dir_path.join(KV_WAL_FILE_NAME);
         let tmp_wal_file_path = store_dir_path.join(TMP_KV_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeyValue)?;
 
-        let store = DashMap::new();
         let mut found_kv_wal = wal_file_path.exists();
 
         if found_kv_wal {
-            if std::fs::metadata(&wal_file_path).unwrap().len() == 0 {
+            if std::fs::metadata(&wal_file_path)?.len() == 0 {
                 let _ = std::fs::remove_file(&wal_file_path);
                 found_kv_wal = false;
             } else {
-                let _ = std::fs::rename(&wal_file_path, &tmp_wal_file_path).unwrap();
+                std::fs::rename(&wal_file_path, &tmp_wal_file_path)?;
             }
         }
 
-        let wal = WalStorage::new_file_based(wal_file_path.as_path());
+        let wal = WalStorage::new_file_based_for_store(wal_file_path.as_path(), StoreType::KeyValue);
+        let wal = match value_transformer {
+            Some(transformer) => wal.with_value_transformer(transformer),
+            None => wal,
+        };
+        let wal = match metrics {
+            Some(metrics) => wal.with_metrics(metrics),
+            None => wal,
+        };
+        let mut store = DurableKeyValueStore::with_wal(wal, merge_operator);
+        store.wal_lock = Some(wal_lock);
+        let mut stats = RecoveryStats::default();
 
         if found_kv_wal {
-            let file = File::open(&tmp_wal_file_path).unwrap();
+            let file = File::open(&tmp_wal_file_path)?;
             info!("found KeyValue WAL file: {}, trying to restore...", &wal_file_path.to_str().unwrap());
 
-            let content_as_slice = unsafe { MmapOptions::new().map(&file).unwrap() };
-
-            let map = crate::wal::collect(content_as_slice.as_ref());
-            info!("restored map with size: {}, adding new new WAL file", map.len());
+            let started = Instant::now();
+            let bytes_read = file.metadata()?.len() as usize;
 
-            for (k, v) in map {
-                let (k, v) = wal.store_put_event(k, v);
-                store.insert(k, v);
-            }
-            info!("{} entries added to store", store.len());
+            let report = store.restore_content(crate::wal::map_or_stream(file), recovery_filter, policy)?;
+            stats = RecoveryStats {
+                blocks_read: report.blocks_read,
+                bytes_read,
+                entries_restored: store.size(),
+                blocks_skipped: report.skipped_blocks.len(),
+                duration: started.elapsed(),
+                corruption: report,
+            };
+            info!("recovery stats: {:?}", stats);
 
             let _ = std::fs::remove_file(tmp_wal_file_path.as_path());
             info!("removed old wal file {}", tmp_wal_file_path.to_str().unwrap());
@@ -58,137 +372,1260 @@ impl DurableKeyValueStore<File> {
             info!("no previous wal log found, starting from scratch: {}", &wal_file_path.to_str().unwrap());
         }
 
-        DurableKeyValueStore { store, wal }
+        Ok((store, stats))
+    }
+}
+
+impl DurableKeyValueStore<MemoryBufferedFile> {
+    /// Same as [`DurableKeyValueStore::init_new`] for the lowest write latency: the WAL is appended in memory and
+    /// persisted to `kv.wal.dat` every `persist_interval` (if given), by [`DurableKeyValueStore::persist`], on
+    /// shutdown and when the store is dropped. Writes since the last persist are lost on a crash. The file is
+    /// restored like the one of [`DurableKeyValueStore::init_new`], so a store can switch between both.
+    pub fn init_new_memory_buffered(store_dir: &str, persist_interval: Option<Duration>) -> io::Result<Self> {
+        let store_dir_path = Path::new(store_dir);
+        let wal_lock = WalLock::acquire(&store_dir_path.join(KV_WAL_LOCK_FILE_NAME))?;
+        let wal_file_path = store_dir_path.join(KV_WAL_FILE_NAME);
+        let tmp_wal_file_path = store_dir_path.join(TMP_KV_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::KeyValue)?;
+
+        let found_kv_wal = wal_file_path.exists() && std::fs::metadata(&wal_file_path)?.len() > 0;
+        if found_kv_wal {
+            std::fs::rename(&wal_file_path, &tmp_wal_file_path)?;
+        } else {
+            let _ = std::fs::remove_file(&wal_file_path);
+        }
+
+        let mut store = DurableKeyValueStore::with_wal(WalStorage::new_memory_buffered(&wal_file_path, persist_interval)?, None);
+        store.wal_lock = Some(wal_lock);
+        if found_kv_wal {
+            info!("found KeyValue WAL file: {}, trying to restore...", wal_file_path.to_str().unwrap());
+            let file = File::open(&tmp_wal_file_path)?;
+            store.restore_content(crate::wal::map_or_stream(file), None, CorruptionPolicy::Abort)?;
+            // the restored entries only exist in memory until persisted
+            store.persist()?;
+            std::fs::remove_file(&tmp_wal_file_path)?;
+        }
+        Ok(store)
+    }
+
+    /// Writes the entries changed since the last persist to the WAL file, see [`MemoryBufferedFile`].
+    pub fn persist(&self) -> io::Result<()> {
+        self.wal.persist()
+    }
+}
+
+impl DurableKeyValueStore<SegmentedWal> {
+    /// Same as [`DurableKeyValueStore::init_new`], with the WAL split into `kv.wal.NNNNN.dat` segments of about
+    /// `max_segment_bytes`. Segments of the previous run are read newest first, skipping the ones fully overwritten
+    /// by newer segments (see [`crate::wal::read_segments_newest_first`]), or replayed in order if that's not
+    /// possible. They are removed afterwards.
+    pub fn init_new_segmented(store_dir: &str, max_segment_bytes: u64) -> io::Result<Self> {
+        let store_dir_path = Path::new(store_dir);
+        let wal_lock = WalLock::acquire(&store_dir_path.join(KV_WAL_LOCK_FILE_NAME))?;
+        let previous_paths = crate::wal::take_previous_segment_paths(store_dir_path, KV_WAL_NAME)?;
+
+        let mut store = DurableKeyValueStore::with_wal(WalStorage::new_segmented(store_dir_path, KV_WAL_NAME, max_segment_bytes)?, None);
+        store.wal_lock = Some(wal_lock);
+
+        if !previous_paths.is_empty() {
+            info!("found {} KeyValue WAL segments, trying to restore...", previous_paths.len());
+            match crate::wal::read_segments_newest_first(&previous_paths)? {
+                Some(read) => {
+                    info!("read {} segments newest first, skipped {}", read.segments_read, read.segments_skipped);
+                    store.restore_entries(read.entries, read.expirations, None)?;
+                }
+                None => {
+                    info!("replaying all segments from the oldest one");
+                    store.restore(&SegmentedWal::read_segments(&previous_paths)?, None, CorruptionPolicy::Abort)?;
+                }
+            }
+            crate::wal::remove_segments(&previous_paths)?;
+        }
+        Ok(store)
     }
 }
 
 impl DurableKeyValueStore<Vec<u8>> {
     #[allow(unused)]
     pub fn new_vec_based() -> Self {
-        DurableKeyValueStore { store: DashMap::new(), wal: WalStorage::new_vec_based() }
+        DurableKeyValueStore::with_wal(WalStorage::new_vec_based(), None)
+    }
+
+    #[allow(unused)]
+    pub fn new_vec_based_without_crc() -> Self {
+        DurableKeyValueStore::with_wal(WalStorage::new_vec_based_without_crc(), None)
+    }
+
+    /// In-memory store whose WAL is compacted whenever it would grow over `max_bytes`, see [`WalStorage::new_vec_based_capped`].
+    #[allow(unused)]
+    pub fn new_vec_based_capped(max_bytes: usize) -> Self {
+        DurableKeyValueStore::with_wal(WalStorage::new_vec_based_capped(max_bytes), None)
+    }
+
+    #[allow(unused)]
+    pub fn new_vec_based_with_merge_operator(
+        merge_operator: impl Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        DurableKeyValueStore::with_wal(WalStorage::new_vec_based(), Some(Arc::new(merge_operator)))
     }
 }
 
 impl<W: Write> DurableKeyValueStore<W> {
+    fn with_wal(wal: WalStorage<W>, merge_operator: Option<Arc<MergeOperator>>) -> Self {
+        DurableKeyValueStore {
+            store: DashMap::default(),
+            versions: DashMap::default(),
+            wal,
+            merge_operator,
+            key_locks: KeyLocks::new(),
+            lru: None,
+            expirations: DashMap::default(),
+            clock: Arc::new(SystemClock),
+            coalescing: None,
+            pending_increments: DashMap::default(),
+            wal_lock: None,
+            limits: SizeLimits::default(),
+        }
+    }
+
+    /// Makes [`DurableKeyValueStore::increment_or_init`] write a counter to the WAL only every
+    /// `config.max_increments` increments or once `config.max_delay` passed since its first unwritten one, instead
+    /// of on every call. The first increment of an absent key is written right away. The limits are checked on
+    /// increments, a counter left pending is written by the next increment, by any other write of the key or by
+    /// [`DurableKeyValueStore::flush_increments`], which `shutdown` calls. Increments not written yet are lost on a
+    /// crash. The version of a counter only goes up when it's written, once for all the increments it carries, as a
+    /// replay of the WAL counts it.
+    pub fn with_increment_coalescing(mut self, config: CoalescingConfig) -> Self {
+        self.coalescing = Some(config);
+        self
+    }
+
+    /// Writes every counter with increments pending from coalescing, returns how many were written.
+    pub fn flush_increments(&self) -> io::Result<usize> {
+        reentrancy::check_not_in_compute();
+        let keys: Vec<Vec<u8>> = self.pending_increments.iter().map(|pending| pending.key().clone()).collect();
+        let mut flushed = 0;
+        for key in keys {
+            // the entry lock keeps increments of the key out until the pending one is written
+            match self.store.get(&key) {
+                Some(value) => {
+                    if self.pending_increments.remove(&key).is_some() {
+                        self.wal.store_put_event(key.clone(), value.to_vec())?;
+                        self.bump_version(&key);
+                        flushed += 1;
+                    }
+                }
+                None => {
+                    self.pending_increments.remove(&key);
+                }
+            }
+        }
+        Ok(flushed)
+    }
+
+    /// Writes the new value of a counter present before, unless coalescing defers it, see
+    /// [`DurableKeyValueStore::with_increment_coalescing`]. Returns whether it was written. Called under the entry
+    /// lock of `key`.
+    fn store_increment(&self, key: &[u8], value: &[u8]) -> io::Result<bool> {
+        if let Some(config) = self.coalescing {
+            let now = self.clock.now();
+            let mut pending = self.pending_increments.entry(key.to_vec())
+                .or_insert(PendingIncrement { increments: 0, since: now });
+            pending.increments += 1;
+            if pending.increments < config.max_increments && now.saturating_sub(pending.since) < config.max_delay.as_millis() as u64 {
+                return Ok(false);
+            }
+        }
+        self.wal.store_put_event(key.to_vec(), value.to_vec())?;
+        Ok(true)
+    }
+
+    /// Reads the time entries expire at from `clock` instead of the system clock, e.g. a
+    /// [`crate::clock::ManualClock`] in tests. Meant to be chained right after a constructor.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Places keys in shards with `shard_hasher` instead of a randomly seeded hasher, see [`ShardHasher`]. Meant to be
+    /// chained right after a constructor, entries already in the store are moved over.
+    pub fn with_shard_hasher(self, shard_hasher: ShardHasher) -> Self {
+        let DurableKeyValueStore { store: old_store, versions: old_versions, wal, merge_operator, key_locks, lru,
+            expirations: old_expirations, clock, coalescing, pending_increments: old_pending_increments, wal_lock, limits } = self;
+        let mut store = DashMap::with_hasher(shard_hasher.clone());
+        store.extend(old_store);
+        let mut versions = DashMap::with_hasher(shard_hasher.clone());
+        versions.extend(old_versions);
+        let mut expirations = DashMap::with_hasher(shard_hasher.clone());
+        expirations.extend(old_expirations);
+        let mut pending_increments = DashMap::with_hasher(shard_hasher);
+        pending_increments.extend(old_pending_increments);
+        DurableKeyValueStore { store, versions, wal, merge_operator, key_locks, lru, expirations, clock, coalescing, pending_increments, wal_lock, limits }
+    }
+
+    /// Rejects keys and values over `limits` in `put`, `put_with_ttl`, `put_returning`, `put_batch`, `put_if_absent`,
+    /// `put_if_version`, `merge`, `set_number`, and in the values set by `compute` and `with_entry`, with an
+    /// `ErrorKind::InvalidInput` error wrapping the [`PigmentError`]. Nothing is written to the WAL then; a batch with
+    /// one oversized entry is rejected as a whole.
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Index of the shard `key` is placed in, stable for a store built with [`ShardHasher::seeded`].
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        self.store.determine_map(key)
+    }
+
+    /// Replays the WAL of a previous run into this store, writing each restored entry once to the new WAL.
+    fn restore(&mut self, bytes: &[u8], recovery_filter: Option<RecoveryFilter>, policy: CorruptionPolicy) -> io::Result<CorruptionReport> {
+        let (map, expirations, report) =
+            crate::wal::read_forward_transformed_with_policy(bytes, self.merge_operator.as_deref(), self.wal.value_transformer().as_deref(), policy)?;
+        self.restore_entries(map, expirations, recovery_filter)?;
+        Ok(report)
+    }
+
+    /// Same as [`DurableKeyValueStore::restore`] for a WAL file which may have to be streamed, see [`WalContent`].
+    fn restore_content(&mut self, content: WalContent, recovery_filter: Option<RecoveryFilter>, policy: CorruptionPolicy)
+                       -> io::Result<CorruptionReport> {
+        let (map, expirations, report) =
+            content.read_forward_transformed(self.merge_operator.as_deref(), self.wal.value_transformer().as_deref(), policy, self.wal.metrics())?;
+        self.restore_entries(map, expirations, recovery_filter)?;
+        Ok(report)
+    }
+
+    /// Expired entries are restored as well, they are only compared with the clock once read. Stops at the first entry
+    /// which can't be written to the new WAL.
+    fn restore_entries(&mut self, map: VersionedMap, mut expirations: Expirations, mut recovery_filter: Option<RecoveryFilter>)
+                       -> io::Result<()> {
+        info!("restored map with size: {}, adding new new WAL file", map.len());
+        self.presize(map.len());
+
+        for (k, (v, version)) in map {
+            let expires_at = expirations.remove(&k);
+            let (k, v) = match recovery_filter.as_mut().map_or(RecoveryAction::Keep, |filter| filter(&k, &v)) {
+                RecoveryAction::Keep => (k, v),
+                RecoveryAction::Skip => continue,
+                RecoveryAction::Replace(k, v) => (k, v),
+            };
+            self.restore_entry(k, v, version, expires_at)?;
+        }
+        info!("{} entries added to store", self.size());
+        Ok(())
+    }
+
+    /// Replaces the maps of a still empty store with ones sized for `entries`, so restoring them doesn't resize the
+    /// shards over and over.
+    fn presize(&mut self, entries: usize) {
+        if self.store.is_empty() {
+            self.store = DashMap::with_capacity_and_hasher(entries, self.store.hasher().clone());
+            self.versions = DashMap::with_capacity_and_hasher(entries, self.versions.hasher().clone());
+        }
+    }
+
+    /// Writes the entry with its version and expiry to the WAL, then puts it in the store as it is.
+    fn restore_entry(&self, k: Vec<u8>, v: Vec<u8>, version: u64, expires_at: Option<u64>) -> io::Result<()> {
+        let (k, v) = self.wal.store_versioned_put_event(k, v, version)?;
+        if let Some(expires_at) = expires_at {
+            self.wal.store_expire_event(k.clone(), expires_at)?;
+            self.expirations.insert(k.clone(), expires_at);
+        }
+        self.versions.insert(k.clone(), version);
+        self.store.insert(k, stored(v));
+        Ok(())
+    }
+
+    /// Independent copy of this store in memory, e.g. for speculative changes, with its own WAL holding a versioned
+    /// put per entry, like a compacted WAL. Entries are copied shard by shard, each as it is when copied, so writes
+    /// running meanwhile may be seen by the fork partially. The fork shares the merge operator and the clock, but
+    /// not the LRU tracking.
+    pub fn fork(&self) -> DurableKeyValueStore<Vec<u8>> {
+        let fork = DurableKeyValueStore::with_wal(self.with_own_transformer(WalStorage::new_vec_based()), self.merge_operator.clone())
+            .with_clock(self.clock.clone());
+        self.copy_to(&fork).expect("vec based WAL writes don't fail");
+        fork
+    }
+
+    /// Same as [`DurableKeyValueStore::fork`], writing the WAL of the fork to `store_dir`, where
+    /// [`DurableKeyValueStore::init_new`] restores it from. Fails with `ErrorKind::AlreadyExists` if `store_dir`
+    /// has a KeyValue WAL already.
+    pub fn fork_to_dir(&self, store_dir: &str) -> io::Result<DurableKeyValueStore<File>> {
+        let wal_lock = WalLock::acquire(&Path::new(store_dir).join(KV_WAL_LOCK_FILE_NAME))?;
+        let wal_file_path = Path::new(store_dir).join(KV_WAL_FILE_NAME);
+        if wal_file_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already has a WAL", store_dir)));
+        }
+        let wal = self.with_own_transformer(WalStorage::new_file_based_for_store(&wal_file_path, StoreType::KeyValue));
+        let mut fork = DurableKeyValueStore::with_wal(wal, self.merge_operator.clone())
+            .with_clock(self.clock.clone());
+        fork.wal_lock = Some(wal_lock);
+        self.copy_to(&fork)?;
+        Ok(fork)
+    }
+
+    /// `wal` encoding values as the WAL of this store does, so a fork of an encrypted store is encrypted too.
+    fn with_own_transformer<F: Write>(&self, wal: WalStorage<F>) -> WalStorage<F> {
+        match self.wal.value_transformer() {
+            Some(transformer) => wal.with_value_transformer(transformer),
+            None => wal,
+        }
+    }
+
+    fn copy_to<F: Write>(&self, fork: &DurableKeyValueStore<F>) -> io::Result<()> {
+        for entry in self.store.iter() {
+            let expires_at = self.expirations.get(entry.key()).map(|expires_at| *expires_at);
+            fork.restore_entry(entry.key().clone(), entry.value().to_vec(), self.version(entry.key()), expires_at)?;
+        }
+        Ok(())
+    }
+
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.store.get(key) {
+        reentrancy::check_not_in_compute();
+        if self.expired(key) {
+            return None;
+        }
+        let result = match self.store.get(key) {
             None => { None }
             Some(inner_val) => {
                 let result = Vec::from(&inner_val.value()[..]);
                 Some(result)
             }
+        };
+        if let (Some(lru), Some(_)) = (&self.lru, &result) {
+            lru.touch(key);
+        }
+        result
+    }
+
+    /// Length of the value of `key`, read under the shard guard without copying the value.
+    pub fn value_len(&self, key: &[u8]) -> Option<usize> {
+        if self.expired(key) {
+            return None;
         }
+        self.store.get(key).map(|inner_val| inner_val.value().len())
+    }
+
+    /// Writes the entry to the WAL and then to the store; if the WAL write fails the store is left unchanged.
+    pub fn put(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &val)?;
+        self.write_and_insert(key, |key| self.wal.store_put_event(key, val).map(|(_, val)| stored(val)))
     }
 
-    pub fn put(&self, key: Vec<u8>, val: Vec<u8>) {
-        let (key, val) = self.wal.store_put_event(key, val);
+    /// Same as [`DurableKeyValueStore::put`], with the entry expiring once `ttl` has passed on the store's clock (see
+    /// [`DurableKeyValueStore::with_clock`]). `get`, `get_versioned` and `contains` don't see an expired entry, which
+    /// still takes memory and is counted by `size` until it's written again or removed, e.g. by
+    /// [`DurableKeyValueStore::purge_expired`]. The expiry is written to the WAL with the put and survives restarts.
+    pub fn put_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> io::Result<()> {
+        self.limits.check(&key, &val)?;
+        let expires_at = self.clock.now().saturating_add(ttl.as_millis() as u64);
+        reentrancy::check_not_in_compute();
 
-        self.store.insert(key, val);
+        let tracked = self.lru.as_ref().map(|_| (key.clone(), key.len() + val.len()));
+        timed(self.wal.metrics(), Operation::Put, || -> io::Result<()> {
+            match self.live_entry(key)? {
+                Entry::Occupied(mut entry) => {
+                    let (_, val) = self.wal.store_expiring_put_event(entry.key().clone(), val, expires_at)?;
+                    *entry.get_mut() = stored(val);
+                    self.bump_version(entry.key());
+                    self.expirations.insert(entry.key().clone(), expires_at);
+                }
+                Entry::Vacant(entry) => {
+                    let (_, val) = self.wal.store_expiring_put_event(entry.key().clone(), val, expires_at)?;
+                    self.bump_version(entry.key());
+                    self.expirations.insert(entry.key().clone(), expires_at);
+                    entry.insert(stored(val));
+                }
+            }
+            Ok(())
+        })?;
+        self.track(tracked);
+        Ok(())
     }
 
-    pub fn compute(&self, key: Vec<u8>, func: impl FnOnce(Option<&[u8]>) -> Vec<u8>) {
-        match self.store.entry(key) {
+    /// Removes entries expired by now, writing a delete for each, and returns how many were removed.
+    pub fn purge_expired(&self) -> io::Result<usize> {
+        reentrancy::check_not_in_compute();
+        let now = self.clock.now();
+        let expired: Vec<Vec<u8>> = self.expirations.iter()
+            .filter(|expiry| *expiry.value() <= now)
+            .map(|expiry| expiry.key().clone())
+            .collect();
+
+        let mut purged = 0;
+        for key in expired {
+            if let Entry::Occupied(entry) = self.store.entry(key) {
+                // rewritten meanwhile without an expiry or with a later one
+                if self.expirations.get(entry.key()).is_none_or(|expires_at| *expires_at > now) {
+                    continue;
+                }
+                self.wal.store_delete_event(entry.key())?;
+                self.versions.remove(entry.key());
+                self.expirations.remove(entry.key());
+                let (key, _) = entry.remove_entry();
+                if let Some(lru) = &self.lru {
+                    lru.forget(&key);
+                }
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Same as [`DurableKeyValueStore::put`], returning the previous value of `key` like `HashMap::insert`. The WAL
+    /// write and the swap happen under the entry lock of `key`, so the returned value is the one this put replaced.
+    pub fn put_returning(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &val)?;
+        let tracked = self.lru.as_ref().map(|_| (key.clone(), key.len() + val.len()));
+        let previous = match self.live_entry(key)? {
             Entry::Occupied(mut entry) => {
-                let new_val = func(Some(entry.get().as_slice()));
-                self.wal.store_put_event(entry.key().clone(), new_val.clone());
-                *entry.get_mut() = new_val;
+                let (_, val) = self.wal.store_put_event(entry.key().clone(), val)?;
+                let previous = std::mem::replace(entry.get_mut(), stored(val));
+                self.bump_version(entry.key());
+                Some(into_vec(previous))
             }
             Entry::Vacant(entry) => {
-                let new_val = func(None);
-                self.wal.store_put_event(entry.key().clone(), new_val.clone());
-                entry.insert(new_val);
+                let (_, val) = self.wal.store_put_event(entry.key().clone(), val)?;
+                self.bump_version(entry.key());
+                entry.insert(stored(val));
+                None
             }
         };
+        self.track(tracked);
+        Ok(previous)
     }
 
-    pub fn increment_or_init(&self, key: Vec<u8>, increment_by: u64) -> Result<u64, ()> {
-        match self.store.entry(key) {
+    /// Value of `key` sharing the stored allocation, so handing it through several layers doesn't copy it.
+    #[cfg(feature = "bytes")]
+    pub fn get_bytes(&self, key: &[u8]) -> Option<bytes::Bytes> {
+        if self.expired(key) {
+            return None;
+        }
+        self.store.get(key).map(|inner_val| inner_val.value().clone())
+    }
+
+    /// Same as [`DurableKeyValueStore::put`], keeping `val` itself in the store. The WAL still gets a copy to serialize.
+    #[cfg(feature = "bytes")]
+    pub fn put_bytes(&self, key: Vec<u8>, val: bytes::Bytes) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &val)?;
+        self.write_and_insert(key, |key| self.wal.store_put_event(key, val.to_vec()).map(|_| val))
+    }
+
+    /// Same as [`DurableKeyValueStore::put`] for servers which must stay responsive: fails with [`WalError::WouldBlock`]
+    /// if the WAL lock isn't acquired within `timeout`, and with [`WalError::Poisoned`] rather than a panic after a
+    /// writer panicked mid-write.
+    pub fn try_put(&self, key: Vec<u8>, val: Vec<u8>, timeout: Duration) -> Result<(), WalError> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &val).map_err(io::Error::from)?;
+        self.write_and_insert(key, |key| self.wal.try_store_put_event(key, val, timeout).map(|(_, val)| stored(val)))
+    }
+
+    /// Writes all entries to the WAL under one lock acquisition and with one write, then applies them to the store.
+    /// The WAL write is all-or-nothing from the store's point of view: if it fails nothing is applied, though a
+    /// file may have received a prefix of the blocks. Each involved shard is locked once (lower shard index first)
+    /// for the whole batch like in [`DurableKeyValueStore::remove_many`], so readers see either none or all of the
+    /// entries of a shard applied; later entries for the same key win.
+    pub fn put_batch(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> io::Result<()> {
+        for (key, val) in &entries {
+            self.limits.check(key, val)?;
+        }
+        reentrancy::check_not_in_compute();
+        let mut shard_indexes: Vec<usize> = entries.iter().map(|(key, _)| self.store.determine_map(key)).collect();
+        shard_indexes.sort_unstable();
+        shard_indexes.dedup();
+
+        let tracked = timed(self.wal.metrics(), Operation::PutBatch, || -> io::Result<_> {
+            let shards = self.store.shards();
+            let mut guards: Vec<_> = shard_indexes.iter().map(|&shard| shards[shard].write()).collect();
+            let entries = self.wal.store_put_batch_event(entries)?;
+
+            let mut tracked = Vec::new();
+            for (key, val) in entries {
+                if self.lru.is_some() {
+                    tracked.push((key.clone(), key.len() + val.len()));
+                }
+                self.bump_version(&key);
+                let guard_index = shard_indexes.binary_search(&self.store.determine_map(&key)).unwrap();
+                guards[guard_index].insert(key, SharedValue::new(stored(val)));
+            }
+            Ok(tracked)
+        })?;
+        for tracked in tracked {
+            self.track(Some(tracked));
+        }
+        Ok(())
+    }
+
+    /// Puts `val` only if `key` is absent or expired, returning whether it did. The check, the WAL write and the insert happen
+    /// under the entry lock, so of concurrent callers exactly one inserts; nothing is written if the key exists.
+    pub fn put_if_absent(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<bool> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &val)?;
+        let tracked = self.lru.as_ref().map(|_| (key.clone(), key.len() + val.len()));
+        match self.live_entry(key)? {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => {
+                self.wal.store_put_event(entry.key().clone(), val.clone())?;
+                self.bump_version(entry.key());
+                entry.insert(stored(val));
+            }
+        }
+        self.track(tracked);
+        Ok(true)
+    }
+
+    /// Value of `key` with its version, which is incremented by every change of the value (starting from 1 for a
+    /// new key). Versions survive recovery, but start over when the key is removed.
+    pub fn get_versioned(&self, key: &[u8]) -> Option<(Vec<u8>, u64)> {
+        if self.expired(key) {
+            return None;
+        }
+        self.store.get(key).map(|inner_val| (inner_val.value().to_vec(), self.version(key)))
+    }
+
+    /// Optimistic concurrency without comparing values: puts `val` only if the version of `key` is still
+    /// `expected_version` (use 0 to put only when `key` is absent or expired) and returns the new version.
+    /// The outer `Err` is a failed WAL write, the store is left unchanged in both error cases.
+    pub fn put_if_version(&self, key: Vec<u8>, val: Vec<u8>, expected_version: u64) -> io::Result<Result<u64, VersionConflict>> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &val)?;
+        let tracked = self.lru.as_ref().map(|_| (key.clone(), key.len() + val.len()));
+        let version = match self.live_entry(key)? {
+            Entry::Occupied(mut entry) => {
+                let actual = self.version(entry.key());
+                if actual != expected_version {
+                    return Ok(Err(VersionConflict { expected: expected_version, actual }));
+                }
+                self.wal.store_put_event(entry.key().clone(), val.clone())?;
+                *entry.get_mut() = stored(val);
+                self.bump_version(entry.key())
+            }
+            Entry::Vacant(entry) => {
+                if expected_version != 0 {
+                    return Ok(Err(VersionConflict { expected: expected_version, actual: 0 }));
+                }
+                self.wal.store_put_event(entry.key().clone(), val.clone())?;
+                let version = self.bump_version(entry.key());
+                entry.insert(stored(val));
+                version
+            }
+        };
+        self.track(tracked);
+        Ok(Ok(version))
+    }
+
+    /// Applies `func` to the current value under the entry lock, writing to the WAL only what it decides to change.
+    /// `func` runs under the entry lock and must not call back into the store: a call taking the lock of the same
+    /// shard would wait for it forever, debug builds panic instead.
+    pub fn compute(&self, key: Vec<u8>, func: impl FnOnce(Option<&[u8]>) -> ComputeResult) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        let tracked = match self.live_entry(key)? {
+            Entry::Occupied(mut entry) => {
+                match reentrancy::run_compute(|| func(Some(&entry.get()[..]))) {
+                    ComputeResult::Keep => None,
+                    ComputeResult::Set(new_val) => {
+                        self.limits.check(entry.key(), &new_val)?;
+                        let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + new_val.len()));
+                        self.wal.store_put_event(entry.key().clone(), new_val.clone())?;
+                        *entry.get_mut() = stored(new_val);
+                        self.bump_version(entry.key());
+                        tracked
+                    }
+                    ComputeResult::Delete => {
+                        self.wal.store_delete_event(entry.key())?;
+                        self.versions.remove(entry.key());
+                        self.expirations.remove(entry.key());
+                        let (key, _) = entry.remove_entry();
+                        if let Some(lru) = &self.lru {
+                            lru.forget(&key);
+                        }
+                        None
+                    }
+                }
+            }
+            Entry::Vacant(entry) => match reentrancy::run_compute(|| func(None)) {
+                ComputeResult::Set(new_val) => {
+                    self.limits.check(entry.key(), &new_val)?;
+                    let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + new_val.len()));
+                    self.wal.store_put_event(entry.key().clone(), new_val.clone())?;
+                    self.bump_version(entry.key());
+                    entry.insert(stored(new_val));
+                    tracked
+                }
+                ComputeResult::Keep | ComputeResult::Delete => None,
+            },
+        };
+        self.track(tracked);
+        Ok(())
+    }
+
+    /// Applies the registered merge operator to the current value and `operand`, only the operand is written to the WAL.
+    /// Fails with `ErrorKind::Unsupported` if the store was created without a merge operator.
+    pub fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        self.limits.check(&key, &operand)?;
+        let merge_operator = match &self.merge_operator {
+            Some(merge_operator) => merge_operator,
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported, "no merge operator registered")),
+        };
+
+        let tracked = match self.live_entry(key)? {
+            Entry::Occupied(mut entry) => {
+                let new_val = merge_operator(Some(&entry.get()[..]), &operand);
+                let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + new_val.len()));
+                self.wal.store_merge_event(entry.key().clone(), operand)?;
+                *entry.get_mut() = stored(new_val);
+                self.bump_version(entry.key());
+                tracked
+            }
+            Entry::Vacant(entry) => {
+                let new_val = merge_operator(None, &operand);
+                let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + new_val.len()));
+                self.wal.store_merge_event(entry.key().clone(), operand)?;
+                self.bump_version(entry.key());
+                entry.insert(stored(new_val));
+                tracked
+            }
+        };
+        self.track(tracked);
+        Ok(())
+    }
+
+    /// Fails with `ErrorKind::InvalidData` if the current value is not an 8 bytes number.
+    pub fn increment_or_init(&self, key: Vec<u8>, increment_by: u64) -> io::Result<u64> {
+        reentrancy::check_not_in_compute();
+        let tracked = self.lru.as_ref().map(|_| (key.clone(), key.len() + 8));
+        let new_num = match self.live_entry(key)? {
             Entry::Occupied(mut entry) => {
-                let entry_bytes = entry.get().as_slice();
+                let entry_bytes = &entry.get()[..];
                 let bytes_arr: [u8; 8] = match <&[u8] as std::convert::TryInto<[u8; 8]>>::try_into(entry_bytes) {
                     Ok(arr) => arr,
                     Err(_) => {
-                        return Err(());
+                        return Err(not_a_number());
                     }
                 };
                 let cur_num = u64::from_ne_bytes(bytes_arr);
-                let new_num = cur_num + increment_by;
-                let new_num_bytes = u64::to_ne_bytes(new_num).to_vec();
-                self.wal.store_put_event(entry.key().clone(), new_num_bytes.clone());
-                *entry.get_mut() = new_num_bytes;
-                Ok(new_num)
+                let new_num = cur_num.checked_add(increment_by).ok_or(PigmentError::Overflow)?;
+                let new_num_bytes = u64::to_ne_bytes(new_num);
+                let written = self.store_increment(entry.key(), &new_num_bytes)?;
+                *entry.get_mut() = stored_slice(&new_num_bytes);
+                if written {
+                    self.bump_version(entry.key());
+                } else {
+                    // the write carrying this increment bumps the version, it drops the expiry already
+                    self.expirations.remove(entry.key());
+                }
+                new_num
             }
             Entry::Vacant(entry) => {
                 let new_num = increment_by;
-                let new_num_bytes = u64::to_ne_bytes(new_num).to_vec();
-                self.wal.store_put_event(entry.key().clone(), new_num_bytes.clone());
-                entry.insert(new_num_bytes);
-                Ok(new_num)
+                let new_num_bytes = u64::to_ne_bytes(new_num);
+                self.wal.store_put_event(entry.key().clone(), new_num_bytes.to_vec())?;
+                self.bump_version(entry.key());
+                entry.insert(stored_slice(&new_num_bytes));
+                new_num
             }
-        }
+        };
+        self.track(tracked);
+        Ok(new_num)
     }
 
-    pub fn decrement(&self, key: Vec<u8>, decrement_by: u64) -> Option<Result<u64, ()>> {
-        match self.store.entry(key) {
+    /// Returns the counter of `key` and sets it to 0, writing the put of 0 to the WAL; 0 without writing anything if
+    /// `key` is absent. Both happen under the entry lock, so every increment is counted by either the returned value
+    /// or the next read, e.g. when periodically draining metrics. An increment pending from coalescing is superseded
+    /// by the written 0. Fails with `ErrorKind::InvalidData` if the current value is not an 8 bytes number.
+    pub fn read_and_reset(&self, key: &[u8]) -> io::Result<u64> {
+        reentrancy::check_not_in_compute();
+        let number = match self.live_entry(key.to_vec())? {
+            Entry::Occupied(mut entry) => {
+                let bytes_arr: [u8; 8] = entry.get()[..].try_into().map_err(|_| not_a_number())?;
+                let zero_bytes = u64::to_ne_bytes(0);
+                self.wal.store_put_event(entry.key().clone(), zero_bytes.to_vec())?;
+                self.pending_increments.remove(key);
+                *entry.get_mut() = stored_slice(&zero_bytes);
+                self.bump_version(entry.key());
+                u64::from_ne_bytes(bytes_arr)
+            }
+            Entry::Vacant(_) => return Ok(0),
+        };
+        self.track(self.lru.as_ref().map(|_| (key.to_vec(), key.len() + 8)));
+        Ok(number)
+    }
+
+    pub fn decrement(&self, key: Vec<u8>, decrement_by: u64) -> Option<io::Result<u64>> {
+        reentrancy::check_not_in_compute();
+        let tracked = self.lru.as_ref().map(|_| (key.clone(), key.len() + 8));
+        let entry = match self.live_entry(key) {
+            Ok(entry) => entry,
+            Err(error) => return Some(Err(error)),
+        };
+        let new_num = match entry {
             Entry::Occupied(mut entry) => {
-                let entry_bytes = entry.get().as_slice();
+                let entry_bytes = &entry.get()[..];
                 let bytes_arr: [u8; 8] = match <&[u8] as std::convert::TryInto<[u8; 8]>>::try_into(entry_bytes) {
                     Ok(arr) => arr,
                     Err(_) => {
-                        return Some(Err(()));
+                        return Some(Err(not_a_number()));
                     }
                 };
                 let cur_num = u64::from_ne_bytes(bytes_arr);
-                let new_num = if decrement_by >= cur_num {
-                    0
-                } else {
-                    cur_num - decrement_by
-                };
-                let new_num_bytes = u64::to_ne_bytes(new_num).to_vec();
-                self.wal.store_put_event(entry.key().clone(), new_num_bytes.clone());
-                *entry.get_mut() = new_num_bytes;
-                Some(Ok(new_num))
+                let new_num = cur_num.saturating_sub(decrement_by);
+                let new_num_bytes = u64::to_ne_bytes(new_num);
+                if let Err(error) = self.wal.store_put_event(entry.key().clone(), new_num_bytes.to_vec()) {
+                    return Some(Err(error));
+                }
+                *entry.get_mut() = stored_slice(&new_num_bytes);
+                self.bump_version(entry.key());
+                new_num
             }
             Entry::Vacant(_) => {
-                None
+                return None;
+            }
+        };
+        self.track(tracked);
+        Some(Ok(new_num))
+    }
+
+    /// Adds `delta` to the f64 stored under `key` (0.0 if absent) and returns the sum. Fails with
+    /// `ErrorKind::InvalidInput` if `delta` or the sum is NaN, and `ErrorKind::InvalidData` if the current value
+    /// isn't an f64 written by this method; nothing is written in either case.
+    pub fn add_f64(&self, key: Vec<u8>, delta: f64) -> io::Result<f64> {
+        reentrancy::check_not_in_compute();
+        if delta.is_nan() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "NaN can't be added"));
+        }
+        let (sum, tracked) = match self.live_entry(key)? {
+            Entry::Occupied(mut entry) => {
+                let sum = decode_f64(&entry.get()[..])? + delta;
+                if sum.is_nan() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "sum is NaN"));
+                }
+                let sum_bytes = encode_f64(sum);
+                let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + sum_bytes.len()));
+                self.wal.store_put_event(entry.key().clone(), sum_bytes.clone())?;
+                *entry.get_mut() = stored(sum_bytes);
+                self.bump_version(entry.key());
+                (sum, tracked)
             }
+            Entry::Vacant(entry) => {
+                let sum_bytes = encode_f64(delta);
+                let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + sum_bytes.len()));
+                self.wal.store_put_event(entry.key().clone(), sum_bytes.clone())?;
+                self.bump_version(entry.key());
+                entry.insert(stored(sum_bytes));
+                (delta, tracked)
+            }
+        };
+        self.track(tracked);
+        Ok(sum)
+    }
+
+    /// Fails with `ErrorKind::InvalidData` if the value wasn't written by [`DurableKeyValueStore::add_f64`].
+    pub fn read_f64(&self, key: &[u8]) -> Option<io::Result<f64>> {
+        if self.expired(key) {
+            return None;
         }
+        self.store.get(key).map(|entry_bytes| decode_f64(&entry_bytes.value()[..]))
     }
 
-    pub fn read_number(&self, key: &[u8]) -> Option<Result<u64, ()>> {
+    /// Fails with [`PigmentError::NotANumber`] if the value isn't 8 bytes long.
+    pub fn read_number(&self, key: &[u8]) -> Option<Result<u64, PigmentError>> {
+        if self.expired(key) {
+            return None;
+        }
         self.store.get(key).map(|entry_bytes| {
-            let byters_arr: [u8; 8] = match <&[u8] as std::convert::TryInto<[u8; 8]>>::try_into(entry_bytes.value().as_slice()) {
+            let byters_arr: [u8; 8] = match <&[u8] as std::convert::TryInto<[u8; 8]>>::try_into(&entry_bytes.value()[..]) {
                 Ok(arr) => arr,
                 Err(_) => {
-                    return Err(());
+                    return Err(PigmentError::NotANumber);
                 }
             };
             Ok(u64::from_ne_bytes(byters_arr))
         })
     }
     
-    pub fn set_number(&self, key: Vec<u8>, number: u64) {
+    pub fn set_number(&self, key: Vec<u8>, number: u64) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
         let value = u64::to_ne_bytes(number).to_vec();
-
-        self.wal.store_put_event(key.clone(), value.clone());
-
-        self.store.insert(key, value);
+        self.limits.check(&key, &value)?;
+        self.write_and_insert(key, |key| self.wal.store_put_event(key, value).map(|(_, value)| stored(value)))
+    }
+
+    /// Runs `func` while holding the entry lock of `key`, so a read-modify-write over the entry can't race
+    /// with other writers of the same key. Changes made through the [`KeyEntry`] are written to the WAL
+    /// and applied to the store after `func` returns, still under the same entry lock. Like
+    /// [`DurableKeyValueStore::compute`], `func` must not call back into the store.
+    pub fn with_entry<R>(&self, key: Vec<u8>, func: impl FnOnce(&mut KeyEntry) -> R) -> io::Result<R> {
+        reentrancy::check_not_in_compute();
+        let (result, tracked) = match self.live_entry(key)? {
+            Entry::Occupied(mut entry) => {
+                let mut key_entry = KeyEntry::new(Some(&entry.get()[..]));
+                let result = reentrancy::run_compute(|| func(&mut key_entry));
+                let tracked = match key_entry.update {
+                    Some(Some(new_val)) => {
+                        self.limits.check(entry.key(), &new_val)?;
+                        let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + new_val.len()));
+                        self.wal.store_put_event(entry.key().clone(), new_val.clone())?;
+                        *entry.get_mut() = stored(new_val);
+                        self.bump_version(entry.key());
+                        tracked
+                    }
+                    Some(None) => {
+                        self.wal.store_delete_event(entry.key())?;
+                        self.versions.remove(entry.key());
+                        self.expirations.remove(entry.key());
+                        let (key, _) = entry.remove_entry();
+                        if let Some(lru) = &self.lru {
+                            lru.forget(&key);
+                        }
+                        None
+                    }
+                    None => None,
+                };
+                (result, tracked)
+            }
+            Entry::Vacant(entry) => {
+                let mut key_entry = KeyEntry::new(None);
+                let result = reentrancy::run_compute(|| func(&mut key_entry));
+                let mut tracked = None;
+                if let Some(Some(new_val)) = key_entry.update {
+                    self.limits.check(entry.key(), &new_val)?;
+                    tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + new_val.len()));
+                    self.wal.store_put_event(entry.key().clone(), new_val.clone())?;
+                    self.bump_version(entry.key());
+                    entry.insert(stored(new_val));
+                }
+                (result, tracked)
+            }
+        };
+        self.track(tracked);
+        Ok(result)
+    }
+
+    /// Swaps values of two existing keys, returns `false` without writing anything if either is missing or expired.
+    /// Both shard locks are held (lower shard index first) while a single WAL block with both puts is written
+    /// and the values are exchanged, so neither readers nor a recovery ever see only one side of the swap.
+    pub fn swap(&self, key_a: &[u8], key_b: &[u8]) -> io::Result<bool> {
+        reentrancy::check_not_in_compute();
+        if key_a == key_b {
+            return Ok(self.contains(key_a));
+        }
+        let shard_a = self.store.determine_map(key_a);
+        let shard_b = self.store.determine_map(key_b);
+        let (low, high) = (shard_a.min(shard_b), shard_a.max(shard_b));
+
+        let shards = self.store.shards();
+        let mut low_guard = shards[low].write();
+        let mut high_guard = if high != low { Some(shards[high].write()) } else { None };
+
+        let value_of = |key: &[u8], shard: usize| {
+            let map = if shard == low { &*low_guard } else { high_guard.as_deref().unwrap() };
+            map.get(key).filter(|_| !self.expired(key)).map(|value| value.get().to_vec())
+        };
+        let (value_a, value_b) = match (value_of(key_a, shard_a), value_of(key_b, shard_b)) {
+            (Some(value_a), Some(value_b)) => (value_a, value_b),
+            _ => return Ok(false),
+        };
+
+        let swapped = self.wal.store_put_many_event(vec![(key_a.to_vec(), value_b), (key_b.to_vec(), value_a)])?;
+
+        let mut tracked = Vec::new();
+        for ((key, value), shard) in swapped.into_iter().zip([shard_a, shard_b]) {
+            if self.lru.is_some() {
+                tracked.push((key.clone(), key.len() + value.len()));
+            }
+            let map = if shard == low { &mut *low_guard } else { high_guard.as_deref_mut().unwrap() };
+            *map.get_mut(key.as_slice()).unwrap().get_mut() = stored(value);
+            self.bump_version(&key);
+        }
+        drop((low_guard, high_guard));
+        for tracked in tracked {
+            self.track(Some(tracked));
+        }
+        Ok(true)
     }
 
     #[allow(unused)]
+    /// Takes the advisory lock of `key` for a workflow spanning several calls, see [`KeyLocks`]. Writes which don't
+    /// take the lock themselves aren't blocked by it.
+    pub fn lock_key(&self, key: &[u8]) -> KeyLockGuard<'_> {
+        self.key_locks.lock(key)
+    }
+
     pub fn contains(&self, key: &[u8]) -> bool {
-        self.store.contains_key(key)
+        !self.expired(key) && self.store.contains_key(key)
+    }
+
+    /// Live entries whose key starts with `prefix`, in key order. Goes through every entry of the store, each shard
+    /// being read as it is when reached.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = self.store.iter()
+            .filter(|entry| entry.key().starts_with(prefix) && !self.expired(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().to_vec()))
+            .collect();
+        entries.sort_unstable_by(|(key, _), (other, _)| key.cmp(other));
+        entries
+    }
+
+    /// View of the keys starting with `prefix`, read and written without it, see [`Namespace`].
+    pub fn namespace(&self, prefix: Vec<u8>) -> Namespace<'_, W> {
+        Namespace::new(self, prefix)
+    }
+
+    /// Some live key picked at random without going through the whole store, e.g. to sample keys for load tests or
+    /// gossip. Keys of shards holding fewer keys are more likely to be picked, so the distribution isn't uniform.
+    pub fn random_key(&self) -> Option<Vec<u8>> {
+        model::random_key(&self.store, |key| !self.expired(key))
+    }
+
+    /// [`DurableKeyValueStore::contains`] of each of `keys`, in input order, locking each involved shard once.
+    pub fn contains_keys(&self, keys: &[&[u8]]) -> Vec<bool> {
+        model::contains_keys(&self.store, keys, |key| !self.expired(key))
+    }
+
+    pub fn remove(&self, key: &[u8]) -> io::Result<()> {
+        reentrancy::check_not_in_compute();
+        timed(self.wal.metrics(), Operation::Remove, || -> io::Result<()> {
+            let entry = self.store.entry(key.to_vec());
+            self.wal.store_delete_event(key)?;
+
+            if let Entry::Occupied(entry) = entry {
+                self.versions.remove(key);
+                self.expirations.remove(key);
+                entry.remove();
+            }
+            Ok(())
+        })?;
+        if let Some(lru) = &self.lru {
+            lru.forget(key);
+        }
+        Ok(())
+    }
+
+    /// Removes all of `keys` present in the store, returning how many were, checkpoints (see
+    /// [`DurableKeyValueStore::set_checkpoint`]) excepted. Deletes of the present keys are written
+    /// with a single WAL write, and each involved shard is locked once (lower shard index first) for the whole
+    /// removal, so readers see either none or all of the keys of a shard removed.
+    pub fn remove_many(&self, keys: &[&[u8]]) -> io::Result<usize> {
+        reentrancy::check_not_in_compute();
+        let mut shard_indexes: Vec<usize> = keys.iter().map(|key| self.store.determine_map(*key)).collect();
+        shard_indexes.sort_unstable();
+        shard_indexes.dedup();
+
+        let shards = self.store.shards();
+        let mut guards: Vec<_> = shard_indexes.iter().map(|&shard| shards[shard].write()).collect();
+        let guard_index = |key: &[u8]| shard_indexes.binary_search(&self.store.determine_map(key)).unwrap();
+
+        let mut seen = HashSet::with_capacity(keys.len());
+        let present: Vec<Vec<u8>> = keys.iter()
+            .filter(|key| !is_checkpoint(key) && seen.insert(**key) && guards[guard_index(key)].contains_key(**key))
+            .map(|key| key.to_vec())
+            .collect();
+        if present.is_empty() {
+            return Ok(0);
+        }
+        self.wal.store_delete_many_event(&present)?;
+
+        for key in &present {
+            guards[guard_index(key)].remove(key.as_slice());
+            self.versions.remove(key);
+            self.expirations.remove(key);
+            if let Some(lru) = &self.lru {
+                lru.forget(key);
+            }
+        }
+        Ok(present.len())
+    }
+
+    /// Removes and yields every entry but the checkpoints, writing a delete to the WAL for each before it is yielded.
+    /// Keys are snapshotted when `drain` is called: keys put afterwards are not drained, while snapshotted keys
+    /// overwritten meanwhile are yielded with their latest value and ones removed meanwhile are skipped.
+    /// Each entry is taken under its entry lock, so a concurrent writer either lands before the delete and is
+    /// drained or after it and stays in the store. A failed WAL write ends the iteration and keeps the entry.
+    pub fn drain(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        reentrancy::check_not_in_compute();
+        let keys: Vec<Vec<u8>> = self.store.iter()
+            .filter(|entry| !is_checkpoint(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        keys.into_iter()
+            .map_while(move |key| match self.take(key) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    error!("failed to write delete while draining, stopping: {}", e);
+                    None
+                }
+            })
+            .flatten()
+    }
+
+    /// Offset in the WAL right after the last write, see [`WalStorage::current_offset`]. A rewrite of the WAL, e.g.
+    /// by a restart through [`DurableKeyValueStore::init_new`] or a compaction, moves blocks, so offsets are only
+    /// comparable within the same WAL; [`DurableKeyValueStore::open_in_place`] keeps them.
+    pub fn current_offset(&self) -> u64 {
+        self.wal.current_offset()
+    }
+
+    /// Durably records `offset` as the progress of the external consumer `name`, e.g. the [`current_offset`] up to
+    /// which it processed the WAL, overwriting its previous checkpoint. The checkpoint is an entry under a reserved
+    /// key prefix (`\0pigment.checkpoint/`), so it's counted by `size` and seen by scans, but it's never evicted by
+    /// an LRU nor removed by `drain`, `retain` or `remove_many`.
+    ///
+    /// [`current_offset`]: DurableKeyValueStore::current_offset
+    pub fn set_checkpoint(&self, name: &str, offset: u64) -> io::Result<()> {
+        self.namespace(CHECKPOINT_KEY_PREFIX.to_vec()).put(name.as_bytes().to_vec(), offset.to_ne_bytes().to_vec())
+    }
+
+    /// Offset last recorded by [`DurableKeyValueStore::set_checkpoint`] for `name`.
+    pub fn get_checkpoint(&self, name: &str) -> Option<u64> {
+        let offset = self.namespace(CHECKPOINT_KEY_PREFIX.to_vec()).get(name.as_bytes())?;
+        offset.try_into().ok().map(u64::from_ne_bytes)
     }
 
-    pub fn remove(&self, key: &[u8]) {
-        self.wal.store_delete_event(&key);
+    /// Removes every entry for which `f(key, value)` returns `false`, like `HashMap::retain`, and returns how many
+    /// were removed. Shards are gone through one at a time under their write lock: deletes of a shard's removed keys
+    /// are written with a single WAL write before they are removed, so a failed write leaves that shard and the
+    /// following ones unchanged. Expired entries not purged yet are passed to `f` as well, checkpoints (see
+    /// [`DurableKeyValueStore::set_checkpoint`]) are kept without. Like a `compute` closure, `f` must not call back
+    /// into the store.
+    pub fn retain(&self, f: impl Fn(&[u8], &[u8]) -> bool) -> io::Result<usize> {
+        reentrancy::check_not_in_compute();
+        let mut removed = 0;
+        for shard in self.store.shards() {
+            let mut guard = shard.write();
+            let dropped: Vec<Vec<u8>> = guard.iter()
+                .filter(|(key, value)| !is_checkpoint(key) && !reentrancy::run_compute(|| f(key, value.get())))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if dropped.is_empty() {
+                continue;
+            }
+            self.wal.store_delete_many_event(&dropped)?;
 
-        self.store.remove(key);
+            for key in &dropped {
+                guard.remove(key.as_slice());
+                self.versions.remove(key);
+                self.expirations.remove(key);
+                if let Some(lru) = &self.lru {
+                    lru.forget(key);
+                }
+            }
+            removed += dropped.len();
+        }
+        Ok(removed)
     }
 
     pub fn size(&self) -> usize {
         self.store.len()
     }
+
+    /// Writes the live entries sorted by key for inspection, keys and values as hex with their lengths, e.g.
+    /// `6b6579 (3 bytes) => 0001ff (3 bytes)`, after a line with their count. Expired entries are left out.
+    pub fn debug_dump(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = self.store.iter()
+            .filter(|entry| !self.expired(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().to_vec()))
+            .collect();
+        entries.sort();
+
+        writeln!(writer, "{} entries", entries.len())?;
+        for (key, value) in entries {
+            writeln!(writer, "{} ({} bytes) => {} ({} bytes)",
+                     faster_hex::hex_string(&key), key.len(), faster_hex::hex_string(&value), value.len())?;
+        }
+        writer.flush()
+    }
+
+    fn take(&self, key: Vec<u8>) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.store.entry(key) {
+            Entry::Occupied(entry) => {
+                self.wal.store_delete_event(entry.key())?;
+                self.versions.remove(entry.key());
+                self.expirations.remove(entry.key());
+                let (key, value) = entry.remove_entry();
+                if let Some(lru) = &self.lru {
+                    lru.forget(&key);
+                }
+                Ok(Some((key, into_vec(value))))
+            }
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+
+    /// Writes the value of `key` to the WAL with `write`, given a copy of the key, then inserts the value it returns.
+    /// Both happen under the entry lock, so the value and its version change together and concurrent writes of `key`
+    /// reach the map in their WAL order.
+    fn write_and_insert<E: From<io::Error>>(&self, key: Vec<u8>, write: impl FnOnce(Vec<u8>) -> Result<StoredValue, E>) -> Result<(), E> {
+        let tracked = timed(self.wal.metrics(), Operation::Put, || -> Result<_, E> {
+            match self.live_entry(key)? {
+                Entry::Occupied(mut entry) => {
+                    let val = write(entry.key().clone())?;
+                    let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + val.len()));
+                    *entry.get_mut() = val;
+                    self.bump_version(entry.key());
+                    Ok(tracked)
+                }
+                Entry::Vacant(entry) => {
+                    let val = write(entry.key().clone())?;
+                    let tracked = self.lru.as_ref().map(|_| (entry.key().clone(), entry.key().len() + val.len()));
+                    self.bump_version(entry.key());
+                    entry.insert(val);
+                    Ok(tracked)
+                }
+            }
+        })?;
+        self.track(tracked);
+        Ok(())
+    }
+
+    /// Entry of `key` under its lock. An expired entry is purged first, with a delete written to the WAL like
+    /// [`DurableKeyValueStore::purge_expired`] does, so it reads as vacant and its version starts over after a replay
+    /// as well.
+    fn live_entry(&self, key: Vec<u8>) -> io::Result<Entry<'_, Vec<u8>, StoredValue, ShardHasher>> {
+        let mut entry = self.store.entry(key);
+        loop {
+            match entry {
+                Entry::Occupied(occupied) if self.expired(occupied.key()) => {
+                    self.wal.store_delete_event(occupied.key())?;
+                    self.versions.remove(occupied.key());
+                    self.expirations.remove(occupied.key());
+                    let (key, _) = occupied.remove_entry();
+                    if let Some(lru) = &self.lru {
+                        lru.forget(&key);
+                    }
+                    entry = self.store.entry(key);
+                }
+                entry => return Ok(entry),
+            }
+        }
+    }
+
+    /// Records a written entry with the LRU, if any, once its entry lock is released: evicting takes other entry
+    /// locks, which must not be taken while holding one. Checkpoints aren't recorded, so they're never evicted.
+    fn track(&self, tracked: Option<(Vec<u8>, usize)>) {
+        if let (Some(lru), Some((key, bytes))) = (&self.lru, tracked.filter(|(key, _)| !is_checkpoint(key))) {
+            let evicted = lru.record(&key, bytes);
+            self.evict(evicted);
+        }
+    }
+
+    /// Removes keys evicted by the LRU. The put which caused the eviction already succeeded, so a failed delete is
+    /// only logged and leaves the key in the store, untracked.
+    fn evict(&self, keys: Vec<Vec<u8>>) {
+        for key in keys {
+            if let Err(e) = self.take(key) {
+                error!("failed to write delete of evicted key: {}", e);
+            }
+        }
+    }
+
+    fn expired(&self, key: &[u8]) -> bool {
+        self.expirations.get(key).is_some_and(|expires_at| *expires_at <= self.clock.now())
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        self.versions.get(key).map_or(0, |version| *version)
+    }
+
+    /// Must be called under the entry lock of `key` in `store`, which is always taken before `versions`, once the
+    /// new value of `key` is written to the WAL. Drops the expiry of `key`, a put with a TTL sets it again
+    /// afterwards, and the increments pending from coalescing, which the written value carries.
+    fn bump_version(&self, key: &[u8]) -> u64 {
+        self.expirations.remove(key);
+        if self.coalescing.is_some() {
+            self.pending_increments.remove(key);
+        }
+        if let Some(mut version) = self.versions.get_mut(key) {
+            *version += 1;
+            return *version;
+        }
+        self.versions.insert(key.to_vec(), 1);
+        1
+    }
+}
+
+fn not_a_number() -> io::Error {
+    PigmentError::NotANumber.into()
+}
+
+fn encode_f64(number: f64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(9);
+    bytes.push(F64_VALUE_TAG);
+    bytes.extend_from_slice(&number.to_le_bytes());
+    bytes
+}
+
+fn decode_f64(bytes: &[u8]) -> io::Result<f64> {
+    match bytes.split_first() {
+        Some((&F64_VALUE_TAG, number_bytes)) if number_bytes.len() == 8 => {
+            Ok(f64::from_le_bytes(number_bytes.try_into().unwrap()))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "stored value is not a tagged f64")),
+    }
+}
+
+impl<W: SyncWal> DurableKeyValueStore<W> {
+    /// Flushes and syncs the WAL and releases it, consuming the store. Once it returns, the WAL files can be
+    /// copied as a consistent snapshot of the store. Increments pending from coalescing are written first.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.flush_increments()?;
+        self.wal.close()
+    }
+}
+
+impl<W: ReadableWal> DurableKeyValueStore<W> {
+    /// Diagnostics over the WAL backing this store, unlike [`DurableKeyValueStore::size`] it counts blocks, not entries.
+    pub fn wal_stats(&self) -> io::Result<WalStats> {
+        self.wal.wal_stats()
+    }
+
+    /// Share of the WAL bytes which don't contribute to the current state, what a compaction would reclaim.
+    pub fn compaction_ratio(&self) -> io::Result<f64> {
+        Ok(1.0 - self.wal_stats()?.live_ratio())
+    }
+}
+
+impl<W: CompactableWal> DurableKeyValueStore<W> {
+    /// Compacts the WAL (see [`WalStorage::compact_key_value`]) only if its [`DurableKeyValueStore::compaction_ratio`]
+    /// is over `ratio_threshold`, so a mostly live WAL isn't rewritten for little gain. Returns whether it did.
+    pub fn compact_if_needed(&self, ratio_threshold: f64) -> io::Result<bool> {
+        if self.compaction_ratio()? <= ratio_threshold {
+            return Ok(false);
+        }
+        self.flush_increments()?;
+        self.wal.compact_key_value(self.merge_operator.as_deref())?;
+        Ok(true)
+    }
+}
+
+/// View of a single locked entry handed to [`DurableKeyValueStore::with_entry`].
+pub struct KeyEntry<'a> {
+    current: Option<&'a [u8]>,
+    update: Option<Option<Vec<u8>>>,
+}
+
+impl<'a> KeyEntry<'a> {
+    fn new(current: Option<&'a [u8]>) -> Self {
+        KeyEntry { current, update: None }
+    }
+
+    /// Current value of the entry, including a not yet applied `put`/`remove`.
+    pub fn get(&self) -> Option<&[u8]> {
+        match &self.update {
+            Some(update) => update.as_deref(),
+            None => self.current,
+        }
+    }
+
+    pub fn put(&mut self, val: Vec<u8>) {
+        self.update = Some(Some(val));
+    }
+
+    pub fn remove(&mut self) {
+        self.update = Some(None);
+    }
 }
 
 mod tests {
@@ -198,8 +1635,8 @@ mod tests {
 
         let store = DurableKeyValueStore::new_vec_based();
 
-        store.put(b"key_1".to_vec(), b"value_1".to_vec());
-        store.put(b"key_2".to_vec(), b"value_2".to_vec());
+        store.put(b"key_1".to_vec(), b"value_1".to_vec()).unwrap();
+        store.put(b"key_2".to_vec(), b"value_2".to_vec()).unwrap();
 
         let res_1 = store.get(b"key_1");
         assert_eq!(res_1.unwrap(), b"value_1");
@@ -210,7 +1647,7 @@ mod tests {
         let res_none = store.get(b"missing_key");
         assert_eq!(res_none, None);
 
-        store.remove(b"key_1");
+        store.remove(b"key_1").unwrap();
         let res_none = store.get(b"key_1");
         assert_eq!(res_none, None);
 
@@ -224,7 +1661,7 @@ mod tests {
         let store = DurableKeyValueStore::new_vec_based();
         assert_eq!(store.get("a".to_string().as_bytes()), None);
 
-        store.compute("a".to_string().into_bytes(), |_| bincode::serialize::<usize>(&0).expect("0 should be serialized") );
+        store.compute("a".to_string().into_bytes(), |_| ComputeResult::Set(bincode::serialize::<usize>(&0).expect("0 should be serialized")) ).unwrap();
 
         let found = store.get("a".to_string().as_bytes()).unwrap();
         let cur_num: usize = bincode::deserialize(found.as_slice()).unwrap();
@@ -233,44 +1670,2110 @@ mod tests {
         store.compute("a".to_string().into_bytes(), |value| {
             let mut cur_num: usize = bincode::deserialize(value.unwrap()).unwrap();
             cur_num += 1;
-            bincode::serialize::<usize>(&cur_num).unwrap()
-        } );
+            ComputeResult::Set(bincode::serialize::<usize>(&cur_num).unwrap())
+        } ).unwrap();
         let found = store.get("a".to_string().as_bytes()).unwrap();
         let cur_num: usize = bincode::deserialize(found.as_slice()).unwrap();
         assert_eq!(cur_num, 1);
     }
 
+    #[cfg(debug_assertions)]
     #[test]
-    fn test_speed_vec() {
+    fn test_reentrant_compute_panics() {
         use super::*;
-        use std::time::Instant;
+        use std::panic::{self, AssertUnwindSafe};
 
-        let start = Instant::now();
         let store = DurableKeyValueStore::new_vec_based();
+        store.put(b"a".to_vec(), b"1".to_vec()).unwrap();
 
-        for i in 0..10_0000 {
-            let bytes = format!("{}", i).into_bytes();
-            store.put(bytes.clone(), bytes);
+        let reentrant_calls: Vec<Box<dyn Fn()>> = vec![
+            Box::new(|| { store.get(b"a"); }),
+            Box::new(|| { store.put(b"b".to_vec(), b"2".to_vec()).unwrap(); }),
+            Box::new(|| { store.compute(b"a".to_vec(), |_| ComputeResult::Keep).unwrap(); }),
+            Box::new(|| { store.put_if_absent(b"b".to_vec(), b"2".to_vec()).unwrap(); }),
+            Box::new(|| { store.increment_or_init(b"b".to_vec(), 1).unwrap(); }),
+            Box::new(|| { store.swap(b"a", b"b").unwrap(); }),
+            Box::new(|| { store.remove_many(&[b"b"]).unwrap(); }),
+        ];
+        for reentrant_call in reentrant_calls {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                store.compute(b"a".to_vec(), |_| {
+                    reentrant_call();
+                    ComputeResult::Delete
+                })
+            }));
+            let message = result.unwrap_err().downcast::<String>().unwrap();
+            assert_eq!(*message, crate::reentrancy::REENTRANT_CALL_MESSAGE);
         }
 
-        let duration = start.elapsed();
-        print!("completed in {}", duration.as_secs_f32());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            store.with_entry(b"a".to_vec(), |_| store.get(b"a"))
+        }));
+        assert!(result.is_err());
+
+        // the store is left unchanged and usable once the closure unwound
+        assert_eq!(store.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b"), None);
+        store.compute(b"a".to_vec(), |_| ComputeResult::Set(b"2".to_vec())).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"2".to_vec()));
     }
 
     #[test]
-    fn test_increment() {
+    fn test_compute_result() {
         use super::*;
+        use crate::wal::model::{DELETE_ACT, PUT_ACT};
 
         let store = DurableKeyValueStore::new_vec_based();
-        let start = std::time::Instant::now();
+        let blocks = || store.wal_stats().unwrap().blocks;
 
-        for _ in 0..100_000 {
-            store.increment_or_init(b"key".to_vec(), 1).unwrap();
+        store.compute(b"a".to_vec(), |_| ComputeResult::Keep).unwrap();
+        store.compute(b"a".to_vec(), |_| ComputeResult::Delete).unwrap();
+        assert_eq!(blocks(), 0);
+        assert!(!store.contains(b"a"));
+
+        store.compute(b"a".to_vec(), |value| {
+            assert_eq!(value, None);
+            ComputeResult::Set(b"A".to_vec())
+        }).unwrap();
+        assert_eq!(blocks(), 1);
+        assert_eq!(store.get_versioned(b"a"), Some((b"A".to_vec(), 1)));
+
+        store.compute(b"a".to_vec(), |value| {
+            assert_eq!(value, Some(b"A".as_slice()));
+            ComputeResult::Keep
+        }).unwrap();
+        assert_eq!(blocks(), 1);
+        assert_eq!(store.get_versioned(b"a"), Some((b"A".to_vec(), 1)));
+
+        store.compute(b"a".to_vec(), |_| ComputeResult::Delete).unwrap();
+        assert_eq!(blocks(), 2);
+        assert_eq!(store.get_versioned(b"a"), None);
+
+        let stats = store.wal_stats().unwrap();
+        assert_eq!((stats.action_count(PUT_ACT), stats.action_count(DELETE_ACT)), (1, 1));
+        assert!(store.wal.read_bytes(crate::wal::read_forward).is_empty());
+    }
+
+    #[test]
+    fn test_with_entry() {
+        use super::*;
+        use std::sync::Arc;
+
+        let store = Arc::new(DurableKeyValueStore::new_vec_based());
+        store.put(b"counter".to_vec(), 0u64.to_ne_bytes().to_vec()).unwrap();
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for _ in 0..250 {
+                    store.with_entry(b"counter".to_vec(), |entry| {
+                        let cur_num = u64::from_ne_bytes(entry.get().unwrap().try_into().unwrap());
+                        entry.put((cur_num + 1).to_ne_bytes().to_vec());
+                    }).unwrap();
+                }
+            })
+        }).collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        assert_eq!(store.read_number(b"counter").unwrap().unwrap(), 1_000);
+
+        let inserted = store.with_entry(b"missing".to_vec(), |entry| {
+            if entry.get().is_none() {
+                entry.put(b"value".to_vec());
+                return true;
+            }
+            false
+        }).unwrap();
+        assert!(inserted);
+        assert_eq!(store.get(b"missing").unwrap(), b"value");
+
+        store.with_entry(b"missing".to_vec(), |entry| entry.remove()).unwrap();
+        assert_eq!(store.get(b"missing"), None);
+        assert_eq!(store.size(), 1);
+    }
+
+    #[test]
+    fn test_failed_wal_write_keeps_store_unchanged() {
+        use super::*;
+
+        struct StorageFullWriter {
+            written: usize,
+            capacity: usize,
         }
 
-        let cur_value = store.read_number(b"key").unwrap().unwrap();
-        let elapsed = start.elapsed().as_millis();
-        println!("val: {}, elapsed millis: {}", cur_value, elapsed);
+        impl Write for StorageFullWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.written + buf.len() > self.capacity {
+                    return Err(io::Error::from(io::ErrorKind::StorageFull));
+                }
+                self.written += buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let writer = StorageFullWriter { written: 0, capacity: 60 };
+        let store = DurableKeyValueStore::with_wal(WalStorage::new_writer_based(writer), None);
+
+        store.put(b"key_1".to_vec(), b"value_1".to_vec()).unwrap();
+
+        let error = store.put(b"key_2".to_vec(), vec![0; 100]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(store.get(b"key_2"), None);
+
+        let error = store.put(b"key_1".to_vec(), vec![0; 100]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(store.get(b"key_1").unwrap(), b"value_1");
+
+        let error = store.increment_or_init(b"counter".to_vec(), 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(store.size(), 1);
+    }
+
+    #[test]
+    fn test_restore_fails_on_wal_write_error() {
+        use super::*;
+
+        /// Takes the header of the WAL only.
+        struct StorageFullWriter {
+            header_written: bool,
+        }
+
+        impl Write for StorageFullWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.header_written {
+                    return Err(io::Error::from(io::ErrorKind::StorageFull));
+                }
+                self.header_written = true;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let previous = DurableKeyValueStore::new_vec_based();
+        previous.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let bytes = previous.wal.read_bytes(|bytes| bytes.to_vec());
+
+        let mut store = DurableKeyValueStore::with_wal(WalStorage::new_writer_based(StorageFullWriter { header_written: false }), None);
+        let error = store.restore(&bytes, None, CorruptionPolicy::Abort).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(store.get(b"key"), None);
+    }
+
+    #[test]
+    fn test_no_append_after_torn_write() {
+        use super::*;
+
+        /// Takes part of a write once it would go over capacity, then fails.
+        struct ShortWriter {
+            written: usize,
+            capacity: usize,
+        }
+
+        impl Write for ShortWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let room = self.capacity - self.written;
+                if room == 0 {
+                    return Err(io::Error::from(io::ErrorKind::StorageFull));
+                }
+                let accepted = buf.len().min(room);
+                self.written += accepted;
+                Ok(accepted)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let writer = ShortWriter { written: 0, capacity: 60 };
+        let store = DurableKeyValueStore::with_wal(WalStorage::new_writer_based(writer), None);
+        store.put(b"key_1".to_vec(), b"value_1".to_vec()).unwrap();
+
+        let error = store.put(b"key_2".to_vec(), vec![0; 100]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(store.get(b"key_2"), None);
+
+        let error = store.try_put(b"key_3".to_vec(), b"v".to_vec(), Duration::from_millis(10)).unwrap_err();
+        assert!(matches!(error, WalError::Poisoned));
+        let error = store.put(b"key_3".to_vec(), b"v".to_vec()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert_eq!(store.get(b"key_3"), None);
+        assert_eq!(store.get(b"key_1"), Some(b"value_1".to_vec()));
+    }
+
+    #[test]
+    fn test_try_put_after_poisoned_write() {
+        use super::*;
+        use std::sync::Arc;
+
+        struct PanickingWriter {
+            writes: usize,
+        }
+
+        impl Write for PanickingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.writes += 1;
+                if self.writes == 3 {
+                    panic!("writer failed mid-write");
+                }
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let writer = PanickingWriter { writes: 0 };
+        let store = Arc::new(DurableKeyValueStore::with_wal(WalStorage::new_writer_based(writer), None));
+        store.put(b"key_1".to_vec(), b"value_1".to_vec()).unwrap();
+
+        let panicking_store = store.clone();
+        let result = std::thread::spawn(move || panicking_store.put(b"key_2".to_vec(), b"value_2".to_vec())).join();
+        assert!(result.is_err());
+
+        let error = store.try_put(b"key_3".to_vec(), b"value_3".to_vec(), Duration::from_millis(10)).unwrap_err();
+        assert!(matches!(error, WalError::Poisoned));
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::Other);
+        assert_eq!(store.get(b"key_3"), None);
+        assert_eq!(store.get(b"key_1"), Some(b"value_1".to_vec()));
+    }
+
+    #[test]
+    fn test_merge() {
+        use super::*;
+
+        let append_operator = |existing: Option<&[u8]>, operand: &[u8]| match existing {
+            Some(existing) => [existing, b",", operand].concat(),
+            None => operand.to_vec(),
+        };
+        let store = DurableKeyValueStore::new_vec_based_with_merge_operator(append_operator);
+
+        store.put(b"a".to_vec(), b"x".to_vec()).unwrap();
+        store.merge(b"a".to_vec(), b"y".to_vec()).unwrap();
+        store.merge(b"a".to_vec(), b"z".to_vec()).unwrap();
+        store.merge(b"b".to_vec(), b"q".to_vec()).unwrap();
+
+        assert_eq!(store.get(b"a").unwrap(), b"x,y,z");
+        assert_eq!(store.get(b"b").unwrap(), b"q");
+
+        let replayed = store.wal.read_bytes(|bytes| crate::wal::read_forward_merging(bytes, &append_operator));
+        assert_eq!(replayed.get(b"a".as_slice()).unwrap(), b"x,y,z");
+        assert_eq!(replayed.get(b"b".as_slice()).unwrap(), b"q");
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(store.wal.read_bytes(|bytes| crate::wal::collect_merging(bytes, &append_operator)), replayed);
+
+        let without_operator = DurableKeyValueStore::new_vec_based();
+        let error = without_operator.merge(b"a".to_vec(), b"y".to_vec()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(without_operator.size(), 0);
+    }
+
+    #[test]
+    fn test_wal_stats() {
+        use super::*;
+        use crate::wal::model::{DELETE_ACT, PUT_ACT, SET_APPEND_ACT};
+
+        let store = DurableKeyValueStore::new_vec_based();
+
+        store.put(b"a".to_vec(), b"A".to_vec()).unwrap();
+        store.put(b"b".to_vec(), b"B".to_vec()).unwrap();
+        store.put(b"a".to_vec(), b"AA".to_vec()).unwrap();
+        store.remove(b"b").unwrap();
+
+        let stats = store.wal_stats().unwrap();
+        assert_eq!(stats.action_count(PUT_ACT), 3);
+        assert_eq!(stats.action_count(DELETE_ACT), 1);
+        assert_eq!(stats.action_count(SET_APPEND_ACT), 0);
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.total_bytes, store.wal.read_bytes(|bytes| bytes.len()));
+
+        let compacted = DurableKeyValueStore::new_vec_based();
+        compacted.put(b"a".to_vec(), b"AA".to_vec()).unwrap();
+        let compacted_stats = compacted.wal_stats().unwrap();
+        assert_eq!(stats.live_bytes, compacted_stats.total_bytes);
+        assert_eq!(compacted_stats.live_ratio(), 1.0);
+        assert!(stats.live_ratio() < 0.5);
+    }
+
+    #[test]
+    fn test_put_if_version() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        let key = b"key".to_vec();
+        assert_eq!(store.put_if_version(key.clone(), b"v1".to_vec(), 1).unwrap(), Err(VersionConflict { expected: 1, actual: 0 }));
+        assert_eq!(store.put_if_version(key.clone(), b"v1".to_vec(), 0).unwrap(), Ok(1));
+
+        let (_, stale_version) = store.get_versioned(&key).unwrap();
+        let (_, fresh_version) = store.get_versioned(&key).unwrap();
+        assert_eq!(store.put_if_version(key.clone(), b"fresh".to_vec(), fresh_version).unwrap(), Ok(2));
+        assert_eq!(store.put_if_version(key.clone(), b"stale".to_vec(), stale_version).unwrap(), Err(VersionConflict { expected: 1, actual: 2 }));
+        assert_eq!(store.get_versioned(&key), Some((b"fresh".to_vec(), 2)));
+
+        store.put(key.clone(), b"put".to_vec()).unwrap();
+        store.compute(key.clone(), |_| ComputeResult::Set(b"computed".to_vec())).unwrap();
+        assert_eq!(store.get_versioned(&key), Some((b"computed".to_vec(), 4)));
+
+        let recovered = store.wal.read_bytes(|bytes| crate::wal::read_forward_versioned(bytes, None));
+        assert_eq!(recovered.get(&key), Some(&(b"computed".to_vec(), 4)));
+
+        let restored = DurableKeyValueStore::new_vec_based();
+        for (k, (v, version)) in recovered {
+            restored.wal.store_versioned_put_event(k, v, version).unwrap();
+        }
+        let restored_versions = restored.wal.read_bytes(|bytes| crate::wal::read_forward_versioned(bytes, None));
+        assert_eq!(restored_versions.get(&key), Some(&(b"computed".to_vec(), 4)));
+        assert_eq!(restored.wal.read_bytes(crate::wal::collect).get(&key), Some(&b"computed".to_vec()));
+
+        store.remove(&key).unwrap();
+        assert_eq!(store.get_versioned(&key), None);
+        assert_eq!(store.put_if_version(key.clone(), b"new".to_vec(), 0).unwrap(), Ok(1));
+    }
+
+    #[test]
+    fn test_put_batch() {
+        use super::*;
+        use std::time::Instant;
+
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..50_000u32)
+            .map(|i| (format!("key_{}", i % 10_000).into_bytes(), i.to_ne_bytes().to_vec()))
+            .collect();
+
+        let sequential = DurableKeyValueStore::new_vec_based();
+        let start = Instant::now();
+        for (key, val) in entries.clone() {
+            sequential.put(key, val).unwrap();
+        }
+        let sequential_millis = start.elapsed().as_millis();
+
+        let batched = DurableKeyValueStore::new_vec_based();
+        let start = Instant::now();
+        for chunk in entries.chunks(1_000) {
+            batched.put_batch(chunk.to_vec()).unwrap();
+        }
+        let batched_millis = start.elapsed().as_millis();
+        println!("sequential put millis: {}, put_batch millis: {}", sequential_millis, batched_millis);
+
+        assert_eq!(batched.size(), 10_000);
+        assert_eq!(batched.get(b"key_0"), Some(40_000u32.to_ne_bytes().to_vec()));
+        assert_eq!(batched.get_versioned(b"key_9999"), Some((49_999u32.to_ne_bytes().to_vec(), 5)));
+        assert_eq!(batched.wal.read_bytes(|bytes| bytes.to_vec()), sequential.wal.read_bytes(|bytes| bytes.to_vec()));
+    }
+
+    #[test]
+    fn test_swap() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.put(b"a".to_vec(), b"A".to_vec()).unwrap();
+        store.put(b"b".to_vec(), b"B".to_vec()).unwrap();
+
+        assert!(!store.swap(b"a", b"missing").unwrap());
+        assert!(store.swap(b"a", b"b").unwrap());
+        assert_eq!(store.get(b"a"), Some(b"B".to_vec()));
+        assert_eq!(store.get(b"b"), Some(b"A".to_vec()));
+        assert!(store.swap(b"a", b"a").unwrap());
+
+        let bytes = store.wal.read_bytes(|bytes| bytes.to_vec());
+        let block_ends: Vec<usize> = crate::wal::iter_actions(&bytes)
+            .map(|action| crate::wal::model::HEADER_LEN as usize + *action.start_offset() as usize + action.block_len())
+            .collect();
+        assert_eq!(block_ends.len(), 3);
+        for end in block_ends {
+            let replayed = crate::wal::read_forward(&bytes[..end]);
+            let values = (replayed.get(b"a".as_slice()).cloned(), replayed.get(b"b".as_slice()).cloned());
+            let original = (Some(b"A".to_vec()), Some(b"B".to_vec()));
+            let swapped = (Some(b"B".to_vec()), Some(b"A".to_vec()));
+            assert!(values.1.is_none() || values == original || values == swapped);
+        }
+        assert_eq!(crate::wal::collect(&bytes), crate::wal::read_forward(&bytes));
+        assert_eq!(crate::wal::read_forward(&bytes).get(b"a".as_slice()), Some(&b"B".to_vec()));
+    }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn test_get_bytes_shares_allocation() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.put(b"a".to_vec(), b"apple".to_vec()).unwrap();
+
+        let first = store.get_bytes(b"a").unwrap();
+        let second = store.get_bytes(b"a").unwrap();
+        assert_eq!(first, b"apple".as_slice());
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        let copied = store.get(b"a").unwrap();
+        assert_ne!(copied.as_ptr(), first.as_ptr());
+
+        let value = bytes::Bytes::from(b"banana".to_vec());
+        store.put_bytes(b"b".to_vec(), value.clone()).unwrap();
+        assert_eq!(store.get_bytes(b"b").unwrap().as_ptr(), value.as_ptr());
+        assert_eq!(store.get_versioned(b"b"), Some((b"banana".to_vec(), 1)));
+        assert_eq!(store.wal.read_bytes(crate::wal::read_forward).get(b"b".as_slice()), Some(&b"banana".to_vec()));
+        assert_eq!(store.get_bytes(b"missing"), None);
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+        use super::*;
+        use std::sync::Arc;
+
+        let store = Arc::new(DurableKeyValueStore::new_vec_based());
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || store.put_if_absent(b"lock".to_vec(), vec![i]).unwrap())
+            })
+            .collect();
+        let inserted: Vec<bool> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+        assert_eq!(inserted.iter().filter(|inserted| **inserted).count(), 1);
+        let winner = inserted.iter().position(|inserted| *inserted).unwrap() as u8;
+        assert_eq!(store.get_versioned(b"lock"), Some((vec![winner], 1)));
+
+        let stats = store.wal_stats().unwrap();
+        assert_eq!(stats.blocks, 1);
+        assert!(!store.put_if_absent(b"lock".to_vec(), vec![99]).unwrap());
+        assert_eq!(store.wal_stats().unwrap().blocks, 1);
+    }
+
+    #[test]
+    fn test_drain() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        for i in 0..100u32 {
+            store.put(format!("key_{}", i).into_bytes(), i.to_ne_bytes().to_vec()).unwrap();
+        }
+        let before = store.wal.read_bytes(crate::wal::read_forward);
+
+        let mut drained = std::collections::HashMap::new();
+        for (key, val) in store.drain() {
+            assert!(!store.contains(&key));
+            drained.insert(key, val);
+        }
+
+        assert_eq!(drained, before);
+        assert_eq!(store.size(), 0);
+        assert_eq!(store.get_versioned(b"key_0"), None);
+        assert!(store.wal.read_bytes(crate::wal::read_forward).is_empty());
+
+        store.put(b"key_0".to_vec(), b"after".to_vec()).unwrap();
+        let replayed = store.wal.read_bytes(crate::wal::read_forward);
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed.get(b"key_0".as_slice()), Some(&b"after".to_vec()));
+    }
+
+    #[test]
+    fn test_speed_vec() {
+        use super::*;
+        use std::time::Instant;
+
+        let start = Instant::now();
+        let store = DurableKeyValueStore::new_vec_based();
+
+        for i in 0..10_0000 {
+            let bytes = format!("{}", i).into_bytes();
+            store.put(bytes.clone(), bytes).unwrap();
+        }
+
+        let duration = start.elapsed();
+        print!("completed in {}", duration.as_secs_f32());
+    }
+
+    #[test]
+    fn test_speed_vec_without_crc() {
+        use super::*;
+        use std::time::Instant;
+
+        let with_crc = DurableKeyValueStore::new_vec_based();
+        let start = Instant::now();
+        for i in 0..10_0000 {
+            let bytes = format!("{}", i).into_bytes();
+            with_crc.put(bytes.clone(), bytes).unwrap();
+        }
+        let with_crc_duration = start.elapsed();
+
+        let without_crc = DurableKeyValueStore::new_vec_based_without_crc();
+        let start = Instant::now();
+        for i in 0..10_0000 {
+            let bytes = format!("{}", i).into_bytes();
+            without_crc.put(bytes.clone(), bytes).unwrap();
+        }
+        let without_crc_duration = start.elapsed();
+
+        println!("with crc: {}, without crc: {}", with_crc_duration.as_secs_f32(), without_crc_duration.as_secs_f32());
+        assert_eq!(without_crc.size(), with_crc.size());
+    }
+
+    #[test]
+    fn test_increment() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        let start = std::time::Instant::now();
+
+        for _ in 0..100_000 {
+            store.increment_or_init(b"key".to_vec(), 1).unwrap();
+        }
+
+        let cur_value = store.read_number(b"key").unwrap().unwrap();
+        let elapsed = start.elapsed().as_millis();
+        println!("val: {}, elapsed millis: {}", cur_value, elapsed);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_increment_time() {
+        use super::*;
+        use std::time::Instant;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        let keys: Vec<Vec<u8>> = (0..10_000u32).map(|i| format!("counter_{}", i).into_bytes()).collect();
+        let start = Instant::now();
+        for _ in 0..100 {
+            for key in &keys {
+                store.increment_or_init(key.clone(), 1).unwrap();
+            }
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(store.read_number(b"counter_0"), Some(Ok(100)));
+        println!("1M increments over 10K counters: {}", elapsed.as_secs_f32());
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        let values: Vec<Vec<u8>> = [0, 1, 8, 15, 16, 17, 64, 4096].iter()
+            .map(|&len| (0..len).map(|i| (i % 251) as u8).collect())
+            .collect();
+        for (i, value) in values.iter().enumerate() {
+            store.put(vec![i as u8], value.clone()).unwrap();
+        }
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(store.get(&[i as u8]).as_ref(), Some(value));
+            assert_eq!(store.value_len(&[i as u8]), Some(value.len()));
+        }
+        // a value shrinking below the inline size and growing past it again
+        store.put(vec![7], vec![1; 3]).unwrap();
+        assert_eq!(store.put_returning(vec![7], vec![2; 100]).unwrap(), Some(vec![1; 3]));
+        assert_eq!(store.put_returning(vec![7], vec![3; 2]).unwrap(), Some(vec![2; 100]));
+        store.increment_or_init(b"counter".to_vec(), 5).unwrap();
+        assert_eq!(store.read_number(b"counter"), Some(Ok(5)));
+        assert_eq!(crate::wal::read_forward(&store.wal.read_bytes(|bytes| bytes.to_vec())).len(), values.len() + 1);
+    }
+
+    #[test]
+    fn test_increment_overflow() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.set_number(b"key".to_vec(), u64::MAX - 1).unwrap();
+        assert_eq!(store.increment_or_init(b"key".to_vec(), 1).unwrap(), u64::MAX);
+
+        let error = store.increment_or_init(b"key".to_vec(), 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(error.get_ref().and_then(|inner| inner.downcast_ref::<PigmentError>()), Some(PigmentError::Overflow)));
+        assert_eq!(store.read_number(b"key"), Some(Ok(u64::MAX)));
+
+        store.put(b"text".to_vec(), b"abc".to_vec()).unwrap();
+        let error = store.increment_or_init(b"text".to_vec(), 1).unwrap_err();
+        assert!(matches!(error.get_ref().and_then(|inner| inner.downcast_ref::<PigmentError>()), Some(PigmentError::NotANumber)));
+    }
+
+    #[test]
+    fn test_read_and_reset() {
+        use super::*;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let store = DurableKeyValueStore::new_vec_based();
+        assert_eq!(store.read_and_reset(b"absent").unwrap(), 0);
+        assert!(!store.contains(b"absent"));
+        store.put(b"text".to_vec(), b"abc".to_vec()).unwrap();
+        assert_eq!(store.read_and_reset(b"text").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.get(b"text"), Some(b"abc".to_vec()));
+
+        let key = b"requests".to_vec();
+        let threads = 4;
+        let increments_per_thread = 2_000;
+        let done = AtomicBool::new(false);
+        let drained = std::thread::scope(|scope| {
+            let drainer = scope.spawn(|| {
+                let mut drained = 0;
+                while !done.load(Ordering::SeqCst) {
+                    drained += store.read_and_reset(&key).unwrap();
+                    std::thread::yield_now();
+                }
+                drained
+            });
+            let incrementers: Vec<_> = (0..threads)
+                .map(|_| scope.spawn(|| {
+                    for _ in 0..increments_per_thread {
+                        store.increment_or_init(key.clone(), 1).unwrap();
+                    }
+                }))
+                .collect();
+            for incrementer in incrementers {
+                incrementer.join().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+            drainer.join().unwrap()
+        });
+        let remaining = store.read_number(&key).unwrap().unwrap();
+        assert_eq!(drained + remaining, (threads * increments_per_thread) as u64);
+
+        let restored = crate::wal::read_forward(&store.wal.read_bytes(|bytes| bytes.to_vec()));
+        assert_eq!(restored.get(&key), Some(&remaining.to_ne_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        use super::*;
+        use crate::key_map_store::DurableKeyMapStore;
+        use crate::key_set_store::DurableKeySetStore;
+
+        let limits = SizeLimits { max_key_bytes: Some(8), max_value_bytes: Some(16) };
+        let pigment_error = |error: io::Error| {
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            error.into_inner().unwrap().downcast::<PigmentError>().map(|error| *error).unwrap()
+        };
+
+        let store = DurableKeyValueStore::new_vec_based().with_size_limits(limits);
+        store.put(b"key".to_vec(), vec![0; 16]).unwrap();
+        let error = store.put(b"key".to_vec(), vec![1; 17]).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        let error = store.put_batch(vec![(b"ok".to_vec(), vec![]), (vec![0; 9], vec![])]).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::KeyTooLarge { len: 9, max: 8 });
+        assert_eq!(store.get(b"key"), Some(vec![0; 16]));
+        assert_eq!(store.get(b"ok"), None);
+        match store.try_put(b"key".to_vec(), vec![1; 17], Duration::from_secs(1)).unwrap_err() {
+            WalError::Io(error) => assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 }),
+            other => panic!("unexpected error: {}", other),
+        }
+        let error = store.set_number(vec![0; 9], 1).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::KeyTooLarge { len: 9, max: 8 });
+        let error = store.compute(b"key".to_vec(), |_| ComputeResult::Set(vec![1; 17])).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        let error = store.compute(vec![0; 9], |_| ComputeResult::Set(vec![])).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::KeyTooLarge { len: 9, max: 8 });
+        let error = store.with_entry(b"key".to_vec(), |entry| entry.put(vec![1; 17])).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        let error = store.with_entry(b"new".to_vec(), |entry| entry.put(vec![1; 17])).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        #[cfg(feature = "bytes")]
+        {
+            let error = store.put_bytes(b"key".to_vec(), bytes::Bytes::from(vec![1; 17])).unwrap_err();
+            assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        }
+        assert_eq!(store.get(b"key"), Some(vec![0; 16]));
+        assert_eq!(store.wal_stats().unwrap().blocks, 1);
+
+        let merge_store = DurableKeyValueStore::new_vec_based_with_merge_operator(|_: Option<&[u8]>, operand: &[u8]| operand.to_vec())
+            .with_size_limits(limits);
+        let error = merge_store.merge(b"key".to_vec(), vec![0; 17]).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        assert_eq!(merge_store.wal_stats().unwrap().blocks, 0);
+
+        let set_store = DurableKeySetStore::new_vec_based().with_size_limits(limits);
+        let error = set_store.append(b"set".to_vec(), vec![0; 17]).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::ValueTooLarge { len: 17, max: 16 });
+        assert!(!set_store.contains_key(b"set"));
+        assert_eq!(set_store.wal_stats().unwrap().blocks, 0);
+
+        let map_store = DurableKeyMapStore::new_vec_based().with_size_limits(limits);
+        let error = map_store.put(vec![0; 9], 1.into(), vec![]).unwrap_err();
+        assert_eq!(pigment_error(error), PigmentError::KeyTooLarge { len: 9, max: 8 });
+        assert_eq!(map_store.wal_stats().unwrap().blocks, 0);
+    }
+
+    #[test]
+    fn test_contains_keys() {
+        use super::*;
+        use crate::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(0));
+        let store = DurableKeyValueStore::new_vec_based().with_clock(clock.clone());
+        store.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.put(b"c".to_vec(), b"1".to_vec()).unwrap();
+        store.put_with_ttl(b"e".to_vec(), b"1".to_vec(), Duration::from_secs(1)).unwrap();
+
+        let keys: [&[u8]; 6] = [b"e", b"a", b"b", b"c", b"d", b"a"];
+        assert_eq!(store.contains_keys(&keys), vec![true, true, false, true, false, true]);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.contains_keys(&keys), vec![false, true, false, true, false, true]);
+    }
+
+    #[test]
+    fn test_random_key() {
+        use super::*;
+        use crate::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(0));
+        let store = DurableKeyValueStore::new_vec_based().with_clock(clock.clone());
+        assert_eq!(store.random_key(), None);
+        for i in 0..100u32 {
+            store.put(i.to_be_bytes().to_vec(), b"value".to_vec()).unwrap();
+        }
+        store.put_with_ttl(b"expiring".to_vec(), b"value".to_vec(), Duration::from_secs(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        let mut seen = HashSet::new();
+        for _ in 0..100_000 {
+            seen.insert(store.random_key().unwrap());
+            if seen.len() == 100 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 100);
+        assert!(!seen.contains(b"expiring".as_slice()));
+    }
+
+    #[test]
+    fn test_increment_coalescing() {
+        use super::*;
+        use crate::clock::ManualClock;
+        use crate::wal::model::PUT_ACT;
+
+        let clock = Arc::new(ManualClock::new(0));
+        let store = DurableKeyValueStore::new_vec_based()
+            .with_clock(clock.clone())
+            .with_increment_coalescing(CoalescingConfig { max_increments: 100, max_delay: Duration::from_secs(1) });
+
+        for _ in 0..1000 {
+            store.increment_or_init(b"key".to_vec(), 1).unwrap();
+        }
+        assert_eq!(store.read_number(b"key"), Some(Ok(1000)));
+        assert_eq!(store.wal_stats().unwrap().action_count(PUT_ACT), 10);
+
+        store.increment_or_init(b"key".to_vec(), 1).unwrap();
+        clock.advance(Duration::from_secs(1));
+        store.increment_or_init(b"key".to_vec(), 1).unwrap();
+        assert_eq!(store.wal_stats().unwrap().action_count(PUT_ACT), 11);
+
+        store.increment_or_init(b"key".to_vec(), 1).unwrap();
+        store.increment_or_init(b"other".to_vec(), 5).unwrap();
+        assert_eq!(store.flush_increments().unwrap(), 1);
+        assert_eq!(store.flush_increments().unwrap(), 0);
+
+        let recovered = store.wal.read_bytes(crate::wal::read_forward);
+        assert_eq!(recovered.get(b"key".as_slice()), Some(&1003u64.to_ne_bytes().to_vec()));
+        assert_eq!(recovered.get(b"other".as_slice()), Some(&5u64.to_ne_bytes().to_vec()));
+        assert_eq!(store.wal_stats().unwrap().action_count(PUT_ACT), 13);
+
+        let replayed = store.wal.read_bytes(|bytes| crate::wal::read_forward_versioned(bytes, None));
+        assert_eq!(replayed.get(b"key".as_slice()).map(|(_, version)| *version), store.get_versioned(b"key").map(|(_, version)| version));
+        assert_eq!(replayed.get(b"other".as_slice()).map(|(_, version)| *version), store.get_versioned(b"other").map(|(_, version)| version));
+    }
+
+    #[test]
+    fn test_capped_wal() {
+        use super::*;
+
+        let max_bytes = 1024;
+        let store = DurableKeyValueStore::new_vec_based_capped(max_bytes);
+
+        for _ in 0..10_000 {
+            store.increment_or_init(b"key".to_vec(), 1).unwrap();
+            assert!(store.wal.read_bytes(|bytes| bytes.len()) <= max_bytes);
+        }
+        assert_eq!(store.read_number(b"key"), Some(Ok(10_000)));
+
+        let recovered = store.wal.read_bytes(crate::wal::read_forward);
+        assert_eq!(recovered.get(b"key".as_slice()), Some(&10_000u64.to_ne_bytes().to_vec()));
+
+        let error = (0..100)
+            .map(|i| store.put(format!("key_{}", i).into_bytes(), vec![0; 32]))
+            .find_map(Result::err)
+            .expect("live state should outgrow the cap");
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+        assert!(store.wal.read_bytes(|bytes| bytes.len()) <= max_bytes);
+
+        let size = store.size();
+        let batch = (0..10).map(|i| (format!("batch_{}", i).into_bytes(), vec![0; 32])).collect();
+        assert_eq!(store.put_batch(batch).unwrap_err().kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(store.size(), size);
+    }
+
+    #[test]
+    fn test_segmented_recovery() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_segmented_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        {
+            let store = DurableKeyValueStore::init_new_segmented(dir_str, 128).unwrap();
+            for i in 0..50u8 {
+                store.put(vec![i], vec![i; 8]).unwrap();
+            }
+            store.put(vec![1], b"updated".to_vec()).unwrap();
+            store.remove(&[2]).unwrap();
+            assert!(SegmentedWal::segment_paths(&dir, KV_WAL_NAME).unwrap().len() > 1);
+        }
+
+        let store = DurableKeyValueStore::init_new_segmented(dir_str, 128).unwrap();
+        assert_eq!(store.size(), 49);
+        assert_eq!(store.get_versioned(&[1]), Some((b"updated".to_vec(), 2)));
+        assert_eq!(store.get(&[2]), None);
+        assert_eq!(store.get(&[49]), Some(vec![49; 8]));
+
+        let leftovers = std::fs::read_dir(&dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().starts_with('.'))
+            .count();
+        assert_eq!(leftovers, 0);
+        assert_eq!(store.wal_stats().unwrap().blocks, 49);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_segmented_recovery_newest_first() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_newest_first_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        {
+            // segments roll over once two puts of 8 byte values were written
+            let store = DurableKeyValueStore::init_new_segmented(dir_str, 80).unwrap();
+            store.put(b"a".to_vec(), vec![1; 8]).unwrap();
+            store.put(b"b".to_vec(), vec![1; 8]).unwrap();
+            store.put(b"c".to_vec(), vec![1; 64]).unwrap();
+            store.remove(b"a").unwrap();
+            store.put(b"a".to_vec(), vec![2; 8]).unwrap();
+            store.remove(b"b").unwrap();
+        }
+        let paths = SegmentedWal::segment_paths(&dir, KV_WAL_NAME).unwrap();
+        assert_eq!(paths.len(), 3);
+
+        // the oldest segment only has `a` and `b`, both deleted in the newest one
+        let read = crate::wal::read_segments_newest_first(&paths).unwrap().unwrap();
+        assert_eq!((read.segments_read, read.segments_skipped), (2, 1));
+        let forward = crate::wal::read_forward_versioned(&SegmentedWal::read_segments(&paths).unwrap(), None);
+        assert_eq!(read.entries, forward);
+
+        let store = DurableKeyValueStore::init_new_segmented(dir_str, 80).unwrap();
+        assert_eq!(store.size(), 2);
+        assert_eq!(store.get_versioned(b"a"), Some((vec![2; 8], 1)));
+        assert_eq!(store.get(b"b"), None);
+        assert_eq!(store.get_versioned(b"c"), Some((vec![1; 64], 1)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_segmented_recovery_newest_first_keeps_expiries() {
+        use super::*;
+        use crate::clock::ManualClock;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_newest_first_ttl_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        {
+            let store = DurableKeyValueStore::init_new_segmented(dir_str, 80).unwrap();
+            store.put_with_ttl(b"t".to_vec(), vec![1; 8], Duration::from_secs(3600)).unwrap();
+            store.put_with_ttl(b"u".to_vec(), vec![1; 8], Duration::from_secs(3600)).unwrap();
+            store.put(b"u".to_vec(), vec![2; 8]).unwrap();
+            store.put_with_ttl(b"d".to_vec(), vec![1; 8], Duration::from_secs(3600)).unwrap();
+            store.remove(b"d").unwrap();
+        }
+        let paths = SegmentedWal::segment_paths(&dir, KV_WAL_NAME).unwrap();
+        let read = crate::wal::read_segments_newest_first(&paths).unwrap().unwrap();
+        let bytes = SegmentedWal::read_segments(&paths).unwrap();
+        let (entries, expirations, _) = crate::wal::read_forward_expiring_with_policy(&bytes, None, CorruptionPolicy::Abort).unwrap();
+        assert_eq!(read.entries, entries);
+        assert_eq!(read.expirations, expirations);
+        assert_eq!(read.expirations.keys().collect::<Vec<_>>(), vec![&b"t".to_vec()]);
+
+        let store = DurableKeyValueStore::init_new_segmented(dir_str, 80).unwrap()
+            .with_clock(Arc::new(ManualClock::new(u64::MAX)));
+        assert_eq!(store.get(b"t"), None);
+        assert_eq!(store.get(b"u"), Some(vec![2; 8]));
+        drop(store);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_shutdown_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let wal_len = || std::fs::metadata(dir.join(KV_WAL_FILE_NAME)).unwrap().len();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        for i in 0..20u8 {
+            store.put(vec![i], vec![i; 8]).unwrap();
+            store.put(vec![i], vec![i; 16]).unwrap();
+        }
+        store.remove(&[3]).unwrap();
+        let expected: Vec<_> = (0..20u8).map(|i| store.get_versioned(&[i])).collect();
+        store.shutdown().unwrap();
+        let uncompacted_len = wal_len();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!((0..20u8).map(|i| store.get_versioned(&[i])).collect::<Vec<_>>(), expected);
+        store.put(vec![0], vec![0; 16]).unwrap();
+        store.shutdown_compacted(dir_str).unwrap();
+        assert!(wal_len() < uncompacted_len);
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.size(), 19);
+        assert_eq!(store.get_versioned(&[0]), Some((vec![0; 16], 3)));
+        assert_eq!((1..20u8).map(|i| store.get_versioned(&[i])).collect::<Vec<_>>(), expected[1..]);
+        assert_eq!(store.wal_stats().unwrap().blocks, 19);
+
+        let error = store.shutdown_compacted(dir.join("missing").to_str().unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_add_f64() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_f64_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        let deltas = [0.1, 0.2, -0.05, 1e-3, 3.25, -1.5];
+        let mut sum = 0.0;
+        for delta in deltas {
+            sum = store.add_f64(b"latency".to_vec(), delta).unwrap();
+        }
+        assert!((sum - deltas.iter().sum::<f64>()).abs() < f64::EPSILON * 8.0);
+        assert_eq!(store.read_f64(b"latency").unwrap().unwrap(), sum);
+
+        assert_eq!(store.add_f64(b"latency".to_vec(), f64::NAN).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.add_f64(b"inf".to_vec(), f64::INFINITY).unwrap(), f64::INFINITY);
+        assert_eq!(store.add_f64(b"inf".to_vec(), f64::NEG_INFINITY).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        store.set_number(b"count".to_vec(), 7).unwrap();
+        assert_eq!(store.add_f64(b"count".to_vec(), 1.0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.read_f64(b"count").unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.read_number(b"latency"), Some(Err(PigmentError::NotANumber)));
+        drop(store);
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.read_f64(b"latency").unwrap().unwrap().to_bits(), sum.to_bits());
+        assert_eq!(store.read_f64(b"inf").unwrap().unwrap(), f64::INFINITY);
+        assert!(store.read_f64(b"missing").is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_checkpoints_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.current_offset(), 0);
+        store.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        let processed = store.current_offset();
+        assert!(processed > 0);
+        store.set_checkpoint("indexer", processed).unwrap();
+        store.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert!(store.current_offset() > processed);
+        assert_eq!(store.get_checkpoint("indexer"), Some(processed));
+        assert_eq!(store.get_checkpoint("other"), None);
+        store.shutdown().unwrap();
+
+        let store = DurableKeyValueStore::open_in_place(dir_str).unwrap();
+        assert_eq!(store.get_checkpoint("indexer"), Some(processed));
+        // the blocks written after the checkpoint start right at it
+        let bytes = std::fs::read(dir.join(KV_WAL_FILE_NAME)).unwrap();
+        let after: Vec<u32> = crate::wal::iter_actions(&bytes).map(|stored_action| *stored_action.start_offset())
+            .filter(|offset| *offset as u64 >= processed)
+            .collect();
+        assert_eq!(after.first().map(|offset| *offset as u64), Some(processed));
+        assert_eq!(after.len(), 2);
+        store.set_checkpoint("indexer", store.current_offset()).unwrap();
+        store.shutdown().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        let checkpoint = store.get_checkpoint("indexer").unwrap();
+        assert!(checkpoint > processed);
+        assert_eq!(store.get(b"c"), Some(b"3".to_vec()));
+
+        let checkpoint_key = [CHECKPOINT_KEY_PREFIX, b"indexer"].concat();
+        assert_eq!(store.remove_many(&[b"a", &checkpoint_key]).unwrap(), 1);
+        assert_eq!(store.retain(|_, _| false).unwrap(), 2);
+        store.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        assert_eq!(store.drain().map(|(key, _)| key).collect::<Vec<_>>(), vec![b"d".to_vec()]);
+        assert_eq!(store.get_checkpoint("indexer"), Some(checkpoint));
+        store.shutdown().unwrap();
+
+        let store = DurableKeyValueStore::new_lru(dir_str, LruConfig { max_entries: 1, max_bytes: usize::MAX });
+        store.set_checkpoint("other", 1).unwrap();
+        store.put(b"e".to_vec(), b"5".to_vec()).unwrap();
+        store.put(b"f".to_vec(), b"6".to_vec()).unwrap();
+        assert_eq!(store.get(b"e"), None);
+        assert_eq!(store.get_checkpoint("indexer"), Some(checkpoint));
+        assert_eq!(store.get_checkpoint("other"), Some(1));
+        store.shutdown().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retain() {
+        use super::*;
+        use crate::wal::model::DELETE_ACT;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        for i in 0..1000u64 {
+            store.put(i.to_be_bytes().to_vec(), i.to_ne_bytes().to_vec()).unwrap();
+        }
+        let is_even = |_key: &[u8], value: &[u8]| u64::from_ne_bytes(value.try_into().unwrap()) % 2 == 0;
+
+        assert_eq!(store.retain(is_even).unwrap(), 500);
+        assert_eq!(store.size(), 500);
+        assert_eq!(store.get(&2u64.to_be_bytes()), Some(2u64.to_ne_bytes().to_vec()));
+        assert_eq!(store.get(&3u64.to_be_bytes()), None);
+        assert_eq!(store.wal_stats().unwrap().action_count(DELETE_ACT), 500);
+        assert_eq!(store.retain(is_even).unwrap(), 0);
+
+        let replayed = crate::wal::read_forward(&store.wal.read_bytes(|bytes| bytes.to_vec()));
+        assert_eq!(replayed.len(), 500);
+        assert!(replayed.values().all(|value| is_even(&[], value)));
+    }
+
+    #[test]
+    fn test_recovery_stats() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_recovery_stats_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let (store, stats) = DurableKeyValueStore::init_new_with_stats(dir_str);
+        assert_eq!(stats, RecoveryStats::default());
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            store.put(key.to_vec(), b"value".to_vec()).unwrap();
+        }
+        store.remove(b"c").unwrap();
+        store.shutdown().unwrap();
+        let wal_len = std::fs::metadata(dir.join(KV_WAL_FILE_NAME)).unwrap().len() as usize;
+
+        let (store, stats) = DurableKeyValueStore::init_new_with_stats(dir_str);
+        assert_eq!(stats.blocks_read, 6);
+        assert_eq!(stats.bytes_read, wal_len);
+        assert_eq!(stats.entries_restored, 4);
+        assert_eq!(stats.blocks_skipped, 0);
+        assert!(stats.corruption.is_clean());
+        assert_eq!(store.size(), 4);
+        store.shutdown().unwrap();
+
+        // the restart compacted the WAL down to a block per entry
+        let (_store, stats) = DurableKeyValueStore::init_new_with_stats(dir_str);
+        assert_eq!((stats.blocks_read, stats.entries_restored), (4, 4));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_streams_wal_when_mmap_fails() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_streamed_recovery_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        for i in 0..1000u32 {
+            store.put(i.to_ne_bytes().to_vec(), vec![i as u8; (i % 64) as usize]).unwrap();
+        }
+        store.remove(&7u32.to_ne_bytes()).unwrap();
+        store.shutdown().unwrap();
+
+        let (store, stats) = crate::wal::streamed::with_mmap_failing(|| DurableKeyValueStore::init_new_with_stats(dir_str));
+        assert_eq!(stats.blocks_read, 1001);
+        assert!(stats.corruption.is_clean());
+        assert_eq!(store.size(), 999);
+        assert_eq!(store.get(&999u32.to_ne_bytes()), Some(vec![999u32 as u8; 999 % 64]));
+        assert_eq!(store.get(&7u32.to_ne_bytes()), None);
+        store.shutdown().unwrap();
+
+        let store = crate::wal::streamed::with_mmap_failing(|| DurableKeyValueStore::open_in_place(dir_str)).unwrap();
+        assert_eq!(store.size(), 999);
+        store.shutdown().unwrap();
+
+        let mut file = File::open(dir.join(KV_WAL_FILE_NAME)).unwrap();
+        assert_eq!(crate::wal::read_backward_seeking(&mut file).unwrap().len(), 999);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corruption_policies() {
+        use super::*;
+        use crate::wal::iter_actions;
+        use crate::wal::model::{WalHeader, ACT_TYPE_FIELD_LEN, CRC32_FIELD_LEN, DATA_SIZE_FIELD_LEN};
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_corruption_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let wal_path = dir.join(KV_WAL_FILE_NAME);
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        store.put(b"a".to_vec(), b"A".to_vec()).unwrap();
+        store.put(b"b".to_vec(), b"B".to_vec()).unwrap();
+        store.put(b"c".to_vec(), b"C".to_vec()).unwrap();
+        store.shutdown().unwrap();
+
+        let mut corrupted = std::fs::read(&wal_path).unwrap();
+        let (header, body) = WalHeader::split(&corrupted);
+        let header_len = header.encoded_len();
+        let body_len = body.len();
+        let blocks: Vec<_> = iter_actions(&corrupted).map(|stored_action| *stored_action.start_offset() as usize).collect();
+        let (b_start, c_start) = (blocks[1], blocks[2]);
+        corrupted[header_len + b_start + (ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN + DATA_SIZE_FIELD_LEN) as usize] ^= 0xff;
+        let restore_with = |bytes: &[u8], policy: CorruptionPolicy| {
+            let _ = std::fs::remove_file(dir.join(TMP_KV_WAL_FILE_NAME));
+            std::fs::write(&wal_path, bytes).unwrap();
+            DurableKeyValueStore::init_new_with_corruption_policy(dir_str, policy).unwrap()
+        };
+
+        let _ = std::fs::remove_file(dir.join(TMP_KV_WAL_FILE_NAME));
+        std::fs::write(&wal_path, &corrupted).unwrap();
+        let error = DurableKeyValueStore::init_new_with_corruption_policy(dir_str, CorruptionPolicy::Abort).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let inner = error.get_ref().and_then(|inner| inner.downcast_ref::<PigmentError>());
+        assert!(matches!(inner, Some(PigmentError::CrcMismatch { offset }) if *offset == b_start));
+
+        let (store, report) = restore_with(&corrupted, CorruptionPolicy::SkipBlock);
+        assert_eq!(store.size(), 2);
+        assert_eq!(store.get(b"a"), Some(b"A".to_vec()));
+        assert_eq!(store.get(b"c"), Some(b"C".to_vec()));
+        assert_eq!(report.skipped_blocks, vec![b_start..c_start]);
+        assert_eq!(report.truncated_at, None);
+        drop(store);
+
+        let (store, report) = restore_with(&corrupted, CorruptionPolicy::TruncateAt);
+        assert_eq!(store.size(), 1);
+        assert_eq!(store.get(b"a"), Some(b"A".to_vec()));
+        assert!(report.skipped_blocks.is_empty());
+        assert_eq!(report.truncated_at, Some(b_start));
+        assert_eq!(report.dropped_bytes(), body_len - b_start);
+        drop(store);
+
+        let (store, report) = restore_with(&corrupted[..corrupted.len() - 2], CorruptionPolicy::SkipBlock);
+        assert_eq!(store.size(), 1);
+        assert_eq!(report.skipped_blocks, vec![b_start..c_start]);
+        assert_eq!(report.truncated_at, Some(c_start));
+        drop(store);
+
+        let (store, report) = DurableKeyValueStore::init_new_with_corruption_policy(dir_str, CorruptionPolicy::Abort).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"A".to_vec()));
+        assert!(report.is_clean());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_in_place() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_in_place_{}", std::process::id()));
+        let copy_dir = dir.join("copy");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&copy_dir).unwrap();
+        let (dir_str, copy_dir_str) = (dir.to_str().unwrap(), copy_dir.to_str().unwrap());
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        for i in 0..50u8 {
+            store.put(vec![i], vec![i; 4]).unwrap();
+            store.increment_or_init(vec![i % 5, 0xff], 1).unwrap();
+        }
+        store.put_batch(vec![(vec![1], b"one".to_vec()), (vec![2], b"two".to_vec())]).unwrap();
+        store.remove(&[3]).unwrap();
+        store.shutdown().unwrap();
+        std::fs::copy(dir.join(KV_WAL_FILE_NAME), copy_dir.join(KV_WAL_FILE_NAME)).unwrap();
+        let wal_len = || std::fs::metadata(copy_dir.join(KV_WAL_FILE_NAME)).unwrap().len();
+        let written_len = wal_len();
+
+        let renamed = DurableKeyValueStore::init_new(dir_str);
+        let in_place = DurableKeyValueStore::open_in_place(copy_dir_str).unwrap();
+        assert_eq!(wal_len(), written_len);
+        assert_eq!(in_place.size(), renamed.size());
+        for key in (0..50u8).map(|i| vec![i]).chain((0..5u8).map(|i| vec![i, 0xff])) {
+            assert_eq!(in_place.get_versioned(&key), renamed.get_versioned(&key));
+        }
+
+        in_place.put(vec![3], b"three".to_vec()).unwrap();
+        in_place.shutdown().unwrap();
+        assert!(wal_len() > written_len);
+        let reopened = DurableKeyValueStore::open_in_place(copy_dir_str).unwrap();
+        assert_eq!(reopened.get_versioned(&[3]), Some((b"three".to_vec(), 1)));
+        assert_eq!(reopened.size(), renamed.size() + 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_in_place_with_merge_operator() {
+        use super::*;
+
+        let append_operator = |existing: Option<&[u8]>, operand: &[u8]| match existing {
+            Some(existing) => [existing, b",", operand].concat(),
+            None => operand.to_vec(),
+        };
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_in_place_merge_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new_with_merge_operator(dir_str, append_operator);
+        store.put(b"a".to_vec(), b"x".to_vec()).unwrap();
+        store.merge(b"a".to_vec(), b"y".to_vec()).unwrap();
+        store.shutdown().unwrap();
+
+        assert_eq!(DurableKeyValueStore::open_in_place(dir_str).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let store = DurableKeyValueStore::open_in_place_with_merge_operator(dir_str, append_operator).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"x,y".to_vec()));
+        store.merge(b"a".to_vec(), b"z".to_vec()).unwrap();
+        store.shutdown().unwrap();
+
+        let store = DurableKeyValueStore::open_in_place_with_merge_operator(dir_str, append_operator).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"x,y,z".to_vec()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_open_in_place_boot_time() {
+        use super::*;
+        use std::time::Instant;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_boot_time_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        for i in 0..1_000_000u32 {
+            store.put(i.to_ne_bytes().to_vec(), vec![7; 1024]).unwrap();
+        }
+        store.shutdown().unwrap();
+
+        let start = Instant::now();
+        DurableKeyValueStore::init_new(dir_str).shutdown().unwrap();
+        let renamed_duration = start.elapsed();
+
+        let start = Instant::now();
+        DurableKeyValueStore::open_in_place(dir_str).unwrap().shutdown().unwrap();
+        let in_place_duration = start.elapsed();
+
+        println!("1GB WAL boot, rename: {}, in place: {}", renamed_duration.as_secs_f32(), in_place_duration.as_secs_f32());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_presized() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_presized_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        for i in 0..10_000u32 {
+            store.put(i.to_be_bytes().to_vec(), i.to_ne_bytes().to_vec()).unwrap();
+        }
+        for i in (0..10_000u32).step_by(3) {
+            store.remove(&i.to_be_bytes()).unwrap();
+        }
+        store.put(7u32.to_be_bytes().to_vec(), b"again".to_vec()).unwrap();
+        drop(store);
+
+        for _ in 0..2 {
+            let store = DurableKeyValueStore::init_new(dir_str);
+            assert_eq!(store.size(), 6_666);
+            assert_eq!(store.get(&3u32.to_be_bytes()), None);
+            assert_eq!(store.get(&4u32.to_be_bytes()), Some(4u32.to_ne_bytes().to_vec()));
+            assert_eq!(store.get(&7u32.to_be_bytes()), Some(b"again".to_vec()));
+            assert_eq!(store.version(&7u32.to_be_bytes()), 2);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_recovery_time() {
+        use super::*;
+        use std::time::Instant;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_recovery_time_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        store.put_batch((0..1_000_000u32).map(|i| (i.to_ne_bytes().to_vec(), i.to_be_bytes().to_vec())).collect()).unwrap();
+        store.shutdown().unwrap();
+
+        let start = Instant::now();
+        let store = DurableKeyValueStore::init_new(dir_str);
+        let restore_duration = start.elapsed();
+        assert_eq!(store.size(), 1_000_000);
+        store.shutdown().unwrap();
+
+        println!("1M entries WAL recovery: {}", restore_duration.as_secs_f32());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_key() {
+        use super::*;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.set_number(b"balance".to_vec(), 0).unwrap();
+        let inside = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        let _guard = store.lock_key(b"balance");
+                        assert!(!inside.swap(true, Ordering::SeqCst));
+                        let balance = store.read_number(b"balance").unwrap().unwrap();
+                        std::thread::sleep(std::time::Duration::from_micros(50));
+                        store.set_number(b"balance".to_vec(), balance + 1).unwrap();
+                        inside.store(false, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(store.read_number(b"balance"), Some(Ok(100)));
+
+        let guard = store.lock_key(b"balance");
+        assert_eq!(guard.key(), b"balance");
+        let _other = store.lock_key(b"other");
+        store.put(b"balance".to_vec(), b"advisory".to_vec()).unwrap();
+        drop(guard);
+        drop(store.lock_key(b"balance"));
+    }
+
+    #[test]
+    fn test_scrubber_reports_corrupted_block() {
+        use super::*;
+        use crate::wal::iter_actions;
+        use crate::wal::model::{WalHeader, ACT_TYPE_FIELD_LEN, CRC32_FIELD_LEN, DATA_SIZE_FIELD_LEN};
+        use std::io::{Seek, SeekFrom};
+        use std::sync::mpsc;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_scrubber_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let wal_path = dir.join(KV_WAL_FILE_NAME);
+
+        let store = DurableKeyValueStore::init_new(dir.to_str().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let config = ScrubConfig { interval: Duration::from_millis(1), bytes_per_pass: 256 };
+        let scrubber = store.start_scrubber(config, move |offset| sender.send(offset).unwrap()).unwrap();
+
+        for i in 0..2_000u32 {
+            store.put(i.to_ne_bytes().to_vec(), vec![7; 64]).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(receiver.try_recv().is_err());
+
+        // the blocks move to a new file, which is the one scrubbed from now on
+        store.wal.compact_key_value(None).unwrap();
+        for i in 0..100u32 {
+            store.put(i.to_ne_bytes().to_vec(), vec![8; 64]).unwrap();
+        }
+
+        let bytes = std::fs::read(&wal_path).unwrap();
+        let header_len = WalHeader::split(&bytes).0.encoded_len();
+        let corrupted_offset = *iter_actions(&bytes).nth(10).unwrap().start_offset() as usize;
+        let data_at = header_len + corrupted_offset + (ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN + DATA_SIZE_FIELD_LEN) as usize;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&wal_path).unwrap();
+        file.seek(SeekFrom::Start(data_at as u64)).unwrap();
+        file.write_all(&[bytes[data_at] ^ 0xff]).unwrap();
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)).unwrap(), corrupted_offset);
+        scrubber.stop();
+
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        use super::*;
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct RecordingMetrics {
+            writes: Mutex<Vec<(Duration, usize)>>,
+            operations: Mutex<Vec<(Operation, Duration)>>,
+            recovery_blocks: Mutex<Vec<Duration>>,
+        }
+
+        impl WalMetrics for RecordingMetrics {
+            fn on_write(&self, held: Duration, bytes: usize) {
+                self.writes.lock().unwrap().push((held, bytes));
+            }
+
+            fn on_operation(&self, operation: Operation, duration: Duration) {
+                self.operations.lock().unwrap().push((operation, duration));
+            }
+
+            fn on_recovery_block(&self, duration: Duration) {
+                self.recovery_blocks.lock().unwrap().push(duration);
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_metrics_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let metrics = Arc::new(RecordingMetrics::default());
+        let store = DurableKeyValueStore::init_new_with_metrics(dir_str, metrics.clone());
+        assert!(metrics.recovery_blocks.lock().unwrap().is_empty());
+        store.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.put_batch(vec![(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]).unwrap();
+        store.remove(b"c").unwrap();
+
+        let operations: Vec<Operation> = metrics.operations.lock().unwrap().iter().map(|(operation, _)| *operation).collect();
+        assert_eq!(operations, vec![Operation::Put, Operation::PutBatch, Operation::Remove]);
+        let (_, put_duration) = metrics.operations.lock().unwrap()[0];
+        assert!(put_duration > Duration::ZERO);
+        let writes = metrics.writes.lock().unwrap().clone();
+        assert_eq!(writes.len(), 3);
+        assert!(writes.iter().all(|(held, bytes)| *held > Duration::ZERO && *bytes > 0));
+        assert!(writes[0].0 <= put_duration);
+        let blocks = store.wal_stats().unwrap().blocks;
+        store.shutdown().unwrap();
+
+        let recovery_metrics = Arc::new(RecordingMetrics::default());
+        let store = DurableKeyValueStore::init_new_with_metrics(dir_str, recovery_metrics.clone());
+        assert_eq!(recovery_metrics.recovery_blocks.lock().unwrap().len(), blocks);
+        assert_eq!(store.size(), 2);
+        // the restored entries are written to the new WAL without being store operations
+        assert!(recovery_metrics.operations.lock().unwrap().is_empty());
+        store.shutdown().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_filter() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_recovery_filter_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        {
+            let store = DurableKeyValueStore::init_new(dir_str);
+            store.put(b"tmp:a".to_vec(), b"1".to_vec()).unwrap();
+            store.put(b"tmp:b".to_vec(), b"2".to_vec()).unwrap();
+            store.put(b"old:c".to_vec(), b"3".to_vec()).unwrap();
+            store.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        }
+
+        {
+            let store = DurableKeyValueStore::init_new_with_recovery_filter(dir_str, |key, value| {
+                if key.starts_with(b"tmp:") {
+                    RecoveryAction::Skip
+                } else if let Some(stripped) = key.strip_prefix(b"old:") {
+                    RecoveryAction::Replace(stripped.to_vec(), value.to_vec())
+                } else {
+                    RecoveryAction::Keep
+                }
+            });
+            assert_eq!(store.size(), 2);
+            assert_eq!(store.get(b"tmp:a"), None);
+            assert_eq!(store.get(b"c"), Some(b"3".to_vec()));
+            assert_eq!(store.get(b"d"), Some(b"4".to_vec()));
+        }
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.size(), 2);
+        assert_eq!(store.get(b"tmp:b"), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_lru_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let config = LruConfig { max_entries: 3, max_bytes: usize::MAX };
+
+        {
+            let store = DurableKeyValueStore::new_lru(dir_str, config);
+            for i in 0..5u8 {
+                store.put(vec![i], vec![i]).unwrap();
+            }
+            assert_eq!(store.size(), 3);
+            assert_eq!(store.get(&[0]), None);
+            assert_eq!(store.get(&[1]), None);
+
+            assert_eq!(store.get(&[2]), Some(vec![2]));
+            store.put(vec![5], vec![5]).unwrap();
+            assert_eq!(store.get(&[3]), None);
+            assert_eq!(store.size(), 3);
+        }
+
+        let store = DurableKeyValueStore::new_lru(dir_str, config);
+        assert_eq!(store.size(), 3);
+        for i in [0u8, 1, 3] {
+            assert_eq!(store.get(&[i]), None);
+        }
+        for i in [2u8, 4, 5] {
+            assert_eq!(store.get(&[i]), Some(vec![i]));
+        }
+        drop(store);
+
+        let store = DurableKeyValueStore::new_lru(dir_str, LruConfig { max_entries: usize::MAX, max_bytes: 4 });
+        assert_eq!(store.size(), 2);
+        store.put(b"big".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(store.get(b"big"), None);
+        assert_eq!(store.size(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lru_tracks_compute_and_with_entry() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_lru_compute_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::new_lru(dir_str, LruConfig { max_entries: 2, max_bytes: usize::MAX });
+        store.compute(vec![0], |_| ComputeResult::Set(vec![0])).unwrap();
+        store.with_entry(vec![1], |entry| entry.put(vec![1])).unwrap();
+        store.compute(vec![2], |_| ComputeResult::Set(vec![2])).unwrap();
+        assert_eq!(store.get(&[0]), None);
+        assert_eq!(store.size(), 2);
+
+        // a deleted key no longer counts, so 1 isn't evicted to make room for 3
+        store.compute(vec![2], |_| ComputeResult::Delete).unwrap();
+        store.put(vec![3], vec![3]).unwrap();
+        assert_eq!(store.get(&[1]), Some(vec![1]));
+
+        store.with_entry(vec![1], |entry| entry.remove()).unwrap();
+        store.put(vec![4], vec![4]).unwrap();
+        assert_eq!(store.get(&[3]), Some(vec![3]));
+        assert_eq!(store.size(), 2);
+
+        store.shutdown().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lru_tracks_every_write() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_lru_writes_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::new_lru(dir_str, LruConfig { max_entries: 2, max_bytes: usize::MAX });
+        assert!(store.put_if_absent(vec![0], vec![0]).unwrap());
+        store.increment_or_init(vec![1], 1).unwrap();
+        assert_eq!(store.put_if_version(vec![2], vec![2], 0).unwrap(), Ok(1));
+        assert_eq!(store.get(&[0]), None);
+        assert_eq!(store.size(), 2);
+
+        store.add_f64(vec![3], 1.0).unwrap();
+        assert_eq!(store.get(&[1]), None);
+        assert!(store.swap(&[2], &[3]).unwrap());
+        store.set_number(vec![4], 4).unwrap();
+        assert_eq!(store.get(&[2]), None);
+        assert_eq!(store.size(), 2);
+
+        assert_eq!(store.drain().count(), 2);
+        store.put(vec![5], vec![5]).unwrap();
+        store.put(vec![6], vec![6]).unwrap();
+        store.put(vec![7], vec![7]).unwrap();
+        assert_eq!(store.get(&[5]), None);
+        assert_eq!(store.size(), 2);
+
+        store.shutdown().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_buffered() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_memory_buffered_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        {
+            let store = DurableKeyValueStore::init_new_memory_buffered(dir_str, Some(Duration::from_secs(60))).unwrap();
+            store.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            store.persist().unwrap();
+            store.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        }
+
+        let store = DurableKeyValueStore::init_new_memory_buffered(dir_str, None).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b"), Some(b"2".to_vec()));
+        drop(store);
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.size(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_returning() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        assert_eq!(store.put_returning(b"k".to_vec(), b"first".to_vec()).unwrap(), None);
+        assert_eq!(store.put_returning(b"k".to_vec(), b"second".to_vec()).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(store.get(b"k"), Some(b"second".to_vec()));
+        assert_eq!(store.get_versioned(b"k").unwrap().1, 2);
+
+        let restored = store.wal.read_bytes(crate::wal::read_forward);
+        assert_eq!(restored.get(b"k".as_slice()), Some(&b"second".to_vec()));
+    }
+
+    #[test]
+    fn test_seeded_shard_hasher() {
+        use super::*;
+
+        let store_a = DurableKeyValueStore::new_vec_based().with_shard_hasher(ShardHasher::seeded(7));
+        let store_b = DurableKeyValueStore::new_vec_based();
+        for i in 0..100u32 {
+            store_b.put(i.to_be_bytes().to_vec(), vec![1]).unwrap();
+        }
+        let store_b = store_b.with_shard_hasher(ShardHasher::seeded(7));
+        assert_eq!(store_b.size(), 100);
+        assert_eq!(store_b.get(&5u32.to_be_bytes()), Some(vec![1]));
+
+        let shards_a: Vec<usize> = (0..100u32).map(|i| store_a.shard_of(&i.to_be_bytes())).collect();
+        let shards_b: Vec<usize> = (0..100u32).map(|i| store_b.shard_of(&i.to_be_bytes())).collect();
+        assert_eq!(shards_a, shards_b);
+
+        let other_seed = DurableKeyValueStore::new_vec_based().with_shard_hasher(ShardHasher::seeded(8));
+        if store_a.store.shards().len() > 1 {
+            assert!((0..100u32).any(|i| other_seed.shard_of(&i.to_be_bytes()) != shards_a[i as usize]));
+        }
+    }
+
+    #[test]
+    fn test_fork() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        store.put(b"b".to_vec(), b"3".to_vec()).unwrap();
+
+        let fork = store.fork();
+        assert_eq!(fork.get_versioned(b"a"), Some((b"2".to_vec(), 2)));
+        assert_eq!(fork.wal_stats().unwrap().blocks, 2);
+
+        fork.put(b"a".to_vec(), b"forked".to_vec()).unwrap();
+        fork.remove(b"b").unwrap();
+        fork.put(b"c".to_vec(), b"4".to_vec()).unwrap();
+        assert_eq!(store.get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"b"), Some(b"3".to_vec()));
+        assert!(!store.contains(b"c"));
+        assert_eq!(store.size(), 2);
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_fork_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+        drop(fork.fork_to_dir(store_dir).unwrap());
+        assert_eq!(fork.fork_to_dir(store_dir).err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+
+        let restored = DurableKeyValueStore::init_new(store_dir);
+        assert_eq!(restored.get_versioned(b"a"), Some((b"forked".to_vec(), 3)));
+        assert_eq!(restored.size(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_second_opener_locked_out() {
+        use super::*;
+        use crate::wal::AlreadyLocked;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_lock_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(store_dir);
+        store.put(b"k".to_vec(), b"v".to_vec()).unwrap();
+        let wal = std::fs::read(dir.join(KV_WAL_FILE_NAME)).unwrap();
+
+        let errors = [
+            DurableKeyValueStore::try_init_new(store_dir).err().unwrap(),
+            DurableKeyValueStore::open_in_place(store_dir).err().unwrap(),
+            DurableKeyValueStore::init_new_memory_buffered(store_dir, None).err().unwrap(),
+            DurableKeyValueStore::init_new_segmented(store_dir, 1024).err().unwrap(),
+        ];
+        for error in errors {
+            assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+            let locked = error.get_ref().unwrap().downcast_ref::<AlreadyLocked>().unwrap();
+            assert_eq!(locked.path, dir.join(KV_WAL_LOCK_FILE_NAME));
+        }
+        assert_eq!(std::fs::read(dir.join(KV_WAL_FILE_NAME)).unwrap(), wal);
+        store.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+
+        store.shutdown().unwrap();
+        let reopened = DurableKeyValueStore::try_init_new(store_dir).unwrap();
+        assert_eq!(reopened.get(b"k2"), Some(b"v2".to_vec()));
+        drop(reopened);
+        assert!(DurableKeyValueStore::open_in_place(store_dir).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_value_transformer() {
+        use super::*;
+
+        struct Xor(u8);
+
+        impl ValueTransformer for Xor {
+            fn encode(&self, value: &[u8]) -> Vec<u8> {
+                value.iter().map(|byte| byte ^ self.0).collect()
+            }
+
+            fn decode(&self, stored: &[u8]) -> Vec<u8> {
+                self.encode(stored)
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_transformer_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+        let secret = b"plaintext secret".to_vec();
+
+        {
+            let store = DurableKeyValueStore::init_new_with_value_transformer(store_dir, Arc::new(Xor(0x5A)));
+            store.put(b"k".to_vec(), secret.clone()).unwrap();
+            store.put_batch(vec![(b"a".to_vec(), b"plain a".to_vec())]).unwrap();
+            assert_eq!(store.get(b"k"), Some(secret.clone()));
+        }
+
+        let wal = std::fs::read(dir.join(KV_WAL_FILE_NAME)).unwrap();
+        assert!(!wal.windows(secret.len()).any(|window| window == secret.as_slice()));
+        assert_eq!(crate::wal::read_forward(&wal).get(b"k".as_slice()), Some(&Xor(0x5A).encode(&secret)));
+
+        let store = DurableKeyValueStore::init_new_with_value_transformer(store_dir, Arc::new(Xor(0x5A)));
+        assert_eq!(store.get(b"k"), Some(secret.clone()));
+        assert_eq!(store.get(b"a"), Some(b"plain a".to_vec()));
+        drop(store);
+
+        let store = DurableKeyValueStore::open_in_place_with_value_transformer(store_dir, Arc::new(Xor(0x5A))).unwrap();
+        assert_eq!(store.get(b"k"), Some(secret.clone()));
+        store.put(b"b".to_vec(), b"plain b".to_vec()).unwrap();
+        drop(store);
+        let store = DurableKeyValueStore::open_in_place_with_value_transformer(store_dir, Arc::new(Xor(0x5A))).unwrap();
+        assert_eq!(store.get(b"b"), Some(b"plain b".to_vec()));
+        drop(store);
+
+        // the CRC covers the encoded bytes, so a flipped ciphertext byte is caught
+        let mut wal = std::fs::read(dir.join(KV_WAL_FILE_NAME)).unwrap();
+        let encoded = Xor(0x5A).encode(&secret);
+        let at = wal.windows(encoded.len()).position(|window| window == encoded.as_slice()).unwrap();
+        wal[at] ^= 1;
+        let (map, _, report) = crate::wal::read_forward_transformed_with_policy(&wal, None, Some(&Xor(0x5A)), CorruptionPolicy::SkipBlock).unwrap();
+        assert_eq!(report.skipped_blocks.len(), 1);
+        assert!(!map.contains_key(b"k".as_slice()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.put(vec![0x00, 0xff, 0x10], vec![0xde, 0xad]).unwrap();
+        store.put(b"text".to_vec(), Vec::new()).unwrap();
+
+        let mut out = Vec::new();
+        store.debug_dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "2 entries\n00ff10 (3 bytes) => dead (2 bytes)\n74657874 (4 bytes) =>  (0 bytes)\n");
+    }
+
+    #[test]
+    fn test_remove_many() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        for i in 0..20u8 {
+            store.put(vec![i], vec![i]).unwrap();
+        }
+        let blocks_before = store.wal_stats().unwrap().blocks;
+
+        let keys: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i * 3]).collect();
+        let mut key_refs: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        key_refs.push(&[0]);
+        key_refs.push(b"absent");
+        // 0, 3, ..., 18 are present, 21 to 27 and the repeated 0 aren't counted
+        assert_eq!(store.remove_many(&key_refs).unwrap(), 7);
+        assert_eq!(store.size(), 13);
+        assert_eq!(store.wal_stats().unwrap().blocks, blocks_before + 7);
+        assert_eq!(store.remove_many(&key_refs).unwrap(), 0);
+        assert_eq!(store.remove_many(&[]).unwrap(), 0);
+
+        let recovered = store.wal.read_bytes(crate::wal::read_forward);
+        assert_eq!(recovered.len(), 13);
+        assert!(!recovered.contains_key([3u8].as_slice()));
+        assert_eq!(recovered.get([4u8].as_slice()), Some(&vec![4]));
+    }
+
+    #[test]
+    fn test_compact_if_needed() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_compact_if_needed_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        for i in 0..100u32 {
+            store.put(i.to_be_bytes().to_vec(), vec![0; 64]).unwrap();
+        }
+        let dense_bytes = store.wal_stats().unwrap().total_bytes;
+        assert!(store.compaction_ratio().unwrap() < 0.01);
+        assert!(!store.compact_if_needed(0.5).unwrap());
+        assert_eq!(store.wal_stats().unwrap().total_bytes, dense_bytes);
+
+        for round in 1..10u8 {
+            for i in 0..100u32 {
+                store.put(i.to_be_bytes().to_vec(), vec![round; 64]).unwrap();
+            }
+        }
+        store.put_with_ttl(b"session".to_vec(), b"token".to_vec(), Duration::from_secs(3600)).unwrap();
+        assert!(store.compaction_ratio().unwrap() > 0.8);
+        assert!(store.compact_if_needed(0.5).unwrap());
+        let stats = store.wal_stats().unwrap();
+        assert!(stats.total_bytes < dense_bytes * 3 / 2);
+        assert_eq!(stats.live_bytes, stats.total_bytes);
+        assert!(!store.compact_if_needed(0.5).unwrap());
+
+        store.put(b"after".to_vec(), b"compaction".to_vec()).unwrap();
+        assert_eq!(std::fs::metadata(dir.join(KV_WAL_FILE_NAME)).unwrap().len() as usize, store.wal_stats().unwrap().total_bytes);
+        drop(store);
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.size(), 102);
+        assert_eq!(store.get(&7u32.to_be_bytes()), Some(vec![9; 64]));
+        assert_eq!(store.version(&7u32.to_be_bytes()), 10);
+        assert_eq!(store.get(b"session"), Some(b"token".to_vec()));
+        assert!(store.expirations.contains_key(b"session".as_slice()));
+        assert_eq!(store.get(b"after"), Some(b"compaction".to_vec()));
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_with_concurrent_writers() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_online_compaction_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        let writers = 4u32;
+        let rounds = 200u32;
+        std::thread::scope(|scope| {
+            for writer in 0..writers {
+                let store = &store;
+                scope.spawn(move || {
+                    for round in 0..rounds {
+                        for i in 0..20u32 {
+                            store.put(format!("{}-{}", writer, i).into_bytes(), round.to_be_bytes().to_vec()).unwrap();
+                        }
+                        store.put(format!("{}-round-{}", writer, round).into_bytes(), vec![1]).unwrap();
+                    }
+                });
+            }
+            for _ in 0..20 {
+                store.compact_if_needed(0.0).unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        store.compact_if_needed(0.0).unwrap();
+
+        let expected_size = (writers * (20 + rounds)) as usize;
+        assert_eq!(store.size(), expected_size);
+        let replayed = crate::wal::try_read_forward(&std::fs::read(dir.join(KV_WAL_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(replayed.len(), expected_size);
+        for (key, value) in &replayed {
+            assert_eq!(store.get(key).as_ref(), Some(value));
+        }
+        drop(store);
+
+        let store = DurableKeyValueStore::init_new(dir_str);
+        assert_eq!(store.size(), expected_size);
+        assert_eq!(store.get(b"3-7"), Some((rounds - 1).to_be_bytes().to_vec()));
+        assert_eq!(store.version(b"3-7"), rounds as u64);
+        assert_eq!(store.get(format!("2-round-{}", rounds - 1).as_bytes()), Some(vec![1]));
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_value_len() {
+        use super::*;
+
+        let store = DurableKeyValueStore::new_vec_based();
+        store.put(b"big".to_vec(), vec![0; 2048]).unwrap();
+        store.put(b"empty".to_vec(), Vec::new()).unwrap();
+
+        assert_eq!(store.value_len(b"big"), Some(2048));
+        assert_eq!(store.value_len(b"empty"), Some(0));
+        assert_eq!(store.value_len(b"missing"), None);
+    }
+
+    #[test]
+    fn test_put_with_ttl_and_manual_clock() {
+        use super::*;
+        use crate::clock::ManualClock;
+
+        let dir = std::env::temp_dir().join(format!("pigment_db_kv_ttl_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+
+        let clock = Arc::new(ManualClock::new(1_000));
+        let store = DurableKeyValueStore::init_new(store_dir).with_clock(clock.clone());
+        store.put_with_ttl(b"session".to_vec(), b"token".to_vec(), Duration::from_secs(10)).unwrap();
+        store.put_with_ttl(b"overwritten".to_vec(), b"1".to_vec(), Duration::from_secs(10)).unwrap();
+        store.put(b"overwritten".to_vec(), b"2".to_vec()).unwrap();
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(store.get(b"session"), Some(b"token".to_vec()));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.get(b"session"), None);
+        assert!(!store.contains(b"session"));
+        assert_eq!(store.get(b"overwritten"), Some(b"2".to_vec()));
+        drop(store);
+
+        // the expiry is recovered with the entry
+        let store = DurableKeyValueStore::init_new(store_dir).with_clock(clock.clone());
+        assert_eq!(store.size(), 2);
+        assert_eq!(store.get(b"session"), None);
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert_eq!(store.size(), 1);
+
+        clock.set(0);
+        assert_eq!(store.get(b"session"), None);
+        assert_eq!(store.get(b"overwritten"), Some(b"2".to_vec()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expired_numbers_are_not_read() {
+        use super::*;
+        use crate::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(1_000));
+        let store = DurableKeyValueStore::new_vec_based().with_clock(clock.clone());
+        store.put_with_ttl(b"count".to_vec(), 7u64.to_ne_bytes().to_vec(), Duration::from_secs(10)).unwrap();
+        store.put_with_ttl(b"ratio".to_vec(), [&[F64_VALUE_TAG][..], &0.5f64.to_le_bytes()].concat(), Duration::from_secs(10)).unwrap();
+        assert_eq!(store.read_number(b"count"), Some(Ok(7)));
+        assert_eq!(store.read_f64(b"ratio").unwrap().unwrap(), 0.5);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(store.read_number(b"count"), None);
+        assert!(store.read_f64(b"ratio").is_none());
+    }
+
+    #[test]
+    fn test_expired_entries_are_vacant_to_writes() {
+        use super::*;
+        use crate::clock::ManualClock;
+
+        let append_operator = |existing: Option<&[u8]>, operand: &[u8]| match existing {
+            Some(existing) => [existing, b",", operand].concat(),
+            None => operand.to_vec(),
+        };
+        let clock = Arc::new(ManualClock::new(1_000));
+        let store = DurableKeyValueStore::new_vec_based_with_merge_operator(append_operator).with_clock(clock.clone());
+        for key in [&b"absent"[..], b"version", b"count", b"merged", b"computed", b"entry", b"returned", b"swapped"] {
+            store.put_with_ttl(key.to_vec(), 7u64.to_ne_bytes().to_vec(), Duration::from_secs(10)).unwrap();
+        }
+        store.put(b"version".to_vec(), 7u64.to_ne_bytes().to_vec()).unwrap();
+        store.put_with_ttl(b"version".to_vec(), 7u64.to_ne_bytes().to_vec(), Duration::from_secs(10)).unwrap();
+        store.put(b"live".to_vec(), b"x".to_vec()).unwrap();
+        clock.advance(Duration::from_secs(10));
+
+        assert!(store.put_if_absent(b"absent".to_vec(), b"new".to_vec()).unwrap());
+        assert_eq!(store.put_if_version(b"version".to_vec(), b"new".to_vec(), 3).unwrap(),
+                   Err(VersionConflict { expected: 3, actual: 0 }));
+        assert_eq!(store.put_if_version(b"version".to_vec(), b"new".to_vec(), 0).unwrap(), Ok(1));
+        assert_eq!(store.increment_or_init(b"count".to_vec(), 1).unwrap(), 1);
+        store.merge(b"merged".to_vec(), b"y".to_vec()).unwrap();
+        assert_eq!(store.get(b"merged"), Some(b"y".to_vec()));
+        store.compute(b"computed".to_vec(), |current| {
+            assert_eq!(current, None);
+            ComputeResult::Keep
+        }).unwrap();
+        assert!(store.with_entry(b"entry".to_vec(), |entry| entry.get().is_none()).unwrap());
+        assert_eq!(store.put_returning(b"returned".to_vec(), b"new".to_vec()).unwrap(), None);
+        assert!(!store.swap(b"swapped", b"live").unwrap());
+        assert_eq!(store.get(b"live"), Some(b"x".to_vec()));
+
+        // dropped entries are deleted in the WAL too, so versions start over after a replay as well
+        let bytes = store.wal.read_bytes(|bytes| bytes.to_vec());
+        let replayed = crate::wal::read_forward_versioned(&bytes, Some(&append_operator));
+        assert_eq!(replayed.get(b"version".as_slice()), Some(&(b"new".to_vec(), 1)));
+        assert_eq!(replayed.get(b"count".as_slice()), Some(&(1u64.to_ne_bytes().to_vec(), 1)));
+        assert!(!replayed.contains_key(b"computed".as_slice()));
     }
 
     #[test]
@@ -284,7 +3787,7 @@ mod tests {
 
         for i in 0..10_000 {
             let bytes = format!("{}", i).into_bytes();
-            store.put(bytes.clone(), bytes);
+            store.put(bytes.clone(), bytes).unwrap();
         }
 
         let duration = start.elapsed();
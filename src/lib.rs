@@ -1,8 +1,27 @@
+// the original tests compare booleans with assert_eq and look keys up through owned vectors
+#![cfg_attr(test, allow(clippy::bool_assert_comparison, clippy::unnecessary_to_owned))]
+
 pub mod key_value_store;
 pub mod key_set_store;
 pub mod key_map_store;
+pub mod sorted_set_store;
+pub mod namespace;
+pub mod linked_map_store;
+pub mod g_counter_store;
+pub mod key_locks;
+pub mod lru;
+pub mod transaction;
+pub mod async_writer;
+pub mod inspect;
+pub mod error;
 pub mod model;
-mod wal;
+pub mod clock;
+mod reentrancy;
+#[cfg(not(feature = "bytes"))]
+mod small_value;
+pub mod wal;
+#[cfg(feature = "mmap-values")]
+pub mod mmap_key_value_store;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,234 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::info;
+
+use crate::model::{LinkedMap, SearchKey, ShardHasher};
+use crate::wal::model::StoreType;
+use crate::wal::{CorruptionPolicy, ReadableWal, SyncWal, WalContent, WalStats, WalStorage};
+
+pub(crate) const LINKED_MAP_WAL_FILE_NAME: &str = "linked_map.wal.dat";
+const TMP_LINKED_MAP_WAL_FILE_NAME: &str = ".linked_map.wal.dat";
+
+/// Map of fields to values per key, like [`crate::key_map_store::DurableKeyMapStore`] but returning fields in the
+/// order they were first put rather than sorted, see [`LinkedMap`]. Writes the same blocks as the sorted map store,
+/// with the field as a `Key::Bytes` search key; the order is the order of the blocks in the WAL, so recovery
+/// preserves it.
+pub struct DurableLinkedMapStore<W: Write> {
+    store: DashMap<Vec<u8>, LinkedMap, ShardHasher>,
+    wal: WalStorage<W>,
+}
+
+impl DurableLinkedMapStore<File> {
+    pub fn init_new(store_dir: &str) -> Self {
+        match Self::try_init_new(store_dir) {
+            Ok(store) => store,
+            Err(e) => panic!("can't restore {}: {}", Path::new(store_dir).join(LINKED_MAP_WAL_FILE_NAME).to_str().unwrap(), e),
+        }
+    }
+
+    /// Same as [`DurableLinkedMapStore::init_new`], failing with a [`crate::wal::StoreTypeMismatch`] if the WAL was
+    /// written by another store type, see [`crate::key_value_store::DurableKeyValueStore::try_init_new`].
+    pub fn try_init_new(store_dir: &str) -> io::Result<Self> {
+        let store_dir_path = Path::new(store_dir);
+        let wal_file_path = store_dir_path.join(LINKED_MAP_WAL_FILE_NAME);
+        let tmp_wal_file_path = store_dir_path.join(TMP_LINKED_MAP_WAL_FILE_NAME);
+        crate::wal::check_store_type(&wal_file_path, StoreType::LinkedMap)?;
+
+        let mut found_wal = wal_file_path.exists();
+        if found_wal {
+            if std::fs::metadata(&wal_file_path)?.len() == 0 {
+                let _ = std::fs::remove_file(&wal_file_path);
+                found_wal = false;
+            } else {
+                std::fs::rename(&wal_file_path, &tmp_wal_file_path)?;
+            }
+        }
+
+        let store = DurableLinkedMapStore {
+            store: DashMap::default(),
+            wal: WalStorage::new_file_based_for_store(wal_file_path.as_path(), StoreType::LinkedMap),
+        };
+
+        if found_wal {
+            info!("found LinkedMap WAL file: {}, trying to restore...", wal_file_path.to_str().unwrap());
+            let file = File::open(&tmp_wal_file_path)?;
+            store.restore(crate::wal::map_or_stream(file))?;
+
+            let _ = std::fs::remove_file(tmp_wal_file_path.as_path());
+            info!("removed old wal file {}", tmp_wal_file_path.to_str().unwrap());
+        } else {
+            info!("no previous wal log found, starting from scratch: {}", wal_file_path.to_str().unwrap());
+        }
+        Ok(store)
+    }
+}
+
+impl DurableLinkedMapStore<Vec<u8>> {
+    #[allow(unused)]
+    pub fn new_vec_based() -> Self {
+        DurableLinkedMapStore { store: DashMap::default(), wal: WalStorage::new_vec_based() }
+    }
+}
+
+impl<W: SyncWal> DurableLinkedMapStore<W> {
+    /// Flushes and syncs the WAL and releases it, consuming the store, see [`WalStorage::close`].
+    pub fn shutdown(self) -> io::Result<()> {
+        self.wal.close()
+    }
+}
+
+impl<W: ReadableWal> DurableLinkedMapStore<W> {
+    /// Diagnostics over the WAL backing this store: block counts per action type and live/total bytes.
+    pub fn wal_stats(&self) -> io::Result<WalStats> {
+        self.wal.wal_stats()
+    }
+}
+
+impl<W: Write> DurableLinkedMapStore<W> {
+    /// Replays the WAL of a previous run, writing the fields of each key to the new WAL in their order. Stops at the
+    /// first field which can't be written.
+    fn restore(&self, content: WalContent) -> io::Result<()> {
+        let (maps, _report) = crate::wal::into_io(crate::wal::replay_for_linked_map(content, CorruptionPolicy::Abort))?;
+        info!("restored linked maps with size: {}, adding new new WAL file", maps.len());
+
+        for (key, linked_map) in maps {
+            for (field, value) in linked_map.iter() {
+                self.wal.store_put_to_map_event(key.clone(), SearchKey::from(field.clone()), value.clone())?;
+            }
+            self.store.insert(key, linked_map);
+        }
+        info!("{} entries added to store", self.size());
+        Ok(())
+    }
+
+    /// Puts `value` at `field` of `key`, a new field goes after the existing ones. Returns the previous value.
+    pub fn put(&self, key: Vec<u8>, field: Vec<u8>, value: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        match self.store.entry(key) {
+            Entry::Occupied(mut entry) => {
+                self.wal.store_put_to_map_event(entry.key().clone(), SearchKey::from(field.clone()), value.clone())?;
+                Ok(entry.get_mut().put(field, value))
+            }
+            Entry::Vacant(entry) => {
+                self.wal.store_put_to_map_event(entry.key().clone(), SearchKey::from(field.clone()), value.clone())?;
+                entry.insert(LinkedMap::default()).put(field, value);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8], field: &[u8]) -> Option<Vec<u8>> {
+        self.store.get(key).and_then(|linked_map| linked_map.get(field).cloned())
+    }
+
+    /// Fields of `key` with their values in the order they were first put.
+    pub fn ordered_entries(&self, key: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.store.get(key).map(|linked_map| {
+            linked_map.iter().map(|(field, value)| (field.clone(), value.clone())).collect()
+        })
+    }
+
+    /// Removes `field` of `key`, returning its value. `key` is deleted with its last field, like
+    /// [`crate::key_map_store::DurableKeyMapStore::remove_from_sorted_map`].
+    pub fn remove(&self, key: &[u8], field: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.store.entry(key.to_vec()) {
+            Entry::Occupied(mut entry) => {
+                if entry.get().get(field).is_none() {
+                    return Ok(None);
+                }
+                self.wal.store_remove_from_sorted_map_event(entry.key().clone(), SearchKey::from(field))?;
+                let removed = entry.get_mut().remove(field);
+                if entry.get().is_empty() {
+                    self.wal.store_delete_event(entry.key())?;
+                    entry.remove();
+                }
+                Ok(removed)
+            }
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+
+    pub fn remove_key(&self, key: &[u8]) -> io::Result<()> {
+        let entry = self.store.entry(key.to_vec());
+        self.wal.store_delete_event(key)?;
+
+        if let Entry::Occupied(entry) = entry {
+            entry.remove();
+        }
+        Ok(())
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.store.contains_key(key)
+    }
+
+    pub fn size(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Writes the maps sorted by key for inspection, see [`crate::key_value_store::DurableKeyValueStore::debug_dump`]:
+    /// a line per key with its field count, followed by its fields in insertion order, indented.
+    pub fn debug_dump(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut maps: Vec<(Vec<u8>, Vec<_>)> = self.store.iter()
+            .map(|entry| (entry.key().clone(), entry.value().iter().map(|(field, value)| (field.clone(), value.clone())).collect()))
+            .collect();
+        maps.sort_by(|(key_a, _), (key_b, _)| key_a.cmp(key_b));
+
+        writeln!(writer, "{} keys", maps.len())?;
+        for (key, fields) in maps {
+            writeln!(writer, "{} ({} bytes): {} fields", faster_hex::hex_string(&key), key.len(), fields.len())?;
+            for (field, value) in fields {
+                writeln!(writer, "  {} ({} bytes) => {} ({} bytes)",
+                         faster_hex::hex_string(&field), field.len(), faster_hex::hex_string(&value), value.len())?;
+            }
+        }
+        writer.flush()
+    }
+
+    pub fn linked_map_size(&self, key: &[u8]) -> Option<usize> {
+        self.store.get(key).map(|linked_map| linked_map.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insertion_order_recovered() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_linked_map_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+        let key = b"profile".to_vec();
+
+        {
+            let store = DurableLinkedMapStore::init_new(store_dir);
+            for field in ["zip", "name", "age", "city"] {
+                store.put(key.clone(), field.as_bytes().to_vec(), field.to_uppercase().into_bytes()).unwrap();
+            }
+            assert_eq!(store.put(key.clone(), b"name".to_vec(), b"Ann".to_vec()).unwrap(), Some(b"NAME".to_vec()));
+            assert_eq!(store.remove(&key, b"age").unwrap(), Some(b"AGE".to_vec()));
+            store.put(key.clone(), b"age".to_vec(), b"42".to_vec()).unwrap();
+            assert_eq!(store.remove(&key, b"missing").unwrap(), None);
+        }
+
+        let store = DurableLinkedMapStore::init_new(store_dir);
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = [("zip", "ZIP"), ("name", "Ann"), ("city", "CITY"), ("age", "42")].iter()
+            .map(|(field, value)| (field.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(store.ordered_entries(&key), Some(expected));
+        assert_eq!(store.get(&key, b"name"), Some(b"Ann".to_vec()));
+
+        for field in ["zip", "name", "city", "age"] {
+            store.remove(&key, field.as_bytes()).unwrap();
+        }
+        assert!(!store.contains_key(&key));
+        drop(store);
+
+        assert_eq!(DurableLinkedMapStore::init_new(store_dir).size(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
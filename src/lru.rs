@@ -0,0 +1,85 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Limits of a store evicting least recently used keys, see
+/// [`crate::key_value_store::DurableKeyValueStore::new_lru`]. `usize::MAX` leaves a limit out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LruConfig {
+    pub max_entries: usize,
+    /// Sum of key and value lengths of the tracked entries.
+    pub max_bytes: usize,
+}
+
+/// Access order of keys, oldest first, with the bytes each entry takes. A single mutex guards it, so every tracked
+/// access of the store goes through it.
+pub(crate) struct LruTracker {
+    config: LruConfig,
+    state: Mutex<LruState>,
+}
+
+#[derive(Default)]
+struct LruState {
+    next_tick: u64,
+    order: BTreeMap<u64, Vec<u8>>,
+    /// Tick of the last access and bytes of each tracked key.
+    entries: HashMap<Vec<u8>, (u64, usize)>,
+    total_bytes: usize,
+}
+
+impl LruTracker {
+    pub(crate) fn new(config: LruConfig) -> Self {
+        LruTracker { config, state: Mutex::new(LruState::default()) }
+    }
+
+    /// Marks an already tracked key as most recently used.
+    pub(crate) fn touch(&self, key: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let tick = state.next_tick();
+        if let Some((last_tick, _)) = state.entries.get_mut(key) {
+            let previous_tick = std::mem::replace(last_tick, tick);
+            let key = state.order.remove(&previous_tick).expect("tracked key should be ordered");
+            state.order.insert(tick, key);
+        }
+    }
+
+    /// Tracks `key` taking `bytes` as most recently used, returns the keys to evict to get back within the limits,
+    /// which are no longer tracked. An entry alone exceeding `max_bytes` is evicted itself, leaving the others.
+    pub(crate) fn record(&self, key: &[u8], bytes: usize) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.untrack(key);
+        if bytes > self.config.max_bytes {
+            return vec![key.to_vec()];
+        }
+        let tick = state.next_tick();
+        state.order.insert(tick, key.to_vec());
+        state.entries.insert(key.to_vec(), (tick, bytes));
+        state.total_bytes += bytes;
+
+        let mut evicted = Vec::new();
+        while state.entries.len() > self.config.max_entries || state.total_bytes > self.config.max_bytes {
+            let (_, oldest_key) = state.order.pop_first().expect("entries over the limit should be ordered");
+            let (_, oldest_bytes) = state.entries.remove(&oldest_key).expect("ordered key should be tracked");
+            state.total_bytes -= oldest_bytes;
+            evicted.push(oldest_key);
+        }
+        evicted
+    }
+
+    pub(crate) fn forget(&self, key: &[u8]) {
+        self.state.lock().unwrap().untrack(key);
+    }
+}
+
+impl LruState {
+    fn next_tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn untrack(&mut self, key: &[u8]) {
+        if let Some((tick, bytes)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.total_bytes -= bytes;
+        }
+    }
+}
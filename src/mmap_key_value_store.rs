@@ -0,0 +1,211 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::RwLock;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::info;
+use memmap::{Mmap, MmapOptions};
+
+use crate::wal::model::*;
+use crate::wal::WalStorage;
+
+const MMAP_KV_WAL_FILE_NAME: &str = "kv.mmap.wal.dat";
+const BINCODE_LEN_FIELD_LEN: usize = 8;
+
+/// Prototype of a KeyValue store keeping values off-heap: the index maps each key to the position of its value
+/// in the WAL file, and reads copy the bytes out of a memory mapping of that file. Memory use is the keys plus
+/// 16 bytes per entry, values are left to the page cache.
+///
+/// Unlike [`crate::key_value_store::DurableKeyValueStore`], recovery doesn't rewrite the WAL (that would move
+/// every value), so superseded blocks are never reclaimed. Only put and delete actions are supported.
+pub struct MmapKeyValueStore {
+    index: DashMap<Vec<u8>, Range<usize>>,
+    wal: WalStorage<File>,
+    file: File,
+    /// Remapped when a read reaches past its end, i.e. after values were appended.
+    mmap: RwLock<Option<Mmap>>,
+}
+
+impl MmapKeyValueStore {
+    pub fn open(store_dir: &str) -> io::Result<Self> {
+        let wal_file_path = Path::new(store_dir).join(MMAP_KV_WAL_FILE_NAME);
+        let wal = WalStorage::open_file_based(&wal_file_path)?;
+        let file = File::open(&wal_file_path)?;
+
+        let store = MmapKeyValueStore { index: DashMap::new(), wal, file, mmap: RwLock::new(None) };
+        store.remap()?;
+        store.build_index()?;
+        info!("indexed {} entries of {}", store.index.len(), wal_file_path.to_str().unwrap());
+
+        Ok(store)
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.get_with(key, <[u8]>::to_vec)
+    }
+
+    /// Gives `func` the value straight from the mapping, without copying it.
+    pub fn get_with<R>(&self, key: &[u8], func: impl FnOnce(&[u8]) -> R) -> io::Result<Option<R>> {
+        let value_range = match self.index.get(key) {
+            Some(value_range) => value_range.value().clone(),
+            None => return Ok(None),
+        };
+
+        if self.mapped_len() < value_range.end {
+            self.remap()?;
+        }
+        let r_lock = self.mmap.read().unwrap();
+        let mmap = r_lock.as_ref().expect("WAL with values should be mapped");
+        Ok(Some(func(&mmap[value_range])))
+    }
+
+    /// The WAL write and the index update happen under the entry lock of `key`, so the index points at the value
+    /// of the last put of `key` in the WAL.
+    pub fn put(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<()> {
+        let entry = self.index.entry(key);
+        let (start_offset, key, val) = self.wal.store_put_event_at(entry.key().clone(), val)?;
+
+        let value_start = self.wal.header_len() + value_offset_in_block(start_offset, key.len());
+        let value_range = value_start..value_start + val.len();
+        match entry {
+            Entry::Occupied(mut entry) => {
+                entry.insert(value_range);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(value_range);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &[u8]) -> io::Result<()> {
+        let entry = self.index.entry(key.to_vec());
+        self.wal.store_delete_event(key)?;
+
+        if let Entry::Occupied(entry) = entry {
+            entry.remove();
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn size(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Heap bytes held by the index: keys and value positions, values themselves stay in the mapped file.
+    pub fn index_heap_bytes(&self) -> usize {
+        self.index.iter()
+            .map(|entry| entry.key().len() + std::mem::size_of::<Range<usize>>())
+            .sum()
+    }
+
+    fn build_index(&self) -> io::Result<()> {
+        let r_lock = self.mmap.read().unwrap();
+        let bytes: &[u8] = match r_lock.as_ref() {
+            Some(mmap) => mmap,
+            None => return Ok(()),
+        };
+        let header_len = self.wal.header_len();
+
+        for stored_action in crate::wal::iter_actions(bytes) {
+            let start_offset = *stored_action.start_offset();
+            match *stored_action.act_type() {
+                DELETE_ACT => {
+                    self.index.remove(stored_action.data());
+                }
+                PUT_ACT | VERSIONED_PUT_ACT => {
+                    let data = stored_action.data();
+                    let key_len = read_len(data, 0)?;
+                    let key = data.get(BINCODE_LEN_FIELD_LEN..BINCODE_LEN_FIELD_LEN + key_len).ok_or_else(corrupt_block)?;
+                    let value_len = read_len(data, BINCODE_LEN_FIELD_LEN + key_len)?;
+
+                    let value_start = header_len + value_offset_in_block(start_offset, key_len);
+                    self.index.insert(key.to_vec(), value_start..value_start + value_len);
+                }
+                act_type => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("action type {} is not supported by the mmap store", act_type)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mapped_len(&self) -> usize {
+        self.mmap.read().unwrap().as_ref().map_or(0, |mmap| mmap.len())
+    }
+
+    fn remap(&self) -> io::Result<()> {
+        let mut w_lock = self.mmap.write().unwrap();
+        let file_len = self.file.metadata()?.len() as usize;
+        if w_lock.as_ref().map_or(0, |mmap| mmap.len()) < file_len {
+            *w_lock = Some(unsafe { MmapOptions::new().map(&self.file)? });
+        }
+        Ok(())
+    }
+}
+
+/// Offset of the value of a put block relative to the end of the header: fixed fields preceding the data,
+/// then `KeyValueData` as bincode writes it, a u64 length before the key and another one before the value.
+fn value_offset_in_block(start_offset: u32, key_len: usize) -> usize {
+    let fields_before_data = (ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN + DATA_SIZE_FIELD_LEN) as usize;
+    start_offset as usize + fields_before_data + BINCODE_LEN_FIELD_LEN + key_len + BINCODE_LEN_FIELD_LEN
+}
+
+fn read_len(data: &[u8], at: usize) -> io::Result<usize> {
+    let len_bytes: [u8; BINCODE_LEN_FIELD_LEN] = data.get(at..at + BINCODE_LEN_FIELD_LEN)
+        .and_then(|len_bytes| len_bytes.try_into().ok())
+        .ok_or_else(corrupt_block)?;
+    Ok(u64::from_le_bytes(len_bytes) as usize)
+}
+
+fn corrupt_block() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "put block is shorter than its lengths")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_match_and_values_stay_off_heap() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_mmap_kv_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let value_of = |i: u32| vec![(i % 251) as u8; 4096];
+        {
+            let store = MmapKeyValueStore::open(dir_str).unwrap();
+            for i in 0..500u32 {
+                store.put(i.to_be_bytes().to_vec(), value_of(i)).unwrap();
+            }
+            store.remove(&7u32.to_be_bytes()).unwrap();
+            assert_eq!(store.get(&3u32.to_be_bytes()).unwrap(), Some(value_of(3)));
+        }
+
+        let store = MmapKeyValueStore::open(dir_str).unwrap();
+        assert_eq!(store.size(), 499);
+        for i in 0..500u32 {
+            let expected = if i == 7 { None } else { Some(value_of(i)) };
+            assert_eq!(store.get(&i.to_be_bytes()).unwrap(), expected);
+        }
+
+        store.put(1u32.to_be_bytes().to_vec(), b"updated".to_vec()).unwrap();
+        assert_eq!(store.get(&1u32.to_be_bytes()).unwrap(), Some(b"updated".to_vec()));
+        assert_eq!(store.get_with(&2u32.to_be_bytes(), |value| value.len()).unwrap(), Some(4096));
+
+        let file_len = std::fs::metadata(dir.join(MMAP_KV_WAL_FILE_NAME)).unwrap().len() as usize;
+        assert!(store.mapped_len() <= file_len);
+        assert!(store.index_heap_bytes() * 100 < file_len);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
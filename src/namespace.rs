@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+
+use crate::key_value_store::DurableKeyValueStore;
+
+/// Keys of a [`DurableKeyValueStore`] starting with `prefix`, seen without it, see
+/// [`DurableKeyValueStore::namespace`]. Entries are stored under the prefixed key, so namespaces of one store share
+/// its WAL and a namespace is nothing but a view. Prefixes of distinct namespaces must not start one another (e.g.
+/// `users` and `users2`), otherwise the shorter one sees the keys of the longer one: ending each with a separator
+/// byte avoids that.
+pub struct Namespace<'a, W: Write> {
+    store: &'a DurableKeyValueStore<W>,
+    prefix: Vec<u8>,
+}
+
+impl<'a, W: Write> Namespace<'a, W> {
+    pub(crate) fn new(store: &'a DurableKeyValueStore<W>, prefix: Vec<u8>) -> Self {
+        Namespace { store, prefix }
+    }
+
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.store.get(&self.prefixed(key))
+    }
+
+    pub fn put(&self, key: Vec<u8>, val: Vec<u8>) -> io::Result<()> {
+        self.store.put(self.prefixed(&key), val)
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.store.contains(&self.prefixed(key))
+    }
+
+    pub fn remove(&self, key: &[u8]) -> io::Result<()> {
+        self.store.remove(&self.prefixed(key))
+    }
+
+    /// Entries of the namespace in key order, keys without the prefix.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        self.prefix_scan(&[])
+    }
+
+    /// Entries of the namespace whose key (without the prefix) starts with `prefix`, in key order.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        let prefix_len = self.prefix.len();
+        self.store.prefix_scan(&self.prefixed(prefix))
+            .into_iter()
+            .map(move |(mut key, val)| (key.split_off(prefix_len), val))
+    }
+
+    /// Removes every entry of the namespace, see [`DurableKeyValueStore::remove_many`], and returns how many were
+    /// removed. Entries put meanwhile may be left.
+    pub fn clear(&self) -> io::Result<usize> {
+        let keys: Vec<Vec<u8>> = self.store.prefix_scan(&self.prefix).into_iter().map(|(key, _)| key).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+        self.store.remove_many(&keys)
+    }
+
+    fn prefixed(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = Vec::with_capacity(self.prefix.len() + key.len());
+        prefixed.extend_from_slice(&self.prefix);
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespaces_are_isolated() {
+        let store = DurableKeyValueStore::new_vec_based();
+        let users = store.namespace(b"users/".to_vec());
+        let orders = store.namespace(b"orders/".to_vec());
+
+        users.put(b"1".to_vec(), b"alice".to_vec()).unwrap();
+        users.put(b"2".to_vec(), b"bob".to_vec()).unwrap();
+        orders.put(b"1".to_vec(), b"book".to_vec()).unwrap();
+        orders.put(b"10".to_vec(), b"pen".to_vec()).unwrap();
+        store.put(b"plain".to_vec(), b"value".to_vec()).unwrap();
+
+        assert_eq!(users.get(b"1"), Some(b"alice".to_vec()));
+        assert_eq!(orders.get(b"1"), Some(b"book".to_vec()));
+        assert_eq!(store.get(b"users/2"), Some(b"bob".to_vec()));
+        assert!(!users.contains(b"10"));
+        assert_eq!(users.iter().collect::<Vec<_>>(), vec![(b"1".to_vec(), b"alice".to_vec()), (b"2".to_vec(), b"bob".to_vec())]);
+        assert_eq!(orders.prefix_scan(b"1").map(|(key, _)| key).collect::<Vec<_>>(), vec![b"1".to_vec(), b"10".to_vec()]);
+
+        assert_eq!(orders.clear().unwrap(), 2);
+        assert_eq!(orders.iter().count(), 0);
+        assert_eq!(users.iter().count(), 2);
+        assert_eq!(store.get(b"plain"), Some(b"value".to_vec()));
+
+        users.remove(b"1").unwrap();
+        assert_eq!(users.get(b"1"), None);
+        assert_eq!(store.size(), 2);
+    }
+}
@@ -0,0 +1,47 @@
+//! Debug-build detection of store calls made from inside a `compute` closure.
+//!
+//! Lock ordering: a store takes the DashMap entry lock of a key first, then the `WalState` write lock, and releases
+//! the WAL lock before the entry lock. Closures given to `compute`, `compute_if_present`, `compute_if_absent` and
+//! `with_entry` run under the entry lock, so calling back into a store from them blocks on the shard of that lock
+//! forever when the key lands in it. Debug builds panic on such a call instead, release builds don't check.
+
+use std::cell::Cell;
+
+pub(crate) const REENTRANT_CALL_MESSAGE: &str = "re-entrant store call inside compute closure";
+
+thread_local! {
+    static IN_COMPUTE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs a user's closure given to a `compute` method, flagging the thread as inside it until it returns or unwinds.
+pub(crate) fn run_compute<R>(func: impl FnOnce() -> R) -> R {
+    let _guard = ComputeGuard::enter();
+    func()
+}
+
+/// Panics in debug builds if the thread is running a `compute` closure, called by store entry points before taking
+/// an entry lock and by the WAL before taking its write lock.
+pub(crate) fn check_not_in_compute() {
+    if cfg!(debug_assertions) && IN_COMPUTE.with(Cell::get) {
+        panic!("{}", REENTRANT_CALL_MESSAGE);
+    }
+}
+
+struct ComputeGuard;
+
+impl ComputeGuard {
+    fn enter() -> Self {
+        if cfg!(debug_assertions) {
+            IN_COMPUTE.with(|in_compute| in_compute.set(true));
+        }
+        ComputeGuard
+    }
+}
+
+impl Drop for ComputeGuard {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            IN_COMPUTE.with(|in_compute| in_compute.set(false));
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::ops::Deref;
+
+/// Values up to this many bytes are held inline by [`SmallValue`].
+pub(crate) const INLINE_CAPACITY: usize = 16;
+
+/// Value held by a map entry: inline when short enough, so counters, flags and other tiny values take no heap
+/// allocation of their own and are read without following a pointer. Longer values keep their `Vec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SmallValue {
+    /// Bytes past `len` are zero.
+    Inline { len: u8, bytes: [u8; INLINE_CAPACITY] },
+    Heap(Vec<u8>),
+}
+
+impl SmallValue {
+    pub(crate) fn from_slice(value: &[u8]) -> Self {
+        if value.len() > INLINE_CAPACITY {
+            return SmallValue::Heap(value.to_vec());
+        }
+        let mut bytes = [0; INLINE_CAPACITY];
+        bytes[..value.len()].copy_from_slice(value);
+        SmallValue::Inline { len: value.len() as u8, bytes }
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        match self {
+            SmallValue::Inline { len, bytes } => bytes[..len as usize].to_vec(),
+            SmallValue::Heap(value) => value,
+        }
+    }
+}
+
+/// Short values are copied inline and their `Vec` dropped, longer ones are kept without copying.
+impl From<Vec<u8>> for SmallValue {
+    fn from(value: Vec<u8>) -> Self {
+        if value.len() <= INLINE_CAPACITY {
+            SmallValue::from_slice(&value)
+        } else {
+            SmallValue::Heap(value)
+        }
+    }
+}
+
+impl Deref for SmallValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            SmallValue::Inline { len, bytes } => &bytes[..*len as usize],
+            SmallValue::Heap(value) => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for len in [0, 1, 8, INLINE_CAPACITY - 1, INLINE_CAPACITY, INLINE_CAPACITY + 1, 1024] {
+            let value: Vec<u8> = (0..len).map(|i| (i % 251) as u8 + 1).collect();
+            let small_value = SmallValue::from(value.clone());
+            assert_eq!(matches!(small_value, SmallValue::Inline { .. }), len <= INLINE_CAPACITY);
+            assert_eq!(&small_value[..], value.as_slice());
+            assert_eq!(small_value.len(), len);
+            assert_eq!(SmallValue::from_slice(&value), small_value);
+            assert_eq!(small_value.into_vec(), value);
+        }
+    }
+}
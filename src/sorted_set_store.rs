@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Bound;
+
+use dashmap::DashMap;
+
+use crate::key_map_store::DurableKeyMapStore;
+use crate::model::{Key, SearchKey};
+
+/// Members of a key with their scores.
+type Scores = HashMap<Vec<u8>, i64>;
+
+/// Sorted sets (like Redis' ZADD and ZRANGEBYSCORE) on top of [`DurableKeyMapStore`]: each member is stored under the
+/// search key `(score, member)`, so the sorted map of a key is ordered by score, then member. A member has a single
+/// score, changing it moves the member with [`DurableKeyMapStore::move_element`].
+pub struct DurableSortedSetStore<W: Write> {
+    store: DurableKeyMapStore<W>,
+    /// Score of each member per key, loaded from `store` on the first change of the key after a restart. Changes of
+    /// a key hold its entry lock here while updating `store`.
+    scores: DashMap<Vec<u8>, Scores>,
+}
+
+impl DurableSortedSetStore<File> {
+    pub fn init_new(store_dir: &str) -> Self {
+        DurableSortedSetStore::new(DurableKeyMapStore::init_new(store_dir))
+    }
+}
+
+impl DurableSortedSetStore<Vec<u8>> {
+    pub fn new_vec_based() -> Self {
+        DurableSortedSetStore::new(DurableKeyMapStore::new_vec_based())
+    }
+}
+
+impl<W: Write> DurableSortedSetStore<W> {
+    /// Every sorted map of `store` is expected to hold `(score, member)` search keys only.
+    pub fn new(store: DurableKeyMapStore<W>) -> Self {
+        DurableSortedSetStore { store, scores: DashMap::new() }
+    }
+
+    /// Adds `member` with `score`, replacing its previous score if any. Returns `true` if `member` is new.
+    pub fn add(&self, key: Vec<u8>, member: Vec<u8>, score: i64) -> io::Result<bool> {
+        let mut scores = self.scores_of(key.clone());
+        match scores.get(&member) {
+            Some(old_score) if *old_score == score => Ok(false),
+            Some(old_score) => {
+                let from = search_key(*old_score, member.clone());
+                self.store.move_element(key, &from, search_key(score, member.clone()), Vec::new())?;
+                scores.insert(member, score);
+                Ok(false)
+            }
+            None => {
+                self.store.put(key, search_key(score, member.clone()), Vec::new())?;
+                scores.insert(member, score);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Removes `member`, returns its score if it was there.
+    pub fn remove(&self, key: Vec<u8>, member: &[u8]) -> io::Result<Option<i64>> {
+        let mut scores = self.scores_of(key.clone());
+        let score = match scores.get(member) {
+            Some(score) => *score,
+            None => return Ok(None),
+        };
+        self.store.remove_from_sorted_map(key, search_key(score, member.to_vec()))?;
+        scores.remove(member);
+        Ok(Some(score))
+    }
+
+    pub fn score(&self, key: &[u8], member: &[u8]) -> Option<i64> {
+        match self.scores.get(key) {
+            Some(scores) => scores.get(member).copied(),
+            None => load_scores(&self.store, key).get(member).copied(),
+        }
+    }
+
+    /// Members with a score within `min..=max` and their scores, in score order, members of the same score in byte
+    /// order.
+    pub fn range_by_score(&self, key: &[u8], min: i64, max: i64) -> Vec<(Vec<u8>, i64)> {
+        let end = match max.checked_add(1) {
+            Some(end) => Bound::Excluded(SearchKey::from(vec![Key::I64(end)])),
+            None => Bound::Unbounded,
+        };
+        self.store.range_search_keys(key, Bound::Included(SearchKey::from(vec![Key::I64(min)])), end)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(score_member)
+            .collect()
+    }
+
+    /// Number of members of `key`.
+    pub fn len(&self, key: &[u8]) -> usize {
+        self.store.sorted_map_size(key).unwrap_or(0)
+    }
+
+    pub fn into_inner(self) -> DurableKeyMapStore<W> {
+        self.store
+    }
+
+    /// Entry of `key` in `scores`, loaded from the store if missing.
+    fn scores_of(&self, key: Vec<u8>) -> dashmap::mapref::one::RefMut<'_, Vec<u8>, Scores> {
+        self.scores.entry(key.clone()).or_insert_with(|| load_scores(&self.store, &key))
+    }
+}
+
+fn search_key(score: i64, member: Vec<u8>) -> SearchKey {
+    SearchKey::from(vec![Key::I64(score), Key::Bytes(member)])
+}
+
+fn score_member(search_key: &SearchKey) -> Option<(Vec<u8>, i64)> {
+    match search_key.slice() {
+        [Key::I64(score), Key::Bytes(member)] => Some((member.clone(), *score)),
+        _ => None,
+    }
+}
+
+fn load_scores<W: Write>(store: &DurableKeyMapStore<W>, key: &[u8]) -> Scores {
+    store.range_keys(key, ..)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(score_member)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_updates_score() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_sorted_set_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+        let key = b"leaderboard".to_vec();
+
+        {
+            let store = DurableSortedSetStore::init_new(store_dir);
+            assert!(store.add(key.clone(), b"alice".to_vec(), 30).unwrap());
+            assert!(store.add(key.clone(), b"bob".to_vec(), 10).unwrap());
+            assert!(store.add(key.clone(), b"carol".to_vec(), 20).unwrap());
+            assert!(!store.add(key.clone(), b"bob".to_vec(), 40).unwrap());
+
+            assert_eq!(store.len(&key), 3);
+            assert_eq!(store.score(&key, b"bob"), Some(40));
+            assert_eq!(store.range_by_score(&key, i64::MIN, i64::MAX),
+                       vec![(b"carol".to_vec(), 20), (b"alice".to_vec(), 30), (b"bob".to_vec(), 40)]);
+            assert_eq!(store.range_by_score(&key, 0, 10), vec![]);
+            assert_eq!(store.range_by_score(&key, 20, 30), vec![(b"carol".to_vec(), 20), (b"alice".to_vec(), 30)]);
+            store.into_inner().shutdown().unwrap();
+        }
+
+        let store = DurableSortedSetStore::init_new(store_dir);
+        assert_eq!(store.range_by_score(&key, i64::MIN, i64::MAX),
+                   vec![(b"carol".to_vec(), 20), (b"alice".to_vec(), 30), (b"bob".to_vec(), 40)]);
+        assert!(!store.add(key.clone(), b"bob".to_vec(), -5).unwrap());
+        assert_eq!(store.into_inner().range_keys(&key, ..).unwrap(), vec![
+            search_key(-5, b"bob".to_vec()), search_key(20, b"carol".to_vec()), search_key(30, b"alice".to_vec()),
+        ]);
+
+        let store = DurableSortedSetStore::new_vec_based();
+        store.add(key.clone(), b"alice".to_vec(), 1).unwrap();
+        assert_eq!(store.remove(key.clone(), b"alice").unwrap(), Some(1));
+        assert_eq!(store.remove(key.clone(), b"alice").unwrap(), None);
+        assert_eq!(store.len(&key), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
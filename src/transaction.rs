@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+
+use crate::key_map_store::DurableKeyMapStore;
+use crate::key_set_store::{DurableKeySetStore, ElementSet};
+use crate::key_value_store::DurableKeyValueStore;
+use crate::model::{SearchKey, TxOp};
+use crate::wal::model::TxRecord;
+use crate::wal::{replay_unfinished_transactions, WalStorage};
+
+const TX_WAL_FILE_NAME: &str = "tx.wal.dat";
+
+/// Op of a transaction with the participant applying it.
+type TxStep<'p> = (TxOp, &'p dyn TxParticipant);
+
+/// Store taking part in transactions, applying the [`TxOp`] kinds it handles.
+pub trait TxParticipant {
+    fn handles(&self, op: &TxOp) -> bool;
+
+    /// Change restoring the current state touched by `op`, taken before `op` is applied.
+    fn undo_of(&self, op: &TxOp) -> TxOp;
+
+    fn apply(&self, op: TxOp) -> io::Result<()>;
+}
+
+impl<W: Write> TxParticipant for DurableKeyValueStore<W> {
+    fn handles(&self, op: &TxOp) -> bool {
+        matches!(op, TxOp::Put { .. } | TxOp::Remove { .. })
+    }
+
+    fn undo_of(&self, op: &TxOp) -> TxOp {
+        let key = match op {
+            TxOp::Put { key, .. } | TxOp::Remove { key } => key,
+            _ => unreachable!("not a key value op: {:?}", op),
+        };
+        match self.get(key) {
+            Some(value) => TxOp::Put { key: key.clone(), value },
+            None => TxOp::Remove { key: key.clone() },
+        }
+    }
+
+    fn apply(&self, op: TxOp) -> io::Result<()> {
+        match op {
+            TxOp::Put { key, value } => self.put(key, value),
+            TxOp::Remove { key } => self.remove(&key),
+            _ => unreachable!("not a key value op: {:?}", op),
+        }
+    }
+}
+
+impl<W: Write, S: ElementSet> TxParticipant for DurableKeySetStore<W, S> {
+    fn handles(&self, op: &TxOp) -> bool {
+        matches!(op, TxOp::SetAppend { .. } | TxOp::SetRemove { .. })
+    }
+
+    fn undo_of(&self, op: &TxOp) -> TxOp {
+        let (key, element) = match op {
+            TxOp::SetAppend { key, element } | TxOp::SetRemove { key, element } => (key.clone(), element.clone()),
+            _ => unreachable!("not a set op: {:?}", op),
+        };
+        if self.contains_in_set(&key, &element) {
+            TxOp::SetAppend { key, element }
+        } else {
+            TxOp::SetRemove { key, element }
+        }
+    }
+
+    fn apply(&self, op: TxOp) -> io::Result<()> {
+        match op {
+            TxOp::SetAppend { key, element } => self.append(key, element),
+            TxOp::SetRemove { key, element } => self.remove_from_set(key, element),
+            _ => unreachable!("not a set op: {:?}", op),
+        }
+    }
+}
+
+impl<W: Write> TxParticipant for DurableKeyMapStore<W> {
+    fn handles(&self, op: &TxOp) -> bool {
+        matches!(op, TxOp::MapPut { .. } | TxOp::MapRemove { .. })
+    }
+
+    fn undo_of(&self, op: &TxOp) -> TxOp {
+        let (key, search_key) = match op {
+            TxOp::MapPut { key, search_key, .. } | TxOp::MapRemove { key, search_key } => (key.clone(), search_key.clone()),
+            _ => unreachable!("not a map op: {:?}", op),
+        };
+        match self.get_element(&key, &search_key) {
+            Some(value) => TxOp::MapPut { key, search_key, value },
+            None => TxOp::MapRemove { key, search_key },
+        }
+    }
+
+    fn apply(&self, op: TxOp) -> io::Result<()> {
+        match op {
+            TxOp::MapPut { key, search_key, value } => self.put(key, search_key, value),
+            TxOp::MapRemove { key, search_key } => self.remove_from_sorted_map(key, search_key).map(|_| ()),
+            _ => unreachable!("not a map op: {:?}", op),
+        }
+    }
+}
+
+/// Changes across stores committed together by [`TransactionLog::commit`].
+#[derive(Debug, Default)]
+pub struct Transaction {
+    ops: Vec<TxOp>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.push(TxOp::Put { key, value })
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.push(TxOp::Remove { key })
+    }
+
+    pub fn set_append(&mut self, key: Vec<u8>, element: Vec<u8>) -> &mut Self {
+        self.push(TxOp::SetAppend { key, element })
+    }
+
+    pub fn set_remove(&mut self, key: Vec<u8>, element: Vec<u8>) -> &mut Self {
+        self.push(TxOp::SetRemove { key, element })
+    }
+
+    pub fn map_put(&mut self, key: Vec<u8>, search_key: SearchKey, value: Vec<u8>) -> &mut Self {
+        self.push(TxOp::MapPut { key, search_key, value })
+    }
+
+    pub fn map_remove(&mut self, key: Vec<u8>, search_key: SearchKey) -> &mut Self {
+        self.push(TxOp::MapRemove { key, search_key })
+    }
+
+    pub fn ops(&self) -> &[TxOp] {
+        &self.ops
+    }
+
+    fn push(&mut self, op: TxOp) -> &mut Self {
+        self.ops.push(op);
+        self
+    }
+}
+
+/// Makes the changes of a [`Transaction`] to several stores all-or-nothing across crashes. Before any change is
+/// applied, a begin record with the changes and their undo is written to a WAL of its own; an end record follows
+/// once all are applied. On restart, [`TransactionLog::init_new`] applies the undo of every transaction without an
+/// end record, latest first.
+///
+/// Each op goes to the first participant handling its kind. Only atomicity is provided: other writers may see or
+/// interleave with changes in progress, callers needing isolation take [`DurableKeyValueStore::lock_key`] first.
+pub struct TransactionLog<W: Write> {
+    wal: WalStorage<W>,
+    next_id: AtomicU64,
+}
+
+impl TransactionLog<File> {
+    /// Rolls back transactions left unfinished by the previous run, then starts with an empty log. `participants`
+    /// must be the stores transactions were committed to, already restored. Returns the number of rolled back
+    /// transactions.
+    pub fn init_new(store_dir: &str, participants: &[&dyn TxParticipant]) -> io::Result<(Self, usize)> {
+        let wal_file_path = Path::new(store_dir).join(TX_WAL_FILE_NAME);
+        let mut rolled_back = 0;
+
+        if wal_file_path.exists() {
+            if std::fs::metadata(&wal_file_path)?.len() > 0 {
+                let file = File::open(&wal_file_path)?;
+                for tx_record in replay_unfinished_transactions(crate::wal::map_or_stream(file))?.into_iter().rev() {
+                    info!("rolling back unfinished transaction {}", tx_record.id());
+                    let (_, undo) = tx_record.owned();
+                    roll_back(undo, participants)?;
+                    rolled_back += 1;
+                }
+            }
+            // undo is applied again if a crash happens before this, which ends in the same state
+            std::fs::remove_file(&wal_file_path)?;
+        }
+
+        Ok((TransactionLog::with_wal(WalStorage::new_file_based(wal_file_path.as_path())), rolled_back))
+    }
+}
+
+impl TransactionLog<Vec<u8>> {
+    pub fn new_vec_based() -> Self {
+        TransactionLog::with_wal(WalStorage::new_vec_based())
+    }
+}
+
+impl<W: Write> TransactionLog<W> {
+    fn with_wal(wal: WalStorage<W>) -> Self {
+        TransactionLog { wal, next_id: AtomicU64::new(0) }
+    }
+
+    /// Applies all changes of `tx` or none. Fails with `ErrorKind::InvalidInput`, before anything is written, if no
+    /// participant handles one of the ops. If applying a change fails, the changes applied so far are undone.
+    pub fn commit(&self, tx: Transaction, participants: &[&dyn TxParticipant]) -> io::Result<()> {
+        let (tx_id, steps, undo) = self.begin(tx, participants)?;
+
+        for (applied, (op, participant)) in steps.into_iter().enumerate() {
+            if let Err(error) = participant.apply(op) {
+                roll_back(undo[..applied].to_vec(), participants)?;
+                self.wal.store_tx_end_event(tx_id)?;
+                return Err(error);
+            }
+        }
+        self.wal.store_tx_end_event(tx_id)
+    }
+
+    /// Writes the begin record of `tx`, returns its id, each op with the participant applying it and the undo.
+    fn begin<'p>(&self, tx: Transaction, participants: &[&'p dyn TxParticipant]) -> io::Result<(u64, Vec<TxStep<'p>>, Vec<TxOp>)> {
+        let targets = tx.ops.iter().map(|op| participant_of(op, participants)).collect::<io::Result<Vec<_>>>()?;
+        let undo = tx.ops.iter().zip(&targets).map(|(op, participant)| participant.undo_of(op)).collect();
+
+        let tx_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let tx_record = TxRecord::new(tx_id, tx.ops, undo);
+        self.wal.store_tx_begin_event(&tx_record)?;
+
+        let (ops, undo) = tx_record.owned();
+        Ok((tx_id, ops.into_iter().zip(targets).collect(), undo))
+    }
+}
+
+/// Applies `undo` in reverse, so the undo of the first change, the state before the transaction, is applied last.
+fn roll_back(undo: Vec<TxOp>, participants: &[&dyn TxParticipant]) -> io::Result<()> {
+    for op in undo.into_iter().rev() {
+        participant_of(&op, participants)?.apply(op)?;
+    }
+    Ok(())
+}
+
+fn participant_of<'p>(op: &TxOp, participants: &[&'p dyn TxParticipant]) -> io::Result<&'p dyn TxParticipant> {
+    participants.iter().copied().find(|participant| participant.handles(op))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no participant handles {:?}", op)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollback_after_crash() {
+        let dir = std::env::temp_dir().join(format!("pigment_db_transaction_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store_dir = dir.to_str().unwrap();
+
+        {
+            let kv = DurableKeyValueStore::init_new(store_dir);
+            let set = DurableKeySetStore::init_new(store_dir);
+            let (log, rolled_back) = TransactionLog::init_new(store_dir, &[&kv, &set]).unwrap();
+            assert_eq!(rolled_back, 0);
+
+            let mut tx = Transaction::new();
+            tx.put(b"user1".to_vec(), b"active".to_vec()).set_append(b"active".to_vec(), b"user1".to_vec());
+            log.commit(tx, &[&kv, &set]).unwrap();
+            assert_eq!(kv.get(b"user1"), Some(b"active".to_vec()));
+            assert!(set.contains_in_set(b"active", b"user1"));
+
+            let mut no_participant = Transaction::new();
+            no_participant.map_remove(b"k".to_vec(), SearchKey::from("k1"));
+            assert_eq!(log.commit(no_participant, &[&kv, &set]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+            // crash between the KV put and the set append
+            let mut tx = Transaction::new();
+            tx.put(b"user1".to_vec(), b"banned".to_vec()).set_remove(b"active".to_vec(), b"user1".to_vec());
+            let (_, steps, _) = log.begin(tx, &[&kv, &set]).unwrap();
+            let (op, participant) = steps.into_iter().next().unwrap();
+            participant.apply(op).unwrap();
+            assert_eq!(kv.get(b"user1"), Some(b"banned".to_vec()));
+        }
+
+        for expected_rolled_back in [1, 0] {
+            let kv = DurableKeyValueStore::init_new(store_dir);
+            let set = DurableKeySetStore::init_new(store_dir);
+            let (_log, rolled_back) = TransactionLog::init_new(store_dir, &[&kv, &set]).unwrap();
+            assert_eq!(rolled_back, expected_rolled_back);
+            assert_eq!(kv.get(b"user1"), Some(b"active".to_vec()));
+            assert!(set.contains_in_set(b"active", b"user1"));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
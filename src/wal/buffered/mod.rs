@@ -0,0 +1,178 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::error;
+
+use crate::wal::{ReadableWal, SyncWal};
+
+/// WAL writer appending to memory only, like the vec based WAL, with the accumulated bytes persisted to a file by
+/// [`MemoryBufferedFile::persist`]: periodically from a background thread if an interval is given, on `sync`
+/// (so [`crate::wal::WalStorage::close`]) and when dropped. The file is a regular WAL, recovered like one.
+///
+/// Durability window: blocks appended since the last persist are lost on a crash, only a clean shutdown or a
+/// persist makes them durable. Persisting doesn't block appends, which only wait for the buffer lock.
+pub struct MemoryBufferedFile {
+    shared: Arc<BufferedState>,
+    persister: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+struct BufferedState {
+    /// Taken before `buffer` by everything needing both, so persists write buffers in append order.
+    file: Mutex<File>,
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl MemoryBufferedFile {
+    /// Creates `file_path`, persisting every `persist_interval` if given. The header is expected to be written
+    /// by `WalStorage`.
+    pub fn create(file_path: &Path, persist_interval: Option<Duration>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).append(true).create_new(true).open(file_path)?;
+        let shared = Arc::new(BufferedState { file: Mutex::new(file), buffer: Mutex::new(Vec::new()) });
+
+        let persister = match persist_interval {
+            None => None,
+            Some(interval) => {
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread_stop = stop.clone();
+                let thread_shared = shared.clone();
+                let handle = thread::Builder::new()
+                    .name("pigment-wal-persister".to_string())
+                    .spawn(move || {
+                        while !thread_stop.load(Ordering::Acquire) {
+                            thread::park_timeout(interval);
+                            if let Err(e) = thread_shared.persist() {
+                                error!("periodic WAL persist failed: {}", e);
+                            }
+                        }
+                    })?;
+                Some((stop, handle))
+            }
+        };
+
+        Ok(MemoryBufferedFile { shared, persister })
+    }
+
+    /// Writes the blocks appended since the last persist to the file, without syncing it.
+    pub fn persist(&self) -> io::Result<()> {
+        self.shared.persist()
+    }
+
+    /// Bytes appended but not persisted yet, i.e. what a crash now would lose.
+    pub fn buffered_len(&self) -> usize {
+        self.shared.buffer.lock().unwrap().len()
+    }
+}
+
+impl BufferedState {
+    fn persist(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let bytes = std::mem::take(&mut *self.buffer.lock().unwrap());
+
+        let mut written = 0;
+        while written < bytes.len() {
+            match file.write(&bytes[written..]) {
+                Ok(0) => {
+                    self.requeue(&bytes[written..]);
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "WAL file accepted no more bytes"));
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.requeue(&bytes[written..]);
+                    return Err(e);
+                }
+            }
+        }
+        file.flush()
+    }
+
+    /// Puts bytes which failed to persist back in front of the ones appended meanwhile, for the next persist.
+    fn requeue(&self, unwritten: &[u8]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let appended = std::mem::replace(&mut *buffer, unwritten.to_vec());
+        buffer.extend_from_slice(&appended);
+    }
+}
+
+impl Write for MemoryBufferedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.buffer.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Nothing to do, blocks reach the file only when persisted.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SyncWal for MemoryBufferedFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.shared.persist()?;
+        self.shared.file.lock().unwrap().sync_all()
+    }
+}
+
+impl ReadableWal for MemoryBufferedFile {
+    /// The persisted bytes followed by the buffered ones.
+    fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        let file = self.shared.file.lock().unwrap();
+        let mut bytes = Vec::new();
+        // the file is opened in append mode, moving the cursor doesn't affect where blocks are written
+        let mut file_ref: &File = &file;
+        file_ref.seek(SeekFrom::Start(0))?;
+        file_ref.read_to_end(&mut bytes)?;
+        bytes.extend_from_slice(&self.shared.buffer.lock().unwrap());
+        Ok(func(&bytes))
+    }
+}
+
+impl Drop for MemoryBufferedFile {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.persister.take() {
+            stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+        if let Err(e) = self.shared.persist() {
+            error!("persisting WAL on drop failed, {} bytes lost: {}", self.buffered_len(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wal::{read_forward, WalStorage};
+
+    #[test]
+    fn test_persist_and_recover() {
+        let path = std::env::temp_dir().join(format!("pigment_db_memory_buffered_{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let wal = WalStorage::new_memory_buffered(&path, None).unwrap();
+        wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+        wal.store_put_event(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        wal.persist().unwrap();
+        wal.store_put_event(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(wal.wal_stats().unwrap().blocks, 3);
+
+        // a crash now loses only `c`
+        let persisted = read_forward(&std::fs::read(&path).unwrap());
+        assert_eq!(persisted.len(), 2);
+        assert_eq!(persisted.get(b"b".as_slice()), Some(&b"2".to_vec()));
+
+        drop(wal);
+        let recovered = read_forward(&std::fs::read(&path).unwrap());
+        assert_eq!(recovered.len(), 3);
+        assert_eq!(recovered.get(b"c".as_slice()), Some(&b"3".to_vec()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::model::{SearchKey, SortedMapEntry, SortedMapKey};
+use crate::wal::model::*;
+use crate::wal::try_iter_actions;
+
+/// How binary keys and values are written into JSON strings by [`export_ndjson`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryEncoding {
+    Base64,
+    Hex,
+}
+
+impl BinaryEncoding {
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            BinaryEncoding::Base64 => base64(bytes),
+            BinaryEncoding::Hex => faster_hex::hex_string(bytes),
+        }
+    }
+}
+
+/// Dumps the WAL as newline-delimited JSON, one object per block in file order, e.g.
+/// `{"seq":0,"offset":0,"op":"put","key":"YQ==","value":"QQ=="}`. Returns the number of written lines.
+///
+/// Never panics on a corrupt WAL: a block with a CRC mismatch or undecodable data becomes an `"error"` record,
+/// a truncated block becomes a last `"error"` record, since nothing after it can be located.
+pub fn export_ndjson(bytes: &[u8], mut writer: impl Write, encoding: BinaryEncoding) -> io::Result<usize> {
+    let (header, _body) = WalHeader::split(bytes);
+    let mut lines = 0;
+
+    for (seq, stored_action) in try_iter_actions(bytes).enumerate() {
+        let line = match stored_action {
+            Ok(stored_action) => {
+                let mut line = format!("{{\"seq\":{},\"offset\":{}", seq, stored_action.start_offset());
+                let fields = if !stored_action.valid_crc(header.crc_scope()) {
+                    Err(format!("crc mismatch, expected: {}", stored_action.crc()))
+                } else {
+                    action_fields(&stored_action, encoding)
+                };
+                match fields {
+                    Ok(fields) => line.push_str(&fields),
+                    Err(error) => write!(line, ",\"error\":{}", json_string(&error)).unwrap(),
+                }
+                line
+            }
+            Err(offset) => format!("{{\"seq\":{},\"offset\":{},\"error\":\"truncated block\"", seq, offset),
+        };
+        writeln!(writer, "{}}}", line)?;
+        lines += 1;
+    }
+    writer.flush()?;
+
+    Ok(lines)
+}
+
+fn action_fields(stored_action: &StoredAction, encoding: BinaryEncoding) -> Result<String, String> {
+    let binary = |bytes: &[u8]| json_string(&encoding.encode(bytes));
+
+    let fields = match *stored_action.act_type() {
+        DELETE_ACT => format!(",\"op\":\"delete\",\"key\":{}", binary(stored_action.data())),
+        PUT_ACT | MERGE_ACT | SET_APPEND_ACT | SET_REMOVE_ACT => {
+            let (op, value_field) = match *stored_action.act_type() {
+                PUT_ACT => ("put", "value"),
+                MERGE_ACT => ("merge", "operand"),
+                SET_APPEND_ACT => ("set_append", "element"),
+                _ => ("set_remove", "element"),
+            };
+            let key_value: KeyValueData = deserialize(stored_action.data())?;
+            let (key, value) = key_value.owned_key_value();
+            format!(",\"op\":\"{}\",\"key\":{},\"{}\":{}", op, binary(&key), value_field, binary(&value))
+        }
+        PUT_MANY_ACT => {
+            let key_values: KeyValuesData = deserialize(stored_action.data())?;
+            let entries: Vec<String> = key_values.owned_entries().iter()
+                .map(|(key, value)| format!("{{\"key\":{},\"value\":{}}}", binary(key), binary(value)))
+                .collect();
+            format!(",\"op\":\"put_many\",\"entries\":[{}]", entries.join(","))
+        }
+        VERSIONED_PUT_ACT => {
+            let key_value_version: VersionedKeyValueData = deserialize(stored_action.data())?;
+            let (key, value, version) = key_value_version.owned_key_value_version();
+            format!(",\"op\":\"versioned_put\",\"key\":{},\"value\":{},\"version\":{}", binary(&key), binary(&value), version)
+        }
+        EXPIRE_ACT => {
+            let expiry: ExpiryData = deserialize(stored_action.data())?;
+            let (key, expires_at) = expiry.owned_key_expires_at();
+            format!(",\"op\":\"expire\",\"key\":{},\"expires_at\":{}", binary(&key), expires_at)
+        }
+        SET_APPEND_MANY_ACT => {
+            let key_elements: SetElementsData = deserialize(stored_action.data())?;
+            let (key, elements) = key_elements.owned_key_elements();
+            let elements: Vec<String> = elements.iter().map(|element| binary(element)).collect();
+            format!(",\"op\":\"set_append_many\",\"key\":{},\"elements\":[{}]", binary(&key), elements.join(","))
+        }
+        SET_MOVE_ACT => {
+            let set_move: SetMoveData = deserialize(stored_action.data())?;
+            let (from_key, to_key, member) = set_move.owned_from_to_member();
+            format!(",\"op\":\"set_move\",\"from_key\":{},\"to_key\":{},\"member\":{}", binary(&from_key), binary(&to_key), binary(&member))
+        }
+        MAP_PUT_ACT => {
+            let entry: SortedMapEntry = deserialize(stored_action.data())?;
+            let (key, search_key, value) = entry.entry();
+            format!(",\"op\":\"map_put\",\"key\":{},\"search_key\":{},\"value\":{}",
+                    binary(&key), search_key_string(&search_key), binary(&value))
+        }
+        MAP_REMOVE_ACT => {
+            let sorted_map_key: SortedMapKey = deserialize(stored_action.data())?;
+            let (key, search_key) = sorted_map_key.owned();
+            format!(",\"op\":\"map_remove\",\"key\":{},\"search_key\":{}", binary(&key), search_key_string(&search_key))
+        }
+        TX_BEGIN_ACT => {
+            let tx_record: TxRecord = deserialize(stored_action.data())?;
+            format!(",\"op\":\"tx_begin\",\"tx_id\":{},\"ops\":{}", tx_record.id(), tx_record.ops().len())
+        }
+        TX_END_ACT => {
+            let tx_id = stored_action.data().try_into().map(u64::from_ne_bytes).map_err(|_| "tx id is not 8 bytes".to_string())?;
+            format!(",\"op\":\"tx_end\",\"tx_id\":{}", tx_id)
+        }
+        PADDING_ACT => format!(",\"op\":\"padding\",\"len\":{}", stored_action.block_len()),
+        TIMESTAMP_ACT => format!(",\"op\":\"timestamp\",\"at\":{}", stored_action.timestamp().ok_or_else(|| "timestamp is not 8 bytes".to_string())?),
+        act_type => return Err(format!("not supported action type: {}", act_type)),
+    };
+    Ok(fields)
+}
+
+fn deserialize<'a, T: serde::Deserialize<'a>>(data: &'a [u8]) -> Result<T, String> {
+    bincode::deserialize(data).map_err(|error| format!("undecodable data: {}", error))
+}
+
+fn search_key_string(search_key: &SearchKey) -> String {
+    json_string(&format!("{:?}", search_key.slice()))
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => write!(json, "\\u{:04x}", ch as u32).unwrap(),
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+    json
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(triple >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::WalStorage;
+
+    #[test]
+    fn test_export_ndjson() {
+        let wal = WalStorage::new_vec_based();
+        wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+        wal.store_append_many_to_set_event(b"s".to_vec(), vec![b"x".to_vec(), b"y".to_vec()]).unwrap();
+        wal.store_put_to_map_event(b"m".to_vec(), 1.into(), b"\"quoted\"".to_vec()).unwrap();
+        wal.store_delete_event(b"a").unwrap();
+        let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+        let mut out = Vec::new();
+        let lines = export_ndjson(&bytes, &mut out, BinaryEncoding::Base64).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(lines, 4);
+        assert_eq!(out.lines().count(), crate::wal::iter_actions(&bytes).count());
+        assert_eq!(out.lines().next().unwrap(), r#"{"seq":0,"offset":0,"op":"put","key":"YQ==","value":"QQ=="}"#);
+        assert!(out.lines().nth(3).unwrap().ends_with(r#""op":"delete","key":"YQ=="}"#));
+
+        let mut out = Vec::new();
+        export_ndjson(&bytes, &mut out, BinaryEncoding::Hex).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().nth(1).unwrap().ends_with(r#""op":"set_append_many","key":"73","elements":["78","79"]}"#));
+        assert!(out.lines().nth(2).unwrap().contains(r#""search_key":"[USIZE(1)]","value":"2271756f74656422""#));
+    }
+
+    #[test]
+    fn test_export_corrupt_wal() {
+        let wal = WalStorage::new_vec_based();
+        wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+        wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+        let mut bytes = wal.read_bytes(|bytes| bytes.to_vec());
+        let header_len = HEADER_LEN as usize;
+        bytes[header_len + FIXED_BLOCK_LEN as usize - 4] ^= 0xFF;
+        bytes.truncate(bytes.len() - 2);
+
+        let mut out = Vec::new();
+        let lines = export_ndjson(&bytes, &mut out, BinaryEncoding::Base64).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(lines, 2);
+        assert!(out.lines().next().unwrap().contains(r#""error":"crc mismatch"#));
+        assert!(out.lines().nth(1).unwrap().contains(r#""error":"truncated block""#));
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::wal::model::*;
+use crate::wal::{try_build_action, ReadableWal, WalStorage};
+
+/// Reads blocks appended to a live WAL in order, started by [`WalStorage::follow`] (e.g. to feed a replica or a
+/// change feed). Only bytes up to the offset the writer advanced to after a complete write are read, so a block
+/// still being written is never seen half way. Blocks are yielded as written, CRC is not verified.
+///
+/// Iterating waits for the next block when caught up, [`WalFollower::try_next`] returns instead. Blocks move when
+/// the WAL is rewritten (compacted, or a capped WAL making room), after which a follower fails once it yielded the
+/// blocks read before.
+pub struct WalFollower<'a, W: ReadableWal> {
+    wal: &'a WalStorage<W>,
+    /// Offset right after the last block read from the WAL.
+    read_offset: u32,
+    /// Times the WAL was rewritten when the follower started.
+    rewrites: u64,
+    pending: VecDeque<StoredAction>,
+}
+
+impl<'a, W: ReadableWal> WalFollower<'a, W> {
+    pub(crate) fn new(wal: &'a WalStorage<W>, from_offset: u32) -> Self {
+        WalFollower { wal, read_offset: from_offset, rewrites: wal.appended.rewrites(), pending: VecDeque::new() }
+    }
+
+    /// Offset of the next block to yield, to resume following from with a new follower.
+    pub fn offset(&self) -> u64 {
+        self.pending.front().map_or(self.read_offset, |stored_action| *stored_action.start_offset()) as u64
+    }
+
+    /// Next block if it's already written, `Ok(None)` when the follower has caught up with the writer. Fails once
+    /// the WAL was rewritten, as its offset doesn't point to a block anymore.
+    pub fn try_next(&mut self) -> io::Result<Option<StoredAction>> {
+        if self.pending.is_empty() {
+            let written_offset = *self.wal.appended.written_offset.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            self.read_up_to(written_offset)?;
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    /// Same as [`WalFollower::try_next`], waiting at most `timeout` for the next block to be appended.
+    pub fn next_timeout(&mut self, timeout: Duration) -> io::Result<Option<StoredAction>> {
+        self.wait_next(Some(Instant::now() + timeout))
+    }
+
+    fn wait_next(&mut self, deadline: Option<Instant>) -> io::Result<Option<StoredAction>> {
+        loop {
+            if let Some(stored_action) = self.try_next()? {
+                return Ok(Some(stored_action));
+            }
+
+            let notifier = &self.wal.appended;
+            let mut written_offset = notifier.written_offset.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            // checked under the lock the writer notifies with, so an append or a rewrite after `try_next` isn't missed
+            while *written_offset <= self.read_offset && notifier.rewrites() == self.rewrites {
+                written_offset = match deadline {
+                    None => notifier.appended.wait(written_offset).unwrap_or_else(|poisoned| poisoned.into_inner()),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Ok(None);
+                        }
+                        notifier.appended.wait_timeout(written_offset, deadline - now)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner()).0
+                    }
+                };
+            }
+        }
+    }
+
+    fn read_up_to(&mut self, written_offset: u32) -> io::Result<()> {
+        self.check_not_rewritten()?;
+        if written_offset <= self.read_offset {
+            return Ok(());
+        }
+        let header_len = self.wal.header.encoded_len();
+        let range = header_len + self.read_offset as usize..header_len + written_offset as usize;
+        let bytes = {
+            let r_lock = self.wal.wal_state.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            // rewrites happen under the write lock, so `written_offset` is one of this writer if none happened
+            self.check_not_rewritten()?;
+            r_lock.writer.read_written_range(range)?
+        };
+
+        let mut actions = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match try_build_action(&mut offset, &bytes) {
+                Some(stored_action) => actions.push(stored_action),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("truncated WAL block at offset {}", self.read_offset as usize + offset)));
+                }
+            }
+        }
+        self.pending.extend(actions);
+        self.read_offset = written_offset;
+        Ok(())
+    }
+
+    fn check_not_rewritten(&self) -> io::Result<()> {
+        if self.wal.appended.rewrites() != self.rewrites {
+            return Err(io::Error::other(format!("WAL was rewritten, the block at offset {} moved", self.read_offset)));
+        }
+        Ok(())
+    }
+}
+
+impl<W: ReadableWal> Iterator for WalFollower<'_, W> {
+    type Item = StoredAction;
+
+    /// Blocks until the next block is appended. Ends only if reading the WAL fails, which is logged; a new follower
+    /// can resume from [`WalFollower::offset`].
+    fn next(&mut self) -> Option<StoredAction> {
+        match self.wait_next(None) {
+            Ok(stored_action) => stored_action,
+            Err(error) => {
+                error!("following WAL failed at offset {}: {}", self.offset(), error);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Another opener holds the lock of the WAL at `path`, e.g. a second process started on the same store directory.
+/// Returned inside an `io::Error` of kind `ResourceBusy` by [`WalLock::acquire`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlreadyLocked {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for AlreadyLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is locked by another opener of the store", self.path.display())
+    }
+}
+
+impl std::error::Error for AlreadyLocked {}
+
+/// Exclusive advisory lock on a lock file next to a WAL, held by a store from before it touches the WAL until it's
+/// dropped. Closing the file releases the lock, also when the process dies. The lock file is left in place, removing
+/// it could let a later opener lock a new file while the old one is still held.
+#[derive(Debug)]
+pub struct WalLock {
+    _file: File,
+}
+
+impl WalLock {
+    /// Takes the lock on `lock_path`, creating the file if needed. Fails right away with an [`AlreadyLocked`] if it
+    /// is held, by this process or another one.
+    pub fn acquire(lock_path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(WalLock { _file: file }),
+            Err(TryLockError::WouldBlock) => {
+                Err(io::Error::new(io::ErrorKind::ResourceBusy, AlreadyLocked { path: lock_path.to_path_buf() }))
+            }
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::wal::model::*;
+use crate::wal::{encode_blocks, or_abort, replay_blocks, CorruptionPolicy, ForwardReplay};
+
+/// Single compacted KeyValue WAL holding the state of `incremental` replayed on top of `base`, e.g. to consolidate
+/// a snapshot with the WAL written after it: keys put by `incremental` take its values, keys it removed are left
+/// out. Like the rewrite of a restart, each live entry is written as one versioned put (plus its expiry if any), in
+/// key order, with offsets chained from the start of the new WAL. The header keeps whether `base` has CRCs.
+/// Panics on a corrupted block or a merge action, as neither WAL can be replayed without a merge operator then.
+pub fn merge_wals(base: &[u8], incremental: &[u8]) -> Vec<u8> {
+    let mut replay = ForwardReplay::new(None, None);
+    for bytes in [base, incremental] {
+        or_abort(replay_blocks(bytes, CorruptionPolicy::Abort, |stored_action| replay.apply(stored_action)));
+    }
+    let (base_header, _) = WalHeader::split(base);
+    let header = WalHeader::new(base_header.crc_enabled()).with_store_type(StoreType::KeyValue);
+
+    let mut entries: Vec<_> = replay.result.into_iter().collect();
+    entries.sort_unstable_by(|(key, _), (other, _)| key.cmp(other));
+    let mut merged = header.to_bytes();
+    let mut offset = 0;
+    for (key, (value, version)) in entries {
+        let expiry = replay.expirations.get(&key).map(|expires_at| ExpiryData::new(key.clone(), *expires_at));
+        let mut actions = vec![StoredAction::versioned_put_action(&offset, &VersionedKeyValueData::new(key, value, version), header.crc_scope())];
+        offset += actions[0].block_len() as u32;
+        if let Some(expiry) = expiry {
+            actions.push(StoredAction::expire_action(&offset, &expiry, header.crc_scope()));
+            offset += actions[1].block_len() as u32;
+        }
+        merged.extend_from_slice(&encode_blocks(&actions));
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{iter_actions, read_backward, read_forward_versioned, WalStorage};
+
+    #[test]
+    fn test_merge_wals() {
+        let base = WalStorage::new_vec_based();
+        for key in [b"a", b"b", b"c", b"d"] {
+            base.store_put_event(key.to_vec(), b"base".to_vec()).unwrap();
+        }
+        base.store_put_event(b"b".to_vec(), b"base again".to_vec()).unwrap();
+        let incremental = WalStorage::new_vec_based();
+        incremental.store_put_event(b"b".to_vec(), b"incremental".to_vec()).unwrap();
+        incremental.store_delete_event(b"c").unwrap();
+        incremental.store_put_event(b"e".to_vec(), b"incremental".to_vec()).unwrap();
+        incremental.store_delete_event(b"absent").unwrap();
+        let base = base.read_bytes(|bytes| bytes.to_vec());
+        let incremental = incremental.read_bytes(|bytes| bytes.to_vec());
+
+        let merged = merge_wals(&base, &incremental);
+        let entries = read_forward_versioned(&merged, None);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[b"a".as_slice()], (b"base".to_vec(), 1));
+        assert_eq!(entries[b"b".as_slice()], (b"incremental".to_vec(), 3));
+        assert_eq!(entries[b"d".as_slice()], (b"base".to_vec(), 1));
+        assert_eq!(entries[b"e".as_slice()], (b"incremental".to_vec(), 1));
+
+        // offsets chain back to the start, so the merged WAL reads backward as well
+        assert_eq!(read_backward(&merged).unwrap().len(), 4);
+        assert_eq!(iter_actions(&merged).count(), 4);
+        assert_eq!(merge_wals(&merged, &[]), merged);
+    }
+}
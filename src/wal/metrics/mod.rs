@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+
+/// Store mutation timed by [`WalMetrics::on_operation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Put,
+    PutBatch,
+    Remove,
+}
+
+/// Sink of the latencies of a WAL and of the store writing to it, see [`crate::wal::WalStorage::with_metrics`]. Every
+/// callback does nothing by default. They're called on the writing thread, some with locks held, so they should only
+/// record the duration, e.g. into a histogram. Without a sink nothing is timed.
+pub trait WalMetrics: Send + Sync {
+    /// Time an append held the WAL write lock to write `bytes`, retries included.
+    fn on_write(&self, _held: Duration, _bytes: usize) {}
+
+    /// Time a store mutation took, from waiting for the entry lock to the in-memory update.
+    fn on_operation(&self, _operation: Operation, _duration: Duration) {}
+
+    /// Time taken to apply a block of the previous WAL during recovery.
+    fn on_recovery_block(&self, _duration: Duration) {}
+}
+
+/// Runs `f`, reporting how long it took as `operation` to `metrics` if any. The clock isn't read without a sink.
+pub(crate) fn timed<R>(metrics: Option<&dyn WalMetrics>, operation: Operation, f: impl FnOnce() -> R) -> R {
+    match metrics {
+        None => f(),
+        Some(metrics) => {
+            let started = Instant::now();
+            let result = f();
+            metrics.on_operation(operation, started.elapsed());
+            result
+        }
+    }
+}
@@ -1,131 +1,1229 @@
-use std::sync::{RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, TryLockError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
 use std::fs::{OpenOptions, File};
-use std::borrow::{BorrowMut, Borrow};
-use std::io::{Write};
+use std::borrow::BorrowMut;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use log::{info, error};
+use log::{info, error, warn};
 
 
 use std::convert::TryInto;
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::path::Path;
-use std::array::TryFromSliceError;
-use crate::model::{SearchKey, SortedMapEntry, SortedMapKey};
+use std::path::{Path, PathBuf};
+use std::ops::Range;
+use crate::clock::{Clock, SystemClock};
+use crate::error::PigmentError;
+use crate::model::{Key, LinkedMap, MergeOperator, SearchKey, SortedMapEntry, SortedMapKey, ValueTransformer};
+use crate::reentrancy;
 use crate::wal::model::*;
 
-mod model;
+pub mod model;
+mod stats;
+mod export;
+mod segmented;
+mod preallocated;
+mod scrub;
+mod follow;
+mod buffered;
+mod repair;
+mod merge;
+mod metrics;
+mod lock;
+mod replay;
+pub(crate) mod streamed;
+#[cfg(feature = "gzip")]
+mod compressed;
+
+pub use stats::{reclaimable_blocks, wal_stats, WalStats};
+pub use export::{export_ndjson, BinaryEncoding};
+pub use segmented::{read_segments_newest_first, remove_segments, take_previous_segment_paths, take_previous_segments, NewestFirstRead, SegmentedWal};
+pub use preallocated::PreallocatedFile;
+pub use scrub::{ScrubConfig, Scrubber};
+pub use follow::WalFollower;
+pub use buffered::MemoryBufferedFile;
+pub use repair::{repair, RepairPolicy};
+pub use merge::merge_wals;
+pub use metrics::{Operation, WalMetrics};
+pub(crate) use metrics::timed;
+pub use lock::{AlreadyLocked, WalLock};
+pub use replay::{replay, replay_with_policy, ReplayVisitor};
+pub use streamed::{read_backward_seeking, read_for_map_from_reader, read_for_set_from_reader, read_forward_transformed_from_reader};
+pub(crate) use streamed::{into_io, map_or_stream, wal_stats_from_reader, WalContent};
+#[cfg(feature = "gzip")]
+pub use compressed::open_read_only_compressed;
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_micros(100);
 
 struct WalState<W: Write> {
     offset: u32,
     writer: W,
+    /// Set while blocks are handed to the writer, and kept set if the writer fails after taking part of them: a
+    /// writer panicking or failing meanwhile may have left a torn block behind.
+    writing: bool,
+    /// Incremented whenever the WAL is rewritten, which moves its blocks.
+    rewrites: u64,
+}
+
+/// Failure of a WAL write which, unlike `io::Error`, tells lock contention and poisoning apart from I/O errors.
+#[derive(Debug)]
+pub enum WalError {
+    /// The write lock wasn't acquired before the timeout.
+    WouldBlock,
+    /// A writer panicked or failed partway while writing blocks, so the end of the WAL may be torn and nothing
+    /// more is appended.
+    Poisoned,
+    Io(io::Error),
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalError::WouldBlock => write!(f, "WAL write lock not acquired in time"),
+            WalError::Poisoned => write!(f, "WAL writer panicked or failed partway while writing blocks"),
+            WalError::Io(error) => write!(f, "WAL write failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl From<io::Error> for WalError {
+    fn from(error: io::Error) -> Self {
+        WalError::Io(error)
+    }
+}
+
+/// Entries returned by the store events, with the plain values given back in place of the encoded ones.
+fn with_plain_values(entries: Vec<(Vec<u8>, Vec<u8>)>, plain_values: Vec<Option<Vec<u8>>>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    entries.into_iter().zip(plain_values)
+        .map(|((key, stored), plain)| (key, plain.unwrap_or(stored)))
+        .collect()
+}
+
+/// Failure of a WAL replay which its [`CorruptionPolicy`] doesn't deal with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalReadError {
+    /// Data of the block at `offset` (relative to the end of the header) passed CRC verification but isn't a valid
+    /// encoding of its action type, e.g. as written by another codec.
+    DecodeFailed { offset: usize },
+    /// Data of the block at `offset` doesn't match its CRC.
+    CrcMismatch { offset: usize },
+    /// The block at `offset` runs past the end of the body at `end`.
+    CutShort { offset: usize, end: usize },
+}
+
+impl fmt::Display for WalReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalReadError::DecodeFailed { offset } => write!(f, "can't decode WAL block at offset {}", offset),
+            WalReadError::CrcMismatch { offset } => write!(f, "CRC mismatch of WAL block at offset {}", offset),
+            WalReadError::CutShort { offset, end } => write!(f, "WAL block at offset {} is cut short at {}", offset, end),
+        }
+    }
+}
+
+impl std::error::Error for WalReadError {}
+
+impl From<WalReadError> for io::Error {
+    fn from(error: WalReadError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+impl From<WalError> for io::Error {
+    fn from(error: WalError) -> Self {
+        match error {
+            WalError::WouldBlock => io::Error::new(io::ErrorKind::WouldBlock, error),
+            WalError::Poisoned => io::Error::other(error),
+            WalError::Io(error) => error,
+        }
+    }
+}
+
+/// A WAL recorded as written by another store than the one opening it, e.g. a directory pointed at the wrong
+/// store. Returned inside an `io::Error` of kind `InvalidData`, see [`check_store_type`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreTypeMismatch {
+    pub expected: StoreType,
+    pub found: StoreType,
+}
+
+impl fmt::Display for StoreTypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WAL was written by a {} store, not a {} store", self.found, self.expected)
+    }
+}
+
+impl std::error::Error for StoreTypeMismatch {}
+
+/// Fails with a [`StoreTypeMismatch`] if the WAL in `file_path` records another store type than `expected`. A
+/// missing or empty file and a WAL without a recorded store type pass, nothing is read past the header.
+pub fn check_store_type(file_path: &Path, expected: StoreType) -> io::Result<()> {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut header_bytes = Vec::with_capacity(MAX_HEADER_LEN as usize);
+    file.take(MAX_HEADER_LEN as u64).read_to_end(&mut header_bytes)?;
+
+    match WalHeader::split(&header_bytes).0.store_type() {
+        Some(found) if found != expected => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, StoreTypeMismatch { expected, found }))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Data of a block longer than its `u32` data size can represent, e.g. a put of a huge value. Returned inside an
+/// `io::Error` of kind `InvalidInput` before anything is written, instead of a block with a truncated size which
+/// would corrupt the WAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+    pub len: usize,
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WAL block data of {} bytes is over the limit of {} bytes", self.len, u32::MAX)
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+fn check_payload_len(len: usize) -> Result<(), PayloadTooLarge> {
+    if len > u32::MAX as usize {
+        return Err(PayloadTooLarge { len });
+    }
+    Ok(())
 }
 
 pub struct WalStorage<W: Write> {
-    wal_state: RwLock<WalState<W>>
+    wal_state: RwLock<WalState<W>>,
+    header: WalHeader,
+    capacity_limit: Option<CapacityLimit<W>>,
+    appended: Arc<AppendNotifier>,
+    retry_policy: Option<RetryPolicy>,
+    value_transformer: Option<Arc<dyn ValueTransformer>>,
+    /// File of a file based WAL, replaced by [`WalStorage::compact_key_value`].
+    file_path: Option<PathBuf>,
+    /// Held by [`WalStorage::compact_key_value`], so compactions don't overlap.
+    compacting: Mutex<()>,
+    /// Time of the writes to a WAL with [`TIMESTAMPED_FLAG`].
+    clock: Arc<dyn Clock>,
+    metrics: Option<Arc<dyn WalMetrics>>,
+}
+
+/// Retries of a write failing with a transient error (`ErrorKind::Interrupted` or `ErrorKind::WouldBlock`), e.g. on
+/// networked storage, see [`WalStorage::with_retry_policy`]. Other errors, like `ErrorKind::StorageFull`, fail
+/// right away. Without a policy only `Interrupted` is retried, as `write_all` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Pause before the first retry, doubled before each next one.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Pauses before the next attempt if `error` is transient and attempts are left, otherwise returns `error`.
+    fn pause_or_fail(&self, failed_attempts: u32, error: io::Error) -> io::Result<()> {
+        if !is_transient(&error) || failed_attempts >= self.max_attempts {
+            return Err(error);
+        }
+        thread::sleep(self.backoff.saturating_mul(1 << (failed_attempts - 1).min(16)));
+        Ok(())
+    }
+}
+
+fn is_transient(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
+
+/// Offset up to which blocks are completely written, for followers waiting on new blocks, and how many times the
+/// WAL was rewritten, for readers holding offsets (followers, the scrubber) to notice their blocks moved.
+pub(crate) struct AppendNotifier {
+    written_offset: Mutex<u32>,
+    /// Only changed under `written_offset`, so a follower checking both under it can't miss a rewrite.
+    rewrites: AtomicU64,
+    appended: Condvar,
+}
+
+impl AppendNotifier {
+    fn new(offset: u32) -> Self {
+        AppendNotifier { written_offset: Mutex::new(offset), rewrites: AtomicU64::new(0), appended: Condvar::new() }
+    }
+
+    fn notify(&self, offset: u32) {
+        *self.written_offset.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = offset;
+        self.appended.notify_all();
+    }
+
+    /// The WAL was rewritten up to `offset`, for the `rewrites`-th time.
+    fn rewritten(&self, offset: u32, rewrites: u64) {
+        let mut written_offset = self.written_offset.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *written_offset = offset;
+        self.rewrites.store(rewrites, Ordering::Release);
+        self.appended.notify_all();
+    }
+
+    fn rewrites(&self) -> u64 {
+        self.rewrites.load(Ordering::Acquire)
+    }
+}
+
+struct CapacityLimit<W> {
+    max_bytes: usize,
+    /// Drops blocks which don't contribute to the current state, returns the new offset.
+    compact: fn(&mut W) -> io::Result<u32>,
+}
+
+impl<W> CapacityLimit<W> {
+    fn fits(&self, offset: u32, blocks_len: usize) -> bool {
+        HEADER_LEN as usize + offset as usize + blocks_len <= self.max_bytes
+    }
 }
 
 impl WalStorage<File> {
     pub fn new_file_based(file_path: &Path) -> Self {
-        let file = OpenOptions::new().write(true).append(true).create_new(true)
-            .open(file_path).unwrap();
+        Self::new_file_based_with_header(file_path, WalHeader::new(true))
+    }
 
-        let wal_state = WalState { offset: 0, writer: file };
-        let wal_state = RwLock::new(wal_state);
+    /// Same as [`WalStorage::new_file_based`], recording `store_type` in the header, see [`check_store_type`].
+    pub fn new_file_based_for_store(file_path: &Path, store_type: StoreType) -> Self {
+        Self::new_file_based_with_header(file_path, WalHeader::new(true).with_store_type(store_type))
+    }
+
+    /// CRC is neither written nor verified, for WALs which are trusted or thrown away anyway.
+    pub fn new_file_based_without_crc(file_path: &Path) -> Self {
+        Self::new_file_based_with_header(file_path, WalHeader::new(false))
+    }
+
+    /// Records the time of each write by `clock`, see [`TIMESTAMPED_FLAG`] and [`collect_as_of`].
+    pub fn new_file_based_timestamped(file_path: &Path, clock: Arc<dyn Clock>) -> Self {
+        Self::new_file_based_with_header(file_path, WalHeader::new(true).timestamped()).with_clock(clock)
+    }
+
+    /// Continues the WAL in `file_path` (creating it if missing): new blocks are appended after the existing ones
+    /// instead of the WAL being rewritten, which keeps offsets of already written blocks valid.
+    pub fn open_file_based(file_path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(file_path)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len == 0 {
+            return Ok(Self::with_header(file, WalHeader::new(true))?.with_file_path(file_path));
+        }
+
+        let mut header_bytes = vec![0; file_len.min(MAX_HEADER_LEN as usize)];
+        file.read_exact(&mut header_bytes)?;
+        let (header, _) = WalHeader::split(&header_bytes);
+        if header.preallocated() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "preallocated WAL can't be continued by appending"));
+        }
+        let offset = (file_len - header.encoded_len()) as u32;
+
+        let wal_state = RwLock::new(WalState { offset, writer: file, writing: false, rewrites: 0 });
+        Ok(WalStorage { wal_state, header, capacity_limit: None, appended: Arc::new(AppendNotifier::new(offset)), retry_policy: None, value_transformer: None,
+                        file_path: Some(file_path.to_path_buf()), compacting: Mutex::new(()), clock: Arc::new(SystemClock), metrics: None })
+    }
+
+    /// Every block starts on a multiple of `alignment` bytes in the file (e.g. 512 or 4096 for direct I/O), the gaps
+    /// are filled with padding blocks. `alignment` must be a power of two.
+    pub fn new_file_based_aligned(file_path: &Path, alignment: u32) -> io::Result<Self> {
+        if !alignment.is_power_of_two() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("WAL alignment {} is not a power of two", alignment)));
+        }
+        let file = OpenOptions::new().read(true).append(true).create_new(true).open(file_path)?;
+
+        Ok(Self::with_header(file, WalHeader::new_aligned(alignment))?.with_file_path(file_path))
+    }
+
+    /// Starts re-verifying CRCs of the written blocks in the background, see [`Scrubber`]. The scrubber moves on to
+    /// the rewritten file when the WAL is compacted.
+    pub fn start_scrubber(&self, config: ScrubConfig, on_corruption: impl Fn(usize) + Send + 'static) -> io::Result<Scrubber> {
+        let file = self.wal_state.read().unwrap().writer.try_clone()?;
+        Scrubber::start(file, self.file_path.clone(), self.appended.clone(), config, on_corruption)
+    }
+
+    fn new_file_based_with_header(file_path: &Path, header: WalHeader) -> Self {
+        let file = OpenOptions::new().read(true).append(true).create_new(true)
+            .open(file_path).unwrap();
 
-        WalStorage { wal_state }
+        Self::with_header(file, header).unwrap().with_file_path(file_path)
     }
 }
 
 impl WalStorage<Vec<u8>> {
     pub fn new_vec_based() -> Self {
-        let vec = Vec::new();
+        Self::with_header(Vec::new(), WalHeader::new(true)).unwrap()
+    }
+
+    /// Skips CRC computation on write and verification on read, the data never leaves memory anyway.
+    pub fn new_vec_based_without_crc() -> Self {
+        Self::with_header(Vec::new(), WalHeader::new(false)).unwrap()
+    }
+
+    /// Records the time of each write by `clock`, see [`TIMESTAMPED_FLAG`] and [`collect_as_of`].
+    pub fn new_vec_based_timestamped(clock: Arc<dyn Clock>) -> Self {
+        Self::with_header(Vec::new(), WalHeader::new(true).timestamped()).unwrap().with_clock(clock)
+    }
 
-        let wal_state = WalState { offset: 0, writer: vec };
+    /// Keeps the WAL within `max_bytes`: when the next block doesn't fit, the Vec is compacted in place to the
+    /// blocks still contributing to the current state. Writes fail with `ErrorKind::OutOfMemory` if that's not enough.
+    pub fn new_vec_based_capped(max_bytes: usize) -> Self {
+        let mut wal = Self::new_vec_based();
+        wal.capacity_limit = Some(CapacityLimit { max_bytes, compact: compact_vec });
+        wal
+    }
+}
+
+impl WalStorage<SegmentedWal> {
+    /// WAL split into segments of about `max_segment_bytes`, see [`SegmentedWal`].
+    pub fn new_segmented(dir: &Path, name: &str, max_segment_bytes: u64) -> io::Result<Self> {
+        let header = WalHeader::new(true);
+        let segmented = SegmentedWal::create(dir, name, header, max_segment_bytes)?;
+
+        Self::with_header(segmented, header)
+    }
+}
+
+impl WalStorage<PreallocatedFile> {
+    /// File based WAL with `size` bytes reserved up front, so appends don't grow the file block by block.
+    /// The logical end is tracked in the header, see [`PreallocatedFile`].
+    pub fn new_file_based_preallocated(file_path: &Path, size: u64) -> io::Result<Self> {
+        let header = WalHeader::new_preallocated();
+        let file = PreallocatedFile::create(file_path, header, size)?;
+
+        Self::with_header(file, header)
+    }
+}
+
+impl WalStorage<MemoryBufferedFile> {
+    /// WAL kept in memory and persisted to `file_path` every `persist_interval` (if given), on close and when
+    /// dropped. Writes since the last persist are lost on a crash, see [`MemoryBufferedFile`].
+    pub fn new_memory_buffered(file_path: &Path, persist_interval: Option<Duration>) -> io::Result<Self> {
+        let file = MemoryBufferedFile::create(file_path, persist_interval)?;
+
+        Self::with_header(file, WalHeader::new(true))
+    }
+
+    /// Writes the blocks appended since the last persist to the file.
+    pub fn persist(&self) -> io::Result<()> {
+        self.wal_state.read().unwrap().writer.persist()
+    }
+}
+
+impl<W: Write> WalStorage<W> {
+    pub fn new_writer_based(writer: W) -> Self {
+        Self::with_header(writer, WalHeader::new(true)).expect("WAL header should be written")
+    }
+
+    fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn with_file_path(mut self, file_path: &Path) -> Self {
+        self.file_path = Some(file_path.to_path_buf());
+        self
+    }
+
+    fn with_header(mut writer: W, header: WalHeader) -> io::Result<Self> {
+        writer.write_all(&header.to_bytes())?;
+        writer.flush()?;
+
+        let wal_state = WalState { offset: 0, writer, writing: false, rewrites: 0 };
         let wal_state = RwLock::new(wal_state);
 
-        WalStorage { wal_state }
+        Ok(WalStorage { wal_state, header, capacity_limit: None, appended: Arc::new(AppendNotifier::new(0)), retry_policy: None, value_transformer: None, file_path: None,
+                        compacting: Mutex::new(()), clock: Arc::new(SystemClock), metrics: None })
+    }
+
+    /// Retries writes failing transiently as `policy` allows before returning the error. Bytes the writer already
+    /// accepted aren't written again, so a retried block isn't duplicated.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Encodes the values of KeyValue puts and merges with `transformer` before they're written, the events still
+    /// return the plain values. The CRC covers the encoded bytes, as they are on disk. Only
+    /// [`read_forward_transformed_with_policy`] given the same transformer reads the plain values back, other
+    /// readers of the WAL see encoded ones.
+    pub fn with_value_transformer(mut self, transformer: Arc<dyn ValueTransformer>) -> Self {
+        self.value_transformer = Some(transformer);
+        self
+    }
+
+    pub fn value_transformer(&self) -> Option<Arc<dyn ValueTransformer>> {
+        self.value_transformer.clone()
+    }
+
+    /// Reports how long each append holds the write lock to `metrics`, see [`WalMetrics`].
+    pub fn with_metrics(mut self, metrics: Arc<dyn WalMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn metrics(&self) -> Option<&dyn WalMetrics> {
+        self.metrics.as_deref()
+    }
+
+    /// Offset the next block will be written at, i.e. the end of the blocks written so far. Relative to the end of
+    /// the header like block start offsets, so it can be given to [`WalStorage::follow`].
+    pub fn current_offset(&self) -> u64 {
+        self.wal_state.read().unwrap().offset as u64
+    }
+}
+
+/// Writer whose already written bytes can be read back, e.g. for diagnostics over a live WAL.
+pub trait ReadableWal: Write {
+    fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R>;
+
+    /// Copy of the written bytes in `range`, which must be within what was written.
+    fn read_written_range(&self, range: Range<usize>) -> io::Result<Vec<u8>> {
+        self.read_written(|bytes| bytes[range].to_vec())
+    }
+}
+
+impl ReadableWal for Vec<u8> {
+    fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        Ok(func(self))
+    }
+}
+
+impl ReadableWal for File {
+    fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        // the file is opened in append mode, moving the cursor doesn't affect where blocks are written
+        let mut file = self;
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        Ok(func(&bytes))
+    }
+
+    fn read_written_range(&self, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let mut file = self;
+        let mut bytes = vec![0; range.len()];
+        file.seek(SeekFrom::Start(range.start as u64))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Writer which can push what it has written down to durable storage, beyond what `flush` guarantees.
+pub trait SyncWal: Write {
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl SyncWal for Vec<u8> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SyncWal for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// Writer whose content can be swapped for a rewritten WAL, see [`WalStorage::compact_key_value`].
+pub trait CompactableWal: ReadableWal + Sized {
+    /// Empty writer the rewritten WAL is written to, while this one keeps taking writes. `path` is the file of a
+    /// file based WAL.
+    fn replacement(&self, path: Option<&Path>) -> io::Result<Self>;
+
+    /// Makes `replacement` the writer in place of this one, later writes go to it.
+    fn replace_with(&mut self, replacement: Self, path: Option<&Path>) -> io::Result<()>;
+}
+
+impl CompactableWal for Vec<u8> {
+    fn replacement(&self, _path: Option<&Path>) -> io::Result<Self> {
+        Ok(Vec::new())
+    }
+
+    fn replace_with(&mut self, replacement: Self, _path: Option<&Path>) -> io::Result<()> {
+        *self = replacement;
+        Ok(())
+    }
+}
+
+/// The rewritten WAL is written next to `path` and renamed over it once complete, so a crash leaves one of both
+/// WALs whole.
+impl CompactableWal for File {
+    fn replacement(&self, path: Option<&Path>) -> io::Result<Self> {
+        let replacement_path = replacement_path(path)?;
+        let _ = std::fs::remove_file(&replacement_path);
+        OpenOptions::new().read(true).append(true).create_new(true).open(&replacement_path)
+    }
+
+    fn replace_with(&mut self, replacement: Self, path: Option<&Path>) -> io::Result<()> {
+        replacement.sync_all()?;
+        std::fs::rename(replacement_path(path)?, path.expect("checked by replacement_path"))?;
+        *self = replacement;
+        Ok(())
+    }
+}
+
+fn replacement_path(path: Option<&Path>) -> io::Result<PathBuf> {
+    path.map(|path| path.with_extension("compacted"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "WAL file path is not known"))
+}
+
+impl<W: SyncWal> WalStorage<W> {
+    /// Flushes and syncs the writer, then releases it. A lock poisoned outside of a write is ignored, as in
+    /// [`WalStorage::try_store_put_event`]; after a writer panicked mid-write it fails with [`WalError::Poisoned`].
+    pub fn close(self) -> io::Result<()> {
+        let mut wal_state = match self.wal_state.into_inner() {
+            Ok(wal_state) => wal_state,
+            Err(poisoned) if !poisoned.get_ref().writing => poisoned.into_inner(),
+            Err(_poisoned) => return Err(WalError::Poisoned.into()),
+        };
+        wal_state.writer.flush()?;
+        wal_state.writer.sync()
+    }
+}
+
+impl<W: CompactableWal> WalStorage<W> {
+    /// Rewrites the WAL of a KeyValue store to a versioned put, and an expiry if any, per live entry, the blocks a
+    /// restart writes. The rewrite is built from a snapshot into a replacement writer while writes go on, only blocks
+    /// appended meanwhile are copied over under the write lock before the writers are swapped. Blocks are moved, so
+    /// offsets taken before are no longer valid and followers of the WAL fail, a scrubber starts over on the new one.
+    pub fn compact_key_value(&self, merge_operator: Option<&MergeOperator>) -> io::Result<()> {
+        let _compacting = self.compacting.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (snapshot, snapshot_offset, rewrites, mut replacement) = {
+            let r_lock = self.wal_state.read().unwrap();
+            let snapshot = r_lock.writer.read_written(|bytes| bytes.to_vec())?;
+            (snapshot, r_lock.offset, r_lock.rewrites, r_lock.writer.replacement(self.file_path.as_deref())?)
+        };
+        let mut offset = self.write_compacted(&mut replacement, &snapshot, merge_operator)?;
+
+        let mut w_lock = self.lock_for_write(None)?;
+        if w_lock.rewrites != rewrites {
+            // a capped WAL compacted itself meanwhile, the snapshot offsets don't match the WAL anymore
+            replacement = w_lock.writer.replacement(self.file_path.as_deref())?;
+            let bytes = w_lock.writer.read_written(|bytes| bytes.to_vec())?;
+            offset = self.write_compacted(&mut replacement, &bytes, merge_operator)?;
+        } else if w_lock.offset != snapshot_offset {
+            let header_len = self.header.encoded_len();
+            let appended = w_lock.writer.read_written_range(header_len + snapshot_offset as usize..header_len + w_lock.offset as usize)?;
+            offset = self.write_moved(&mut replacement, &appended, offset)?;
+        }
+
+        let before = w_lock.offset;
+        w_lock.writer.replace_with(replacement, self.file_path.as_deref())?;
+        w_lock.offset = offset;
+        w_lock.rewrites += 1;
+        self.appended.rewritten(offset, w_lock.rewrites);
+        info!("compacted WAL from {} to {} bytes", before, offset);
+        Ok(())
+    }
+
+    /// Writes the header and the compacted blocks of the WAL in `bytes` to `writer`, returns the offset after them.
+    fn write_compacted(&self, writer: &mut W, bytes: &[u8], merge_operator: Option<&MergeOperator>) -> io::Result<u32> {
+        let (map, expirations, _report) = replay_forward_transformed(bytes, merge_operator, self.value_transformer.as_deref(), CorruptionPolicy::Abort, None)?;
+
+        let mut compacted = self.header.to_bytes();
+        let mut offset = 0;
+        for (key, (value, version)) in map {
+            let expiry = expirations.get(&key).map(|expires_at| ExpiryData::new(key.clone(), *expires_at));
+            let key_value_version = VersionedKeyValueData::new(key, self.encode_value(value).0, version);
+            let mut actions = vec![StoredAction::versioned_put_action(&offset, &key_value_version, self.header.crc_scope())];
+            if let Some(expiry) = expiry {
+                let expire_offset = offset + actions[0].block_len() as u32;
+                actions.push(StoredAction::expire_action(&expire_offset, &expiry, self.header.crc_scope()));
+            }
+            let actions = self.aligned(actions, offset);
+            offset += blocks_len(&actions) as u32;
+            compacted.extend_from_slice(&encode_blocks(&actions));
+        }
+        writer.write_all(&compacted)?;
+        Ok(offset)
+    }
+
+    /// Writes the blocks in `blocks` (without header) to `writer` moved to start at `offset`, padding is dropped.
+    /// Returns the offset after them.
+    fn write_moved(&self, writer: &mut W, blocks: &[u8], mut offset: u32) -> io::Result<u32> {
+        let start_offset = offset;
+        let mut block_offset = 0;
+        let mut moved = Vec::new();
+        while block_offset < blocks.len() {
+            let stored_action = build_action(&mut block_offset, blocks);
+            if *stored_action.act_type() == PADDING_ACT {
+                continue;
+            }
+            let moved_action = stored_action.moved_to(offset, self.header.crc_scope());
+            offset += moved_action.block_len() as u32;
+            moved.push(moved_action);
+        }
+        let moved = self.aligned(moved, start_offset);
+        writer.write_all(&encode_blocks(&moved))?;
+        Ok(start_offset + blocks_len(&moved) as u32)
+    }
+}
+
+impl<W: ReadableWal> WalStorage<W> {
+    /// Block counts per action type and live/total bytes of the WAL written so far.
+    pub fn wal_stats(&self) -> io::Result<WalStats> {
+        let r_lock = self.wal_state.read().unwrap();
+        r_lock.writer.read_written(wal_stats)
+    }
+
+    /// Follows blocks appended from `from_offset` on, like `tail -f`, see [`WalFollower`]. `from_offset` is relative
+    /// to the end of the header like block start offsets and must be the start of a block, e.g. 0 or the
+    /// [`WalFollower::offset`] of an earlier follower to resume where it stopped.
+    pub fn follow(&self, from_offset: u64) -> WalFollower<'_, W> {
+        WalFollower::new(self, from_offset as u32)
+    }
+
+    /// Blocks with sequence numbers from `from_seq` to `to_seq`, both included, in order, e.g. for a follower catching
+    /// up from `last_applied + 1`. The sequence number of a block is its position in the WAL counting from 0, the
+    /// `seq` of [`export_ndjson`]; it changes when a capped WAL is compacted. Found by a forward scan stopping at
+    /// `to_seq`, a range past the last block is empty.
+    pub fn actions_between(&self, from_seq: u64, to_seq: u64) -> io::Result<Vec<StoredAction>> {
+        let r_lock = self.wal_state.read().unwrap();
+        r_lock.writer.read_written(|bytes| {
+            try_iter_actions(bytes)
+                .enumerate()
+                .skip_while(|(seq, _)| (*seq as u64) < from_seq)
+                .take_while(|(seq, _)| (*seq as u64) <= to_seq)
+                .map(|(_, stored_action)| stored_action.map_err(|offset| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("truncated WAL block at offset {}", offset))
+                }))
+                .collect()
+        })?
+    }
+}
+
+impl WalStorage<Vec<u8>> {
+    /// Gives `func` access to the WAL bytes written so far, without copying them.
+    pub fn read_bytes<R>(&self, func: impl FnOnce(&[u8]) -> R) -> R {
+        func(&self.wal_state.read().unwrap().writer)
+    }
+}
+
+impl<W: Write, S: Write> WalStorage<TeeWriter<W, S>> {
+    /// Mirrors every WAL block into `secondary` (e.g. a stream to a replica) in addition to `primary`.
+    /// A failing secondary is reported through `on_secondary_error` and detached, the primary write path is not affected.
+    pub fn with_tee(primary: W, secondary: S, on_secondary_error: impl Fn(&io::Error) + Send + Sync + 'static) -> Self {
+        let writer = TeeWriter { primary, secondary: Some(secondary), on_secondary_error: Box::new(on_secondary_error) };
+
+        Self::with_header(writer, WalHeader::new(true)).expect("WAL header should be written to primary")
+    }
+}
+
+pub struct TeeWriter<W: Write, S: Write> {
+    primary: W,
+    secondary: Option<S>,
+    on_secondary_error: Box<dyn Fn(&io::Error) + Send + Sync>,
+}
+
+impl<W: Write, S: Write> TeeWriter<W, S> {
+    fn secondary_failed(&mut self, error: io::Error) {
+        error!("secondary WAL sink failed, detaching it: {}", error);
+        (self.on_secondary_error)(&error);
+        self.secondary = None;
+    }
+}
+
+impl<W: Write, S: Write> Write for TeeWriter<W, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.primary.write(buf)?;
+        if let Some(secondary) = self.secondary.as_mut() {
+            if let Err(error) = secondary.write_all(&buf[..written]) {
+                self.secondary_failed(error);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.primary.flush()?;
+        if let Some(secondary) = self.secondary.as_mut() {
+            if let Err(error) = secondary.flush() {
+                self.secondary_failed(error);
+            }
+        }
+        Ok(())
     }
 }
 
 impl<W: Write> WalStorage<W> {
-    pub fn store_put_event(&self, key: Vec<u8>, value: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
-        let mut w_lock = self.wal_state.write().unwrap();
+    pub fn store_put_event(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let (stored, plain) = self.encode_value(value);
+        let key_value = KeyValueData::new(key, stored);
+        self.append(|offset| StoredAction::put_action(offset, &key_value, self.header.crc_scope()))?;
 
-        let key_value = KeyValueData::new(key, value);
-        let put_action = StoredAction::put_action(w_lock.offset.borrow(), &key_value);
+        let (key, stored) = key_value.owned_key_value();
+        Ok((key, plain.unwrap_or(stored)))
+    }
 
-        write(w_lock.writer.borrow_mut(), &put_action);
-        increment_offset(w_lock.offset.borrow_mut(), &put_action);
+    /// Stores puts of all `entries` as a single block, which replay applies entirely or not at all.
+    pub fn store_put_many_event(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (entries, plain_values) = self.encode_values(entries);
+        let key_values = KeyValuesData::new(entries);
+        self.append(|offset| StoredAction::put_many_action(offset, &key_values, self.header.crc_scope()))?;
 
-        key_value.owned_key_value()
+        Ok(with_plain_values(key_values.owned_entries(), plain_values))
     }
 
-    pub fn store_delete_event(&self, key: &[u8]) {
-        let mut w_lock = self.wal_state.write().unwrap();
+    /// Value as written to the WAL, with the plain one alongside if a value transformer encoded it.
+    fn encode_value(&self, value: Vec<u8>) -> (Vec<u8>, Option<Vec<u8>>) {
+        match &self.value_transformer {
+            Some(transformer) => (transformer.encode(&value), Some(value)),
+            None => (value, None),
+        }
+    }
 
-        let put_action = StoredAction::delete_action(w_lock.offset.borrow(), key);
+    fn encode_values(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> EncodedEntries {
+        entries.into_iter()
+            .map(|(key, value)| {
+                let (stored, plain) = self.encode_value(value);
+                ((key, stored), plain)
+            })
+            .unzip()
+    }
+
+    /// Stores a put block per entry under a single lock acquisition with a single write: either all blocks
+    /// are handed to the writer or, if building or writing fails, the offset is not advanced and an error returned.
+    pub fn store_put_batch_event(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (entries, plain_values) = self.encode_values(entries);
+        let key_values: Vec<KeyValueData> = entries.into_iter()
+            .map(|(key, value)| KeyValueData::new(key, value))
+            .collect();
+
+        self.append_all(|offset| {
+            let mut offset = *offset;
+            key_values.iter()
+                .map(|key_value| {
+                    let put_action = StoredAction::put_action(&offset, key_value, self.header.crc_scope());
+                    offset += put_action.block_len() as u32;
+                    put_action
+                })
+                .collect()
+        })?;
+
+        let key_values = key_values.into_iter().map(KeyValueData::owned_key_value).collect();
+        Ok(with_plain_values(key_values, plain_values))
+    }
+
+    /// Stores only the merge operand, the value is rebuilt on replay by folding operands over the last put.
+    pub fn store_merge_event(&self, key: Vec<u8>, operand: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let (stored, plain) = self.encode_value(operand);
+        let key_operand = KeyValueData::new(key, stored);
+        self.append(|offset| StoredAction::merge_action(offset, &key_operand, self.header.crc_scope()))?;
+
+        let (key, stored) = key_operand.owned_key_value();
+        Ok((key, plain.unwrap_or(stored)))
+    }
+
+    /// Same as [`WalStorage::store_put_event`], also returning the start offset of the written block.
+    pub fn store_put_event_at(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<(u32, Vec<u8>, Vec<u8>)> {
+        let (stored, plain) = self.encode_value(value);
+        let key_value = KeyValueData::new(key, stored);
+        let start_offset = self.append(|offset| StoredAction::put_action(offset, &key_value, self.header.crc_scope()))?;
+
+        let (key, stored) = key_value.owned_key_value();
+        Ok((start_offset, key, plain.unwrap_or(stored)))
+    }
+
+    /// Same as [`WalStorage::store_put_event`], but gives up with [`WalError::WouldBlock`] if the write lock isn't
+    /// acquired within `timeout` and returns [`WalError::Poisoned`] instead of panicking on a poisoned lock.
+    pub fn try_store_put_event(&self, key: Vec<u8>, value: Vec<u8>, timeout: Duration) -> Result<(Vec<u8>, Vec<u8>), WalError> {
+        let (stored, plain) = self.encode_value(value);
+        let key_value = KeyValueData::new(key, stored);
+        let w_lock = self.lock_for_write(Some(timeout))?;
+        self.append_locked(w_lock, |offset| vec![StoredAction::put_action(offset, &key_value, self.header.crc_scope())])?;
+
+        let (key, stored) = key_value.owned_key_value();
+        Ok((key, plain.unwrap_or(stored)))
+    }
 
-        write(w_lock.writer.borrow_mut(), &put_action);
-        increment_offset(w_lock.offset.borrow_mut(), &put_action);
+    /// Length of the header preceding the blocks, 0 for a legacy WAL.
+    pub fn header_len(&self) -> usize {
+        self.header.encoded_len()
     }
 
-    pub fn store_append_to_set_event(&self, key: Vec<u8>, set_key: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
-        let mut w_lock = self.wal_state.write().unwrap();
+    /// Put which also records the version of the key, so versions survive the WAL rewrite on recovery.
+    pub fn store_versioned_put_event(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let (stored, plain) = self.encode_value(value);
+        let key_value_version = VersionedKeyValueData::new(key, stored, version);
+        self.append(|offset| StoredAction::versioned_put_action(offset, &key_value_version, self.header.crc_scope()))?;
+
+        let (key, stored, _version) = key_value_version.owned_key_value_version();
+        Ok((key, plain.unwrap_or(stored)))
+    }
+
+    /// Put followed by the expiry of the key, written together so an expiring put is never recovered without it.
+    pub fn store_expiring_put_event(&self, key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let expiry = ExpiryData::new(key.clone(), expires_at);
+        let (stored, plain) = self.encode_value(value);
+        let key_value = KeyValueData::new(key, stored);
+
+        self.append_all(|offset| {
+            let put_action = StoredAction::put_action(offset, &key_value, self.header.crc_scope());
+            let expire_offset = *offset + put_action.block_len() as u32;
+            let expire_action = StoredAction::expire_action(&expire_offset, &expiry, self.header.crc_scope());
+            vec![put_action, expire_action]
+        })?;
+
+        let (key, stored) = key_value.owned_key_value();
+        Ok((key, plain.unwrap_or(stored)))
+    }
+
+    /// Expiry of a key already put, e.g. rewritten right after its versioned put on recovery.
+    pub fn store_expire_event(&self, key: Vec<u8>, expires_at: u64) -> io::Result<()> {
+        let expiry = ExpiryData::new(key, expires_at);
+        self.append(|offset| StoredAction::expire_action(offset, &expiry, self.header.crc_scope()))?;
+
+        Ok(())
+    }
+
+    pub fn store_delete_event(&self, key: &[u8]) -> io::Result<()> {
+        self.append(|offset| StoredAction::delete_action(offset, key, self.header.crc_scope()))?;
+
+        Ok(())
+    }
+
+    /// Stores a delete block per key under a single lock acquisition with a single write, like
+    /// [`WalStorage::store_put_batch_event`].
+    pub fn store_delete_many_event(&self, keys: &[Vec<u8>]) -> io::Result<()> {
+        self.append_all(|offset| {
+            let mut offset = *offset;
+            keys.iter()
+                .map(|key| {
+                    let delete_action = StoredAction::delete_action(&offset, key, self.header.crc_scope());
+                    offset += delete_action.block_len() as u32;
+                    delete_action
+                })
+                .collect()
+        })?;
+
+        Ok(())
+    }
 
+    pub fn store_append_to_set_event(&self, key: Vec<u8>, set_key: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>)> {
         let key_value = KeyValueData::new(key, set_key);
-        let put_action = StoredAction::append_to_set(w_lock.offset.borrow(), &key_value);
+        self.append(|offset| StoredAction::append_to_set(offset, &key_value, self.header.crc_scope()))?;
 
-        write(w_lock.writer.borrow_mut(), &put_action);
-        increment_offset(w_lock.offset.borrow_mut(), &put_action);
+        Ok(key_value.owned_key_value())
+    }
+
+    /// Stores appends of several elements to one set as a single block, so the key is written once.
+    pub fn store_append_many_to_set_event(&self, key: Vec<u8>, elements: Vec<Vec<u8>>) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let key_elements = SetElementsData::new(key, elements);
+        self.append(|offset| StoredAction::append_many_to_set(offset, &key_elements, self.header.crc_scope()))?;
 
-        key_value.owned_key_value()
+        Ok(key_elements.owned_key_elements())
     }
 
-    pub fn store_remove_from_set_event(&self, key: Vec<u8>, value: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
-        let mut w_lock = self.wal_state.write().unwrap();
+    /// Stores an append block of `element` per key under a single lock acquisition with a single write, like
+    /// [`WalStorage::store_put_batch_event`].
+    pub fn store_append_to_sets_event(&self, keys: &[Vec<u8>], element: &[u8]) -> io::Result<()> {
+        let key_values: Vec<KeyValueData> = keys.iter()
+            .map(|key| KeyValueData::new(key.clone(), element.to_vec()))
+            .collect();
+
+        self.append_all(|offset| {
+            let mut offset = *offset;
+            key_values.iter()
+                .map(|key_value| {
+                    let append_action = StoredAction::append_to_set(&offset, key_value, self.header.crc_scope());
+                    offset += append_action.block_len() as u32;
+                    append_action
+                })
+                .collect()
+        })?;
+
+        Ok(())
+    }
 
+    pub fn store_remove_from_set_event(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>)> {
         let key_value = KeyValueData::new(key, value);
-        let put_action = StoredAction::remove_from_set(w_lock.offset.borrow(), &key_value);
+        self.append(|offset| StoredAction::remove_from_set(offset, &key_value, self.header.crc_scope()))?;
 
-        write(w_lock.writer.borrow_mut(), &put_action);
-        increment_offset(w_lock.offset.borrow_mut(), &put_action);
+        Ok(key_value.owned_key_value())
+    }
 
-        key_value.owned_key_value()
+    /// Move of `member` between two sets as a single block, see [`SetMoveData`].
+    pub fn store_set_move_event(&self, from_key: Vec<u8>, to_key: Vec<u8>, member: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        let set_move = SetMoveData::new(from_key, to_key, member);
+        self.append(|offset| StoredAction::set_move_action(offset, &set_move, self.header.crc_scope()))?;
+
+        Ok(set_move.owned_from_to_member())
     }
 
-    pub fn store_put_to_map_event(&self, key: Vec<u8>, search_key: SearchKey, element: Vec<u8>) -> (Vec<u8>, SearchKey, Vec<u8>) {
-        let mut w_lock = self.wal_state.write().unwrap();
+    pub fn store_put_to_map_event(&self, key: Vec<u8>, search_key: SearchKey, element: Vec<u8>) -> io::Result<(Vec<u8>, SearchKey, Vec<u8>)> {
+        let entry = SortedMapEntry::new(key, search_key, element);
+        self.append(|offset| StoredAction::put_to_sorted_map(offset, &entry, self.header.crc_scope()))?;
 
+        Ok(entry.entry())
+    }
+
+    pub fn store_remove_from_sorted_map_event(&self, key: Vec<u8>, search_key: SearchKey) -> io::Result<(Vec<u8>, SearchKey)> {
+        let sorted_map_key = SortedMapKey::new(key, search_key);
+        self.append(|offset| StoredAction::remove_from_sorted_map(offset, &sorted_map_key, self.header.crc_scope()))?;
+
+        Ok(sorted_map_key.owned())
+    }
+
+    /// Removal of `from` followed by the put of `element` at `search_key`, both written at once.
+    pub fn store_move_in_sorted_map_event(&self, key: Vec<u8>, from: SearchKey, search_key: SearchKey, element: Vec<u8>)
+                                          -> io::Result<(Vec<u8>, SearchKey, Vec<u8>)> {
+        let sorted_map_key = SortedMapKey::new(key.clone(), from);
         let entry = SortedMapEntry::new(key, search_key, element);
-        let put_action = StoredAction::put_to_sorted_map(w_lock.offset.borrow(), &entry);
+        self.append_all(|offset| {
+            let remove_action = StoredAction::remove_from_sorted_map(offset, &sorted_map_key, self.header.crc_scope());
+            let put_offset = *offset + remove_action.block_len() as u32;
+            vec![remove_action, StoredAction::put_to_sorted_map(&put_offset, &entry, self.header.crc_scope())]
+        })?;
 
-        write(w_lock.writer.borrow_mut(), &put_action);
-        increment_offset(w_lock.offset.borrow_mut(), &put_action);
+        Ok(entry.entry())
+    }
 
-        entry.entry()
+    pub fn store_tx_begin_event(&self, tx_record: &TxRecord) -> io::Result<()> {
+        self.append(|offset| StoredAction::tx_begin_action(offset, tx_record, self.header.crc_scope()))?;
+        Ok(())
     }
 
-    pub fn store_remove_from_sorted_map_event(&self, key: Vec<u8>, search_key: SearchKey) -> (Vec<u8>, SearchKey) {
-        let mut w_lock = self.wal_state.write().unwrap();
+    pub fn store_tx_end_event(&self, tx_id: u64) -> io::Result<()> {
+        self.append(|offset| StoredAction::tx_end_action(offset, tx_id, self.header.crc_scope()))?;
+        Ok(())
+    }
 
-        let sorted_map_key = SortedMapKey::new(key, search_key);
-        let put_action = StoredAction::remove_from_sorted_map(w_lock.offset.borrow(), &sorted_map_key);
+    /// Writes the action built for the current offset, see [`WalStorage::append_all`].
+    fn append(&self, build_action: impl Fn(&u32) -> StoredAction) -> io::Result<u32> {
+        self.append_all(|offset| vec![build_action(offset)])
+    }
+
+    /// Writes the actions built for the current offset under a single lock acquisition and with a single `write_all`.
+    /// A capped WAL over its limit is compacted first and the actions are rebuilt for the new offset, if even the
+    /// compacted WAL has no room it fails with `OutOfMemory`. Returns the start offset of the first written block.
+    fn append_all(&self, build_actions: impl Fn(&u32) -> Vec<StoredAction>) -> io::Result<u32> {
+        let w_lock = self.lock_for_write(None)?;
+        self.append_locked(w_lock, build_actions)
+    }
+
+    fn append_locked(&self, mut w_lock: RwLockWriteGuard<'_, WalState<W>>, build_actions: impl Fn(&u32) -> Vec<StoredAction>) -> io::Result<u32> {
+        let locked_at = self.metrics.as_ref().map(|_| Instant::now());
+        let mut actions = self.aligned(self.stamped(&build_actions, w_lock.offset), w_lock.offset);
+        for stored_action in &actions {
+            check_payload_len(stored_action.data().len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        if let Some(limit) = &self.capacity_limit {
+            if !limit.fits(w_lock.offset, blocks_len(&actions)) {
+                w_lock.offset = (limit.compact)(w_lock.writer.borrow_mut())?;
+                w_lock.rewrites += 1;
+                self.appended.rewritten(w_lock.offset, w_lock.rewrites);
+                actions = self.aligned(self.stamped(&build_actions, w_lock.offset), w_lock.offset);
+
+                if !limit.fits(w_lock.offset, blocks_len(&actions)) {
+                    return Err(io::Error::new(io::ErrorKind::OutOfMemory,
+                                              format!("WAL can't fit {} more bytes in {} bytes even after compaction", blocks_len(&actions), limit.max_bytes)));
+                }
+            }
+        }
+
+        let start_offset = actions.iter()
+            .find(|stored_action| *stored_action.act_type() != PADDING_ACT && *stored_action.act_type() != TIMESTAMP_ACT)
+            .map_or(w_lock.offset, |stored_action| *stored_action.start_offset());
+        let blocks = encode_blocks(&actions);
+        w_lock.writing = true;
+        let written = write_blocks(w_lock.writer.borrow_mut(), &blocks, self.retry_policy.as_ref());
+        // the offset isn't advanced on failure, so blocks appended after a torn prefix wouldn't be found where
+        // their start offsets say: once the writer took any bytes, further appends fail with `WalError::Poisoned`
+        w_lock.writing = matches!(written, Err((accepted, _)) if accepted > 0);
+        written.map_err(|(_accepted, error)| error)?;
+        #[cfg(debug_assertions)]
+        let offset_before = w_lock.offset;
+        if let Some(last_action) = actions.last() {
+            increment_offset(w_lock.offset.borrow_mut(), last_action);
+        }
+        #[cfg(debug_assertions)]
+        verify_offset_chain(&blocks, offset_before, w_lock.offset);
+        self.appended.notify(w_lock.offset);
+        if let (Some(metrics), Some(locked_at)) = (&self.metrics, locked_at) {
+            metrics.on_write(locked_at.elapsed(), blocks.len());
+        }
+
+        Ok(start_offset)
+    }
+
+    /// Actions built for `offset`, preceded by the time of the write if the WAL records it.
+    fn stamped(&self, build_actions: &impl Fn(&u32) -> Vec<StoredAction>, offset: u32) -> Vec<StoredAction> {
+        if !self.header.has_timestamps() {
+            return build_actions(&offset);
+        }
+        let timestamp_action = StoredAction::timestamp_action(&offset, self.clock.now(), self.header.crc_scope());
+        let mut actions = build_actions(&(offset + timestamp_action.block_len() as u32));
+        actions.insert(0, timestamp_action);
+        actions
+    }
+
+    /// Moves `actions` laid out from `offset` so each starts on the alignment of the header, with padding blocks
+    /// filling the gaps. A gap too short for a padding block is extended by another alignment.
+    fn aligned(&self, actions: Vec<StoredAction>, mut offset: u32) -> Vec<StoredAction> {
+        let alignment = self.header.alignment();
+        if alignment <= 1 {
+            return actions;
+        }
+
+        let mut aligned_actions = Vec::with_capacity(actions.len() * 2);
+        for stored_action in actions {
+            // the header is padded to the alignment, so aligning the offset aligns the block in the file
+            let mut gap = offset.next_multiple_of(alignment) - offset;
+            if gap != 0 && gap < FIXED_BLOCK_LEN as u32 {
+                gap += alignment;
+            }
+            if gap != 0 {
+                aligned_actions.push(StoredAction::padding_action(&offset, gap, self.header.crc_scope()));
+                offset += gap;
+            }
+            let moved_action = stored_action.moved_to(offset, self.header.crc_scope());
+            offset += moved_action.block_len() as u32;
+            aligned_actions.push(moved_action);
+        }
+        aligned_actions
+    }
+
+    /// Waits for the write lock, at most `timeout` if given. A lock poisoned by a panic before any bytes reached
+    /// the writer (e.g. while building blocks) is cleared, as the offset still matches the written blocks. After a
+    /// write failed partway it fails with [`WalError::Poisoned`].
+    fn lock_for_write(&self, timeout: Option<Duration>) -> Result<RwLockWriteGuard<'_, WalState<W>>, WalError> {
+        reentrancy::check_not_in_compute();
+        let deadline = match timeout {
+            None => {
+                return match self.wal_state.write() {
+                    Ok(w_lock) => not_torn(w_lock),
+                    Err(poisoned) => self.recover_poisoned(poisoned.into_inner()),
+                };
+            }
+            Some(timeout) => Instant::now() + timeout,
+        };
+
+        loop {
+            match self.wal_state.try_write() {
+                Ok(w_lock) => return not_torn(w_lock),
+                Err(TryLockError::Poisoned(poisoned)) => return self.recover_poisoned(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return Err(WalError::WouldBlock),
+                Err(TryLockError::WouldBlock) => thread::sleep(LOCK_RETRY_INTERVAL),
+            }
+        }
+    }
+
+    fn recover_poisoned<'a>(&self, w_lock: RwLockWriteGuard<'a, WalState<W>>) -> Result<RwLockWriteGuard<'a, WalState<W>>, WalError> {
+        if w_lock.writing {
+            return Err(WalError::Poisoned);
+        }
+        warn!("WAL lock poisoned outside of a write, continuing at offset {}", w_lock.offset);
+        self.wal_state.clear_poison();
+        Ok(w_lock)
+    }
+}
+
+fn not_torn<W: Write>(w_lock: RwLockWriteGuard<'_, WalState<W>>) -> Result<RwLockWriteGuard<'_, WalState<W>>, WalError> {
+    if w_lock.writing {
+        return Err(WalError::Poisoned);
+    }
+    Ok(w_lock)
+}
+
+fn blocks_len(actions: &[StoredAction]) -> usize {
+    actions.iter().map(StoredAction::block_len).sum()
+}
 
+/// Rewrites `bytes` keeping the header and only live blocks, whose start offsets are moved to their new positions.
+fn compact_vec(bytes: &mut Vec<u8>) -> io::Result<u32> {
+    let header_len = HEADER_LEN as usize;
+    let (header, body) = WalHeader::split(bytes);
+    let reclaimable: HashSet<usize> = reclaimable_blocks(body).into_iter().map(|range| range.start).collect();
 
-        write(w_lock.writer.borrow_mut(), &put_action);
-        increment_offset(w_lock.offset.borrow_mut(), &put_action);
+    let mut compacted = Vec::with_capacity(bytes.len());
+    compacted.extend_from_slice(&bytes[..header_len]);
 
-        sorted_map_key.owned()
+    let mut offset: u32 = 0;
+    for stored_action in iter_actions(bytes) {
+        if reclaimable.contains(&(*stored_action.start_offset() as usize)) {
+            continue;
+        }
+        let moved_action = stored_action.moved_to(offset, header.crc_scope());
+        write(&mut compacted, std::slice::from_ref(&moved_action), None)?;
+        offset += moved_action.block_len() as u32;
+    }
+    info!("compacted WAL from {} to {} bytes", bytes.len(), compacted.len());
+
+    *bytes = compacted;
+    Ok(offset)
+}
+
+/// Serializes `actions` back to back in the WAL block layout, without touching any writer.
+fn encode_blocks(actions: &[StoredAction]) -> Vec<u8> {
+    let mut blocks = Vec::with_capacity(blocks_len(actions));
+    for put_action in actions {
+        blocks.extend_from_slice(&put_action.act_type().to_ne_bytes());
+        blocks.extend_from_slice(&put_action.crc().to_ne_bytes());
+        blocks.extend_from_slice(&put_action.data_size().to_ne_bytes());
+        blocks.extend_from_slice(put_action.data());
+        blocks.extend_from_slice(&put_action.start_offset().to_ne_bytes());
     }
+    blocks
 }
 
-fn write<W: Write>(file: &mut W, put_action: &StoredAction) {
-    let _ = file.write(&put_action.act_type().to_ne_bytes()).unwrap();
-    let _ = file.write(&put_action.crc().to_ne_bytes()).unwrap();
-    let _ = file.write(&put_action.data_size().to_ne_bytes()).unwrap();
-    let _ = file.write(put_action.data()).unwrap();
-    let _ = file.write(&put_action.start_offset().to_ne_bytes()).unwrap();
-    let _ = file.flush().unwrap();
+/// Debug builds only: checks that each block just written from `offset_before` is found where its `start_offset`
+/// field says, and that the offset moved past exactly the written bytes. A broken chain would otherwise go unnoticed
+/// until a backward read fails to locate the blocks.
+#[cfg(debug_assertions)]
+fn verify_offset_chain(blocks: &[u8], offset_before: u32, offset_after: u32) {
+    let mut local_offset = 0;
+    while local_offset < blocks.len() {
+        let block_start = local_offset;
+        let stored_action = try_build_action(&mut local_offset, blocks)
+            .unwrap_or_else(|| panic!("written WAL block at offset {} is cut short", offset_before as usize + block_start));
+        let expected = offset_before as u64 + block_start as u64;
+        assert_eq!(*stored_action.start_offset() as u64, expected,
+                   "WAL block written at offset {} records start offset {}", expected, stored_action.start_offset());
+    }
+    assert_eq!(offset_after as u64, offset_before as u64 + blocks.len() as u64,
+               "WAL offset moved from {} to {} over {} written bytes", offset_before, offset_after, blocks.len());
+}
+
+/// Encodes `actions` and writes them with [`write_blocks`].
+fn write<W: Write>(file: &mut W, actions: &[StoredAction], retry_policy: Option<&RetryPolicy>) -> io::Result<()> {
+    write_blocks(file, &encode_blocks(actions), retry_policy).map_err(|(_accepted, error)| error)
+}
+
+/// Writes all blocks in one go, so a failing writer (e.g. `ErrorKind::StorageFull`) is reported to the caller
+/// before the offset is advanced or any in-memory state is touched. Transient failures are retried per `retry_policy`.
+/// On failure also returns how many bytes the writer took before it failed, all of them if only the flush failed.
+fn write_blocks<W: Write>(file: &mut W, blocks: &[u8], retry_policy: Option<&RetryPolicy>) -> Result<(), (usize, io::Error)> {
+    let mut failed_attempts = 0;
+    let mut on_error = |error: io::Error| {
+        failed_attempts += 1;
+        match retry_policy {
+            Some(policy) => policy.pause_or_fail(failed_attempts, error),
+            None if error.kind() == io::ErrorKind::Interrupted => Ok(()),
+            None => Err(error),
+        }
+    };
+
+    let mut remaining = blocks;
+    while !remaining.is_empty() {
+        let accepted = blocks.len() - remaining.len();
+        match file.write(remaining) {
+            Ok(0) => return Err((accepted, io::Error::new(io::ErrorKind::WriteZero, "failed to write whole WAL blocks"))),
+            Ok(written) => remaining = &remaining[written..],
+            Err(error) => on_error(error).map_err(|error| (accepted, error))?,
+        }
+    }
+    loop {
+        match file.flush() {
+            Ok(()) => return Ok(()),
+            Err(error) => on_error(error).map_err(|error| (blocks.len(), error))?,
+        }
+    }
 }
 
 fn increment_offset(offset: &mut u32, put_action: &StoredAction) {
@@ -135,56 +1233,268 @@ fn increment_offset(offset: &mut u32, put_action: &StoredAction) {
 }
 
 pub fn read_forward(bytes: &[u8]) -> HashMap<Vec<u8>, Vec<u8>> {
-    let mut result = HashMap::new();
-    if bytes.is_empty() {
-        return result;
+    replay_forward(bytes, None)
+}
+
+/// Same as [`read_forward`], failing with a [`WalReadError`] instead of panicking on a corrupted block, e.g. one
+/// which passes CRC verification but can't be decoded.
+pub fn try_read_forward(bytes: &[u8]) -> Result<HashMap<Vec<u8>, Vec<u8>>, WalReadError> {
+    let (map, _report) = replay_forward_versioned(bytes, None, CorruptionPolicy::Abort)?;
+    Ok(map.into_iter().map(|(key, (value, _version))| (key, value)).collect())
+}
+
+/// Like [`read_forward`] (or [`read_forward_merging`] with `merge_operator`), also returning the version of each
+/// key: every put or merge since the key was last deleted increments it, a versioned put sets it.
+pub fn read_forward_versioned(bytes: &[u8], merge_operator: Option<&MergeOperator>) -> HashMap<Vec<u8>, (Vec<u8>, u64)> {
+    or_abort(replay_forward_versioned(bytes, merge_operator, CorruptionPolicy::Abort)).0
+}
+
+/// Like [`read_forward_versioned`], dealing with corrupted blocks as `policy` says and reporting the dropped ones.
+/// Fails only under [`CorruptionPolicy::Abort`], on the first corrupted block.
+pub fn read_forward_versioned_with_policy(bytes: &[u8], merge_operator: Option<&MergeOperator>, policy: CorruptionPolicy)
+                                          -> Result<(VersionedMap, CorruptionReport), WalReadError> {
+    replay_forward_versioned(bytes, merge_operator, policy)
+}
+
+/// Like [`read_forward_versioned_with_policy`], also returning the expiry of each key put with one. Expired entries
+/// are kept, it's up to the caller to compare expiries with its clock.
+pub fn read_forward_expiring_with_policy(bytes: &[u8], merge_operator: Option<&MergeOperator>, policy: CorruptionPolicy)
+                                         -> Result<(VersionedMap, Expirations, CorruptionReport), WalReadError> {
+    replay_forward_expiring(bytes, merge_operator, policy)
+}
+
+/// Like [`read_forward_expiring_with_policy`] for a WAL written with a value transformer, see
+/// [`WalStorage::with_value_transformer`]: values and merge operands are decoded with `transformer` as they're read.
+pub fn read_forward_transformed_with_policy(bytes: &[u8], merge_operator: Option<&MergeOperator>,
+                                            transformer: Option<&dyn ValueTransformer>, policy: CorruptionPolicy)
+                                            -> Result<(VersionedMap, Expirations, CorruptionReport), WalReadError> {
+    replay_forward_transformed(bytes, merge_operator, transformer, policy, None)
+}
+
+/// Like [`read_forward`], additionally folding `MERGE_ACT` operands over the preceding value with `merge_operator`.
+pub fn read_forward_merging(bytes: &[u8], merge_operator: &MergeOperator) -> HashMap<Vec<u8>, Vec<u8>> {
+    replay_forward(bytes, Some(merge_operator))
+}
+
+fn replay_forward(bytes: &[u8], merge_operator: Option<&MergeOperator>) -> HashMap<Vec<u8>, Vec<u8>> {
+    or_abort(replay_forward_versioned(bytes, merge_operator, CorruptionPolicy::Abort)).0.into_iter()
+        .map(|(key, (value, _version))| (key, value))
+        .collect()
+}
+
+fn replay_forward_versioned(bytes: &[u8], merge_operator: Option<&MergeOperator>, policy: CorruptionPolicy)
+                            -> Result<(VersionedMap, CorruptionReport), WalReadError> {
+    let (result, _expirations, report) = replay_forward_expiring(bytes, merge_operator, policy)?;
+    Ok((result, report))
+}
+
+fn replay_forward_expiring(bytes: &[u8], merge_operator: Option<&MergeOperator>, policy: CorruptionPolicy)
+                           -> Result<(VersionedMap, Expirations, CorruptionReport), WalReadError> {
+    replay_forward_transformed(bytes, merge_operator, None, policy, None)
+}
+
+pub(crate) fn replay_forward_transformed<S: ReplaySource>(source: S, merge_operator: Option<&MergeOperator>,
+                                               transformer: Option<&dyn ValueTransformer>, policy: CorruptionPolicy,
+                                               metrics: Option<&dyn WalMetrics>)
+                                               -> Result<(VersionedMap, Expirations, CorruptionReport), S::Error> {
+    let mut replay = ForwardReplay::new(merge_operator, transformer).with_metrics(metrics);
+    let report = source.replay(policy, |stored_action| replay.apply(stored_action))?;
+    Ok((replay.result, replay.expirations, report))
+}
+
+/// KeyValue state built by [`replay_forward_transformed`] one block at a time, whatever the blocks are read from.
+struct ForwardReplay<'a> {
+    merge_operator: Option<&'a MergeOperator>,
+    transformer: Option<&'a dyn ValueTransformer>,
+    /// Times each applied block, see [`WalMetrics::on_recovery_block`].
+    metrics: Option<&'a dyn WalMetrics>,
+    result: VersionedMap,
+    expirations: Expirations,
+}
+
+impl<'a> ForwardReplay<'a> {
+    fn new(merge_operator: Option<&'a MergeOperator>, transformer: Option<&'a dyn ValueTransformer>) -> Self {
+        ForwardReplay { merge_operator, transformer, metrics: None, result: HashMap::new(), expirations: HashMap::new() }
     }
-    let mut offset = 0;
 
-    while offset < bytes.len() {
-        let stored_action = build_action(&mut offset, bytes);
+    fn with_metrics(mut self, metrics: Option<&'a dyn WalMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 
-        let actual_crc = model::crc(stored_action.data());
-        if actual_crc != *stored_action.crc() {
-            panic!("wrong crc !!"); // todo: better error handling
+    fn decode(&self, stored: Vec<u8>) -> Vec<u8> {
+        match self.transformer {
+            Some(transformer) => transformer.decode(&stored),
+            None => stored,
         }
+    }
+
+    fn apply(&mut self, stored_action: StoredAction) -> bincode::Result<()> {
+        match self.metrics {
+            None => self.apply_block(stored_action),
+            Some(metrics) => {
+                let started = Instant::now();
+                let applied = self.apply_block(stored_action);
+                metrics.on_recovery_block(started.elapsed());
+                applied
+            }
+        }
+    }
 
+    fn apply_block(&mut self, stored_action: StoredAction) -> bincode::Result<()> {
         match *stored_action.act_type() {
             model::DELETE_ACT => {
-                result.remove(stored_action.data());
+                self.result.remove(stored_action.data());
+                self.expirations.remove(stored_action.data());
             }
             model::PUT_ACT => {
-                let put_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+                let put_action: KeyValueData = bincode::deserialize(stored_action.data())?;
                 let (key, value) = put_action.owned_key_value();
-                result.insert(key, value);
+                let version = self.result.get(&key).map_or(0, |(_, version)| *version) + 1;
+                self.expirations.remove(&key);
+                let value = self.decode(value);
+                self.result.insert(key, (value, version));
+            }
+            model::PUT_MANY_ACT => {
+                let put_action: KeyValuesData = bincode::deserialize(stored_action.data())?;
+                for (key, value) in put_action.owned_entries() {
+                    let version = self.result.get(&key).map_or(0, |(_, version)| *version) + 1;
+                    self.expirations.remove(&key);
+                    let value = self.decode(value);
+                    self.result.insert(key, (value, version));
+                }
+            }
+            model::VERSIONED_PUT_ACT => {
+                let put_action: VersionedKeyValueData = bincode::deserialize(stored_action.data())?;
+                let (key, value, version) = put_action.owned_key_value_version();
+                self.expirations.remove(&key);
+                let value = self.decode(value);
+                self.result.insert(key, (value, version));
+            }
+            model::EXPIRE_ACT => {
+                let expiry: ExpiryData = bincode::deserialize(stored_action.data())?;
+                let (key, expires_at) = expiry.owned_key_expires_at();
+                if self.result.contains_key(&key) {
+                    self.expirations.insert(key, expires_at);
+                }
             }
-            _ => { panic!("not supported action type: {}", stored_action.act_type()) }
+            model::MERGE_ACT => {
+                let merge_operator = self.merge_operator
+                    .ok_or_else(|| bincode::ErrorKind::Custom("merge operator is required to replay merge actions".to_string()))?;
+                let merge_action: KeyValueData = bincode::deserialize(stored_action.data())?;
+                let (key, operand) = merge_action.owned_key_value();
+                let operand = self.decode(operand);
+                let (current, version) = match self.result.get(&key) {
+                    Some((value, version)) => (Some(value.as_slice()), *version),
+                    None => (None, 0),
+                };
+                let merged = merge_operator(current, &operand);
+                self.expirations.remove(&key);
+                self.result.insert(key, (merged, version + 1));
+            }
+            model::PADDING_ACT => {}
+            act_type => return Err(not_supported(act_type)),
         }
+        Ok(())
     }
-    result
 }
 
-pub fn read_for_set(bytes: &[u8]) -> HashMap<Vec<u8>, HashSet<Vec<u8>>> {
-    let mut result = HashMap::new();
-    if bytes.is_empty() {
-        return result;
+/// Value and version of each key, as replayed by [`read_forward_versioned`].
+pub type VersionedMap = HashMap<Vec<u8>, (Vec<u8>, u64)>;
+/// Expiry of each key put with one, in milliseconds since the Unix epoch, see [`read_forward_expiring_with_policy`].
+pub type Expirations = HashMap<Vec<u8>, u64>;
+/// Sorted map of each key, as replayed by [`read_for_map`].
+pub type SortedMaps = HashMap<Vec<u8>, BTreeMap<SearchKey, Vec<u8>>>;
+/// Set of each key, as replayed by [`read_for_set`].
+pub type Sets = HashMap<Vec<u8>, HashSet<Vec<u8>>>;
+/// Linked map of each key, as replayed by [`read_for_linked_map`].
+pub type LinkedMaps = HashMap<Vec<u8>, LinkedMap>;
+
+/// What replay does with a block failing CRC verification or decoding, or cut short by the end of the WAL (e.g. by
+/// a crash during the write).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    /// Fails the replay, so nothing is lost silently. Replays which can't fail panic instead.
+    #[default]
+    Abort,
+    /// Drops only the bad block and goes on with the next one, located by the data size of the bad block.
+    /// A cut short block ends the replay, as nothing follows it.
+    SkipBlock,
+    /// Drops the bad block and everything after it, keeping the state as of the last good block.
+    TruncateAt,
+}
+
+/// Blocks dropped by a replay under a [`CorruptionPolicy`], offsets are relative to the end of the header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorruptionReport {
+    /// Blocks the replay went through, dropped ones included. A block cut short by the end of the WAL isn't one.
+    pub blocks_read: usize,
+    /// Blocks failing CRC verification or decoding dropped by [`CorruptionPolicy::SkipBlock`].
+    pub skipped_blocks: Vec<Range<usize>>,
+    /// Start of the dropped rest of the WAL: the first bad block under [`CorruptionPolicy::TruncateAt`], or a block
+    /// cut short by the end of the WAL.
+    pub truncated_at: Option<usize>,
+    pub truncated_bytes: usize,
+}
+
+impl CorruptionReport {
+    pub fn is_clean(&self) -> bool {
+        self.skipped_blocks.is_empty() && self.truncated_at.is_none()
     }
-    let mut offset = 0;
 
-    while offset < bytes.len() {
-        let stored_action = build_action(&mut offset, bytes);
+    pub fn dropped_bytes(&self) -> usize {
+        self.skipped_blocks.iter().map(|range| range.len()).sum::<usize>() + self.truncated_bytes
+    }
 
-        let actual_crc = model::crc(stored_action.data());
-        if actual_crc != *stored_action.crc() {
-            panic!("wrong crc !!"); // todo: better error handling
-        }
+    fn truncate_at(&mut self, offset: usize, body_len: usize) {
+        self.truncated_at = Some(offset);
+        self.truncated_bytes = body_len - offset;
+    }
+}
 
+/// Result of [`analyze`]: the live KeyValue state plus byte ranges of blocks superseded or deleted later in the log.
+#[derive(Debug)]
+pub struct CompactionPlan {
+    pub live: HashMap<Vec<u8>, Vec<u8>>,
+    pub reclaimable: Vec<Range<usize>>,
+}
+
+impl CompactionPlan {
+    pub fn reclaimable_bytes(&self) -> usize {
+        self.reclaimable.iter().map(|range| range.len()).sum()
+    }
+}
+
+/// Same last-writer-wins replay as [`read_forward`], additionally tracking blocks that no longer contribute to
+/// the live state, so a compaction tool can estimate how much space a rewrite would reclaim.
+pub fn analyze(bytes: &[u8]) -> CompactionPlan {
+    let live = read_forward(bytes);
+    let (_header, body) = WalHeader::split(bytes);
+    let reclaimable = reclaimable_blocks(body);
+
+    CompactionPlan { live, reclaimable }
+}
+
+pub fn read_for_set(bytes: &[u8]) -> HashMap<Vec<u8>, HashSet<Vec<u8>>> {
+    or_abort(read_for_set_with_policy(bytes, CorruptionPolicy::Abort)).0
+}
+
+/// Like [`read_for_set`], dealing with corrupted blocks as `policy` says and reporting the dropped ones.
+/// Fails only under [`CorruptionPolicy::Abort`], on the first corrupted block.
+pub fn read_for_set_with_policy(bytes: &[u8], policy: CorruptionPolicy) -> Result<(Sets, CorruptionReport), WalReadError> {
+    replay_for_set(bytes, policy)
+}
+
+/// Same as [`read_for_set_with_policy`] for a WAL mapped in memory or streamed, see [`ReplaySource`].
+pub(crate) fn replay_for_set<S: ReplaySource>(source: S, policy: CorruptionPolicy) -> Result<(Sets, CorruptionReport), S::Error> {
+    let mut result = HashMap::new();
+    let report = source.replay(policy, |stored_action| {
         match *stored_action.act_type() {
             model::DELETE_ACT => {
                 result.remove(stored_action.data());
             }
             model::SET_APPEND_ACT => {
-                let put_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+                let put_action: KeyValueData = bincode::deserialize(stored_action.data())?;
                 let (key, set_element) = put_action.owned_key_value();
 
                 match result.get_mut(&key) {
@@ -198,41 +1508,60 @@ pub fn read_for_set(bytes: &[u8]) -> HashMap<Vec<u8>, HashSet<Vec<u8>>> {
                     }
                 }
             }
+            model::SET_APPEND_MANY_ACT => {
+                let append_action: SetElementsData = bincode::deserialize(stored_action.data())?;
+                let (key, set_elements) = append_action.owned_key_elements();
+
+                result.entry(key).or_insert_with(HashSet::new).extend(set_elements);
+            }
             model::SET_REMOVE_ACT => {
-                let put_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+                let put_action: KeyValueData = bincode::deserialize(stored_action.data())?;
                 let (key, value) = put_action.owned_key_value();
                 match result.get_mut(&key) {
                     None => {}
                     Some(hashset) => { hashset.remove(&value); }
                 }
             }
-            _ => { panic!("not supported action type: {}", stored_action.act_type()) }
+            model::SET_MOVE_ACT => {
+                let set_move: SetMoveData = bincode::deserialize(stored_action.data())?;
+                let (from_key, to_key, member) = set_move.owned_from_to_member();
+                if let Some(hashset) = result.get_mut(&from_key) {
+                    hashset.remove(&member);
+                    if hashset.is_empty() {
+                        result.remove(&from_key);
+                    }
+                }
+                result.entry(to_key).or_insert_with(HashSet::new).insert(member);
+            }
+            model::PADDING_ACT => {}
+            act_type => return Err(not_supported(act_type)),
         }
-    }
-    result
+        Ok(())
+    })?;
+    Ok((result, report))
 }
 
 pub fn read_for_map(bytes: &[u8]) -> HashMap<Vec<u8>, BTreeMap<SearchKey, Vec<u8>>> {
-    let mut result = HashMap::new();
-    if bytes.is_empty() {
-        return result;
-    }
-    let mut offset = 0;
-
-    while offset < bytes.len() {
-        let stored_action = build_action(&mut offset, bytes);
+    or_abort(read_for_map_with_policy(bytes, CorruptionPolicy::Abort)).0
+}
 
-        let actual_crc = model::crc(stored_action.data());
-        if actual_crc != *stored_action.crc() {
-            panic!("wrong crc !!"); // todo: better error handling
-        }
+/// Like [`read_for_map`], dealing with corrupted blocks as `policy` says and reporting the dropped ones.
+/// Fails only under [`CorruptionPolicy::Abort`], on the first corrupted block.
+pub fn read_for_map_with_policy(bytes: &[u8], policy: CorruptionPolicy) -> Result<(SortedMaps, CorruptionReport), WalReadError> {
+    replay_for_map(bytes, policy)
+}
 
+/// Same as [`read_for_map_with_policy`] for a WAL mapped in memory or streamed, see [`ReplaySource`].
+pub(crate) fn replay_for_map<S: ReplaySource>(source: S, policy: CorruptionPolicy)
+                                              -> Result<(SortedMaps, CorruptionReport), S::Error> {
+    let mut result = HashMap::new();
+    let report = source.replay(policy, |stored_action| {
         match *stored_action.act_type() {
             DELETE_ACT => {
                 result.remove(stored_action.data());
             }
             MAP_PUT_ACT => {
-                let put_action: SortedMapEntry = bincode::deserialize(stored_action.data()).expect("SortedMapEntry should be deserialized");
+                let put_action: SortedMapEntry = bincode::deserialize(stored_action.data())?;
                 let (key, search_key, element) = put_action.entry();
 
                 match result.get_mut(&key) {
@@ -247,17 +1576,215 @@ pub fn read_for_map(bytes: &[u8]) -> HashMap<Vec<u8>, BTreeMap<SearchKey, Vec<u8
                 }
             }
             MAP_REMOVE_ACT => {
-                let remove_action: SortedMapKey = bincode::deserialize(stored_action.data()).expect("SortedMapEntry should be deserialized");
+                let remove_action: SortedMapKey = bincode::deserialize(stored_action.data())?;
                 let (key, search_key) = remove_action.owned();
                 match result.get_mut(&key) {
                     None => {}
                     Some(map) => { map.remove(&search_key); }
                 }
             }
-            _ => { panic!("not supported action type: {}", stored_action.act_type()) }
+            PADDING_ACT => {}
+            act_type => return Err(not_supported(act_type)),
+        }
+        Ok(())
+    })?;
+    Ok((result, report))
+}
+
+/// Linked map of each key with fields in insertion order, as written by
+/// [`crate::linked_map_store::DurableLinkedMapStore`]: sorted map blocks whose search key is the field as a single
+/// `Key::Bytes`, replayed in file order.
+pub fn read_for_linked_map(bytes: &[u8]) -> HashMap<Vec<u8>, LinkedMap> {
+    or_abort(read_for_linked_map_with_policy(bytes, CorruptionPolicy::Abort)).0
+}
+
+/// Like [`read_for_linked_map`], dealing with corrupted blocks as `policy` says and reporting the dropped ones.
+pub fn read_for_linked_map_with_policy(bytes: &[u8], policy: CorruptionPolicy)
+                                       -> Result<(HashMap<Vec<u8>, LinkedMap>, CorruptionReport), WalReadError> {
+    replay_for_linked_map(bytes, policy)
+}
+
+/// Same as [`read_for_linked_map_with_policy`] for a WAL mapped in memory or streamed, see [`ReplaySource`].
+pub(crate) fn replay_for_linked_map<S: ReplaySource>(source: S, policy: CorruptionPolicy)
+                                                     -> Result<(LinkedMaps, CorruptionReport), S::Error> {
+    let mut result: HashMap<Vec<u8>, LinkedMap> = HashMap::new();
+    let report = source.replay(policy, |stored_action| {
+        match *stored_action.act_type() {
+            DELETE_ACT => {
+                result.remove(stored_action.data());
+            }
+            MAP_PUT_ACT => {
+                let put_action: SortedMapEntry = bincode::deserialize(stored_action.data())?;
+                let (key, search_key, value) = put_action.entry();
+                result.entry(key).or_default().put(linked_field(search_key)?, value);
+            }
+            MAP_REMOVE_ACT => {
+                let remove_action: SortedMapKey = bincode::deserialize(stored_action.data())?;
+                let (key, search_key) = remove_action.owned();
+                if let Some(linked_map) = result.get_mut(&key) {
+                    linked_map.remove(&linked_field(search_key)?);
+                }
+            }
+            PADDING_ACT => {}
+            act_type => return Err(not_supported(act_type)),
+        }
+        Ok(())
+    })?;
+    Ok((result, report))
+}
+
+fn linked_field(search_key: SearchKey) -> bincode::Result<Vec<u8>> {
+    match <[Key; 1]>::try_from(search_key.into_key_vec()) {
+        Ok([Key::Bytes(field)]) => Ok(field),
+        _ => Err(Box::new(bincode::ErrorKind::Custom("linked map field is not a single bytes key".to_string()))),
+    }
+}
+
+fn not_supported(act_type: u8) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(format!("not supported action type: {}", act_type)))
+}
+
+/// Passes each block of `bytes` passing CRC verification to `apply`, in file order. A block failing it, cut short by
+/// the end of `bytes` or which `apply` fails to decode, e.g. of an action type it doesn't know, is handled according
+/// to `policy`, failing the replay with the matching [`WalReadError`] under [`CorruptionPolicy::Abort`].
+fn replay_blocks(bytes: &[u8], policy: CorruptionPolicy, apply: impl FnMut(StoredAction) -> bincode::Result<()>)
+                 -> Result<CorruptionReport, WalReadError> {
+    let (header, bytes) = WalHeader::split(bytes);
+    replay_block_source(&header, SliceBlocks { bytes, offset: 0 }, policy, apply)
+}
+
+/// WAL whose blocks can be replayed in file order like [`replay_blocks`] does for a slice, e.g. a
+/// [`streamed::WalContent`] which may be read from the file a block at a time.
+pub(crate) trait ReplaySource {
+    type Error: From<WalReadError>;
+
+    fn replay(self, policy: CorruptionPolicy, apply: impl FnMut(StoredAction) -> bincode::Result<()>)
+              -> Result<CorruptionReport, Self::Error>;
+}
+
+impl ReplaySource for &[u8] {
+    type Error = WalReadError;
+
+    fn replay(self, policy: CorruptionPolicy, apply: impl FnMut(StoredAction) -> bincode::Result<()>)
+              -> Result<CorruptionReport, WalReadError> {
+        replay_blocks(self, policy, apply)
+    }
+}
+
+/// Blocks of the body of a WAL, in file order, see [`replay_block_source`].
+trait BlockSource {
+    /// Next block with its offset, `None` past the last one, `Err` with the offset of a block cut short by the end
+    /// of the body.
+    fn next_block(&mut self) -> Option<Result<(usize, StoredAction), usize>>;
+
+    /// Bytes of the body, read up to its end if needed.
+    fn body_len(&mut self) -> usize;
+}
+
+struct SliceBlocks<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl BlockSource for SliceBlocks<'_> {
+    fn next_block(&mut self) -> Option<Result<(usize, StoredAction), usize>> {
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+        let block_offset = self.offset;
+        Some(try_build_action(&mut self.offset, self.bytes).map(|stored_action| (block_offset, stored_action)).ok_or(block_offset))
+    }
+
+    fn body_len(&mut self) -> usize {
+        self.bytes.len()
+    }
+}
+
+/// Same as [`replay_blocks`] for the blocks of `source`, so the body can be read from a slice or a stream alike.
+fn replay_block_source(header: &WalHeader, mut source: impl BlockSource, policy: CorruptionPolicy,
+                       mut apply: impl FnMut(StoredAction) -> bincode::Result<()>) -> Result<CorruptionReport, WalReadError> {
+    let mut report = CorruptionReport::default();
+
+    while let Some(block) = source.next_block() {
+        let (block_offset, stored_action) = match block {
+            Ok(block) => block,
+            Err(block_offset) if policy == CorruptionPolicy::Abort => {
+                return Err(WalReadError::CutShort { offset: block_offset, end: source.body_len() });
+            }
+            Err(block_offset) => {
+                report.truncate_at(block_offset, source.body_len());
+                break;
+            }
+        };
+        let offset = block_offset + stored_action.block_len();
+        report.blocks_read += 1;
+
+        if !stored_action.valid_crc(header.crc_scope()) {
+            match policy {
+                CorruptionPolicy::Abort => return Err(WalReadError::CrcMismatch { offset: block_offset }),
+                CorruptionPolicy::SkipBlock => {
+                    report.skipped_blocks.push(block_offset..offset);
+                    continue;
+                }
+                CorruptionPolicy::TruncateAt => {
+                    report.truncate_at(block_offset, source.body_len());
+                    break;
+                }
+            }
+        }
+        if *stored_action.act_type() == TIMESTAMP_ACT {
+            continue;
+        }
+        if let Err(error) = apply(stored_action) {
+            warn!("can't decode block at offset {}: {}", block_offset, error);
+            match policy {
+                CorruptionPolicy::Abort => return Err(WalReadError::DecodeFailed { offset: block_offset }),
+                CorruptionPolicy::SkipBlock => report.skipped_blocks.push(block_offset..offset),
+                CorruptionPolicy::TruncateAt => {
+                    report.truncate_at(block_offset, source.body_len());
+                    break;
+                }
+            }
         }
     }
-    result
+
+    if !report.is_clean() {
+        warn!("dropped {} bytes of corrupted WAL blocks: {:?}", report.dropped_bytes(), report);
+    }
+    Ok(report)
+}
+
+/// Value of a replay which panics on corrupted blocks, as under [`CorruptionPolicy::Abort`] before it could fail.
+pub(crate) fn or_abort<T>(result: Result<T, WalReadError>) -> T {
+    result.unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// Transactions begun but never ended in a transaction WAL, in the order they began.
+pub fn read_unfinished_transactions(bytes: &[u8]) -> Result<Vec<TxRecord>, WalReadError> {
+    replay_unfinished_transactions(bytes)
+}
+
+/// Same as [`read_unfinished_transactions`] for a WAL mapped in memory or streamed, see [`ReplaySource`].
+pub(crate) fn replay_unfinished_transactions<S: ReplaySource>(source: S) -> Result<Vec<TxRecord>, S::Error> {
+    let mut unfinished: BTreeMap<u64, TxRecord> = BTreeMap::new();
+    let mut order = Vec::new();
+    source.replay(CorruptionPolicy::Abort, |stored_action| {
+        match *stored_action.act_type() {
+            TX_BEGIN_ACT => {
+                let tx_record: TxRecord = bincode::deserialize(stored_action.data())?;
+                order.push(tx_record.id());
+                unfinished.insert(tx_record.id(), tx_record);
+            }
+            TX_END_ACT => {
+                let tx_id = u64::from_ne_bytes(stored_action.data().try_into().expect("tx id should be 8 bytes"));
+                unfinished.remove(&tx_id);
+            }
+            PADDING_ACT => {}
+            act_type => return Err(not_supported(act_type)),
+        }
+        Ok(())
+    })?;
+    Ok(order.into_iter().filter_map(|tx_id| unfinished.remove(&tx_id)).collect())
 }
 
 fn build_action(offset: &mut usize, bytes: &[u8]) -> StoredAction {
@@ -292,89 +1819,223 @@ fn build_action(offset: &mut usize, bytes: &[u8]) -> StoredAction {
     StoredAction::new(act_type, crc, data_size, data, start_offset)
 }
 
+/// Reads the block at `offset` like `build_action`, `None` if `bytes` end before the whole block.
+fn try_build_action(offset: &mut usize, bytes: &[u8]) -> Option<StoredAction> {
+    let data_size_start = *offset + (ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN) as usize;
+    let data_size_arr: [u8; 4] = bytes.get(data_size_start..data_size_start + DATA_SIZE_FIELD_LEN as usize)?.try_into().ok()?;
+    let block_len = FIXED_BLOCK_LEN as usize + u32::from_ne_bytes(data_size_arr) as usize;
+    if bytes.len() - *offset < block_len {
+        return None;
+    }
+    Some(build_action(offset, bytes))
+}
+
+/// Same as [`iter_actions`] but never panics: a truncated block is yielded as `Err` with its offset and ends
+/// the iteration, since blocks after it can't be located.
+pub fn try_iter_actions(bytes: &[u8]) -> impl Iterator<Item = Result<StoredAction, usize>> + '_ {
+    let (_header, bytes) = WalHeader::split(bytes);
+    let mut offset = 0;
+
+    std::iter::from_fn(move || {
+        if offset >= bytes.len() {
+            return None;
+        }
+        let block_offset = offset;
+        match try_build_action(&mut offset, bytes) {
+            Some(stored_action) => Some(Ok(stored_action)),
+            None => {
+                offset = bytes.len();
+                Some(Err(block_offset))
+            }
+        }
+    })
+}
+
+/// Iterates over all blocks of `bytes` in file order, without CRC verification.
+pub fn iter_actions(bytes: &[u8]) -> impl Iterator<Item = StoredAction> + '_ {
+    let (_header, bytes) = WalHeader::split(bytes);
+    let mut offset = 0;
+
+    std::iter::from_fn(move || {
+        if offset >= bytes.len() {
+            return None;
+        }
+        Some(build_action(&mut offset, bytes))
+    })
+}
+
+/// State of a KeyValue WAL as of `timestamp` (milliseconds since the Unix epoch): blocks written after it are
+/// ignored. Only a WAL with [`TIMESTAMPED_FLAG`] records when its blocks were written, all blocks of others are read.
+pub fn collect_as_of(bytes: &[u8], timestamp: u64) -> HashMap<Vec<u8>, Vec<u8>> {
+    read_forward(&bytes[..len_as_of(bytes, timestamp)])
+}
+
+/// Length of the WAL up to the first write after `timestamp`, its prefix is a WAL on its own.
+fn len_as_of(bytes: &[u8], timestamp: u64) -> usize {
+    let (header, _) = WalHeader::split(bytes);
+    if !header.has_timestamps() {
+        return bytes.len();
+    }
+    iter_actions(bytes)
+        .find(|stored_action| stored_action.timestamp().is_some_and(|written_at| written_at > timestamp))
+        .map_or(bytes.len(), |stored_action| header.encoded_len() + *stored_action.start_offset() as usize)
+}
+
+/// Reads a KeyValue WAL from the end, falling back to a forward read. `MERGE_ACT` blocks are skipped by the
+/// fallback, a WAL written with a merge operator is read by [`collect_merging`].
 pub fn collect(bytes: &[u8]) -> HashMap<Vec<u8>, Vec<u8>> {
+    collect_with_report(bytes).0
+}
+
+/// Same as [`collect`], also returning the blocks skipped by the fallback to a forward read under
+/// [`CorruptionPolicy::SkipBlock`], taken when reading from the end fails. The report is clean if it wasn't taken.
+pub fn collect_with_report(bytes: &[u8]) -> (HashMap<Vec<u8>, Vec<u8>>, CorruptionReport) {
+    collect_reporting(bytes, None)
+}
+
+/// Same as [`collect`], folding `MERGE_ACT` operands with `merge_operator`. Reading from the end fails on the last
+/// merge, which can't be folded without the values before it, so the WAL is then replayed from the start.
+pub fn collect_merging(bytes: &[u8], merge_operator: &MergeOperator) -> HashMap<Vec<u8>, Vec<u8>> {
+    collect_reporting(bytes, Some(merge_operator)).0
+}
+
+fn collect_reporting(bytes: &[u8], merge_operator: Option<&MergeOperator>) -> (HashMap<Vec<u8>, Vec<u8>>, CorruptionReport) {
     info!("trying to read result from end");
     match read_backward(bytes) {
-        Ok(val) => { val }
-        Err(_) => {
-            error!("error happened while reading from end, reading bytes from start");
-            read_forward(bytes)
+        Ok(val) => (val, CorruptionReport::default()),
+        Err(e) => {
+            error!("error happened while reading from end: {}, reading bytes from start", e);
+            let (map, report) = or_abort(replay_forward_versioned(bytes, merge_operator, CorruptionPolicy::SkipBlock));
+            if !report.is_clean() {
+                warn!("skipped {} corrupted blocks reading from start", report.skipped_blocks.len());
+            }
+            (map.into_iter().map(|(key, (value, _version))| (key, value)).collect(), report)
         }
     }
 }
 
-pub fn read_backward(bytes: &[u8]) -> Result<HashMap<Vec<u8>, Vec<u8>>, ()> {
-    let mut result = HashMap::new();
-    let mut removed_keys = HashSet::new();
-
-    let size = bytes.len();
-    let mut offset = match prev_block_start_offset(size, bytes) {
-        Ok(val) => val,
-        Err(_err) => { return Err(()); }
-    };
+/// Reads blocks from the last one back, failing with the [`PigmentError`] of the first one which can't be read.
+pub fn read_backward(bytes: &[u8]) -> Result<HashMap<Vec<u8>, Vec<u8>>, PigmentError> {
+    read_backward_while(bytes, |_, _| true).map(|(map, _)| map)
+}
 
-    let mut stored_action = build_action(&mut offset, bytes);
+/// Same as [`read_backward`], stopping as soon as `max_keys` live entries were found, for callers knowing how many
+/// keys the store holds. Keys removed by a later block don't count.
+pub fn read_backward_until(bytes: &[u8], max_keys: usize) -> Result<HashMap<Vec<u8>, Vec<u8>>, PigmentError> {
+    read_backward_while(bytes, |map, _| map.len() < max_keys).map(|(map, _)| map)
+}
 
-    update_backward_reading_map(&stored_action, &mut result, &mut removed_keys);
+/// Same as [`read_backward`], stopping once `window` blocks in a row brought no key which wasn't found or removed
+/// yet. Keys last written further back than that are missed, so it only fits WALs whose keys are all rewritten
+/// regularly (e.g. a few hot keys updated over and over).
+pub fn read_backward_until_stable(bytes: &[u8], window: usize) -> Result<HashMap<Vec<u8>, Vec<u8>>, PigmentError> {
+    let mut seen_keys = 0;
+    let mut stale_blocks = 0;
+    read_backward_while(bytes, |map, removed_keys| {
+        if map.len() + removed_keys.len() == seen_keys {
+            stale_blocks += 1;
+        } else {
+            seen_keys = map.len() + removed_keys.len();
+            stale_blocks = 0;
+        }
+        stale_blocks <= window
+    }).map(|(map, _)| map)
+}
 
-    let mut last_consumed = stored_action.start_offset() == &0;
+/// Entries read backward with the number of blocks read.
+type BackwardRead = (HashMap<Vec<u8>, Vec<u8>>, usize);
+/// Entries with their values as written to the WAL, and the plain values of those a value transformer encoded.
+type EncodedEntries = (Vec<(Vec<u8>, Vec<u8>)>, Vec<Option<Vec<u8>>>);
 
-    while !last_consumed {
-        let mut offset = match prev_block_start_offset(*stored_action.start_offset() as usize, bytes) {
-            Ok(val) => val,
-            Err(_) => { return Err(()); }
-        };
-        stored_action = build_action(&mut offset, bytes);
-        update_backward_reading_map(&stored_action, &mut result, &mut removed_keys);
-        if stored_action.start_offset() == &0 {
-            last_consumed = true;
+/// Reads blocks from the last one back while `keep_reading` holds for the entries found and keys removed so far.
+fn read_backward_while(bytes: &[u8], mut keep_reading: impl FnMut(&HashMap<Vec<u8>, Vec<u8>>, &HashSet<Vec<u8>>) -> bool)
+                       -> Result<BackwardRead, PigmentError> {
+    let (header, bytes) = WalHeader::split(bytes);
+    let mut result = HashMap::new();
+    let mut removed_keys = HashSet::new();
+    let mut blocks_read = 0;
+
+    let mut block_end = bytes.len();
+    while block_end > 0 && keep_reading(&result, &removed_keys) {
+        let block_offset = prev_block_start_offset(block_end, bytes)
+            .ok_or(PigmentError::Corruption { offset: block_end })?;
+        // a start offset at or past the end of its block would index out of the WAL or never move back
+        if block_offset >= block_end {
+            return Err(PigmentError::Corruption { offset: block_end });
         }
+        let mut offset = block_offset;
+        let stored_action = match try_build_action(&mut offset, &bytes[..block_end]) {
+            Some(stored_action) if stored_action.block_len() == block_end - block_offset => stored_action,
+            _ => return Err(PigmentError::Corruption { offset: block_end }),
+        };
+        update_backward_reading_map(&stored_action, &header, &mut result, &mut removed_keys).map_err(|error| match error {
+            BackwardReadError::Decode => PigmentError::Decode { offset: block_offset },
+            BackwardReadError::CrcMismatch => PigmentError::CrcMismatch { offset: block_offset },
+        })?;
+        blocks_read += 1;
+        block_end = block_offset;
     }
-    Ok(result)
+    Ok((result, blocks_read))
+}
+
+/// Why a block read backward can't be applied, [`read_backward_while`] adds its offset.
+enum BackwardReadError {
+    Decode,
+    CrcMismatch,
 }
 
-fn update_backward_reading_map(stored_action: &StoredAction, map: &mut HashMap<Vec<u8>, Vec<u8>>, removed_keys: &mut HashSet<Vec<u8>>) {
+fn update_backward_reading_map(stored_action: &StoredAction, header: &WalHeader, map: &mut HashMap<Vec<u8>, Vec<u8>>, removed_keys: &mut HashSet<Vec<u8>>)
+                                -> Result<(), BackwardReadError> {
     match *stored_action.act_type() {
         model::DELETE_ACT => {
             let key = stored_action.data().to_vec();
             if !map.contains_key(&key) {
-                let valid_crc = valid_crc(stored_action.crc(), stored_action.data());
-                if !valid_crc {
-                    panic!("not valid crc"); // todo: revert to forward
+                if !stored_action.valid_crc(header.crc_scope()) {
+                    return Err(BackwardReadError::CrcMismatch);
                 }
                 removed_keys.insert(key);
             }
         }
-        model::PUT_ACT => {
-            let put_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+        model::PUT_ACT | model::VERSIONED_PUT_ACT => {
+            let put_action: KeyValueData = bincode::deserialize(stored_action.data()).map_err(|_| BackwardReadError::Decode)?;
             let (key, value) = put_action.owned_key_value();
 
             if !map.contains_key(&key) && !removed_keys.contains(&key) {
-                let valid_crc = valid_crc(stored_action.crc(), stored_action.data());
-                if !valid_crc {
-                    panic!("not valid crc"); // todo: revert to forward
+                if !stored_action.valid_crc(header.crc_scope()) {
+                    return Err(BackwardReadError::CrcMismatch);
                 }
                 map.insert(key, value);
             }
         }
-        _ => { panic!("not supported action type: {}", stored_action.act_type()) }
+        model::PUT_MANY_ACT => {
+            let put_action: KeyValuesData = bincode::deserialize(stored_action.data()).map_err(|_| BackwardReadError::Decode)?;
+            let mut crc_verified = false;
+            for (key, value) in put_action.owned_entries() {
+                if !map.contains_key(&key) && !removed_keys.contains(&key) {
+                    if !crc_verified && !stored_action.valid_crc(header.crc_scope()) {
+                        return Err(BackwardReadError::CrcMismatch);
+                    }
+                    crc_verified = true;
+                    map.insert(key, value);
+                }
+            }
+        }
+        // values are read regardless of their expiry, like any other caller of the raw map would
+        model::PADDING_ACT | model::TIMESTAMP_ACT | model::EXPIRE_ACT => {}
+        // a merge can't be folded without the value before it, which is further back
+        _ => return Err(BackwardReadError::Decode),
     }
+    Ok(())
 }
 
-fn prev_block_start_offset(idx: usize, bytes: &[u8]) -> Result<usize, TryFromSliceError> {
+/// Start offset recorded at the end of the block ending at `idx`, `None` if fewer bytes than it takes come before.
+fn prev_block_start_offset(idx: usize, bytes: &[u8]) -> Option<usize> {
     let block_start_len = BLOCK_START_OFFSET_LEN as usize;
-    let block_start_slice = &bytes[idx - block_start_len..idx];
-    let block_start_arr: [u8; 4] = match block_start_slice.try_into() {
-        Ok(arr) => arr,
-        Err(error) => return Err(error)
-    };
-    Ok(u32::from_ne_bytes(block_start_arr) as usize)
+    let block_start_slice = bytes.get(idx.checked_sub(block_start_len)?..idx)?;
+    let block_start_arr: [u8; 4] = block_start_slice.try_into().ok()?;
+    Some(u32::from_ne_bytes(block_start_arr) as usize)
 }
 
-fn valid_crc(expected_crc: &u32, data: &[u8]) -> bool {
-    let actual_crc = model::crc(data);
-    actual_crc == *expected_crc
-}
 
 #[ignore]
 #[test]
@@ -387,11 +2048,11 @@ fn test_with_file() {
     }
     let wal = WalStorage::new_file_based(Path::new(file_path));
 
-    wal.store_put_event(b"x".to_vec(), b"X".to_vec());
-    wal.store_put_event(b"a".to_vec(), b"A".to_vec());
-    wal.store_put_event(b"a".to_vec(), b"AAA".to_vec());
-    wal.store_put_event(b"b".to_vec(), b"B!".to_vec());
-    wal.store_delete_event(&b"x".to_vec());
+    wal.store_put_event(b"x".to_vec(), b"X".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"AAA".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B!".to_vec()).unwrap();
+    wal.store_delete_event(&b"x".to_vec()).unwrap();
 
 
     let bytes = std::fs::read(file_path).unwrap();
@@ -406,11 +2067,11 @@ fn test_with_file() {
 fn test_with_vec() {
     let wal = WalStorage::new_vec_based();
 
-    wal.store_put_event(b"x".to_vec(), b"X".to_vec());
-    wal.store_put_event(b"a".to_vec(), b"A".to_vec());
-    wal.store_put_event(b"a".to_vec(), b"AAA".to_vec());
-    wal.store_put_event(b"b".to_vec(), b"B!".to_vec());
-    wal.store_delete_event(&b"x".to_vec());
+    wal.store_put_event(b"x".to_vec(), b"X".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"AAA".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B!".to_vec()).unwrap();
+    wal.store_delete_event(&b"x".to_vec()).unwrap();
 
     let map = collect(&wal.wal_state.read().unwrap().writer);
     // let map = read_forward(&wal.wal_state.read().unwrap().writer);
@@ -420,6 +2081,231 @@ fn test_with_vec() {
     assert_eq!(map.len(), 2);
 }
 
+#[test]
+fn test_try_store_put_event() {
+    let wal = std::sync::Arc::new(WalStorage::new_vec_based());
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+    let r_lock = wal.wal_state.read().unwrap();
+    let result = wal.try_store_put_event(b"b".to_vec(), b"2".to_vec(), Duration::from_millis(10));
+    assert!(matches!(result, Err(WalError::WouldBlock)));
+    drop(r_lock);
+
+    let poisoning_wal = wal.clone();
+    let result = thread::spawn(move || {
+        let _w_lock = poisoning_wal.wal_state.write().unwrap();
+        panic!("panic outside of a write");
+    }).join();
+    assert!(result.is_err());
+    assert!(wal.wal_state.is_poisoned());
+
+    wal.try_store_put_event(b"b".to_vec(), b"2".to_vec(), Duration::from_millis(10)).unwrap();
+    wal.store_put_event(b"c".to_vec(), b"3".to_vec()).unwrap();
+    assert!(!wal.wal_state.is_poisoned());
+
+    let map = read_forward(&wal.wal_state.read().unwrap().writer);
+    assert_eq!(map.len(), 3);
+    assert_eq!(map.get(b"b".as_slice()), Some(&b"2".to_vec()));
+}
+
+#[test]
+fn test_with_tee() {
+    let wal = WalStorage::with_tee(Vec::new(), Vec::new(), |_| panic!("Vec sink should not fail"));
+
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+    wal.store_delete_event(b"a").unwrap();
+
+    let state = wal.wal_state.read().unwrap();
+    let primary = &state.writer.primary;
+    let secondary = state.writer.secondary.as_ref().unwrap();
+    assert!(!primary.is_empty());
+    assert_eq!(primary, secondary);
+
+    let map = collect(secondary);
+    assert_eq!(map.get(b"b".as_slice()), Some(&b"B".to_vec()));
+    assert_eq!(map.len(), 1);
+}
+
+#[test]
+fn test_append_many_to_set_size() {
+    let key = vec![7u8; 200];
+    let elements: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_ne_bytes().to_vec()).collect();
+
+    let per_element_wal = WalStorage::new_vec_based();
+    for element in &elements {
+        per_element_wal.store_append_to_set_event(key.clone(), element.clone()).unwrap();
+    }
+    let batched_wal = WalStorage::new_vec_based();
+    batched_wal.store_append_many_to_set_event(key.clone(), elements.clone()).unwrap();
+
+    let per_element_bytes = &per_element_wal.wal_state.read().unwrap().writer;
+    let batched_bytes = &batched_wal.wal_state.read().unwrap().writer;
+    println!("per element: {} bytes, batched: {} bytes", per_element_bytes.len(), batched_bytes.len());
+    assert!(batched_bytes.len() * 10 < per_element_bytes.len());
+
+    let per_element_sets = read_for_set(per_element_bytes);
+    let batched_sets = read_for_set(batched_bytes);
+    assert_eq!(batched_sets.get(&key).unwrap().len(), 1000);
+    assert_eq!(per_element_sets, batched_sets);
+}
+
+#[test]
+fn test_analyze() {
+    let wal = WalStorage::new_vec_based();
+
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"AA".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"AAA".to_vec()).unwrap();
+
+    let wal_bytes = wal.wal_state.read().unwrap().writer.clone();
+    let plan = analyze(&wal_bytes);
+    let (_header, bytes) = WalHeader::split(&wal_bytes);
+
+    assert_eq!(plan.live, read_forward(&wal_bytes));
+    assert_eq!(plan.live.get(b"a".as_slice()), Some(&b"AAA".to_vec()));
+    assert_eq!(plan.reclaimable.len(), 2);
+
+    let mut offset = plan.reclaimable[0].start;
+    let first_put = build_action(&mut offset, bytes);
+    assert_eq!(offset, plan.reclaimable[0].end);
+    assert_eq!(first_put.start_offset(), &0);
+
+    let mut offset = plan.reclaimable[1].start;
+    let second_put: KeyValueData = bincode::deserialize(build_action(&mut offset, bytes).data()).unwrap();
+    assert_eq!(second_put.owned_key_value(), (b"a".to_vec(), b"AA".to_vec()));
+
+    wal.store_delete_event(b"b").unwrap();
+    let plan = analyze(&wal.wal_state.read().unwrap().writer);
+    assert_eq!(plan.reclaimable.len(), 4);
+    assert_eq!(plan.live.len(), 1);
+}
+
+#[test]
+fn test_without_crc() {
+    let wal = WalStorage::new_vec_based_without_crc();
+
+    wal.store_put_event(b"x".to_vec(), b"X".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"a".to_vec(), b"AAA".to_vec()).unwrap();
+    wal.store_delete_event(b"x").unwrap();
+
+    let bytes = wal.wal_state.read().unwrap().writer.clone();
+    let (header, body) = WalHeader::split(&bytes);
+    assert!(!header.crc_enabled());
+
+    let mut offset = 0;
+    while offset < body.len() {
+        assert_eq!(build_action(&mut offset, body).crc(), &NO_CRC);
+    }
+
+    let forward = read_forward(&bytes);
+    assert_eq!(forward.get(b"a".as_slice()), Some(&b"AAA".to_vec()));
+    assert_eq!(forward.len(), 1);
+    assert_eq!(read_backward(&bytes).unwrap(), forward);
+}
+
+#[test]
+fn test_read_legacy_without_header() {
+    let wal = WalStorage::new_vec_based();
+
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+
+    let bytes = wal.wal_state.read().unwrap().writer.clone();
+    let (header, body) = WalHeader::split(&bytes);
+    assert_eq!(header, WalHeader::new(true));
+    assert_eq!(body.len(), bytes.len() - HEADER_LEN as usize);
+
+    let mut legacy = Vec::new();
+    let a = StoredAction::put_action(&0, &KeyValueData::new(b"a".to_vec(), b"A".to_vec()), CrcScope::Data);
+    let b = StoredAction::put_action(&(a.block_len() as u32), &KeyValueData::new(b"b".to_vec(), b"B".to_vec()), CrcScope::Data);
+    write(&mut legacy, &[a, b], None).unwrap();
+    assert_eq!(legacy.len(), body.len());
+
+    assert_eq!(read_forward(&legacy), read_forward(&bytes));
+    assert_eq!(read_backward(&legacy).unwrap().len(), 2);
+}
+
+#[test]
+fn test_block_crc_detects_flipped_fields() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+
+    let bytes = wal.wal_state.read().unwrap().writer.clone();
+    let (header, body) = WalHeader::split(&bytes);
+    assert_eq!(header.crc_scope(), CrcScope::Block);
+    assert!(iter_actions(&bytes).all(|stored_action| stored_action.valid_crc(header.crc_scope())));
+
+    let data_size_at = (ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN) as usize;
+    let start_offset_at = iter_actions(&bytes).next().unwrap().block_len() - BLOCK_START_OFFSET_LEN as usize;
+    let crc_at = ACT_TYPE_FIELD_LEN as usize;
+    for (field, at) in [("act_type", 0), ("crc", crc_at), ("data_size", data_size_at), ("start_offset", start_offset_at)] {
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_LEN as usize + at] ^= 1;
+
+        let detected = match try_iter_actions(&corrupted).next().unwrap() {
+            Ok(stored_action) => !stored_action.valid_crc(header.crc_scope()),
+            Err(_truncated) => true,
+        };
+        assert!(detected, "flipped bit in {} was not detected", field);
+    }
+
+    let data_only_crc = crc(iter_actions(&bytes).next().unwrap().data());
+    assert_ne!(u32::from_ne_bytes(body[crc_at..crc_at + 4].try_into().unwrap()), data_only_crc);
+}
+
+#[test]
+fn test_aligned_blocks() {
+    for alignment in [512, 4096] {
+        let path = std::env::temp_dir().join(format!("pigment_db_aligned_{}_{}.dat", alignment, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(WalStorage::new_file_based_aligned(&path, 1000).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        let wal = WalStorage::new_file_based_aligned(&path, alignment).unwrap();
+        wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+        wal.store_put_event(b"b".to_vec(), vec![7; alignment as usize - 20]).unwrap();
+        wal.store_put_event(b"c".to_vec(), vec![8; alignment as usize * 2]).unwrap();
+        wal.store_put_batch_event(vec![(b"a".to_vec(), b"AA".to_vec()), (b"d".to_vec(), b"D".to_vec())]).unwrap();
+        wal.store_delete_event(b"b").unwrap();
+        let (start_offset, _, _) = wal.store_put_event_at(b"e".to_vec(), b"E".to_vec()).unwrap();
+        assert_eq!(start_offset % alignment, 0);
+        drop(wal);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let (header, _) = WalHeader::split(&bytes);
+        assert_eq!(header.alignment(), alignment);
+        assert_eq!(header.encoded_len(), alignment as usize);
+
+        let blocks: Vec<StoredAction> = iter_actions(&bytes).filter(|stored_action| *stored_action.act_type() != PADDING_ACT).collect();
+        assert_eq!(blocks.len(), 7);
+        for stored_action in &blocks {
+            assert_eq!(*stored_action.start_offset() % alignment, 0);
+            assert_eq!((header.encoded_len() + *stored_action.start_offset() as usize) % alignment as usize, 0);
+            assert!(stored_action.valid_crc(header.crc_scope()));
+        }
+
+        let map = read_forward(&bytes);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get(b"a".as_slice()), Some(&b"AA".to_vec()));
+        assert!(!map.contains_key(b"b".as_slice()));
+        assert_eq!(read_backward(&bytes).unwrap(), map);
+
+        let wal = WalStorage::open_file_based(&path).unwrap();
+        wal.store_put_event(b"f".to_vec(), b"F".to_vec()).unwrap();
+        let stats = wal.wal_stats().unwrap();
+        drop(wal);
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), stats.total_bytes);
+        assert_eq!(*iter_actions(&bytes).last().unwrap().start_offset() % alignment, 0);
+        assert_eq!(read_forward(&bytes).len(), 5);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
 #[test]
 #[ignore]
 fn test_read_backward() {
@@ -436,4 +2322,384 @@ fn test_read_backward() {
     for (k, v) in result {
         println!("key: {}, value: {}", String::from_utf8_lossy(&k), String::from_utf8_lossy(&v));
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_follow() {
+    let file_path = std::env::temp_dir().join(format!("pigment_db_follow_{}.wal", std::process::id()));
+    let wal = WalStorage::new_file_based_aligned(&file_path, 64).unwrap();
+    let key_of = |stored_action: &StoredAction| {
+        let key_value: KeyValueData = bincode::deserialize(stored_action.data()).unwrap();
+        key_value.owned_key_value().0
+    };
+
+    let followed: Vec<Vec<u8>> = thread::scope(|scope| {
+        let follower = scope.spawn(|| {
+            wal.follow(0)
+                .filter(|stored_action| *stored_action.act_type() == PUT_ACT)
+                .take(100)
+                .map(|stored_action| key_of(&stored_action))
+                .collect()
+        });
+        for i in 0..100u32 {
+            wal.store_put_event(i.to_be_bytes().to_vec(), vec![0; i as usize]).unwrap();
+        }
+        follower.join().unwrap()
+    });
+    assert_eq!(followed, (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>());
+
+    let mut all_blocks = Vec::new();
+    let mut follower = wal.follow(0);
+    while let Some(stored_action) = follower.try_next().unwrap() {
+        all_blocks.push(stored_action);
+    }
+    assert!(all_blocks.len() > 100);
+
+    let mut follower = wal.follow(0);
+    for _ in 0..50 {
+        follower.try_next().unwrap().unwrap();
+    }
+    let mut resumed = wal.follow(follower.offset());
+    let mut rest = Vec::new();
+    while let Some(stored_action) = resumed.try_next().unwrap() {
+        rest.push(stored_action);
+    }
+    assert_eq!(rest.len(), all_blocks.len() - 50);
+    assert_eq!(rest[0].start_offset(), all_blocks[50].start_offset());
+    assert!(resumed.next_timeout(Duration::from_millis(10)).unwrap().is_none());
+
+    wal.store_put_event(b"last".to_vec(), vec![]).unwrap();
+    let mut last = resumed.next_timeout(Duration::from_millis(10)).unwrap().unwrap();
+    while *last.act_type() == PADDING_ACT {
+        last = resumed.next_timeout(Duration::from_millis(10)).unwrap().unwrap();
+    }
+    assert_eq!(key_of(&last), b"last");
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn test_follower_fails_after_rewrite() {
+    let file_path = std::env::temp_dir().join(format!("pigment_db_follow_rewrite_{}.wal", std::process::id()));
+    let _ = std::fs::remove_file(&file_path);
+    let wal = WalStorage::open_file_based(&file_path).unwrap();
+    for i in 0..10u32 {
+        wal.store_put_event(b"key".to_vec(), i.to_ne_bytes().to_vec()).unwrap();
+    }
+
+    let mut follower = wal.follow(0);
+    follower.try_next().unwrap().unwrap();
+    thread::scope(|scope| {
+        let waiting = scope.spawn(|| {
+            let mut waiting = wal.follow(0);
+            while waiting.try_next().unwrap().is_some() {}
+            waiting.next_timeout(Duration::from_secs(10))
+        });
+        thread::sleep(Duration::from_millis(20));
+        wal.compact_key_value(None).unwrap();
+        assert!(waiting.join().unwrap().is_err());
+    });
+
+    // blocks read before the rewrite are still yielded
+    for _ in 1..10 {
+        follower.try_next().unwrap().unwrap();
+    }
+    assert!(follower.try_next().is_err());
+
+    let mut restarted = wal.follow(0);
+    let compacted = restarted.try_next().unwrap().unwrap();
+    assert_eq!(*compacted.act_type(), VERSIONED_PUT_ACT);
+    assert!(restarted.try_next().unwrap().is_none());
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn test_read_backward_early_exit() {
+    let wal = WalStorage::new_vec_based();
+    for round in 0..100u8 {
+        for key in [b"a", b"b", b"c"] {
+            wal.store_put_event(key.to_vec(), vec![round]).unwrap();
+        }
+    }
+    wal.store_put_event(b"d".to_vec(), vec![0]).unwrap();
+    wal.store_delete_event(b"d").unwrap();
+
+    wal.read_bytes(|bytes| {
+        let (full, full_blocks) = read_backward_while(bytes, |_, _| true).unwrap();
+        assert_eq!(full_blocks, 302);
+        assert_eq!(full.len(), 3);
+        assert_eq!(full.get(b"a".as_slice()), Some(&vec![99]));
+
+        let (until, until_blocks) = read_backward_while(bytes, |map, _| map.len() < 3).unwrap();
+        assert_eq!(until, full);
+        // the removed `d` is read too before `a` is found
+        assert_eq!(until_blocks, 5);
+        assert_eq!(read_backward_until(bytes, 3).unwrap(), full);
+
+        assert_eq!(read_backward_until_stable(bytes, 10).unwrap(), full);
+        assert_eq!(read_backward_until(bytes, 0).unwrap().len(), 0);
+    });
+}
+
+#[test]
+fn test_retry_transient_write_failures() {
+    struct FlakyWriter {
+        bytes: Vec<u8>,
+        /// Kind of error returned by the next `failures` writes after the header.
+        failure: io::ErrorKind,
+        failures: u32,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.bytes.is_empty() && self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(self.failure));
+            }
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ReadableWal for FlakyWriter {
+        fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+            Ok(func(&self.bytes))
+        }
+    }
+
+    let policy = RetryPolicy { max_attempts: 3, backoff: Duration::from_millis(1) };
+    let flaky_wal = |failure, failures| {
+        WalStorage::new_writer_based(FlakyWriter { bytes: Vec::new(), failure, failures }).with_retry_policy(policy)
+    };
+
+    let wal = flaky_wal(io::ErrorKind::WouldBlock, 2);
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    assert_eq!(wal.wal_stats().unwrap().blocks, 1);
+
+    let wal = flaky_wal(io::ErrorKind::WouldBlock, 3);
+    assert_eq!(wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    let wal = flaky_wal(io::ErrorKind::StorageFull, 1);
+    assert_eq!(wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap_err().kind(), io::ErrorKind::StorageFull);
+    wal.store_put_event(b"b".to_vec(), b"2".to_vec()).unwrap();
+    assert_eq!(wal.wal_stats().unwrap().blocks, 1);
+}
+
+#[test]
+fn test_undecodable_block() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    // CRC covers whatever data the block has, a put with data of another codec passes verification
+    let bad_offset = wal.append(|offset| {
+        StoredAction::new(PUT_ACT, 0, 3, vec![0xff; 3], *offset).moved_to(*offset, wal.header.crc_scope())
+    }).unwrap() as usize;
+    wal.store_put_event(b"b".to_vec(), b"2".to_vec()).unwrap();
+    let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+    assert_eq!(try_read_forward(&bytes), Err(WalReadError::DecodeFailed { offset: bad_offset }));
+    let error = read_forward_versioned_with_policy(&bytes, None, CorruptionPolicy::Abort).unwrap_err();
+    assert_eq!(io::Error::from(error).kind(), io::ErrorKind::InvalidData);
+    assert_eq!(read_backward(&bytes), Err(PigmentError::Decode { offset: bad_offset }));
+
+    let (map, report) = read_forward_versioned_with_policy(&bytes, None, CorruptionPolicy::SkipBlock).unwrap();
+    assert_eq!(map.len(), 2);
+    assert_eq!(report.skipped_blocks, vec![bad_offset..bad_offset + FIXED_BLOCK_LEN as usize + 3]);
+
+    let (map, report) = read_forward_versioned_with_policy(&bytes, None, CorruptionPolicy::TruncateAt).unwrap();
+    assert_eq!(map.keys().collect::<Vec<_>>(), vec![&b"a".to_vec()]);
+    assert_eq!(report.truncated_at, Some(bad_offset));
+}
+
+#[test]
+fn test_read_backward_crc_mismatch() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    let last_offset = wal.read_bytes(|bytes| WalHeader::split(bytes).1.len());
+    wal.store_put_event(b"b".to_vec(), b"2".to_vec()).unwrap();
+    let mut bytes = wal.read_bytes(|bytes| bytes.to_vec());
+    // last byte of the value, right before the block start offset
+    let value_idx = bytes.len() - BLOCK_START_OFFSET_LEN as usize - 1;
+    bytes[value_idx] ^= 0xff;
+
+    assert_eq!(read_backward(&bytes), Err(PigmentError::CrcMismatch { offset: last_offset }));
+    assert_eq!(read_backward_until(&bytes, 1), Err(PigmentError::CrcMismatch { offset: last_offset }));
+}
+
+#[test]
+fn test_collect_with_corrupted_block_start_offsets() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"2".to_vec()).unwrap();
+    let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+    let body_len = WalHeader::split(&bytes).1.len();
+    let start_offset_idx = bytes.len() - BLOCK_START_OFFSET_LEN as usize;
+
+    // past the end of the WAL
+    let mut past_end = bytes.clone();
+    past_end[start_offset_idx..].copy_from_slice(&1000u32.to_ne_bytes());
+    assert_eq!(read_backward(&past_end), Err(PigmentError::Corruption { offset: body_len }));
+    let (map, _) = collect_with_report(&past_end);
+    assert_eq!(map.get(b"a".as_slice()), Some(&b"1".to_vec()));
+
+    // the end of its own block, which would be read over and over
+    let mut own_end = bytes.clone();
+    own_end[start_offset_idx..].copy_from_slice(&(body_len as u32).to_ne_bytes());
+    assert_eq!(read_backward(&own_end), Err(PigmentError::Corruption { offset: body_len }));
+    assert_eq!(collect(&own_end).get(b"a".as_slice()), Some(&b"1".to_vec()));
+
+    // within the previous block, so the block read from there isn't as long as the gap to the end
+    let mut inside_previous = bytes.clone();
+    inside_previous[start_offset_idx..].copy_from_slice(&1u32.to_ne_bytes());
+    assert_eq!(read_backward(&inside_previous), Err(PigmentError::Corruption { offset: body_len }));
+    assert_eq!(collect(&inside_previous).get(b"a".as_slice()), Some(&b"1".to_vec()));
+}
+
+#[test]
+fn test_unreadable_blocks_are_errors() {
+    let wal = WalStorage::new_vec_based_without_crc();
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    let merge_offset = wal.read_bytes(|bytes| WalHeader::split(bytes).1.len());
+    wal.store_merge_event(b"a".to_vec(), b"2".to_vec()).unwrap();
+    let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+    assert_eq!(read_backward(&bytes), Err(PigmentError::Decode { offset: merge_offset }));
+    assert_eq!(read_forward_transformed_with_policy(&bytes, None, None, CorruptionPolicy::Abort).unwrap_err(),
+               WalReadError::DecodeFailed { offset: merge_offset });
+
+    let mut unknown_type = bytes.clone();
+    let header_len = unknown_type.len() - WalHeader::split(&bytes).1.len();
+    unknown_type[header_len + merge_offset] = 0xEE;
+    assert_eq!(read_backward(&unknown_type), Err(PigmentError::Decode { offset: merge_offset }));
+    let (map, _, report) = read_forward_transformed_with_policy(&unknown_type, None, None, CorruptionPolicy::SkipBlock).unwrap();
+    assert_eq!(map.get(b"a".as_slice()), Some(&(b"1".to_vec(), 1)));
+    assert_eq!(report.skipped_blocks.len(), 1);
+
+    // fewer bytes after the header than a block start offset takes
+    let mut cut = bytes[..header_len].to_vec();
+    cut.extend_from_slice(&[0, 0]);
+    assert_eq!(read_backward(&cut), Err(PigmentError::Corruption { offset: 2 }));
+    assert_eq!(try_read_forward(&cut), Err(WalReadError::CutShort { offset: 0, end: 2 }));
+}
+
+#[test]
+fn test_unknown_action_types_follow_policy() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_append_to_set_event(b"s".to_vec(), b"1".to_vec()).unwrap();
+    let put_offset = wal.read_bytes(|bytes| WalHeader::split(bytes).1.len());
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+    let body_len = WalHeader::split(&bytes).1.len();
+
+    assert_eq!(read_for_set_with_policy(&bytes, CorruptionPolicy::Abort).unwrap_err(), WalReadError::DecodeFailed { offset: put_offset });
+    let (sets, report) = read_for_set_with_policy(&bytes, CorruptionPolicy::SkipBlock).unwrap();
+    assert_eq!(sets.get(b"s".as_slice()).map(HashSet::len), Some(1));
+    assert_eq!(report.skipped_blocks, vec![put_offset..body_len]);
+
+    let (maps, report) = read_for_map_with_policy(&bytes, CorruptionPolicy::TruncateAt).unwrap();
+    assert!(maps.is_empty());
+    assert_eq!(report.truncated_at, Some(0));
+    let (linked_maps, report) = read_for_linked_map_with_policy(&bytes, CorruptionPolicy::SkipBlock).unwrap();
+    assert!(linked_maps.is_empty());
+    assert_eq!(report.skipped_blocks.len(), 2);
+    assert_eq!(read_unfinished_transactions(&bytes).unwrap_err(), WalReadError::DecodeFailed { offset: 0 });
+
+    let mut crc_mismatch = bytes.clone();
+    let value_idx = crc_mismatch.len() - BLOCK_START_OFFSET_LEN as usize - 1;
+    crc_mismatch[value_idx] ^= 0xff;
+    assert_eq!(read_for_set_with_policy(&crc_mismatch, CorruptionPolicy::Abort).unwrap_err(), WalReadError::CrcMismatch { offset: put_offset });
+}
+
+#[test]
+fn test_collect_as_of() {
+    use crate::clock::ManualClock;
+
+    let clock = Arc::new(ManualClock::new(1_000));
+    let wal = WalStorage::new_vec_based_timestamped(clock.clone());
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"1".to_vec()).unwrap();
+    clock.set(2_000);
+    wal.store_put_event(b"a".to_vec(), b"2".to_vec()).unwrap();
+    wal.store_delete_event(b"b").unwrap();
+    clock.set(3_000);
+    wal.store_put_batch_event(vec![(b"c".to_vec(), b"3".to_vec()), (b"a".to_vec(), b"3".to_vec())]).unwrap();
+    let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+    assert_eq!(collect_as_of(&bytes, 999), HashMap::new());
+    assert_eq!(collect_as_of(&bytes, 1_000), HashMap::from([(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"1".to_vec())]));
+    assert_eq!(collect_as_of(&bytes, 2_999), HashMap::from([(b"a".to_vec(), b"2".to_vec())]));
+    assert_eq!(collect_as_of(&bytes, 3_000), read_forward(&bytes));
+    assert_eq!(read_forward(&bytes).get(b"a".as_slice()), Some(&b"3".to_vec()));
+    assert_eq!(read_backward(&bytes).unwrap(), read_forward(&bytes));
+    let timestamps: Vec<u64> = iter_actions(&bytes).filter_map(|stored_action| stored_action.timestamp()).collect();
+    assert_eq!(timestamps, vec![1_000, 1_000, 2_000, 2_000, 3_000]);
+
+    let untimed = WalStorage::new_vec_based();
+    untimed.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    assert_eq!(untimed.read_bytes(|bytes| collect_as_of(bytes, 0)).len(), 1);
+}
+
+#[test]
+fn test_collect_skips_corrupted_blocks() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"1".to_vec()).unwrap();
+    let last_offset = wal.read_bytes(|bytes| WalHeader::split(bytes).1.len());
+    wal.store_put_event(b"b".to_vec(), b"2".to_vec()).unwrap();
+    let mut bytes = wal.read_bytes(|bytes| bytes.to_vec());
+    let value_idx = bytes.len() - BLOCK_START_OFFSET_LEN as usize - 1;
+    bytes[value_idx] ^= 0xff;
+    assert!(read_backward(&bytes).is_err());
+
+    let (map, report) = collect_with_report(&bytes);
+    assert_eq!(map.get(b"a".as_slice()), Some(&b"1".to_vec()));
+    assert_eq!(map.get(b"b".as_slice()), Some(&b"1".to_vec()));
+    assert_eq!(report.skipped_blocks, vec![last_offset..WalHeader::split(&bytes).1.len()]);
+    assert_eq!(collect(&bytes), map);
+
+    let (_, report) = collect_with_report(&wal.read_bytes(|bytes| bytes.to_vec()));
+    assert!(report.is_clean());
+}
+
+#[test]
+fn test_actions_between() {
+    let wal = WalStorage::new_vec_based();
+    for i in 0..10u8 {
+        wal.store_put_event(vec![i], vec![i]).unwrap();
+    }
+
+    let keys_of = |actions: Vec<StoredAction>| -> Vec<Vec<u8>> {
+        actions.iter()
+            .map(|stored_action| bincode::deserialize::<KeyValueData>(stored_action.data()).unwrap().owned_key_value().0)
+            .collect()
+    };
+    assert_eq!(keys_of(wal.actions_between(3, 5).unwrap()), vec![vec![3], vec![4], vec![5]]);
+    assert_eq!(keys_of(wal.actions_between(8, 100).unwrap()), vec![vec![8], vec![9]]);
+    assert_eq!(wal.actions_between(10, 20).unwrap().len(), 0);
+    assert_eq!(wal.actions_between(5, 4).unwrap().len(), 0);
+}
+
+#[test]
+fn test_payload_too_large() {
+    assert_eq!(check_payload_len(u32::MAX as usize), Ok(()));
+    let error = check_payload_len(u32::MAX as usize + 1).unwrap_err();
+    assert_eq!(error, PayloadTooLarge { len: u32::MAX as usize + 1 });
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn test_offset_chain_verified() {
+    let wal = WalStorage::new_vec_based();
+    wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+    wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+
+    // a block recording a start offset one past where it's written, as a miscounted offset would
+    let key_value = KeyValueData::new(b"c".to_vec(), b"C".to_vec());
+    let skewed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        wal.append(|offset| StoredAction::put_action(&(offset + 1), &key_value, CrcScope::Data))
+    }));
+    let message = skewed.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("records start offset"), "{}", message);
+}
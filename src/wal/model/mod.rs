@@ -1,6 +1,8 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 use crc32fast::Hasher;
-use crate::model::{SortedMapEntry, SortedMapKey};
+use crate::model::{SortedMapEntry, SortedMapKey, TxOp};
 
 pub const ACT_TYPE_FIELD_LEN: u8 = 1;
 pub const CRC32_FIELD_LEN: u8 = 4;
@@ -9,13 +11,257 @@ pub const BLOCK_START_OFFSET_LEN: u8 = 4;
 pub const FIXED_BLOCK_LEN: u8 =
     ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN + DATA_SIZE_FIELD_LEN + BLOCK_START_OFFSET_LEN;
 
+pub const WAL_MAGIC: &[u8; 4] = b"PGWL";
+pub const WAL_FORMAT_VERSION: u8 = 3;
+/// First format version whose CRC covers the whole block rather than only its data.
+pub const BLOCK_CRC_VERSION: u8 = 2;
+/// First format version with [`TIMESTAMPED_FLAG`].
+pub const TIMESTAMP_VERSION: u8 = 3;
+pub const MAGIC_FIELD_LEN: u8 = 4;
+pub const VERSION_FIELD_LEN: u8 = 1;
+pub const FLAGS_FIELD_LEN: u8 = 1;
+pub const HEADER_LEN: u8 = MAGIC_FIELD_LEN + VERSION_FIELD_LEN + FLAGS_FIELD_LEN;
+
+pub const NO_CRC_FLAG: u8 = 1;
+/// The header is followed by the logical length of the body, bytes past it (e.g. preallocated zeros) are not blocks.
+pub const PREALLOCATED_FLAG: u8 = 2;
+pub const LOGICAL_LEN_FIELD_LEN: u8 = 8;
+/// The header is followed by the alignment every block starts on, relative to the start of the file. The header is
+/// zero padded up to it and gaps between blocks are filled with [`PADDING_ACT`] blocks.
+pub const ALIGNED_FLAG: u8 = 4;
+pub const ALIGNMENT_FIELD_LEN: u8 = 4;
+/// The header is followed by the [`StoreType`] which wrote the WAL, after the alignment if both are present.
+pub const STORE_TYPE_FLAG: u8 = 8;
+pub const STORE_TYPE_FIELD_LEN: u8 = 1;
+/// Every write starts with a [`TIMESTAMP_ACT`] block holding the wall clock time of the write.
+pub const TIMESTAMPED_FLAG: u8 = 16;
+/// Bytes of the header with every optional field, before any padding.
+pub const MAX_HEADER_LEN: u8 = HEADER_LEN + LOGICAL_LEN_FIELD_LEN + ALIGNMENT_FIELD_LEN + STORE_TYPE_FIELD_LEN;
+pub const NO_CRC: u32 = 0;
+
 pub const DELETE_ACT: u8 = 0;
 pub const PUT_ACT: u8 = 1;
 pub const SET_APPEND_ACT: u8 = 2;
 pub const SET_REMOVE_ACT: u8 = 3;
 pub const MAP_PUT_ACT: u8 = 4;
 pub const MAP_REMOVE_ACT: u8 = 5;
+pub const SET_APPEND_MANY_ACT: u8 = 6;
+pub const MERGE_ACT: u8 = 7;
+pub const VERSIONED_PUT_ACT: u8 = 8;
+pub const PUT_MANY_ACT: u8 = 9;
+/// Start of a transaction: its changes and how to undo them, see [`TxRecord`].
+pub const TX_BEGIN_ACT: u8 = 11;
+/// The transaction with the id in the data was committed or rolled back, its undo is no longer needed.
+pub const TX_END_ACT: u8 = 12;
+/// Zero filled block carrying nothing, written only to move the next block to the alignment of the WAL.
+pub const PADDING_ACT: u8 = 10;
+/// Expiry of the key put by the preceding block, see [`ExpiryData`].
+pub const EXPIRE_ACT: u8 = 13;
+/// Member moved from one set to another, see [`SetMoveData`].
+pub const SET_MOVE_ACT: u8 = 14;
+/// Milliseconds since the Unix epoch the blocks following it were written at, up to the next one, see
+/// [`TIMESTAMPED_FLAG`].
+pub const TIMESTAMP_ACT: u8 = 15;
+
+
+/// Store whose blocks a WAL holds, recorded in the header so a WAL isn't replayed by the wrong store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreType {
+    KeyValue = 1,
+    KeySet = 2,
+    KeyMap = 3,
+    LinkedMap = 4,
+}
+
+impl StoreType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(StoreType::KeyValue),
+            2 => Some(StoreType::KeySet),
+            3 => Some(StoreType::KeyMap),
+            4 => Some(StoreType::LinkedMap),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StoreType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreType::KeyValue => write!(f, "key value"),
+            StoreType::KeySet => write!(f, "key set"),
+            StoreType::KeyMap => write!(f, "key map"),
+            StoreType::LinkedMap => write!(f, "linked map"),
+        }
+    }
+}
+
+/// Written once at the start of a WAL, blocks follow right after it and their offsets are relative to the end of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalHeader {
+    version: u8,
+    flags: u8,
+    alignment: u32,
+    store_type: Option<StoreType>,
+}
+
+impl WalHeader {
+    pub fn new(crc_enabled: bool) -> Self {
+        let flags = if crc_enabled { 0 } else { NO_CRC_FLAG };
+        WalHeader { version: WAL_FORMAT_VERSION, flags, alignment: 1, store_type: None }
+    }
+
+    /// Same header, recording `store_type`, see [`STORE_TYPE_FLAG`].
+    pub fn with_store_type(self, store_type: StoreType) -> Self {
+        WalHeader { flags: self.flags | STORE_TYPE_FLAG, store_type: Some(store_type), ..self }
+    }
+
+    /// Same header, with the time of each write recorded, see [`TIMESTAMPED_FLAG`].
+    pub fn timestamped(self) -> Self {
+        WalHeader { flags: self.flags | TIMESTAMPED_FLAG, ..self }
+    }
+
+    /// Header of a WAL in a preallocated file, see [`PREALLOCATED_FLAG`]. The logical length is written as 0 and
+    /// kept up to date by the writer.
+    pub fn new_preallocated() -> Self {
+        WalHeader { version: WAL_FORMAT_VERSION, flags: PREALLOCATED_FLAG, alignment: 1, store_type: None }
+    }
+
+    /// Header of a WAL whose blocks all start on a multiple of `alignment` bytes in the file, see [`ALIGNED_FLAG`].
+    pub fn new_aligned(alignment: u32) -> Self {
+        WalHeader { version: WAL_FORMAT_VERSION, flags: ALIGNED_FLAG, alignment, store_type: None }
+    }
 
+    /// WAL written before the header was introduced: no magic and every block has a CRC.
+    pub fn legacy() -> Self {
+        WalHeader { version: 0, flags: 0, alignment: 1, store_type: None }
+    }
+
+    /// Splits `bytes` into the header and the blocks following it, up to the logical length if the WAL is preallocated.
+    /// Bytes not starting with [`WAL_MAGIC`] are read as a legacy WAL without header.
+    pub fn split(bytes: &[u8]) -> (WalHeader, &[u8]) {
+        let (header, logical_len) = WalHeader::decode(bytes);
+        let body = bytes.get(header.encoded_len()..).unwrap_or_default();
+        match logical_len {
+            Some(logical_len) => (header, &body[..logical_len.min(body.len())]),
+            None => (header, body),
+        }
+    }
+
+    /// Header at the start of `bytes` with the logical length of the body if the WAL is preallocated, for readers
+    /// which only have the first [`MAX_HEADER_LEN`] bytes at hand, see [`WalHeader::split`].
+    pub fn decode(bytes: &[u8]) -> (WalHeader, Option<usize>) {
+        if bytes.len() < HEADER_LEN as usize || !bytes.starts_with(WAL_MAGIC) {
+            return (WalHeader::legacy(), None);
+        }
+        let version = bytes[MAGIC_FIELD_LEN as usize];
+        if version > WAL_FORMAT_VERSION {
+            panic!("not supported WAL format version: {}", version);
+        }
+        let flags = bytes[(MAGIC_FIELD_LEN + VERSION_FIELD_LEN) as usize];
+        let mut header = WalHeader { version, flags, alignment: 1, store_type: None };
+        let mut field_offset = HEADER_LEN as usize;
+        let mut logical_len = None;
+        if header.preallocated() {
+            let field = bytes.get(field_offset..field_offset + LOGICAL_LEN_FIELD_LEN as usize).unwrap_or_default();
+            logical_len = Some(field.try_into().map_or(0, u64::from_ne_bytes) as usize);
+            field_offset += LOGICAL_LEN_FIELD_LEN as usize;
+        }
+        if header.aligned() {
+            let field = bytes.get(field_offset..field_offset + ALIGNMENT_FIELD_LEN as usize).unwrap_or_default();
+            header.alignment = field.try_into().map_or(1, u32::from_ne_bytes).max(1);
+            field_offset += ALIGNMENT_FIELD_LEN as usize;
+        }
+        if header.flags & STORE_TYPE_FLAG != 0 {
+            header.store_type = bytes.get(field_offset).copied().and_then(StoreType::from_u8);
+        }
+        (header, logical_len)
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(WAL_MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.flags);
+        if self.preallocated() {
+            bytes.extend_from_slice(&0u64.to_ne_bytes());
+        }
+        if self.aligned() {
+            bytes.extend_from_slice(&self.alignment.to_ne_bytes());
+        }
+        if let Some(store_type) = self.store_type {
+            bytes.push(store_type as u8);
+        }
+        bytes.resize(self.encoded_len(), 0);
+        bytes
+    }
+
+    /// Number of bytes the header occupies in the WAL, a legacy WAL has none. An aligned header is padded up to the
+    /// alignment so the first block is aligned too.
+    pub fn encoded_len(&self) -> usize {
+        if self.version == 0 {
+            return 0;
+        }
+        let mut len = HEADER_LEN as usize;
+        if self.preallocated() {
+            len += LOGICAL_LEN_FIELD_LEN as usize;
+        }
+        if self.aligned() {
+            len += ALIGNMENT_FIELD_LEN as usize;
+        }
+        if self.flags & STORE_TYPE_FLAG != 0 {
+            len += STORE_TYPE_FIELD_LEN as usize;
+        }
+        len.next_multiple_of(self.alignment as usize)
+    }
+
+    pub fn preallocated(&self) -> bool {
+        self.flags & PREALLOCATED_FLAG != 0
+    }
+
+    pub fn aligned(&self) -> bool {
+        self.flags & ALIGNED_FLAG != 0
+    }
+
+    pub fn has_timestamps(&self) -> bool {
+        self.version >= TIMESTAMP_VERSION && self.flags & TIMESTAMPED_FLAG != 0
+    }
+
+    /// Store which wrote the WAL, `None` if it wasn't recorded (e.g. WALs written before it was).
+    pub fn store_type(&self) -> Option<StoreType> {
+        self.store_type
+    }
+
+    /// Multiple of bytes every block starts on in the file, 1 for a WAL that isn't aligned.
+    pub fn alignment(&self) -> u32 {
+        self.alignment
+    }
+
+    pub fn crc_enabled(&self) -> bool {
+        self.flags & NO_CRC_FLAG == 0
+    }
+
+    pub fn crc_scope(&self) -> CrcScope {
+        if !self.crc_enabled() {
+            CrcScope::None
+        } else if self.version >= BLOCK_CRC_VERSION {
+            CrcScope::Block
+        } else {
+            CrcScope::Data
+        }
+    }
+}
+
+/// What the CRC of a block is computed over, decided by the WAL header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcScope {
+    /// CRC isn't written, the field holds [`NO_CRC`].
+    None,
+    /// Only the data, as written by WALs before [`BLOCK_CRC_VERSION`].
+    Data,
+    /// Action type, data size, data and start offset, so a flip in any field besides the CRC itself is detected.
+    Block,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyValueData {
@@ -36,6 +282,136 @@ impl KeyValueData {
     }
 }
 
+/// `KeyValueData` followed by the version of the key. Fields are serialized in order and bincode allows
+/// trailing bytes, so readers interested only in the value deserialize it as `KeyValueData`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionedKeyValueData {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+
+    #[serde(with = "serde_bytes")]
+    value: Vec<u8>,
+
+    version: u64,
+}
+
+impl VersionedKeyValueData {
+    pub fn new(key: Vec<u8>, value: Vec<u8>, version: u64) -> Self {
+        VersionedKeyValueData { key, value, version }
+    }
+
+    pub fn owned_key_value_version(self) -> (Vec<u8>, Vec<u8>, u64) {
+        (self.key, self.value, self.version)
+    }
+}
+
+/// Time after which the value of a key is no longer returned, in milliseconds since the Unix epoch. Any later write
+/// of the key drops it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExpiryData {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+
+    expires_at: u64,
+}
+
+impl ExpiryData {
+    pub fn new(key: Vec<u8>, expires_at: u64) -> Self {
+        ExpiryData { key, expires_at }
+    }
+
+    pub fn owned_key_expires_at(self) -> (Vec<u8>, u64) {
+        (self.key, self.expires_at)
+    }
+}
+
+/// Removal of `member` from the set of `from_key` and its append to the set of `to_key`, in one block so a
+/// recovery sees the member in exactly one of them. `from_key` is deleted if the move leaves it empty.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMoveData {
+    #[serde(with = "serde_bytes")]
+    from_key: Vec<u8>,
+
+    #[serde(with = "serde_bytes")]
+    to_key: Vec<u8>,
+
+    #[serde(with = "serde_bytes")]
+    member: Vec<u8>,
+}
+
+impl SetMoveData {
+    pub fn new(from_key: Vec<u8>, to_key: Vec<u8>, member: Vec<u8>) -> Self {
+        SetMoveData { from_key, to_key, member }
+    }
+
+    pub fn owned_from_to_member(self) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        (self.from_key, self.to_key, self.member)
+    }
+}
+
+/// Puts of several keys in one block, so they are recovered all or none.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyValuesData {
+    entries: Vec<KeyValueData>,
+}
+
+impl KeyValuesData {
+    pub fn new(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+        let entries = entries.into_iter().map(|(key, value)| KeyValueData::new(key, value)).collect();
+        KeyValuesData { entries }
+    }
+
+    pub fn owned_entries(self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.entries.into_iter().map(KeyValueData::owned_key_value).collect()
+    }
+}
+
+/// Changes of a transaction together with the undo of each, taken before any change was applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TxRecord {
+    id: u64,
+    ops: Vec<TxOp>,
+    undo: Vec<TxOp>,
+}
+
+impl TxRecord {
+    pub fn new(id: u64, ops: Vec<TxOp>, undo: Vec<TxOp>) -> Self {
+        TxRecord { id, ops, undo }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn ops(&self) -> &[TxOp] {
+        &self.ops
+    }
+
+    /// Changes and their undo, in the order of the changes.
+    pub fn owned(self) -> (Vec<TxOp>, Vec<TxOp>) {
+        (self.ops, self.undo)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetElementsData {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+
+    elements: Vec<serde_bytes::ByteBuf>,
+}
+
+impl SetElementsData {
+    pub fn new(key: Vec<u8>, elements: Vec<Vec<u8>>) -> Self {
+        let elements = elements.into_iter().map(serde_bytes::ByteBuf::from).collect();
+        SetElementsData { key, elements }
+    }
+
+    pub fn owned_key_elements(self) -> (Vec<u8>, Vec<Vec<u8>>) {
+        (self.key, self.elements.into_iter().map(serde_bytes::ByteBuf::into_vec).collect())
+    }
+}
+
 #[derive(Debug)]
 pub struct StoredAction {
     act_type: u8,
@@ -52,62 +428,172 @@ impl StoredAction {
 }
 
 impl StoredAction {
-    pub fn put_action(offset: &u32, key_value: &KeyValueData) -> Self {
+    pub fn put_action(offset: &u32, key_value: &KeyValueData, crc_scope: CrcScope) -> Self {
         let act_type = PUT_ACT;
         let data = bincode::serialize(&key_value).expect("key_value should be serialized with bincode");
-        let crc = crc(&data);
         let data_size = data.len() as u32;
         let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
 
         StoredAction { act_type, crc, data_size, data, start_offset }
     }
 
-    pub fn delete_action(offset: &u32, key: &[u8]) -> Self {
+    pub fn versioned_put_action(offset: &u32, key_value_version: &VersionedKeyValueData, crc_scope: CrcScope) -> Self {
+        let act_type = VERSIONED_PUT_ACT;
+        let data = bincode::serialize(&key_value_version).expect("key_value_version should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn timestamp_action(offset: &u32, millis: u64, crc_scope: CrcScope) -> Self {
+        let act_type = TIMESTAMP_ACT;
+        let data = millis.to_ne_bytes().to_vec();
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    /// Milliseconds of a [`TIMESTAMP_ACT`] block, `None` for other blocks.
+    pub fn timestamp(&self) -> Option<u64> {
+        if self.act_type != TIMESTAMP_ACT {
+            return None;
+        }
+        self.data.as_slice().try_into().ok().map(u64::from_ne_bytes)
+    }
+
+    pub fn expire_action(offset: &u32, expiry: &ExpiryData, crc_scope: CrcScope) -> Self {
+        let act_type = EXPIRE_ACT;
+        let data = bincode::serialize(&expiry).expect("expiry should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn put_many_action(offset: &u32, key_values: &KeyValuesData, crc_scope: CrcScope) -> Self {
+        let act_type = PUT_MANY_ACT;
+        let data = bincode::serialize(&key_values).expect("key_values should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn delete_action(offset: &u32, key: &[u8], crc_scope: CrcScope) -> Self {
         let act_type = DELETE_ACT;
-        let crc = crc(key);
         let data = key.to_vec();
         let data_size = data.len() as u32;
         let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn merge_action(offset: &u32, key_operand: &KeyValueData, crc_scope: CrcScope) -> Self {
+        let act_type = MERGE_ACT;
+        let data = bincode::serialize(&key_operand).expect("key_operand should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
 
         StoredAction { act_type, crc, data_size, data, start_offset }
     }
 
-    pub fn append_to_set(offset: &u32, key_value: &KeyValueData) -> Self {
+    pub fn append_to_set(offset: &u32, key_value: &KeyValueData, crc_scope: CrcScope) -> Self {
         let act_type = SET_APPEND_ACT;
         let data = bincode::serialize(&key_value).expect("key_value should be serialized with bincode");
-        let crc = crc(&data);
         let data_size = data.len() as u32;
         let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn append_many_to_set(offset: &u32, key_elements: &SetElementsData, crc_scope: CrcScope) -> Self {
+        let act_type = SET_APPEND_MANY_ACT;
+        let data = bincode::serialize(&key_elements).expect("set elements should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
 
         StoredAction { act_type, crc, data_size, data, start_offset }
     }
 
-    pub fn remove_from_set(offset: &u32, key_value: &KeyValueData) -> Self {
+    pub fn remove_from_set(offset: &u32, key_value: &KeyValueData, crc_scope: CrcScope) -> Self {
         let act_type = SET_REMOVE_ACT;
         let data = bincode::serialize(&key_value).expect("key_value should be serialized with bincode");
-        let crc = crc(&data);
         let data_size = data.len() as u32;
         let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn set_move_action(offset: &u32, set_move: &SetMoveData, crc_scope: CrcScope) -> Self {
+        let act_type = SET_MOVE_ACT;
+        let data = bincode::serialize(&set_move).expect("set move should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
 
         StoredAction { act_type, crc, data_size, data, start_offset }
     }
 
-    pub fn put_to_sorted_map(offset: &u32, entry: &SortedMapEntry) -> Self {
+    pub fn put_to_sorted_map(offset: &u32, entry: &SortedMapEntry, crc_scope: CrcScope) -> Self {
         let act_type = MAP_PUT_ACT;
         let data = bincode::serialize(&entry).expect("sorted element should be serialized with bincode");
-        let crc = crc(&data);
         let data_size = data.len() as u32;
         let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
 
         StoredAction { act_type, crc, data_size, data, start_offset }
     }
 
-    pub fn remove_from_sorted_map(offset: &u32, search_map_key: &SortedMapKey) -> Self {
+    pub fn remove_from_sorted_map(offset: &u32, search_map_key: &SortedMapKey, crc_scope: CrcScope) -> Self {
         let act_type = MAP_REMOVE_ACT;
         let data = bincode::serialize(search_map_key).expect("map entry should be serialized with bincode");
-        let crc = crc(&data);
         let data_size = data.len() as u32;
         let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn tx_begin_action(offset: &u32, tx_record: &TxRecord, crc_scope: CrcScope) -> Self {
+        let act_type = TX_BEGIN_ACT;
+        let data = bincode::serialize(&tx_record).expect("tx record should be serialized with bincode");
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    pub fn tx_end_action(offset: &u32, tx_id: u64, crc_scope: CrcScope) -> Self {
+        let act_type = TX_END_ACT;
+        let data = tx_id.to_ne_bytes().to_vec();
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
+
+        StoredAction { act_type, crc, data_size, data, start_offset }
+    }
+
+    /// Block moving the next one from `offset` by `gap` bytes, its whole block length. `gap` must be at least
+    /// [`FIXED_BLOCK_LEN`].
+    pub fn padding_action(offset: &u32, gap: u32, crc_scope: CrcScope) -> Self {
+        let act_type = PADDING_ACT;
+        let data = vec![0; (gap - FIXED_BLOCK_LEN as u32) as usize];
+        let data_size = data.len() as u32;
+        let start_offset = *offset;
+        let crc = checksum(act_type, &data, start_offset, crc_scope);
 
         StoredAction { act_type, crc, data_size, data, start_offset }
     }
@@ -131,6 +617,37 @@ impl StoredAction {
     pub fn start_offset(&self) -> &u32 {
         &self.start_offset
     }
+
+    /// Length of the whole block: fixed fields plus data.
+    pub fn block_len(&self) -> usize {
+        FIXED_BLOCK_LEN as usize + self.data_size as usize
+    }
+
+    /// Whether the stored CRC matches the block, always `true` for [`CrcScope::None`].
+    pub fn valid_crc(&self, crc_scope: CrcScope) -> bool {
+        crc_scope == CrcScope::None || checksum(self.act_type, &self.data, self.start_offset, crc_scope) == self.crc
+    }
+
+    /// Same block starting at `start_offset`, with the CRC recomputed as the offset may be covered by it.
+    pub fn moved_to(self, start_offset: u32, crc_scope: CrcScope) -> Self {
+        let crc = checksum(self.act_type, &self.data, start_offset, crc_scope);
+        StoredAction { crc, start_offset, ..self }
+    }
+}
+
+fn checksum(act_type: u8, data: &[u8], start_offset: u32, crc_scope: CrcScope) -> u32 {
+    match crc_scope {
+        CrcScope::None => NO_CRC,
+        CrcScope::Data => crc(data),
+        CrcScope::Block => {
+            let mut hasher = Hasher::new();
+            hasher.update(&act_type.to_ne_bytes());
+            hasher.update(&(data.len() as u32).to_ne_bytes());
+            hasher.update(data);
+            hasher.update(&start_offset.to_ne_bytes());
+            hasher.finalize()
+        }
+    }
 }
 
 pub fn crc(bytes: &[u8]) -> u32 {
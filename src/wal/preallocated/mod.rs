@@ -0,0 +1,109 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::wal::model::*;
+use crate::wal::{ReadableWal, SyncWal};
+
+/// WAL writer over a file whose space is reserved up front with `set_len`, so appending blocks doesn't grow the
+/// file. The physical end is the reserved size (or beyond, once exceeded), the logical end is kept in the header
+/// and updated on every flush, after the blocks themselves are written: a crash in between loses the last block
+/// rather than exposing a partially written one, and readers never mistake the zero padding for blocks.
+pub struct PreallocatedFile {
+    file: File,
+    header_len: u64,
+    len: u64,
+}
+
+impl PreallocatedFile {
+    /// Creates `file_path` with `size` bytes reserved, the header is expected to be written by `WalStorage`.
+    pub fn create(file_path: &Path, header: WalHeader, size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(file_path)?;
+        file.set_len(size)?;
+
+        Ok(PreallocatedFile { file, header_len: header.encoded_len() as u64, len: 0 })
+    }
+
+    /// Length of the file up to the end of the last written block, the header included.
+    pub fn logical_len(&self) -> u64 {
+        self.len
+    }
+
+    fn write_logical_len(&mut self) -> io::Result<()> {
+        let body_len = self.len - self.header_len;
+        self.file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        self.file.write_all(&body_len.to_ne_bytes())?;
+        self.file.seek(SeekFrom::Start(self.len))?;
+        Ok(())
+    }
+}
+
+impl Write for PreallocatedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.len >= self.header_len {
+            self.write_logical_len()?;
+        }
+        self.file.flush()
+    }
+}
+
+impl SyncWal for PreallocatedFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl ReadableWal for PreallocatedFile {
+    fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        let mut file = &self.file;
+        let mut bytes = vec![0; self.len as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut bytes)?;
+        file.seek(SeekFrom::Start(self.len))?;
+        Ok(func(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{iter_actions, read_backward, read_forward, WalStorage};
+
+    #[test]
+    fn test_recover_with_trailing_zeros() {
+        let path = std::env::temp_dir().join(format!("pigment_db_preallocated_{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let wal = WalStorage::new_file_based_preallocated(&path, 64 * 1024).unwrap();
+        wal.store_put_event(b"a".to_vec(), b"A".to_vec()).unwrap();
+        wal.store_put_event(b"b".to_vec(), b"B".to_vec()).unwrap();
+        wal.store_put_event(b"a".to_vec(), b"AA".to_vec()).unwrap();
+        wal.store_delete_event(b"b").unwrap();
+        assert_eq!(wal.wal_stats().unwrap().blocks, 4);
+        drop(wal);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 64 * 1024);
+        assert!(bytes[bytes.len() - 1024..].iter().all(|byte| *byte == 0));
+
+        let (header, body) = WalHeader::split(&bytes);
+        assert!(header.preallocated());
+        assert_eq!(header.encoded_len(), (HEADER_LEN + LOGICAL_LEN_FIELD_LEN) as usize);
+        let blocks_len: usize = iter_actions(&bytes).map(|stored_action| stored_action.block_len()).sum();
+        assert_eq!(body.len(), blocks_len);
+        assert_eq!(iter_actions(&bytes).count(), 4);
+
+        let map = read_forward(&bytes);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(b"a".as_slice()), Some(&b"AA".to_vec()));
+        assert_eq!(read_backward(&bytes).unwrap(), map);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use log::warn;
+
+use crate::wal::model::*;
+use crate::wal::{try_iter_actions, write};
+
+/// What [`repair`] does with a block whose stored CRC doesn't match it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairPolicy {
+    /// Keeps the block, rewriting its CRC from the data as it is. Right only when the CRC field itself got
+    /// corrupted; corrupted data is accepted as valid from then on.
+    TrustDataRecomputeCrc,
+    /// Replaces the block with a padding block of the same length, so later blocks keep their offsets.
+    DropBadBlocks,
+}
+
+/// Copy of the WAL in `bytes` whose blocks all pass CRC verification, with the ones that didn't handled as `policy`
+/// says. Meant for an operator salvaging a WAL replay refuses, never applied automatically. Block headers must be
+/// intact: a block cut short, or whose data size runs past the end, ends the copy right before it.
+pub fn repair(bytes: &[u8], policy: RepairPolicy) -> Vec<u8> {
+    let (header, body) = WalHeader::split(bytes);
+    let header_len = bytes.len() - body.len();
+    let mut repaired = bytes[..header_len].to_vec();
+
+    for stored_action in try_iter_actions(bytes) {
+        let stored_action = match stored_action {
+            Ok(stored_action) => stored_action,
+            Err(offset) => {
+                warn!("dropping WAL bytes from the torn block at offset {}", offset);
+                break;
+            }
+        };
+        let stored_action = if stored_action.valid_crc(header.crc_scope()) {
+            stored_action
+        } else {
+            let offset = *stored_action.start_offset();
+            warn!("repairing block at offset {} with bad crc: {:?}", offset, policy);
+            match policy {
+                RepairPolicy::TrustDataRecomputeCrc => stored_action.moved_to(offset, header.crc_scope()),
+                RepairPolicy::DropBadBlocks => StoredAction::padding_action(&offset, stored_action.block_len() as u32, header.crc_scope()),
+            }
+        };
+        write(&mut repaired, std::slice::from_ref(&stored_action), None).expect("writing to a vec doesn't fail");
+    }
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{iter_actions, read_forward, WalStorage};
+
+    #[test]
+    fn test_repair_bad_crc_fields() {
+        let wal = WalStorage::new_vec_based();
+        for i in 0..5u8 {
+            wal.store_put_event(vec![i], vec![i]).unwrap();
+        }
+        let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+        let header_len = wal.header_len();
+        let (header, _) = WalHeader::split(&bytes);
+
+        let mut corrupted = bytes.clone();
+        for block in [1, 3] {
+            let offset = *iter_actions(&bytes).nth(block).unwrap().start_offset() as usize;
+            let crc_at = header_len + offset + ACT_TYPE_FIELD_LEN as usize;
+            corrupted[crc_at] ^= 0xff;
+        }
+        let invalid = iter_actions(&corrupted).filter(|stored_action| !stored_action.valid_crc(header.crc_scope())).count();
+        assert_eq!(invalid, 2);
+
+        let trusted = repair(&corrupted, RepairPolicy::TrustDataRecomputeCrc);
+        assert_eq!(trusted, bytes);
+        assert!(iter_actions(&trusted).all(|stored_action| stored_action.valid_crc(header.crc_scope())));
+        assert_eq!(read_forward(&trusted).len(), 5);
+
+        let dropped = repair(&corrupted, RepairPolicy::DropBadBlocks);
+        assert_eq!(dropped.len(), bytes.len());
+        assert!(iter_actions(&dropped).all(|stored_action| stored_action.valid_crc(header.crc_scope())));
+        let map = read_forward(&dropped);
+        assert_eq!(map.len(), 3);
+        assert!(!map.contains_key([1].as_slice()) && !map.contains_key([3].as_slice()));
+    }
+}
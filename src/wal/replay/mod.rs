@@ -0,0 +1,170 @@
+use crate::model::{SearchKey, SortedMapEntry, SortedMapKey};
+use crate::wal::model::*;
+use crate::wal::{replay_blocks, CorruptionPolicy, CorruptionReport, WalReadError};
+
+/// Receives the changes of a WAL replayed by [`replay`], in file order, to build a projection of it other than the
+/// built-in stores (e.g. a search index). Keys and values are passed as stored, i.e. still encoded by a
+/// [`crate::model::ValueTransformer`] if the store had one. Every method does nothing by default, so a visitor only
+/// implements the changes it cares about.
+pub trait ReplayVisitor {
+    fn on_put(&mut self, _key: Vec<u8>, _value: Vec<u8>) {}
+
+    /// A put recording the version of the value, [`ReplayVisitor::on_put`] by default.
+    fn on_versioned_put(&mut self, key: Vec<u8>, value: Vec<u8>, _version: u64) {
+        self.on_put(key, value)
+    }
+
+    /// Removal of `key`, whatever the kind of store.
+    fn on_delete(&mut self, _key: Vec<u8>) {}
+
+    fn on_merge(&mut self, _key: Vec<u8>, _operand: Vec<u8>) {}
+
+    /// Expiry of the current value of `key`, in milliseconds since the Unix epoch.
+    fn on_expire(&mut self, _key: Vec<u8>, _expires_at: u64) {}
+
+    fn on_set_append(&mut self, _key: Vec<u8>, _element: Vec<u8>) {}
+
+    fn on_set_remove(&mut self, _key: Vec<u8>, _element: Vec<u8>) {}
+
+    fn on_set_move(&mut self, _from_key: Vec<u8>, _to_key: Vec<u8>, _member: Vec<u8>) {}
+
+    fn on_map_put(&mut self, _key: Vec<u8>, _search_key: SearchKey, _element: Vec<u8>) {}
+
+    fn on_map_remove(&mut self, _key: Vec<u8>, _search_key: SearchKey) {}
+
+    fn on_tx_begin(&mut self, _tx_record: TxRecord) {}
+
+    fn on_tx_end(&mut self, _tx_id: u64) {}
+}
+
+/// Replays `bytes` of a WAL of any store into `visitor`. Blocks written together (e.g. by a `put_many`) are passed
+/// one change at a time, padding and timestamp blocks are skipped. Panics on a corrupted block like the built-in
+/// replays do, see [`replay_with_policy`] to tolerate them.
+pub fn replay(bytes: &[u8], visitor: &mut impl ReplayVisitor) -> Result<(), WalReadError> {
+    replay_with_policy(bytes, visitor, CorruptionPolicy::Abort).map(|_report| ())
+}
+
+/// Like [`replay`], dealing with corrupted blocks as `policy` says and reporting the dropped ones. A block of an
+/// unknown action type counts as undecodable.
+pub fn replay_with_policy(bytes: &[u8], visitor: &mut impl ReplayVisitor, policy: CorruptionPolicy)
+                          -> Result<CorruptionReport, WalReadError> {
+    replay_blocks(bytes, policy, |stored_action| {
+        match *stored_action.act_type() {
+            DELETE_ACT => visitor.on_delete(stored_action.data().to_vec()),
+            PUT_ACT => {
+                let (key, value) = bincode::deserialize::<KeyValueData>(stored_action.data())?.owned_key_value();
+                visitor.on_put(key, value);
+            }
+            PUT_MANY_ACT => {
+                let key_values: KeyValuesData = bincode::deserialize(stored_action.data())?;
+                for (key, value) in key_values.owned_entries() {
+                    visitor.on_put(key, value);
+                }
+            }
+            VERSIONED_PUT_ACT => {
+                let versioned: VersionedKeyValueData = bincode::deserialize(stored_action.data())?;
+                let (key, value, version) = versioned.owned_key_value_version();
+                visitor.on_versioned_put(key, value, version);
+            }
+            MERGE_ACT => {
+                let (key, operand) = bincode::deserialize::<KeyValueData>(stored_action.data())?.owned_key_value();
+                visitor.on_merge(key, operand);
+            }
+            EXPIRE_ACT => {
+                let (key, expires_at) = bincode::deserialize::<ExpiryData>(stored_action.data())?.owned_key_expires_at();
+                visitor.on_expire(key, expires_at);
+            }
+            SET_APPEND_ACT => {
+                let (key, element) = bincode::deserialize::<KeyValueData>(stored_action.data())?.owned_key_value();
+                visitor.on_set_append(key, element);
+            }
+            SET_APPEND_MANY_ACT => {
+                let (key, elements) = bincode::deserialize::<SetElementsData>(stored_action.data())?.owned_key_elements();
+                for element in elements {
+                    visitor.on_set_append(key.clone(), element);
+                }
+            }
+            SET_REMOVE_ACT => {
+                let (key, element) = bincode::deserialize::<KeyValueData>(stored_action.data())?.owned_key_value();
+                visitor.on_set_remove(key, element);
+            }
+            SET_MOVE_ACT => {
+                let set_move: SetMoveData = bincode::deserialize(stored_action.data())?;
+                let (from_key, to_key, member) = set_move.owned_from_to_member();
+                visitor.on_set_move(from_key, to_key, member);
+            }
+            MAP_PUT_ACT => {
+                let (key, search_key, element) = bincode::deserialize::<SortedMapEntry>(stored_action.data())?.entry();
+                visitor.on_map_put(key, search_key, element);
+            }
+            MAP_REMOVE_ACT => {
+                let (key, search_key) = bincode::deserialize::<SortedMapKey>(stored_action.data())?.owned();
+                visitor.on_map_remove(key, search_key);
+            }
+            TX_BEGIN_ACT => visitor.on_tx_begin(bincode::deserialize(stored_action.data())?),
+            TX_END_ACT => {
+                let tx_id = bincode::deserialize::<[u8; 8]>(stored_action.data()).map(u64::from_ne_bytes)?;
+                visitor.on_tx_end(tx_id);
+            }
+            PADDING_ACT => {}
+            act_type => return Err(Box::new(bincode::ErrorKind::Custom(format!("not supported action type: {}", act_type)))),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::wal::WalStorage;
+
+    /// Counts replayed changes per kind.
+    #[derive(Default)]
+    struct CountingVisitor {
+        counts: HashMap<&'static str, usize>,
+    }
+
+    impl CountingVisitor {
+        fn count(&mut self, kind: &'static str) {
+            *self.counts.entry(kind).or_insert(0) += 1;
+        }
+    }
+
+    impl ReplayVisitor for CountingVisitor {
+        fn on_put(&mut self, _key: Vec<u8>, _value: Vec<u8>) { self.count("put") }
+        fn on_delete(&mut self, _key: Vec<u8>) { self.count("delete") }
+        fn on_set_append(&mut self, _key: Vec<u8>, _element: Vec<u8>) { self.count("set_append") }
+        fn on_set_remove(&mut self, _key: Vec<u8>, _element: Vec<u8>) { self.count("set_remove") }
+        fn on_map_put(&mut self, _key: Vec<u8>, _search_key: SearchKey, _element: Vec<u8>) { self.count("map_put") }
+        fn on_tx_end(&mut self, _tx_id: u64) { self.count("tx_end") }
+    }
+
+    #[test]
+    fn test_replay_counts_changes() {
+        let wal = WalStorage::new_vec_based();
+        wal.store_put_event(b"a".to_vec(), b"1".to_vec()).unwrap();
+        wal.store_put_many_event(vec![(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]).unwrap();
+        wal.store_versioned_put_event(b"a".to_vec(), b"4".to_vec(), 2).unwrap();
+        wal.store_delete_event(b"b").unwrap();
+        wal.store_append_to_set_event(b"s".to_vec(), b"x".to_vec()).unwrap();
+        wal.store_append_many_to_set_event(b"s".to_vec(), vec![b"y".to_vec(), b"z".to_vec()]).unwrap();
+        wal.store_remove_from_set_event(b"s".to_vec(), b"x".to_vec()).unwrap();
+        wal.store_put_to_map_event(b"m".to_vec(), 1.into(), b"v".to_vec()).unwrap();
+        wal.store_merge_event(b"a".to_vec(), b"5".to_vec()).unwrap();
+        wal.store_tx_end_event(7).unwrap();
+        let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+        let mut visitor = CountingVisitor::default();
+        replay(&bytes, &mut visitor).unwrap();
+        assert_eq!(visitor.counts, HashMap::from([
+            ("put", 4), ("delete", 1), ("set_append", 3), ("set_remove", 1), ("map_put", 1), ("tx_end", 1),
+        ]));
+
+        let mut visitor = CountingVisitor::default();
+        let report = replay_with_policy(&bytes[..bytes.len() - 1], &mut visitor, CorruptionPolicy::TruncateAt).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(visitor.counts.get("tx_end"), None);
+        assert_eq!(visitor.counts.get("put"), Some(&4));
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::error;
+use memmap::MmapOptions;
+
+use crate::wal::model::*;
+use crate::wal::{try_build_action, AppendNotifier};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubConfig {
+    /// Pause between two passes.
+    pub interval: Duration,
+    /// Blocks verified by a pass add up to at least this many bytes, unless the written end is reached first.
+    pub bytes_per_pass: usize,
+}
+
+/// Background thread re-verifying CRCs of a file WAL, started by [`crate::wal::WalStorage::start_scrubber`].
+/// Each pass continues where the previous one stopped and the scrub starts over from the first block once the
+/// written end is reached, so a corrupted block is reported again on every round until the WAL is rewritten. Once
+/// it is, the scrub starts over from the first block of the new file. Stopped when dropped.
+pub struct Scrubber {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Reads through its own handle of the WAL file, which is mapped anew on every pass up to the current file length.
+    /// Appends only ever grow the file and a block is verified only once all its bytes are within the length, so
+    /// blocks being appended concurrently are left for a later pass. A rewrite renames a new file over `path`, which
+    /// is opened anew once `appended` tells of it. `on_corruption` gets the offset of each block failing CRC
+    /// verification, relative to the end of the header like block start offsets.
+    pub(crate) fn start(file: File, path: Option<PathBuf>, appended: Arc<AppendNotifier>, config: ScrubConfig,
+                        on_corruption: impl Fn(usize) + Send + 'static) -> io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = thread::Builder::new()
+            .name("pigment-wal-scrubber".to_string())
+            .spawn(move || {
+                let mut file = file;
+                let mut rewrites = appended.rewrites();
+                let mut offset = 0;
+                while !thread_stop.load(Ordering::Acquire) {
+                    let current_rewrites = appended.rewrites();
+                    if let Some(path) = path.as_ref().filter(|_| current_rewrites != rewrites) {
+                        match File::open(path) {
+                            Ok(rewritten_file) => {
+                                file = rewritten_file;
+                                rewrites = current_rewrites;
+                                offset = 0;
+                            }
+                            Err(error) => error!("opening rewritten WAL {} to scrub failed: {}", path.display(), error),
+                        }
+                    }
+                    if let Err(error) = scrub_pass(&file, &mut offset, config.bytes_per_pass, &on_corruption) {
+                        error!("WAL scrub pass failed: {}", error);
+                    }
+                    thread::park_timeout(config.interval);
+                }
+            })?;
+
+        Ok(Scrubber { stop, handle: Some(handle) })
+    }
+
+    /// Stops the scrubber, waiting for a pass in progress to finish.
+    pub fn stop(mut self) {
+        self.stop_thread();
+    }
+
+    fn stop_thread(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+/// Verifies blocks from `offset` on, leaving `offset` at the first block not verified yet (0 once the end was reached).
+fn scrub_pass(file: &File, offset: &mut usize, bytes_per_pass: usize, on_corruption: &impl Fn(usize)) -> io::Result<()> {
+    let file_len = file.metadata()?.len() as usize;
+    if file_len == 0 {
+        return Ok(());
+    }
+    let mmap = unsafe { MmapOptions::new().len(file_len).map(file)? };
+    let (header, body) = WalHeader::split(&mmap);
+
+    let mut verified_bytes = 0;
+    while verified_bytes < bytes_per_pass {
+        let block_offset = *offset;
+        if block_offset >= body.len() {
+            *offset = 0;
+            return Ok(());
+        }
+        let stored_action = match try_build_action(offset, body) {
+            Some(stored_action) => stored_action,
+            // still being written (or a corrupted size pointing past the end), retried by the next pass
+            None => return Ok(()),
+        };
+        if !stored_action.valid_crc(header.crc_scope()) {
+            on_corruption(block_offset);
+        }
+        verified_bytes += stored_action.block_len();
+    }
+    Ok(())
+}
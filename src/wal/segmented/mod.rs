@@ -0,0 +1,429 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::wal::model::*;
+use crate::wal::{try_iter_actions, Expirations, ReadableWal, SyncWal, VersionedMap};
+
+const SEGMENT_EXTENSION: &str = "dat";
+const KEY_INDEX_EXTENSION: &str = "keys";
+
+/// WAL writer split into numbered segment files (`<name>.00001.dat`, `<name>.00002.dat`, ...), each starting with
+/// the WAL header. When a flush leaves the current segment at `max_segment_bytes` or more, the next write goes to
+/// a new segment; `WalStorage` flushes after every block, so a block never spans two segments.
+///
+/// Block start offsets continue across segments, so the segments concatenated by [`SegmentedWal::read_segments`]
+/// form a regular WAL which any reader (including the backward one) accepts.
+///
+/// A segment of KeyValue blocks gets a key index (`<name>.NNNNN.keys`) listing the keys it touches when it's
+/// complete, which lets [`read_segments_newest_first`] skip segments without reading them.
+pub struct SegmentedWal {
+    dir: PathBuf,
+    name: String,
+    header: WalHeader,
+    max_segment_bytes: u64,
+    segment_no: u32,
+    segment: File,
+    segment_len: u64,
+}
+
+impl SegmentedWal {
+    /// Creates the first segment, the header is expected to be written by `WalStorage`.
+    pub fn create(dir: &Path, name: &str, header: WalHeader, max_segment_bytes: u64) -> io::Result<Self> {
+        let segment_no = 1;
+        let segment = create_segment(dir, name, segment_no)?;
+
+        Ok(SegmentedWal {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            header,
+            max_segment_bytes,
+            segment_no,
+            segment,
+            segment_len: 0,
+        })
+    }
+
+    /// Paths of all segments of `name` in `dir`, in write order.
+    pub fn segment_paths(dir: &Path, name: &str) -> io::Result<Vec<PathBuf>> {
+        let mut segments = Vec::new();
+        for dir_entry in std::fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let segment_no = path.file_name()
+                .and_then(|file_name| file_name.to_str())
+                .and_then(|file_name| parse_segment_no(file_name, name));
+            if let Some(segment_no) = segment_no {
+                segments.push((segment_no, path));
+            }
+        }
+        segments.sort();
+
+        Ok(segments.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Concatenates segments into a single WAL: the header of the first segment followed by the blocks of all of them.
+    pub fn read_segments(paths: &[PathBuf]) -> io::Result<Vec<u8>> {
+        let mut wal = Vec::new();
+        for path in paths {
+            let mut bytes = Vec::new();
+            File::open(path)?.read_to_end(&mut bytes)?;
+            if wal.is_empty() {
+                wal = bytes;
+            } else {
+                let (_header, body) = WalHeader::split(&bytes);
+                wal.extend_from_slice(body);
+            }
+        }
+        Ok(wal)
+    }
+
+    pub fn segment_no(&self) -> u32 {
+        self.segment_no
+    }
+
+    fn roll_over(&mut self) -> io::Result<()> {
+        let complete_path = segment_path(&self.dir, &self.name, self.segment_no);
+        if let Err(e) = write_key_index(&complete_path) {
+            warn!("no key index written for WAL segment {}: {}", complete_path.to_str().unwrap(), e);
+        }
+
+        self.segment_no += 1;
+        self.segment = create_segment(&self.dir, &self.name, self.segment_no)?;
+        self.segment_len = 0;
+        info!("rolled over WAL {} to segment {}", self.name, self.segment_no);
+
+        self.write_all(&self.header.to_bytes())?;
+        self.segment.flush()
+    }
+}
+
+impl Write for SegmentedWal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.segment.write(buf)?;
+        self.segment_len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.segment.flush()?;
+        if self.segment_len > HEADER_LEN as u64 && self.segment_len >= self.max_segment_bytes {
+            self.roll_over()?;
+        }
+        Ok(())
+    }
+}
+
+impl SyncWal for SegmentedWal {
+    /// Syncs the current segment, previous ones are complete and were flushed before the rollover.
+    fn sync(&mut self) -> io::Result<()> {
+        self.segment.sync_all()
+    }
+}
+
+impl ReadableWal for SegmentedWal {
+    fn read_written<R>(&self, func: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        let paths = SegmentedWal::segment_paths(&self.dir, &self.name)?;
+        let bytes = SegmentedWal::read_segments(&paths)?;
+        Ok(func(&bytes))
+    }
+}
+
+/// Moves segments of a previous run out of the way (prefixing them with a dot), so new segments can be created
+/// while the old ones are replayed. Returns the concatenated content and the moved paths, to be removed after replay.
+pub fn take_previous_segments(dir: &Path, name: &str) -> io::Result<Option<(Vec<u8>, Vec<PathBuf>)>> {
+    let moved = take_previous_segment_paths(dir, name)?;
+    if moved.is_empty() {
+        return Ok(None);
+    }
+    let bytes = SegmentedWal::read_segments(&moved)?;
+
+    Ok(Some((bytes, moved)))
+}
+
+/// Same as [`take_previous_segments`] without reading the segments, their key indexes are moved along.
+/// Returns the moved segment paths in write order, to be removed with [`remove_segments`] after replay.
+pub fn take_previous_segment_paths(dir: &Path, name: &str) -> io::Result<Vec<PathBuf>> {
+    let mut moved = Vec::new();
+    for path in SegmentedWal::segment_paths(dir, name)? {
+        let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or_default();
+        let tmp_path = dir.join(format!(".{}", file_name));
+        std::fs::rename(&path, &tmp_path)?;
+        if key_index_path(&path).exists() {
+            std::fs::rename(key_index_path(&path), key_index_path(&tmp_path))?;
+        }
+        moved.push(tmp_path);
+    }
+    Ok(moved)
+}
+
+/// Removes segments and their key indexes.
+pub fn remove_segments(paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        std::fs::remove_file(path)?;
+        if key_index_path(path).exists() {
+            std::fs::remove_file(key_index_path(path))?;
+        }
+    }
+    Ok(())
+}
+
+/// KeyValue state of the segments in `paths` (in write order) read by [`read_segments_newest_first`].
+#[derive(Debug)]
+pub struct NewestFirstRead {
+    pub entries: VersionedMap,
+    pub expirations: Expirations,
+    pub segments_read: usize,
+    /// Segments whose keys were all resolved by newer ones, so they weren't read at all.
+    pub segments_skipped: usize,
+}
+
+/// What reading backward found out about a key so far.
+enum KeyState {
+    /// Latest value, with the plain puts found so far, each adding one to the version on top of an older base.
+    Pending { value: Vec<u8>, puts: u64 },
+    /// Value and version, `None` if the key is deleted. Older blocks of the key don't matter anymore.
+    Resolved(Option<(Vec<u8>, u64)>),
+}
+
+/// Replays KeyValue segments from the newest block back, with the same result as
+/// [`crate::wal::read_forward_expiring_with_policy`] over all of them. A key is resolved by its latest delete or
+/// versioned put, which fixes the version; an older segment whose key index lists only resolved keys is skipped, as
+/// none of its blocks can change the result. The expiry of a key is the one written after its latest put.
+/// Store restores write a versioned put of every key first, so WALs with a few hot keys are mostly skipped.
+///
+/// Returns `Ok(None)` when the segments can't be read this way and should be replayed forward: a segment besides
+/// the newest has no key index, a block is torn or fails its CRC, or a merge has to be folded.
+pub fn read_segments_newest_first(paths: &[PathBuf]) -> io::Result<Option<NewestFirstRead>> {
+    let mut keys = HashMap::new();
+    let mut expirations = Expirations::new();
+    let mut segments_read = 0;
+    let mut segments_skipped = 0;
+
+    for (idx, path) in paths.iter().enumerate().rev() {
+        if idx + 1 < paths.len() {
+            let key_index = match read_key_index(path) {
+                Some(key_index) => key_index,
+                None => {
+                    info!("no key index for WAL segment {}", path.to_str().unwrap());
+                    return Ok(None);
+                }
+            };
+            if key_index.iter().all(|key| matches!(keys.get(key), Some(KeyState::Resolved(_)))) {
+                segments_skipped += 1;
+                continue;
+            }
+        }
+
+        let bytes = std::fs::read(path)?;
+        if !read_segment_backward(&bytes, &mut keys, &mut expirations) {
+            return Ok(None);
+        }
+        segments_read += 1;
+    }
+
+    let entries: VersionedMap = keys.into_iter()
+        .filter_map(|(key, state)| match state {
+            KeyState::Pending { value, puts } => Some((key, (value, puts))),
+            KeyState::Resolved(entry) => entry.map(|entry| (key, entry)),
+        })
+        .collect();
+    // an expiry written after a delete, with no put following it, doesn't apply
+    expirations.retain(|key, _| entries.contains_key(key));
+    Ok(Some(NewestFirstRead { entries, expirations, segments_read, segments_skipped }))
+}
+
+/// Applies the blocks of a segment to `keys` and `expirations` last one first, returns false if a block can't be
+/// applied backward.
+fn read_segment_backward(bytes: &[u8], keys: &mut HashMap<Vec<u8>, KeyState>, expirations: &mut Expirations) -> bool {
+    let (header, _body) = WalHeader::split(bytes);
+    let stored_actions = match try_iter_actions(bytes).collect::<Result<Vec<_>, _>>() {
+        Ok(stored_actions) => stored_actions,
+        Err(_) => return false,
+    };
+
+    stored_actions.into_iter().rev()
+        .all(|stored_action| stored_action.valid_crc(header.crc_scope()) && apply_backward(keys, expirations, stored_action).is_some())
+}
+
+/// Applies a block read backward to `keys` and `expirations`, `None` if it can't be decoded or has to be replayed
+/// forward.
+fn apply_backward(keys: &mut HashMap<Vec<u8>, KeyState>, expirations: &mut Expirations, stored_action: StoredAction) -> Option<()> {
+    match *stored_action.act_type() {
+        DELETE_ACT => resolve(keys, stored_action.data().to_vec(), None),
+        PUT_ACT => {
+            let put_action: KeyValueData = bincode::deserialize(stored_action.data()).ok()?;
+            let (key, value) = put_action.owned_key_value();
+            count_put(keys, key, value);
+        }
+        PUT_MANY_ACT => {
+            let put_action: KeyValuesData = bincode::deserialize(stored_action.data()).ok()?;
+            for (key, value) in put_action.owned_entries() {
+                count_put(keys, key, value);
+            }
+        }
+        VERSIONED_PUT_ACT => {
+            let put_action: VersionedKeyValueData = bincode::deserialize(stored_action.data()).ok()?;
+            let (key, value, version) = put_action.owned_key_value_version();
+            resolve(keys, key, Some((value, version)));
+        }
+        MERGE_ACT => {
+            let merge_action: KeyValueData = bincode::deserialize(stored_action.data()).ok()?;
+            let (key, _operand) = merge_action.owned_key_value();
+            if !matches!(keys.get(&key), Some(KeyState::Resolved(_))) {
+                return None;
+            }
+        }
+        EXPIRE_ACT => {
+            let expiry: ExpiryData = bincode::deserialize(stored_action.data()).ok()?;
+            let (key, expires_at) = expiry.owned_key_expires_at();
+            // only the latest expiry counts, and only if no put or delete of the key came after it
+            if !keys.contains_key(&key) && !expirations.contains_key(&key) {
+                expirations.insert(key, expires_at);
+            }
+        }
+        PADDING_ACT | TIMESTAMP_ACT => {}
+        _ => return None,
+    }
+    Some(())
+}
+
+fn count_put(keys: &mut HashMap<Vec<u8>, KeyState>, key: Vec<u8>, value: Vec<u8>) {
+    match keys.entry(key) {
+        Entry::Vacant(entry) => {
+            entry.insert(KeyState::Pending { value, puts: 1 });
+        }
+        Entry::Occupied(mut entry) => {
+            if let KeyState::Pending { puts, .. } = entry.get_mut() {
+                *puts += 1;
+            }
+        }
+    }
+}
+
+/// Resolves `key` on top of `base`, the entry left by a delete (`None`) or a versioned put.
+fn resolve(keys: &mut HashMap<Vec<u8>, KeyState>, key: Vec<u8>, base: Option<(Vec<u8>, u64)>) {
+    let state = keys.entry(key).or_insert(KeyState::Pending { value: Vec::new(), puts: 0 });
+    *state = match std::mem::replace(state, KeyState::Resolved(None)) {
+        KeyState::Pending { puts: 0, .. } => KeyState::Resolved(base),
+        KeyState::Pending { value, puts } => KeyState::Resolved(Some((value, base.map_or(0, |(_, version)| version) + puts))),
+        resolved => resolved,
+    };
+}
+
+/// Writes the keys touched by the complete segment at `segment_path`, unless it has other than KeyValue blocks.
+fn write_key_index(segment_path: &Path) -> io::Result<()> {
+    let bytes = std::fs::read(segment_path)?;
+    let mut keys = HashSet::new();
+    for stored_action in try_iter_actions(&bytes) {
+        let stored_action = stored_action
+            .map_err(|offset| io::Error::new(io::ErrorKind::InvalidData, format!("torn block at offset {}", offset)))?;
+        match block_keys(&stored_action) {
+            Some(block_keys) => keys.extend(block_keys),
+            None => return Ok(()),
+        }
+    }
+
+    let keys: Vec<Vec<u8>> = keys.into_iter().collect();
+    let encoded = bincode::serialize(&keys).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    std::fs::write(key_index_path(segment_path), encoded)
+}
+
+fn read_key_index(segment_path: &Path) -> Option<Vec<Vec<u8>>> {
+    let bytes = std::fs::read(key_index_path(segment_path)).ok()?;
+    bincode::deserialize(&bytes).ok()
+}
+
+/// Keys a KeyValue block touches, `None` for blocks of other stores.
+fn block_keys(stored_action: &StoredAction) -> Option<Vec<Vec<u8>>> {
+    match *stored_action.act_type() {
+        DELETE_ACT => Some(vec![stored_action.data().to_vec()]),
+        PUT_ACT | VERSIONED_PUT_ACT | MERGE_ACT => {
+            let key_value: KeyValueData = bincode::deserialize(stored_action.data()).ok()?;
+            Some(vec![key_value.owned_key_value().0])
+        }
+        PUT_MANY_ACT => {
+            let put_action: KeyValuesData = bincode::deserialize(stored_action.data()).ok()?;
+            Some(put_action.owned_entries().into_iter().map(|(key, _)| key).collect())
+        }
+        EXPIRE_ACT => {
+            let expiry: ExpiryData = bincode::deserialize(stored_action.data()).ok()?;
+            Some(vec![expiry.owned_key_expires_at().0])
+        }
+        PADDING_ACT | TIMESTAMP_ACT => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+fn key_index_path(segment_path: &Path) -> PathBuf {
+    segment_path.with_extension(KEY_INDEX_EXTENSION)
+}
+
+fn segment_path(dir: &Path, name: &str, segment_no: u32) -> PathBuf {
+    dir.join(format!("{}.{:05}.{}", name, segment_no, SEGMENT_EXTENSION))
+}
+
+fn create_segment(dir: &Path, name: &str, segment_no: u32) -> io::Result<File> {
+    OpenOptions::new().append(true).create_new(true).open(segment_path(dir, name, segment_no))
+}
+
+fn parse_segment_no(file_name: &str, name: &str) -> Option<u32> {
+    file_name.strip_prefix(name)?
+        .strip_prefix('.')?
+        .strip_suffix(SEGMENT_EXTENSION)?
+        .strip_suffix('.')?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::WalStorage;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pigment_db_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rollover() {
+        let dir = test_dir("segmented_rollover");
+        let wal = WalStorage::new_segmented(&dir, "kv.wal", 64).unwrap();
+
+        for i in 0..20u8 {
+            wal.store_put_event(vec![i], vec![i; 16]).unwrap();
+        }
+        wal.store_delete_event(&[0]).unwrap();
+
+        let paths = SegmentedWal::segment_paths(&dir, "kv.wal").unwrap();
+        assert!(paths.len() > 1);
+        assert!(paths[0].ends_with("kv.wal.00001.dat"));
+        for path in &paths {
+            let bytes = std::fs::read(path).unwrap();
+            assert!(bytes.starts_with(WAL_MAGIC));
+            assert!(crate::wal::try_iter_actions(&bytes).all(|action| action.is_ok()));
+        }
+
+        let bytes = SegmentedWal::read_segments(&paths).unwrap();
+        let forward = crate::wal::read_forward(&bytes);
+        assert_eq!(forward.len(), 19);
+        assert_eq!(forward.get(&vec![19]), Some(&vec![19; 16]));
+        assert_eq!(crate::wal::collect(&bytes), forward);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_segment_no() {
+        assert_eq!(parse_segment_no("kv.wal.00012.dat", "kv.wal"), Some(12));
+        assert_eq!(parse_segment_no(".kv.wal.00012.dat", "kv.wal"), None);
+        assert_eq!(parse_segment_no("set.wal.00001.dat", "kv.wal"), None);
+        assert_eq!(parse_segment_no("kv.wal.dat", "kv.wal"), None);
+    }
+}
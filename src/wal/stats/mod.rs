@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+use crate::model::{SearchKey, SortedMapEntry, SortedMapKey};
+use crate::wal::iter_actions;
+use crate::wal::model::*;
+
+/// Block counts and sizes of a WAL, see [`wal_stats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalStats {
+    /// Number of blocks per action type (`PUT_ACT`, `DELETE_ACT`, ...).
+    pub action_counts: BTreeMap<u8, usize>,
+    pub blocks: usize,
+    /// All WAL bytes, header included.
+    pub total_bytes: usize,
+    /// Bytes of blocks still contributing to the current state, the rest would be reclaimed by a compaction.
+    pub live_bytes: usize,
+}
+
+impl WalStats {
+    pub fn action_count(&self, act_type: u8) -> usize {
+        self.action_counts.get(&act_type).copied().unwrap_or(0)
+    }
+
+    pub fn live_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+        self.live_bytes as f64 / self.total_bytes as f64
+    }
+}
+
+pub fn wal_stats(bytes: &[u8]) -> WalStats {
+    stats_of(iter_actions(bytes), bytes.len())
+}
+
+/// Same as [`wal_stats`] for the blocks of a WAL `total_bytes` long, however they're read.
+pub(crate) fn stats_of(actions: impl Iterator<Item = StoredAction>, total_bytes: usize) -> WalStats {
+    let mut action_counts = BTreeMap::new();
+    let mut blocks = 0;
+    let actions = actions.inspect(|stored_action| {
+        *action_counts.entry(*stored_action.act_type()).or_insert(0) += 1;
+        blocks += 1;
+    });
+    let reclaimable: usize = reclaimable_of(actions).iter().map(|range| range.len()).sum();
+
+    WalStats { action_counts, blocks, total_bytes, live_bytes: total_bytes - reclaimable }
+}
+
+struct Block {
+    range: Range<usize>,
+    live_refs: usize,
+}
+
+#[derive(Default)]
+struct KeyBlocks {
+    value: Vec<usize>,
+    set_elements: HashMap<Vec<u8>, usize>,
+    map_entries: BTreeMap<SearchKey, usize>,
+}
+
+impl KeyBlocks {
+    fn all(self) -> impl Iterator<Item = usize> {
+        self.value.into_iter()
+            .chain(self.set_elements.into_values())
+            .chain(self.map_entries.into_values())
+    }
+}
+
+fn release(blocks: &mut [Block], idx: usize) {
+    blocks[idx].live_refs -= 1;
+}
+
+/// Byte ranges (relative to the end of the header) of blocks which no longer contribute to the state of any
+/// store type: superseded puts, removed set elements and map entries, deleted keys and the removals themselves.
+pub fn reclaimable_blocks(body: &[u8]) -> Vec<Range<usize>> {
+    reclaimable_of(iter_actions(body))
+}
+
+fn reclaimable_of(actions: impl Iterator<Item = StoredAction>) -> Vec<Range<usize>> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut keys: HashMap<Vec<u8>, KeyBlocks> = HashMap::new();
+
+    for stored_action in actions {
+        let start = *stored_action.start_offset() as usize;
+        let range = start..start + stored_action.block_len();
+        let idx = blocks.len();
+
+        let live_refs = match *stored_action.act_type() {
+            PUT_ACT | VERSIONED_PUT_ACT | MERGE_ACT => {
+                let put_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+                let (key, _) = put_action.owned_key_value();
+                let key_blocks = keys.entry(key).or_default();
+                if *stored_action.act_type() != MERGE_ACT {
+                    for superseded in key_blocks.value.drain(..) {
+                        release(&mut blocks, superseded);
+                    }
+                }
+                key_blocks.value.push(idx);
+                1
+            }
+            EXPIRE_ACT => {
+                let expiry: ExpiryData = bincode::deserialize(stored_action.data()).expect("ExpiryData should be deserialized");
+                let (key, _) = expiry.owned_key_expires_at();
+                keys.entry(key).or_default().value.push(idx);
+                1
+            }
+            PUT_MANY_ACT => {
+                let put_action: KeyValuesData = bincode::deserialize(stored_action.data()).expect("KeyValuesData should be deserialized");
+                let put_keys: HashSet<Vec<u8>> = put_action.owned_entries().into_iter().map(|(key, _)| key).collect();
+                let live_refs = put_keys.len();
+                for key in put_keys {
+                    let key_blocks = keys.entry(key).or_default();
+                    for superseded in key_blocks.value.drain(..) {
+                        release(&mut blocks, superseded);
+                    }
+                    key_blocks.value.push(idx);
+                }
+                live_refs
+            }
+            DELETE_ACT => {
+                if let Some(key_blocks) = keys.remove(stored_action.data()) {
+                    for deleted in key_blocks.all() {
+                        release(&mut blocks, deleted);
+                    }
+                }
+                0
+            }
+            SET_APPEND_ACT => {
+                let append_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+                let (key, element) = append_action.owned_key_value();
+                if let Some(superseded) = keys.entry(key).or_default().set_elements.insert(element, idx) {
+                    release(&mut blocks, superseded);
+                }
+                1
+            }
+            SET_APPEND_MANY_ACT => {
+                let append_action: SetElementsData = bincode::deserialize(stored_action.data()).expect("SetElementsData should be deserialized");
+                let (key, elements) = append_action.owned_key_elements();
+                let elements: HashSet<Vec<u8>> = elements.into_iter().collect();
+                let live_refs = elements.len();
+                let key_blocks = keys.entry(key).or_default();
+                for element in elements {
+                    if let Some(superseded) = key_blocks.set_elements.insert(element, idx) {
+                        release(&mut blocks, superseded);
+                    }
+                }
+                live_refs
+            }
+            SET_REMOVE_ACT => {
+                let remove_action: KeyValueData = bincode::deserialize(stored_action.data()).expect("KeyValueData should be deserialized");
+                let (key, element) = remove_action.owned_key_value();
+                if let Some(removed) = keys.get_mut(&key).and_then(|key_blocks| key_blocks.set_elements.remove(&element)) {
+                    release(&mut blocks, removed);
+                }
+                0
+            }
+            SET_MOVE_ACT => {
+                let move_action: SetMoveData = bincode::deserialize(stored_action.data()).expect("SetMoveData should be deserialized");
+                let (from_key, to_key, member) = move_action.owned_from_to_member();
+                if let Some(moved) = keys.get_mut(&from_key).and_then(|key_blocks| key_blocks.set_elements.remove(&member)) {
+                    release(&mut blocks, moved);
+                }
+                if let Some(superseded) = keys.entry(to_key).or_default().set_elements.insert(member, idx) {
+                    release(&mut blocks, superseded);
+                }
+                1
+            }
+            MAP_PUT_ACT => {
+                let put_action: SortedMapEntry = bincode::deserialize(stored_action.data()).expect("SortedMapEntry should be deserialized");
+                let (key, search_key, _) = put_action.entry();
+                if let Some(superseded) = keys.entry(key).or_default().map_entries.insert(search_key, idx) {
+                    release(&mut blocks, superseded);
+                }
+                1
+            }
+            MAP_REMOVE_ACT => {
+                let remove_action: SortedMapKey = bincode::deserialize(stored_action.data()).expect("SortedMapKey should be deserialized");
+                let (key, search_key) = remove_action.owned();
+                if let Some(removed) = keys.get_mut(&key).and_then(|key_blocks| key_blocks.map_entries.remove(&search_key)) {
+                    release(&mut blocks, removed);
+                }
+                0
+            }
+            PADDING_ACT | TIMESTAMP_ACT | TX_BEGIN_ACT | TX_END_ACT => 0,
+            _ => { panic!("not supported action type: {}", stored_action.act_type()) }
+        };
+        blocks.push(Block { range, live_refs });
+    }
+
+    blocks.into_iter()
+        .filter(|block| block.live_refs == 0)
+        .map(|block| block.range)
+        .collect()
+}
@@ -0,0 +1,337 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+
+use log::warn;
+use memmap::{Mmap, MmapOptions};
+
+use crate::error::PigmentError;
+use crate::model::{MergeOperator, ValueTransformer};
+use crate::wal::model::*;
+use crate::wal::stats::{stats_of, WalStats};
+use crate::wal::{replay_block_source, replay_for_map, replay_for_set, replay_forward_transformed, replay_blocks,
+                 update_backward_reading_map, BackwardReadError, BlockSource, CorruptionPolicy, CorruptionReport,
+                 Expirations, ReplaySource, Sets, SortedMaps, VersionedMap, WalMetrics};
+
+#[cfg(test)]
+thread_local! {
+    /// Makes [`map_or_stream`] fail to map files on this thread, to test the streaming fallback.
+    static MMAP_FAILS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// WAL file to recover from: mapped in memory, or left to be streamed when it can't be (e.g. bigger than the
+/// address space or the `vm.max_map_count` limit allows).
+pub(crate) enum WalContent {
+    Mapped(Mmap),
+    Streamed(File),
+}
+
+impl WalContent {
+    /// Replays the KeyValue WAL like [`crate::wal::read_forward_transformed_with_policy`], from memory or streamed.
+    /// Fails on a block `policy` doesn't deal with like reading the file does, see [`into_io`]. Each applied block is
+    /// timed by `metrics` if given.
+    pub(crate) fn read_forward_transformed(self, merge_operator: Option<&MergeOperator>, transformer: Option<&dyn ValueTransformer>,
+                                           policy: CorruptionPolicy, metrics: Option<&dyn WalMetrics>)
+                                           -> io::Result<(VersionedMap, Expirations, CorruptionReport)> {
+        into_io(replay_forward_transformed(self, merge_operator, transformer, policy, metrics))
+    }
+}
+
+impl ReplaySource for WalContent {
+    type Error = PigmentError;
+
+    fn replay(self, policy: CorruptionPolicy, apply: impl FnMut(StoredAction) -> bincode::Result<()>)
+              -> Result<CorruptionReport, PigmentError> {
+        match self {
+            WalContent::Mapped(mmap) => Ok(replay_blocks(mmap.as_ref(), policy, apply)?),
+            WalContent::Streamed(file) => StreamedWal(file).replay(policy, apply),
+        }
+    }
+}
+
+/// WAL read from a reader through a buffer, holding only a block at a time.
+struct StreamedWal<R: Read>(R);
+
+impl<R: Read> ReplaySource for StreamedWal<R> {
+    type Error = PigmentError;
+
+    fn replay(self, policy: CorruptionPolicy, apply: impl FnMut(StoredAction) -> bincode::Result<()>)
+              -> Result<CorruptionReport, PigmentError> {
+        let (header, mut source) = streamed_blocks(self.0)?;
+        let report = replay_block_source(&header, &mut source, policy, apply)?;
+        match source.error {
+            Some(error) => Err(PigmentError::Io(error)),
+            None => Ok(report),
+        }
+    }
+}
+
+/// Value of a replay of a [`WalContent`], a block its policy doesn't deal with fails it with `InvalidData` wrapping
+/// the [`PigmentError`], an error reading the file as it is.
+pub(crate) fn into_io<T>(result: Result<T, PigmentError>) -> io::Result<T> {
+    result.map_err(io::Error::from)
+}
+
+/// Maps `file` in memory, falling back to streaming it if the mapping fails instead of failing the recovery.
+pub(crate) fn map_or_stream(file: File) -> WalContent {
+    match map(&file) {
+        Ok(mmap) => WalContent::Mapped(mmap),
+        Err(error) => {
+            warn!("can't map WAL file in memory ({}), streaming it instead", error);
+            WalContent::Streamed(file)
+        }
+    }
+}
+
+fn map(file: &File) -> io::Result<Mmap> {
+    #[cfg(test)]
+    if MMAP_FAILS.with(|fails| fails.get()) {
+        return Err(io::Error::other("mapping disabled by test"));
+    }
+    unsafe { MmapOptions::new().map(file) }
+}
+
+/// Runs `f` with every [`map_or_stream`] of this thread failing to map the file.
+#[cfg(test)]
+pub(crate) fn with_mmap_failing<T>(f: impl FnOnce() -> T) -> T {
+    MMAP_FAILS.with(|fails| fails.set(true));
+    let result = f();
+    MMAP_FAILS.with(|fails| fails.set(false));
+    result
+}
+
+/// Same as [`crate::wal::read_forward_transformed_with_policy`] reading the WAL from `reader` through a buffer, for
+/// files which can't be mapped in memory. Only a block at a time is held, besides the replayed entries.
+pub fn read_forward_transformed_from_reader(reader: impl Read, merge_operator: Option<&MergeOperator>,
+                                            transformer: Option<&dyn ValueTransformer>, policy: CorruptionPolicy)
+                                            -> Result<(VersionedMap, Expirations, CorruptionReport), PigmentError> {
+    replay_forward_transformed(StreamedWal(reader), merge_operator, transformer, policy, None)
+}
+
+/// Same as [`crate::wal::read_for_set_with_policy`] reading the WAL from `reader`, see
+/// [`read_forward_transformed_from_reader`].
+pub fn read_for_set_from_reader(reader: impl Read, policy: CorruptionPolicy) -> Result<(Sets, CorruptionReport), PigmentError> {
+    replay_for_set(StreamedWal(reader), policy)
+}
+
+/// Same as [`crate::wal::read_for_map_with_policy`] reading the WAL from `reader`, see
+/// [`read_forward_transformed_from_reader`].
+pub fn read_for_map_from_reader(reader: impl Read, policy: CorruptionPolicy)
+                                -> Result<(SortedMaps, CorruptionReport), PigmentError> {
+    replay_for_map(StreamedWal(reader), policy)
+}
+
+/// Same as [`crate::wal::wal_stats`] reading the `total_bytes` of the WAL from `reader` a block at a time. Blocks
+/// after one cut short by the end of the WAL aren't counted.
+pub(crate) fn wal_stats_from_reader(reader: impl Read, total_bytes: usize) -> io::Result<WalStats> {
+    let (_header, mut source) = streamed_blocks(reader)?;
+    let mut blocks = &mut source;
+    let stats = stats_of(std::iter::from_fn(|| blocks.next_block()?.ok().map(|(_, stored_action)| stored_action)), total_bytes);
+    match source.error {
+        Some(error) => Err(error),
+        None => Ok(stats),
+    }
+}
+
+/// Header of the WAL of `reader` and the blocks of its body, read through a buffer.
+fn streamed_blocks(reader: impl Read) -> io::Result<(WalHeader, StreamedBlocks<impl Read>)> {
+    let mut reader = BufReader::new(reader);
+    let (header, read_ahead, logical_len) = read_header(&mut reader)?;
+    let body = Cursor::new(read_ahead).chain(reader).take(logical_len.map_or(u64::MAX, |len| len as u64));
+    Ok((header, StreamedBlocks { body, offset: 0, error: None }))
+}
+
+/// Same as [`crate::wal::read_backward`] seeking within `reader` from the last block back, instead of indexing a
+/// slice of the whole WAL.
+pub fn read_backward_seeking<R: Read + Seek>(reader: &mut R) -> Result<HashMap<Vec<u8>, Vec<u8>>, PigmentError> {
+    let file_len = reader.seek(SeekFrom::End(0))? as usize;
+    reader.seek(SeekFrom::Start(0))?;
+    let (header, _, logical_len) = read_header(reader)?;
+    let body_start = header.encoded_len();
+    let body_len = file_len.saturating_sub(body_start);
+    let body_len = logical_len.map_or(body_len, |logical_len| logical_len.min(body_len));
+
+    let mut result = HashMap::new();
+    let mut removed_keys = HashSet::new();
+    let mut block_end = body_len;
+    while block_end > 0 {
+        let block_start_len = BLOCK_START_OFFSET_LEN as usize;
+        if block_end < block_start_len {
+            return Err(PigmentError::Corruption { offset: block_end });
+        }
+        reader.seek(SeekFrom::Start((body_start + block_end - block_start_len) as u64))?;
+        let mut block_start_arr = [0; 4];
+        reader.read_exact(&mut block_start_arr)?;
+        let block_offset = u32::from_ne_bytes(block_start_arr) as usize;
+        if block_offset >= block_end {
+            return Err(PigmentError::Corruption { offset: block_end });
+        }
+
+        reader.seek(SeekFrom::Start((body_start + block_offset) as u64))?;
+        let block_len = block_end - block_offset;
+        let stored_action = match read_block(&mut reader.take(block_len as u64))? {
+            Some(Ok(stored_action)) if stored_action.block_len() == block_len => stored_action,
+            _ => return Err(PigmentError::Corruption { offset: block_end }),
+        };
+        update_backward_reading_map(&stored_action, &header, &mut result, &mut removed_keys).map_err(|error| match error {
+            BackwardReadError::Decode => PigmentError::Decode { offset: block_offset },
+            BackwardReadError::CrcMismatch => PigmentError::CrcMismatch { offset: block_offset },
+        })?;
+        block_end = block_offset;
+    }
+    Ok(result)
+}
+
+/// Reads the header from the first [`MAX_HEADER_LEN`] bytes of `reader`, leaving it at the start of the body.
+/// Returns the header, the bytes of the body read along with it and the logical length of a preallocated WAL.
+fn read_header(reader: &mut impl Read) -> io::Result<(WalHeader, Vec<u8>, Option<usize>)> {
+    let mut header_bytes = Vec::with_capacity(MAX_HEADER_LEN as usize);
+    reader.take(MAX_HEADER_LEN as u64).read_to_end(&mut header_bytes)?;
+    let (header, logical_len) = WalHeader::decode(&header_bytes);
+
+    let header_len = header.encoded_len();
+    if header_len > header_bytes.len() {
+        io::copy(&mut reader.take((header_len - header_bytes.len()) as u64), &mut io::sink())?;
+        return Ok((header, Vec::new(), logical_len));
+    }
+    Ok((header, header_bytes.split_off(header_len), logical_len))
+}
+
+/// Next block of `reader`, `None` at its end, `Err` with the bytes read of a block cut short by it.
+fn read_block(reader: &mut impl Read) -> io::Result<Option<Result<StoredAction, usize>>> {
+    let mut fixed = [0; (ACT_TYPE_FIELD_LEN + CRC32_FIELD_LEN + DATA_SIZE_FIELD_LEN) as usize];
+    let fixed_read = read_up_to(reader, &mut fixed)?;
+    if fixed_read == 0 {
+        return Ok(None);
+    }
+    if fixed_read < fixed.len() {
+        return Ok(Some(Err(fixed_read)));
+    }
+    let act_type = fixed[0];
+    let crc = u32::from_ne_bytes(fixed[1..5].try_into().unwrap());
+    let data_size = u32::from_ne_bytes(fixed[5..9].try_into().unwrap());
+
+    // read in chunks rather than allocated up front, the size of a corrupted block can be anything
+    let mut data = Vec::new();
+    reader.take(data_size as u64).read_to_end(&mut data)?;
+    let mut block_start_arr = [0; 4];
+    let block_start_read = if data.len() == data_size as usize { read_up_to(reader, &mut block_start_arr)? } else { 0 };
+    if block_start_read < block_start_arr.len() {
+        return Ok(Some(Err(fixed.len() + data.len() + block_start_read)));
+    }
+    Ok(Some(Ok(StoredAction::new(act_type, crc, data_size, data, u32::from_ne_bytes(block_start_arr)))))
+}
+
+/// Fills `buf` unless `reader` ends first, returns the bytes read.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read)
+}
+
+/// Blocks read one at a time from the body of a WAL. An I/O error ends them and is kept in `error`.
+struct StreamedBlocks<R: Read> {
+    body: R,
+    offset: usize,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> BlockSource for &mut StreamedBlocks<R> {
+    fn next_block(&mut self) -> Option<Result<(usize, StoredAction), usize>> {
+        let block_offset = self.offset;
+        match read_block(&mut self.body) {
+            Ok(None) => None,
+            Ok(Some(Ok(stored_action))) => {
+                self.offset += stored_action.block_len();
+                Some(Ok((block_offset, stored_action)))
+            }
+            Ok(Some(Err(read))) => {
+                self.offset += read;
+                Some(Err(block_offset))
+            }
+            Err(error) => {
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+
+    fn body_len(&mut self) -> usize {
+        match io::copy(&mut self.body, &mut io::sink()) {
+            Ok(rest) => self.offset += rest as usize,
+            Err(error) => self.error = Some(error),
+        }
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::SearchKey;
+    use crate::wal::{read_backward, read_for_map_with_policy, read_for_set_with_policy, read_forward_transformed_with_policy,
+                     wal_stats, WalStorage};
+
+    #[test]
+    fn test_streamed_reads_match_mapped_ones() {
+        let wal = WalStorage::new_vec_based();
+        for i in 0..100u8 {
+            wal.store_put_event(vec![i % 10], vec![i; i as usize]).unwrap();
+        }
+        wal.store_delete_event(&[3]).unwrap();
+        let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+        let (map, expirations, report) = read_forward_transformed_from_reader(bytes.as_slice(), None, None, CorruptionPolicy::Abort).unwrap();
+        let expected = read_forward_transformed_with_policy(&bytes, None, None, CorruptionPolicy::Abort).unwrap();
+        assert_eq!((map, expirations, report), expected);
+        assert_eq!(read_backward_seeking(&mut Cursor::new(&bytes)).unwrap(), read_backward(&bytes).unwrap());
+
+        let cut = &bytes[..bytes.len() - 3];
+        let (map, _, report) = read_forward_transformed_from_reader(cut, None, None, CorruptionPolicy::TruncateAt).unwrap();
+        let (expected_map, _, expected_report) = read_forward_transformed_with_policy(cut, None, None, CorruptionPolicy::TruncateAt).unwrap();
+        assert_eq!(map, expected_map);
+        assert_eq!(report, expected_report);
+        assert_eq!(report.truncated_bytes, report.dropped_bytes());
+        assert!(report.truncated_at.is_some());
+    }
+
+    #[test]
+    fn test_streamed_set_and_map_reads_match_mapped_ones() {
+        let wal = WalStorage::new_vec_based();
+        for i in 0..100u8 {
+            wal.store_append_to_set_event(vec![i % 10], vec![i]).unwrap();
+        }
+        wal.store_remove_from_set_event(vec![3], vec![13]).unwrap();
+        wal.store_delete_event(&[4]).unwrap();
+        let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+        let streamed = read_for_set_from_reader(bytes.as_slice(), CorruptionPolicy::Abort).unwrap();
+        assert_eq!(streamed, read_for_set_with_policy(&bytes, CorruptionPolicy::Abort).unwrap());
+        assert_eq!(streamed.0.len(), 9);
+        assert_eq!(wal_stats_from_reader(bytes.as_slice(), bytes.len()).unwrap(), wal_stats(&bytes));
+
+        let cut = &bytes[..bytes.len() - 3];
+        let streamed = read_for_set_from_reader(cut, CorruptionPolicy::TruncateAt).unwrap();
+        assert_eq!(streamed, read_for_set_with_policy(cut, CorruptionPolicy::TruncateAt).unwrap());
+        assert!(streamed.1.truncated_at.is_some());
+
+        let wal = WalStorage::new_vec_based();
+        for i in 0..100usize {
+            wal.store_put_to_map_event(vec![(i % 10) as u8], SearchKey::from(i), vec![i as u8]).unwrap();
+        }
+        wal.store_remove_from_sorted_map_event(vec![3], SearchKey::from(13)).unwrap();
+        let bytes = wal.read_bytes(|bytes| bytes.to_vec());
+
+        let streamed = read_for_map_from_reader(bytes.as_slice(), CorruptionPolicy::Abort).unwrap();
+        assert_eq!(streamed, read_for_map_with_policy(&bytes, CorruptionPolicy::Abort).unwrap());
+        assert_eq!(streamed.0[&vec![3]].len(), 9);
+        assert_eq!(wal_stats_from_reader(bytes.as_slice(), bytes.len()).unwrap(), wal_stats(&bytes));
+    }
+}